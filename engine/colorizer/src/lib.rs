@@ -0,0 +1,141 @@
+//! Maps values such as weights and neuron outputs to colors, so that every visualization of a network uses the same
+//! scale.  Shared by the web UI's layer visualizations and the driver.
+
+use std::mem::MaybeUninit;
+
+use libnn::Conv2DLayer;
+use palette::{
+    encoding::{Linear, Srgb},
+    rgb::Rgb,
+    Gradient,
+};
+
+const COLORIZER_LUT_SIZE: usize = 512;
+type ColorizerLUT = [[u8; 4]; COLORIZER_LUT_SIZE];
+
+static mut COLORIZER_LUT: *const ColorizerLUT = std::ptr::null();
+
+fn colorizer_lut() -> &'static ColorizerLUT { unsafe { &*COLORIZER_LUT } }
+
+const COLORIZER_LUT_RANGE: [f32; 2] = [-2.5, 2.5];
+
+fn build_lut(gradient: &Gradient<Rgb<Linear<Srgb>>>) -> ColorizerLUT {
+    let [min, max] = COLORIZER_LUT_RANGE;
+    let range = max - min;
+
+    let mut lut = MaybeUninit::<ColorizerLUT>::uninit();
+    for i in 0..COLORIZER_LUT_SIZE {
+        // We want to be able to look up in the LUT by raw, unscaled values from [-2.5, 2.5]
+        // The scaler converts the raw value to a value in [-1, 1]
+        // Then we convert that to a value in [0, COLORIZER_LUT_SIZE]
+        let x = min + (i as f32 / (COLORIZER_LUT_SIZE - 1) as f32) * range; // [-2.5, 2.5]
+        let scaled_x = scale_output(x); // [-1, 1]
+        let scaled_x = scaled_x + 1.0; // [0, 2]
+        let scaled_x = scaled_x / 2.0; // [0, 1]
+
+        let color = gradient.get(scaled_x);
+        unsafe {
+            (lut.as_mut_ptr() as *mut [u8; 4]).add(i).write([
+                (color.red * 255.) as u8,
+                (color.green * 255.) as u8,
+                (color.blue * 255.) as u8,
+                255,
+            ])
+        }
+    }
+
+    unsafe { lut.assume_init() }
+}
+
+fn build_even_color_steps(color_steps: &[[u8; 3]]) -> Vec<(f32, Rgb<Linear<Srgb>>)> {
+    color_steps
+        .iter()
+        .enumerate()
+        .map(|(i, [r, g, b])| {
+            (
+                i as f32 / ((color_steps.len() - 1) as f32),
+                Rgb::new(*r as f32 / 255., *g as f32 / 255., *b as f32 / 255.),
+            )
+        })
+        .collect()
+}
+
+pub fn initialize_colorizer_luts() {
+    if unsafe { !COLORIZER_LUT.is_null() } {
+        return;
+    }
+
+    let color_steps = build_even_color_steps(&[
+        [10, 243, 255],
+        [18, 194, 227],
+        [27, 145, 198],
+        [28, 99, 150],
+        [22, 58, 83],
+        [16, 16, 16],
+        [112, 112, 10],
+        [207, 207, 3],
+        [255, 204, 0],
+        [255, 102, 0],
+        [255, 0, 0],
+    ]);
+
+    let lut = build_lut(&Gradient::with_domain(color_steps));
+    unsafe { COLORIZER_LUT = Box::into_raw(Box::new(lut)) };
+}
+
+/// Scales inputs into the range [-1, 1]
+fn scale_output(value: f32) -> f32 { fastapprox::fast::tanh(value * 0.8) }
+
+fn clamp(min: f32, max: f32, val: f32) -> f32 {
+    if val < min {
+        min
+    } else if val > max {
+        max
+    } else {
+        val
+    }
+}
+
+pub fn colorize_output(val: f32) -> [u8; 4] {
+    let val = clamp(COLORIZER_LUT_RANGE[0], COLORIZER_LUT_RANGE[1], val);
+    // Scale val from [-2.5, 2.5] to [0, COLORIZER_LUT_SIZE]
+    let lut_ix = ((val + 2.5) * (COLORIZER_LUT_SIZE - 1) as f32 / 5.) as usize;
+    debug_assert!(lut_ix < COLORIZER_LUT_SIZE);
+
+    let lut = colorizer_lut();
+    unsafe { *lut.get_unchecked(lut_ix) }
+}
+
+/// The width and height in pixels of the image rendered by `colorize_conv_kernels` for `layer`.
+pub fn conv_kernels_image_size(layer: &Conv2DLayer) -> (usize, usize) {
+    (
+        layer.input_shape.channels * (layer.config.kernel_width + 1) - 1,
+        layer.config.out_channels * (layer.config.kernel_height + 1) - 1,
+    )
+}
+
+/// Renders the kernels of a convolution layer as an RGBA image with one row of tiles per output channel and one
+/// column per input channel.  Each tile is `kernel_width` by `kernel_height` pixels, and tiles are separated by a
+/// transparent pixel; see `conv_kernels_image_size` for the size of the whole image.
+pub fn colorize_conv_kernels(layer: &Conv2DLayer) -> Vec<u8> {
+    initialize_colorizer_luts();
+    let (kernel_height, kernel_width) = (layer.config.kernel_height, layer.config.kernel_width);
+    let in_channels = layer.input_shape.channels;
+    let (width, height) = conv_kernels_image_size(layer);
+
+    let mut buf = vec![0; width * height * 4];
+    for out_channel in 0..layer.config.out_channels {
+        let kernel = &layer.weights[out_channel];
+        for in_channel in 0..in_channels {
+            for kernel_y in 0..kernel_height {
+                for kernel_x in 0..kernel_width {
+                    let weight = kernel[(in_channel * kernel_height + kernel_y) * kernel_width + kernel_x];
+                    let y = out_channel * (kernel_height + 1) + kernel_y;
+                    let x = in_channel * (kernel_width + 1) + kernel_x;
+                    buf[(y * width + x) * 4..(y * width + x + 1) * 4].copy_from_slice(&colorize_output(weight));
+                }
+            }
+        }
+    }
+    buf
+}
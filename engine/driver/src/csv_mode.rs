@@ -0,0 +1,412 @@
+//! Trains a network on tabular data loaded from a CSV file and optionally writes predictions for another CSV file.
+//!
+//! Usage:
+//!
+//! ```text
+//! driver csv <train.csv> --target <column> [--target <column> ...] [--features <col1,col2,...>]
+//!     [--predict <input.csv>] [--out <predictions.csv>] [--no-header] [--delimiter <char>]
+//!     [--hidden <size1,size2,...>] [--learning-rate <rate>] [--epochs <count>] [--validation-fraction <fraction>]
+//!     [--categorical <col1,col2,...> [--embedding-dim <size>]]
+//! ```
+//!
+//! Columns can be given by name or by zero-based index.  If `--features` isn't given, every column other than the
+//! targets is used as an input.  Inputs are standardized to zero mean and unit variance using statistics from the
+//! training split, leaving out the validation examples.
+//!
+//! `--categorical` marks feature columns that hold integer category IDs starting at zero.  Rather than being
+//! standardized, each of them is replaced by a learned vector of `--embedding-dim` values (4 by default) before being
+//! fed into the first hidden layer.  Categorical columns have to be given by name unless the file has no header.
+//!
+//! Predictions are written to stdout when `--out` isn't given, so progress is logged to stderr to keep the output a
+//! valid CSV file.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+};
+
+use libnn::*;
+use rand::Rng;
+
+use crate::build_network;
+
+struct CsvModeArgs {
+    train_path: String,
+    targets: Vec<ColumnSelector>,
+    features: Option<Vec<ColumnSelector>>,
+    predict_path: Option<String>,
+    out_path: Option<String>,
+    options: CsvOptions,
+    hidden_layer_sizes: Vec<usize>,
+    learning_rate: Weight,
+    epochs: usize,
+    validation_fraction: f32,
+    categorical: Vec<ColumnSelector>,
+    embedding_dim: usize,
+}
+
+fn parse_list<T: std::str::FromStr>(flag: &str, value: &str) -> Result<Vec<T>, String> {
+    value
+        .split(',')
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| {
+            item.trim()
+                .parse()
+                .map_err(|_| format!("invalid value {:?} for {}", item, flag))
+        })
+        .collect()
+}
+
+fn parse_args(args: &[String]) -> Result<CsvModeArgs, String> {
+    let mut args = args.iter();
+    let train_path = args.next().ok_or("missing path to the training CSV")?.clone();
+    let mut parsed = CsvModeArgs {
+        train_path,
+        targets: Vec::new(),
+        features: None,
+        predict_path: None,
+        out_path: None,
+        options: CsvOptions::default(),
+        hidden_layer_sizes: vec![16],
+        learning_rate: 0.01,
+        epochs: 100,
+        validation_fraction: 0.2,
+        categorical: Vec::new(),
+        embedding_dim: 4,
+    };
+
+    while let Some(flag) = args.next() {
+        if flag == "--no-header" {
+            parsed.options.has_header = false;
+            continue;
+        }
+
+        let value = args.next().ok_or_else(|| format!("missing value for {}", flag))?;
+        let invalid = || format!("invalid value {:?} for {}", value, flag);
+        match flag.as_str() {
+            "--target" => parsed.targets.push(value.parse().unwrap()),
+            "--features" => parsed.features = Some(parse_list(flag, value)?),
+            "--predict" => parsed.predict_path = Some(value.clone()),
+            "--out" => parsed.out_path = Some(value.clone()),
+            "--delimiter" => {
+                let mut chars = value.chars();
+                parsed.options.delimiter = chars.next().ok_or_else(invalid)?;
+                if chars.next().is_some() {
+                    return Err(invalid());
+                }
+            },
+            "--hidden" => parsed.hidden_layer_sizes = parse_list(flag, value)?,
+            "--learning-rate" => parsed.learning_rate = value.parse().map_err(|_| invalid())?,
+            "--epochs" => parsed.epochs = value.parse().map_err(|_| invalid())?,
+            "--validation-fraction" => parsed.validation_fraction = value.parse().map_err(|_| invalid())?,
+            "--categorical" => parsed.categorical = parse_list(flag, value)?,
+            "--embedding-dim" => parsed.embedding_dim = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("unknown flag {}", flag)),
+        }
+    }
+
+    if parsed.targets.is_empty() {
+        return Err("at least one --target column is required".to_owned());
+    }
+    if parsed.hidden_layer_sizes.is_empty() || parsed.hidden_layer_sizes.contains(&0) {
+        return Err("--hidden must list at least one non-empty layer".to_owned());
+    }
+    if parsed.embedding_dim == 0 {
+        return Err("--embedding-dim must be greater than zero".to_owned());
+    }
+    if !(0. ..1.).contains(&parsed.validation_fraction) {
+        return Err("--validation-fraction must be at least 0 and less than 1".to_owned());
+    }
+    Ok(parsed)
+}
+
+fn load_csv(
+    path: &str,
+    options: &CsvOptions,
+    features: Option<&[ColumnSelector]>,
+    targets: &[ColumnSelector],
+) -> Result<CsvData, String> {
+    let file = File::open(path).map_err(|err| format!("couldn't open {}: {}", path, err))?;
+    read_csv(BufReader::new(file), options, features, targets).map_err(|err| format!("{}: {}", path, err))
+}
+
+/// Finds the categorical columns among the features and counts the categories in each of them.
+fn find_categorical_inputs(
+    categorical: &[ColumnSelector],
+    data: &CsvData,
+    has_header: bool,
+) -> Result<Vec<CategoricalInput>, String> {
+    let dims = data.feature_names.len();
+    categorical
+        .iter()
+        .map(|selector| {
+            let name = match (selector, has_header) {
+                (ColumnSelector::Name(name), _) => name.clone(),
+                (ColumnSelector::Index(ix), false) => ix.to_string(),
+                (ColumnSelector::Index(ix), true) =>
+                    return Err(format!("categorical column {} has to be given by name", ix)),
+            };
+            let input_ix = data
+                .feature_names
+                .iter()
+                .position(|feature_name| *feature_name == name)
+                .ok_or_else(|| format!("categorical column {:?} isn't one of the features", name))?;
+
+            let mut category_count = 0;
+            for example in data.dataset.inputs().chunks_exact(dims) {
+                let id = example[input_ix];
+                if id < 0. || id.fract() != 0. {
+                    return Err(format!("column {:?} holds {}, which isn't a category ID", name, id));
+                }
+                category_count = category_count.max(id as usize + 1);
+            }
+            Ok(CategoricalInput {
+                input_ix,
+                category_count,
+            })
+        })
+        .collect()
+}
+
+/// Per-feature mean and standard deviation, used to standardize inputs so that columns with large values don't
+/// swamp the rest or blow up training.
+struct Standardizer {
+    means: Vec<Weight>,
+    std_devs: Vec<Weight>,
+}
+
+impl Standardizer {
+    fn fit(dataset: &InMemoryDataset) -> Self {
+        let dims = dataset.input_dims();
+        let count = dataset.len().max(1) as Weight;
+        let mut means = vec![0.; dims];
+        for example in dataset.inputs().chunks_exact(dims) {
+            for (mean, &value) in means.iter_mut().zip(example) {
+                *mean += value / count;
+            }
+        }
+        let mut std_devs = vec![0.; dims];
+        for example in dataset.inputs().chunks_exact(dims) {
+            for ((std_dev, &mean), &value) in std_devs.iter_mut().zip(&means).zip(example) {
+                *std_dev += (value - mean) * (value - mean) / count;
+            }
+        }
+        for std_dev in &mut std_devs {
+            *std_dev = if *std_dev > 0. { std_dev.sqrt() } else { 1. };
+        }
+
+        Standardizer { means, std_devs }
+    }
+
+    /// Leaves the given inputs as they are.
+    fn skip(&mut self, input_ixs: &[usize]) {
+        for &ix in input_ixs {
+            self.means[ix] = 0.;
+            self.std_devs[ix] = 1.;
+        }
+    }
+
+    fn apply(&self, inputs: &[Weight]) -> Vec<Weight> {
+        inputs
+            .chunks_exact(self.means.len())
+            .flat_map(|example| {
+                example
+                    .iter()
+                    .zip(&self.means)
+                    .zip(&self.std_devs)
+                    .map(|((&value, &mean), &std_dev)| (value - mean) / std_dev)
+            })
+            .collect()
+    }
+
+    fn apply_to_dataset(&self, dataset: &InMemoryDataset) -> InMemoryDataset {
+        InMemoryDataset::new(
+            dataset.input_dims(),
+            dataset.output_dims(),
+            self.apply(dataset.inputs()),
+            dataset.targets().to_owned(),
+        )
+        .unwrap()
+    }
+}
+
+/// Quotes a CSV field if it contains anything that would otherwise break it up.
+fn escape_field(field: &str, delimiter: char) -> String {
+    if field.contains(delimiter) || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn write_predictions(
+    out: &mut dyn Write,
+    delimiter: char,
+    data: &CsvData,
+    target_names: &[String],
+    predictions: &[Weight],
+) -> std::io::Result<()> {
+    let delimiter_str = delimiter.to_string();
+    let header: Vec<String> = data
+        .feature_names
+        .iter()
+        .chain(target_names)
+        .map(|name| escape_field(name, delimiter))
+        .collect();
+    writeln!(out, "{}", header.join(&delimiter_str))?;
+
+    let inputs = data.dataset.inputs().chunks_exact(data.feature_names.len());
+    for (example, outputs) in inputs.zip(predictions.chunks_exact(target_names.len())) {
+        let row: Vec<String> = example.iter().chain(outputs).map(|value| value.to_string()).collect();
+        writeln!(out, "{}", row.join(&delimiter_str))?;
+    }
+    out.flush()
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = parse_args(args)?;
+    let mut rng = pcg::Pcg::default();
+
+    let mut training_data = load_csv(&args.train_path, &args.options, args.features.as_deref(), &args.targets)?;
+    eprintln!(
+        "Loaded {} examples from {} with features {:?} and targets {:?}",
+        training_data.dataset.len(),
+        args.train_path,
+        training_data.feature_names,
+        training_data.target_names
+    );
+    if training_data.dataset.is_empty() {
+        return Err(format!("{} doesn't contain any examples", args.train_path));
+    }
+
+    let categorical_inputs = find_categorical_inputs(&args.categorical, &training_data, args.options.has_header)?;
+    training_data.dataset.shuffle(&mut rng);
+    let splits = training_data
+        .dataset
+        .split(1. - args.validation_fraction, args.validation_fraction);
+    // Without a validation split, the network is validated against the data it's trained on
+    let raw_train = if splits.validation.is_empty() {
+        &training_data.dataset
+    } else {
+        &splits.train
+    };
+    // Only the training split is used to fit the standardizer so that nothing about the held out examples leaks into
+    // training
+    let mut standardizer = Standardizer::fit(raw_train);
+    standardizer.skip(
+        &categorical_inputs
+            .iter()
+            .map(|input| input.input_ix)
+            .collect::<Vec<_>>(),
+    );
+    let train = standardizer.apply_to_dataset(raw_train);
+    let validation = match splits.validation.is_empty() {
+        true => None,
+        false => Some(standardizer.apply_to_dataset(&splits.validation)),
+    };
+    let validation = validation.as_ref().unwrap_or(&train);
+
+    let embedding = match categorical_inputs.is_empty() {
+        true => None,
+        false => Some(EmbeddingLayer::new(
+            train.input_dims(),
+            categorical_inputs,
+            args.embedding_dim,
+            &mut |_, _| rng.gen_range(-0.5, 0.5),
+        )),
+    };
+    let dense_input_count = embedding
+        .as_ref()
+        .map_or(train.input_dims(), |layer| layer.output_count());
+    let layer_sizes: Vec<usize> = std::iter::once(dense_input_count)
+        .chain(args.hidden_layer_sizes.iter().copied())
+        .chain(std::iter::once(train.output_dims()))
+        .collect();
+    let mut feature_layers = Vec::new();
+    if let Some(embedding) = embedding {
+        eprintln!(
+            "Embedding {} categories into vectors of {} values",
+            embedding.vectors.row_count(),
+            embedding.dim()
+        );
+        feature_layers.push(FeatureLayer::Embedding(embedding));
+    }
+    let mut network = build_network(
+        feature_layers,
+        &layer_sizes,
+        &LeakyReLU,
+        &Identity,
+        &MeanSquaredError,
+        args.learning_rate,
+        &mut rng,
+    )
+    .map_err(|err| err.to_string())?;
+    let config = EarlyStoppingConfig {
+        eval_interval: train.len(),
+        max_iterations: train.len() * args.epochs,
+        ..Default::default()
+    };
+    let report = network
+        .train_with_early_stopping(
+            train.inputs(),
+            train.targets(),
+            validation.inputs(),
+            validation.targets(),
+            args.learning_rate,
+            &config,
+        )
+        .map_err(|err| err.to_string())?;
+    eprintln!(
+        "Stopped after {} epochs: {:?}; best validation cost={} after epoch {}",
+        report.iterations / train.len(),
+        report.stop_reason,
+        report.best_validation_cost,
+        report.best_iteration / train.len()
+    );
+
+    let predict_path = match &args.predict_path {
+        Some(predict_path) => predict_path,
+        None => return Ok(()),
+    };
+    // Pick the same columns out of the prediction file that the network was trained on
+    let features: Vec<ColumnSelector> = training_data
+        .feature_names
+        .iter()
+        .map(|name| match args.options.has_header {
+            true => ColumnSelector::Name(name.clone()),
+            false => name.parse().unwrap(),
+        })
+        .collect();
+    let prediction_data = load_csv(predict_path, &args.options, Some(&features), &[])?;
+
+    let inputs = standardizer.apply(prediction_data.dataset.inputs());
+    let mut predictions = Vec::with_capacity(prediction_data.dataset.len() * network.output_count());
+    for example in inputs.chunks_exact(network.input_count()) {
+        predictions.extend_from_slice(network.try_compute(example).map_err(|err| err.to_string())?);
+    }
+
+    let res = match &args.out_path {
+        Some(out_path) => {
+            let file = File::create(out_path).map_err(|err| format!("couldn't create {}: {}", out_path, err))?;
+            write_predictions(
+                &mut BufWriter::new(file),
+                args.options.delimiter,
+                &prediction_data,
+                &training_data.target_names,
+                &predictions,
+            )
+        },
+        None => write_predictions(
+            &mut std::io::stdout().lock(),
+            args.options.delimiter,
+            &prediction_data,
+            &training_data.target_names,
+            &predictions,
+        ),
+    };
+    res.map_err(|err| format!("couldn't write predictions: {}", err))?;
+    if let Some(out_path) = &args.out_path {
+        eprintln!("Wrote {} predictions to {}", prediction_data.dataset.len(), out_path);
+    }
+    Ok(())
+}
@@ -3,8 +3,61 @@ use std::io::BufRead;
 use libnn::*;
 use rand::prelude::*;
 
-fn main() {
-    let mut init_weights = |_output_ix: usize, _input_ix: usize| -> Weight { rand::thread_rng().gen_range(-1.0..1.0) };
+mod csv_mode;
+mod mnist_mode;
+mod sequence_mode;
+
+/// Builds a fully connected network with randomly initialized weights.  `layer_sizes` holds the number of inputs, then
+/// the size of each hidden layer and finally the number of outputs.  Weights are drawn uniformly from a range scaled by
+/// the number of inputs to each layer so that activations keep a similar magnitude as they go deeper.
+///
+/// `feature_layers` are run before the dense layers, so the number of inputs has to match the size of their outputs.
+fn build_network(
+    feature_layers: Vec<FeatureLayer>,
+    layer_sizes: &[usize],
+    hidden_activation_fn: &'static dyn ActivationFunction,
+    output_activation_fn: &'static dyn ActivationFunction,
+    cost_fn: &'static dyn CostFunction,
+    learning_rate: Weight,
+    rng: &mut pcg::Pcg,
+) -> Result<Network, NnError> {
+    assert!(layer_sizes.len() >= 2, "need at least an input and an output size");
+    let init_weights = |input_count: usize| {
+        let scale = (3. / input_count as Weight).sqrt();
+        move |rng: &mut pcg::Pcg| rng.gen_range(-scale, scale)
+    };
+
+    let hidden_layers = layer_sizes
+        .windows(2)
+        .take(layer_sizes.len() - 2)
+        .map(|sizes| {
+            let init_weight = init_weights(sizes[0]);
+            DenseLayer::new(
+                sizes[1],
+                sizes[0],
+                &mut |_, _| init_weight(rng),
+                &mut |_| 0.,
+                hidden_activation_fn,
+            )
+        })
+        .collect();
+
+    let input_count = layer_sizes[layer_sizes.len() - 2];
+    let init_weight = init_weights(input_count);
+    let outputs = OutputLayer::new(
+        output_activation_fn,
+        cost_fn,
+        &mut |_, _| init_weight(rng),
+        input_count,
+        layer_sizes[layer_sizes.len() - 1],
+    );
+    Network::new(feature_layers, hidden_layers, Box::new(outputs), learning_rate)
+}
+
+/// Trains a small network on a hardcoded function of two inputs and then evaluates inputs read from stdin.
+fn run_toy_example() {
+    let mut rng = pcg::Pcg::default();
+    let mut init_weights = |_output_ix: usize, _input_ix: usize| -> Weight { rng.gen_range(-1.0, 1.0) };
 
     let mut init_biases = |_neuron_ix| -> Weight { 0. };
 
@@ -13,8 +66,9 @@ fn main() {
     let learning_rate = 0.1;
     let hidden_layer_neuron_count = 10;
 
-    let mut network: Network = Network {
-        hidden_layers: vec![
+    let mut network: Network = Network::new(
+        Vec::new(),
+        vec![
             DenseLayer::new(
                 hidden_layer_neuron_count,
                 INPUT_COUNT,
@@ -30,7 +84,7 @@ fn main() {
                 &Tanh,
             ),
         ],
-        outputs: Box::new(OutputLayer::new(
+        Box::new(OutputLayer::new(
             &Identity,
             &MeanSquaredError,
             &mut init_weights,
@@ -38,27 +92,36 @@ fn main() {
             OUTPUT_COUNT,
         )),
         learning_rate,
-    };
+    )
+    .unwrap();
 
-    for _ in 0..2_000_000 {
-        let example_1 = rand::thread_rng().gen_range(-1.0..1.);
-        let example_2 = rand::thread_rng().gen_range(-1.0..1.);
-        let expected_output = &[if example_1 > 0.5 || example_2 > example_1 {
+    let mut dataset = InMemoryDataset::empty(INPUT_COUNT, OUTPUT_COUNT);
+    for _ in 0..110_000 {
+        let example_1 = rng.gen_range(-1.0, 1.);
+        let example_2 = rng.gen_range(-1.0, 1.);
+        let expected_output = if example_1 > 0.5 || example_2 > example_1 {
             1.
         } else {
             0.
-        }];
-
-        network.train_one_example(&[example_1, example_2], expected_output, learning_rate);
-
-        if network.outputs.costs[0] > 100_000. {
-            println!(
-                "hidden weight={:?}, hidden bias={:?}, output weight={:?}",
-                network.hidden_layers[0].weights, network.hidden_layers[0].biases, network.outputs.weights
-            );
-            panic!("Cost fn explosion");
-        }
+        };
+        dataset.push(&[example_1, example_2], &[expected_output]).unwrap();
     }
+    let splits = dataset.split(0.9, 0.1);
+
+    let report = network
+        .train_with_early_stopping(
+            splits.train.inputs(),
+            splits.train.targets(),
+            splits.validation.inputs(),
+            splits.validation.targets(),
+            learning_rate,
+            &EarlyStoppingConfig::default(),
+        )
+        .unwrap();
+    println!(
+        "Stopped after {} iterations: {:?}; best validation cost={} at iteration {}",
+        report.iterations, report.stop_reason, report.best_validation_cost, report.best_iteration
+    );
 
     println!(
         "hidden weight={:?}, hidden bias={:?}, output weight={:?}",
@@ -81,3 +144,25 @@ fn main() {
         println!("{:?}", network.compute(&[first, second]));
     }
 }
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let res = match args.first().map(String::as_str) {
+        None => {
+            run_toy_example();
+            Ok(())
+        },
+        Some("csv") => csv_mode::run(&args[1..]),
+        Some("mnist") => mnist_mode::run(&args[1..]),
+        Some("sequence") => sequence_mode::run(&args[1..]),
+        Some(mode) => Err(format!(
+            "unknown mode {:?}; run with no arguments for the toy example, `csv` to train on a CSV file, `mnist` to \
+             train on IDX files or `sequence` to train a recurrent network on a time series",
+            mode
+        )),
+    };
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
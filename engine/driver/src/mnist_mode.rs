@@ -0,0 +1,451 @@
+//! Trains a classifier on MNIST-style IDX files and reports its accuracy on the test set after every epoch.
+//!
+//! Usage:
+//!
+//! ```text
+//! driver mnist <data-dir> [--hidden <size1,size2,...>] [--learning-rate <rate>] [--epochs <count>]
+//!     [--train-limit <count>] [--batch-size <count> [--threads <count>] | --hogwild <threads> | --conv <channels>]
+//!     [--kernel-image <path.png>]
+//!     [--quantize <calibration-count>]
+//!     [--prune <target-sparsity> [--prune-rounds <count>] [--prune-scope <global|per-layer>] [--rewind <yes|no>]]
+//! ```
+//!
+//! `data-dir` must contain the four decompressed MNIST files (`train-images-idx3-ubyte`, `train-labels-idx1-ubyte`,
+//! `t10k-images-idx3-ubyte` and `t10k-labels-idx1-ubyte`).  Fashion-MNIST uses the same names and works as well.
+//!
+//! By default the weights are updated after every example.  With `--batch-size`, the gradients are averaged over
+//! mini-batches instead, and each batch is split across `--threads` threads (all available cores by default).
+//! Averaging makes each update smaller, so a larger learning rate is usually needed.
+//!
+//! `--hogwild` keeps updating after every example but splits the examples across several threads that all update the
+//! same weights without locking.  Compare its per-epoch costs and accuracies against a run without any of these flags
+//! to see how much the unsynchronized updates hurt convergence.
+//!
+//! `--conv` puts a convolution layer with `channels` 5x5 kernels and 2x2 max pooling in front of the hidden layers,
+//! turning the network into a small CNN.  Convolution layers are only supported when updating after every example, so
+//! it can't be combined with `--batch-size` or `--hogwild`.  `--kernel-image` writes the learned kernels to a PNG file
+//! after training, colored the same way as the weights in the web UI, with one row per output channel.
+//!
+//! `--prune` runs a prune-retrain schedule: after the initial `--epochs` of training, the weights with the smallest
+//! magnitudes are pruned, and the network is trained for `--epochs` more.  This repeats `--prune-rounds` times (3 by
+//! default), removing the same fraction of the remaining weights each round so that `target-sparsity` of them are
+//! pruned at the end.  With `--rewind yes` (the default), the surviving weights are reset to their initial values
+//! before re-training each round, as in lottery ticket experiments; with `--rewind no` training continues from the
+//! pruned weights instead.
+//!
+//! `--quantize` converts the trained network to 8-bit weights after the last epoch, calibrating on the first
+//! `calibration-count` training images, and reports how much accuracy was lost with both per-layer and per-channel
+//! weight scales.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+    thread,
+    time::Instant,
+};
+
+use libnn::*;
+use rand::Rng;
+
+use crate::build_network;
+
+const CLASS_COUNT: usize = 10;
+/// Each weight is drawn as a square of this many pixels in the kernel image.
+const KERNEL_IMAGE_SCALE: usize = 8;
+
+struct MnistModeArgs {
+    data_dir: String,
+    hidden_layer_sizes: Vec<usize>,
+    learning_rate: Weight,
+    epochs: usize,
+    train_limit: Option<usize>,
+    batch_size: Option<usize>,
+    thread_count: Option<usize>,
+    hogwild_thread_count: Option<usize>,
+    conv_channels: Option<usize>,
+    kernel_image_path: Option<String>,
+    calibration_count: Option<usize>,
+    pruning: Option<PruningSchedule>,
+}
+
+fn parse_args(args: &[String]) -> Result<MnistModeArgs, String> {
+    let mut args = args.iter();
+    let data_dir = args
+        .next()
+        .ok_or("missing path to the directory holding the IDX files")?
+        .clone();
+    let mut parsed = MnistModeArgs {
+        data_dir,
+        hidden_layer_sizes: vec![128],
+        learning_rate: 0.01,
+        epochs: 5,
+        train_limit: None,
+        batch_size: None,
+        thread_count: None,
+        hogwild_thread_count: None,
+        conv_channels: None,
+        kernel_image_path: None,
+        calibration_count: None,
+        pruning: None,
+    };
+    let mut pruning = PruningSchedule {
+        rounds: 3,
+        ..Default::default()
+    };
+    let mut prune = false;
+
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("missing value for {}", flag))?;
+        let invalid = || format!("invalid value {:?} for {}", value, flag);
+        match flag.as_str() {
+            "--hidden" =>
+                parsed.hidden_layer_sizes = value
+                    .split(',')
+                    .map(|size| size.trim().parse().map_err(|_| invalid()))
+                    .collect::<Result<_, _>>()?,
+            "--learning-rate" => parsed.learning_rate = value.parse().map_err(|_| invalid())?,
+            "--epochs" => parsed.epochs = value.parse().map_err(|_| invalid())?,
+            "--train-limit" => parsed.train_limit = Some(value.parse().map_err(|_| invalid())?),
+            "--batch-size" => parsed.batch_size = Some(value.parse().map_err(|_| invalid())?),
+            "--threads" => parsed.thread_count = Some(value.parse().map_err(|_| invalid())?),
+            "--hogwild" => parsed.hogwild_thread_count = Some(value.parse().map_err(|_| invalid())?),
+            "--conv" => parsed.conv_channels = Some(value.parse().map_err(|_| invalid())?),
+            "--kernel-image" => parsed.kernel_image_path = Some(value.clone()),
+            "--quantize" => parsed.calibration_count = Some(value.parse().map_err(|_| invalid())?),
+            "--prune" => {
+                prune = true;
+                pruning.target_sparsity = value.parse().map_err(|_| invalid())?;
+            },
+            "--prune-rounds" => pruning.rounds = value.parse().map_err(|_| invalid())?,
+            "--prune-scope" =>
+                pruning.scope = match value.as_str() {
+                    "global" => PruningScope::Global,
+                    "per-layer" => PruningScope::PerLayer,
+                    _ => return Err(invalid()),
+                },
+            "--rewind" =>
+                pruning.rewind = match value.as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(invalid()),
+                },
+            _ => return Err(format!("unknown flag {}", flag)),
+        }
+    }
+
+    if parsed.hidden_layer_sizes.contains(&0) {
+        return Err("hidden layers can't be empty".to_owned());
+    }
+    if parsed.batch_size == Some(0) || parsed.thread_count == Some(0) || parsed.hogwild_thread_count == Some(0) {
+        return Err("batch size and thread counts must be greater than zero".to_owned());
+    }
+    if parsed.conv_channels == Some(0) {
+        return Err("--conv needs at least one channel".to_owned());
+    }
+    if parsed.conv_channels.is_some() && (parsed.batch_size.is_some() || parsed.hogwild_thread_count.is_some()) {
+        return Err("--conv can't be combined with --batch-size or --hogwild".to_owned());
+    }
+    if parsed.kernel_image_path.is_some() && parsed.conv_channels.is_none() {
+        return Err("--kernel-image requires --conv".to_owned());
+    }
+    if parsed.calibration_count == Some(0) {
+        return Err("--quantize needs at least one calibration example".to_owned());
+    }
+    if prune {
+        if !(0. ..1.).contains(&pruning.target_sparsity) {
+            return Err("the target sparsity must be at least 0 and less than 1".to_owned());
+        }
+        parsed.pruning = Some(pruning);
+    }
+    if parsed.hogwild_thread_count.is_some() && parsed.batch_size.is_some() {
+        return Err("--hogwild can't be combined with --batch-size".to_owned());
+    }
+    if parsed.thread_count.is_some() && parsed.batch_size.is_none() {
+        return Err("--threads requires --batch-size".to_owned());
+    }
+    Ok(parsed)
+}
+
+/// Some mirrors name the files with a `.` instead of a `-` before the `idx`, so both are tried.
+fn load_idx(data_dir: &str, name: &str) -> Result<IdxArray, String> {
+    let dir = Path::new(data_dir);
+    let mut path = dir.join(name);
+    if !path.exists() {
+        path = dir.join(name.replacen("-idx", ".idx", 1));
+    }
+
+    let file = File::open(&path).map_err(|err| format!("couldn't open {}: {}", dir.join(name).display(), err))?;
+    read_idx(BufReader::new(file)).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// Returns the dataset along with the shape of each image.
+fn load_dataset(data_dir: &str, prefix: &str) -> Result<(InMemoryDataset, ImageShape), String> {
+    let images = load_idx(data_dir, &format!("{}-images-idx3-ubyte", prefix))?;
+    let labels = load_idx(data_dir, &format!("{}-labels-idx1-ubyte", prefix))?;
+    let image_shape = match images.dims[..] {
+        [_, height, width] => ImageShape::new(1, height, width),
+        _ => ImageShape::new(1, 1, images.entry_size()),
+    };
+    let dataset =
+        idx_classification_dataset(&images, &labels, CLASS_COUNT, 1. / 255.).map_err(|err| err.to_string())?;
+    Ok((dataset, image_shape))
+}
+
+/// A convolution layer with `channels` 5x5 kernels followed by 2x2 max pooling.  The kernels are padded so that the
+/// convolution keeps the size of the image.
+fn build_feature_layers(image_shape: ImageShape, channels: usize, rng: &mut pcg::Pcg) -> Vec<FeatureLayer> {
+    let config = Conv2DConfig {
+        out_channels: channels,
+        kernel_height: 5,
+        kernel_width: 5,
+        stride: 1,
+        padding: 2,
+    };
+    let scale = (3. / (image_shape.channels * config.kernel_height * config.kernel_width) as Weight).sqrt();
+    let conv = Conv2DLayer::new(
+        image_shape,
+        config,
+        &mut |_, _| rng.gen_range(-scale, scale),
+        &mut |_| 0.,
+        &LeakyReLU,
+    );
+    let pool = Pool2DLayer::new(conv.output_shape, PoolingMode::Max, 2, 2);
+    let flatten = FlattenLayer::new(pool.output_shape);
+
+    vec![
+        FeatureLayer::Conv2D(conv),
+        FeatureLayer::Pool2D(pool),
+        FeatureLayer::Flatten(flatten),
+    ]
+}
+
+enum Trainer {
+    /// Updates the weights after every example on the current thread
+    Sequential,
+    Batched(BatchTrainer, usize),
+    Hogwild(HogwildTrainer),
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = parse_args(args)?;
+    let mut rng = pcg::Pcg::default();
+
+    let (mut train, image_shape) = load_dataset(&args.data_dir, "train")?;
+    if let Some(train_limit) = args.train_limit {
+        if train_limit < train.len() {
+            train = train.subset(&(0..train_limit).collect::<Vec<_>>());
+        }
+    }
+    let (test, _) = load_dataset(&args.data_dir, "t10k")?;
+    if train.is_empty() || train.input_dims() != test.input_dims() {
+        return Err(format!(
+            "expected a non-empty training set with the same image size as the test set; got {} training images of {} \
+             pixels and test images of {} pixels",
+            train.len(),
+            train.input_dims(),
+            test.input_dims()
+        ));
+    }
+    println!(
+        "Loaded {} training and {} test images of {} pixels",
+        train.len(),
+        test.len(),
+        train.input_dims()
+    );
+
+    let feature_layers = match args.conv_channels {
+        Some(channels) => build_feature_layers(image_shape, channels, &mut rng),
+        None => Vec::new(),
+    };
+    let dense_input_count = feature_layers
+        .last()
+        .map_or(train.input_dims(), |layer| layer.output_shape().len());
+    let layer_sizes: Vec<usize> = std::iter::once(dense_input_count)
+        .chain(args.hidden_layer_sizes.iter().copied())
+        .chain(std::iter::once(CLASS_COUNT))
+        .collect();
+    if !feature_layers.is_empty() {
+        let shapes: Vec<String> = std::iter::once(image_shape)
+            .chain(feature_layers.iter().map(FeatureLayer::output_shape))
+            .map(|shape| format!("{}x{}x{}", shape.channels, shape.height, shape.width))
+            .collect();
+        println!("Feature layer shapes: {}", shapes.join(" -> "));
+    }
+    let mut network = build_network(
+        feature_layers,
+        &layer_sizes,
+        &LeakyReLU,
+        &Softmax,
+        &CrossEntropy,
+        args.learning_rate,
+        &mut rng,
+    )
+    .map_err(|err| err.to_string())?;
+
+    let mut trainer = match (args.batch_size, args.hogwild_thread_count) {
+        (Some(batch_size), _) => {
+            let thread_count = args
+                .thread_count
+                .unwrap_or_else(|| thread::available_parallelism().map_or(1, |count| count.get()));
+            println!(
+                "Training on batches of {} examples using {} threads",
+                batch_size, thread_count
+            );
+            let trainer = BatchTrainer::new(&network, thread_count).map_err(|err| err.to_string())?;
+            Trainer::Batched(trainer, batch_size)
+        },
+        (None, Some(thread_count)) => {
+            println!("Training with Hogwild using {} threads", thread_count);
+            Trainer::Hogwild(HogwildTrainer::new(&network, thread_count).map_err(|err| err.to_string())?)
+        },
+        (None, None) => Trainer::Sequential,
+    };
+
+    let initial_weights = network.snapshot();
+    let accuracy = network.classification_accuracy(&test).map_err(|err| err.to_string())?;
+    println!("Test accuracy before training: {:.2}%", accuracy * 100.);
+    train_epochs(&mut network, &mut trainer, &train, &test, &args, &mut rng)?;
+
+    if let Some(pruning) = &args.pruning {
+        let fraction_per_round = pruning.fraction_per_round();
+        for round_ix in 1..=pruning.rounds {
+            network.prune_by_magnitude(fraction_per_round, pruning.scope);
+            if pruning.rewind {
+                network.rewind_weights(&initial_weights);
+            }
+            let layer_sparsities: Vec<String> = network
+                .weight_sparsity()
+                .iter()
+                .map(|layer| format!("{:.1}%", layer.sparsity() * 100.))
+                .collect();
+            println!(
+                "Pruning round {}: {:.1}% of weights pruned (per layer: {})",
+                round_ix,
+                network.sparsity() * 100.,
+                layer_sparsities.join(", ")
+            );
+            train_epochs(&mut network, &mut trainer, &train, &test, &args, &mut rng)?;
+        }
+    }
+
+    if let Some(path) = &args.kernel_image_path {
+        if let Some(FeatureLayer::Conv2D(layer)) = network.feature_layers.first() {
+            write_kernel_image(layer, path).map_err(|err| format!("couldn't write {}: {}", path, err))?;
+            println!("Wrote the learned kernels to {}", path);
+        }
+    }
+
+    if let Some(calibration_count) = args.calibration_count {
+        report_quantization(&network, &train, &test, calibration_count)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the kernels of `layer` to a PNG file as laid out by `colorize_conv_kernels`, with every weight scaled up
+/// to a `KERNEL_IMAGE_SCALE` pixel square.
+fn write_kernel_image(layer: &Conv2DLayer, path: &str) -> Result<(), png::EncodingError> {
+    let kernels = colorizer::colorize_conv_kernels(layer);
+    let (width, height) = colorizer::conv_kernels_image_size(layer);
+
+    let scaled_width = width * KERNEL_IMAGE_SCALE;
+    let mut pixels = Vec::with_capacity(kernels.len() * KERNEL_IMAGE_SCALE * KERNEL_IMAGE_SCALE);
+    for y in 0..height * KERNEL_IMAGE_SCALE {
+        for x in 0..scaled_width {
+            let src_ix = (y / KERNEL_IMAGE_SCALE * width + x / KERNEL_IMAGE_SCALE) * 4;
+            pixels.extend_from_slice(&kernels[src_ix..src_ix + 4]);
+        }
+    }
+
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        scaled_width as u32,
+        (height * KERNEL_IMAGE_SCALE) as u32,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)
+}
+
+/// Trains for `args.epochs` epochs, printing the cost and test accuracy after each one.
+fn train_epochs(
+    network: &mut Network,
+    trainer: &mut Trainer,
+    train: &InMemoryDataset,
+    test: &InMemoryDataset,
+    args: &MnistModeArgs,
+    rng: &mut pcg::Pcg,
+) -> Result<(), String> {
+    for epoch in 1..=args.epochs {
+        let start = Instant::now();
+        let cost = match trainer {
+            Trainer::Sequential => network.fit(train, 1, rng).map(|costs| costs[0]),
+            Trainer::Batched(trainer, batch_size) => {
+                let mut total_cost = 0.;
+                for batch in train.shuffled_batches(*batch_size, rng).iter() {
+                    let batch_cost = trainer
+                        .train_batch(network, &batch, args.learning_rate)
+                        .map_err(|err| err.to_string())?;
+                    total_cost += batch_cost * batch.len() as Weight;
+                }
+                Ok(total_cost / train.len() as Weight)
+            },
+            Trainer::Hogwild(trainer) => trainer.train_epoch(network, train, args.learning_rate, rng),
+        }
+        .map_err(|err| err.to_string())?;
+        let elapsed = start.elapsed();
+
+        let accuracy = network.classification_accuracy(test).map_err(|err| err.to_string())?;
+        println!(
+            "Epoch {}: training cost={:.5}, test accuracy={:.2}% ({:.1}s)",
+            epoch,
+            cost,
+            accuracy * 100.,
+            elapsed.as_secs_f32()
+        );
+    }
+
+    Ok(())
+}
+
+fn report_quantization(
+    network: &Network,
+    train: &InMemoryDataset,
+    test: &InMemoryDataset,
+    calibration_count: usize,
+) -> Result<(), String> {
+    let calibration_data = train.subset(&(0..calibration_count.min(train.len())).collect::<Vec<_>>());
+    let float_bytes: usize = network
+        .hidden_layers
+        .iter()
+        .map(|layer| layer.weights.row_count() * layer.weights.col_count() + layer.biases.len())
+        .chain(std::iter::once(
+            network.outputs.weights.row_count() * network.outputs.weights.col_count(),
+        ))
+        .sum::<usize>()
+        * std::mem::size_of::<Weight>();
+
+    for &granularity in &[WeightGranularity::PerLayer, WeightGranularity::PerChannel] {
+        let start = Instant::now();
+        let mut quantized =
+            QuantizedNetwork::quantize(network, &calibration_data, granularity).map_err(|err| err.to_string())?;
+        let elapsed = start.elapsed();
+        let report = quantized.compare(network, test).map_err(|err| err.to_string())?;
+        println!(
+            "Quantized ({:?}, {:.1}s): test accuracy={:.2}% ({:+.2}%), predictions agree on {:.2}% of images, mean \
+             output error={:.5}, max output error={:.5}, {} bytes ({:.1}x smaller)",
+            granularity,
+            elapsed.as_secs_f32(),
+            report.quantized_accuracy * 100.,
+            report.accuracy_delta() * 100.,
+            report.argmax_agreement * 100.,
+            report.mean_output_error,
+            report.max_output_error,
+            quantized.parameter_bytes(),
+            float_bytes as f32 / quantized.parameter_bytes() as f32
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,195 @@
+//! Trains a recurrent network to predict the next value of a synthetic time series and compares its error on held out
+//! data against always predicting the last value seen.
+//!
+//! Usage:
+//!
+//! ```text
+//! driver sequence [--cell <rnn|lstm|gru>] [--hidden <size1,size2,...>] [--learning-rate <rate>] [--epochs <count>]
+//!     [--window <timesteps>] [--bptt <timesteps>] [--length <count>]
+//! ```
+//!
+//! The series is the sum of two sine waves with different periods plus a bit of noise, `length` values long.  The
+//! first 80% of it is cut into windows of `window` timesteps to train on, and the network is trained to output the
+//! next value of the series at every timestep of each window.  With `--bptt`, backpropagation through time is
+//! truncated to chunks of that many timesteps instead of running over whole windows.
+
+use std::time::Instant;
+
+use libnn::*;
+use rand::{seq::SliceRandom, Rng};
+
+struct SequenceModeArgs {
+    cell_type: CellType,
+    hidden_layer_sizes: Vec<usize>,
+    learning_rate: Weight,
+    epochs: usize,
+    window: usize,
+    bptt_steps: Option<usize>,
+    length: usize,
+}
+
+fn parse_args(args: &[String]) -> Result<SequenceModeArgs, String> {
+    let mut args = args.iter();
+    let mut parsed = SequenceModeArgs {
+        cell_type: CellType::Lstm,
+        hidden_layer_sizes: vec![16],
+        learning_rate: 0.01,
+        epochs: 20,
+        window: 30,
+        bptt_steps: None,
+        length: 5000,
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("missing value for {}", flag))?;
+        let invalid = || format!("invalid value {:?} for {}", value, flag);
+        match flag.as_str() {
+            "--cell" =>
+                parsed.cell_type = match value.as_str() {
+                    "rnn" => CellType::Rnn,
+                    "lstm" => CellType::Lstm,
+                    "gru" => CellType::Gru,
+                    _ => return Err(invalid()),
+                },
+            "--hidden" =>
+                parsed.hidden_layer_sizes = value
+                    .split(',')
+                    .map(|size| size.trim().parse().map_err(|_| invalid()))
+                    .collect::<Result<_, _>>()?,
+            "--learning-rate" => parsed.learning_rate = value.parse().map_err(|_| invalid())?,
+            "--epochs" => parsed.epochs = value.parse().map_err(|_| invalid())?,
+            "--window" => parsed.window = value.parse().map_err(|_| invalid())?,
+            "--bptt" => parsed.bptt_steps = Some(value.parse().map_err(|_| invalid())?),
+            "--length" => parsed.length = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("unknown flag {}", flag)),
+        }
+    }
+
+    if parsed.hidden_layer_sizes.is_empty() || parsed.hidden_layer_sizes.contains(&0) {
+        return Err("--hidden must list at least one non-empty layer".to_owned());
+    }
+    if parsed.window == 0 || parsed.bptt_steps == Some(0) {
+        return Err("--window and --bptt must be greater than zero".to_owned());
+    }
+    if parsed.length < 10 * (parsed.window + 1) {
+        return Err("--length must be at least 10 times longer than --window".to_owned());
+    }
+    Ok(parsed)
+}
+
+fn generate_series(length: usize, rng: &mut pcg::Pcg) -> Vec<Weight> {
+    (0..length)
+        .map(|t| {
+            let t = t as Weight;
+            0.6 * (t * 0.13).sin() + 0.3 * (t * 0.031).sin() + rng.gen_range(-0.05, 0.05)
+        })
+        .collect()
+}
+
+/// A slice of the series along with the next value after each of its timesteps.
+struct Window {
+    inputs: Vec<[Weight; 1]>,
+    targets: Vec<[Weight; 1]>,
+}
+
+/// Splits `series` into consecutive windows.
+fn windows(series: &[Weight], window: usize) -> Vec<Window> {
+    series
+        .windows(window + 1)
+        .step_by(window)
+        .map(|values| {
+            let inputs = values[..window].iter().map(|&value| [value]).collect();
+            let targets = values[1..].iter().map(|&value| [value]).collect();
+            Window { inputs, targets }
+        })
+        .collect()
+}
+
+fn build_sequence_network(args: &SequenceModeArgs, rng: &mut pcg::Pcg) -> Result<SequenceNetwork, String> {
+    let mut input_count = 1;
+    let mut recurrent_layers = Vec::new();
+    for &hidden_size in &args.hidden_layer_sizes {
+        let scale = (1. / (input_count + hidden_size) as f32).sqrt();
+        recurrent_layers.push(RecurrentLayer::new(
+            args.cell_type,
+            input_count,
+            hidden_size,
+            &mut |_, _| rng.gen_range(-scale, scale),
+        ));
+        input_count = hidden_size;
+    }
+    let scale = (1. / input_count as f32).sqrt();
+    let outputs = OutputLayer::new(
+        &IDENTITY,
+        &MEAN_SQUARED_ERROR,
+        &mut |_, _| rng.gen_range(-scale, scale),
+        input_count,
+        1,
+    );
+
+    let mut network = SequenceNetwork::new(recurrent_layers, Box::new(outputs)).map_err(|err| err.to_string())?;
+    network.bptt_steps = args.bptt_steps;
+    Ok(network)
+}
+
+/// The mean squared error of predicting the next value at every timestep of every window.
+fn evaluate(network: &mut SequenceNetwork, windows: &[Window]) -> Result<Weight, String> {
+    let mut total_cost = 0.;
+    let mut count = 0;
+    for window in windows {
+        let outputs = network
+            .compute_sequence(&window.inputs)
+            .map_err(|err| err.to_string())?;
+        for (output, target) in outputs.iter().zip(&window.targets) {
+            total_cost += (target[0] - output[0]) * (target[0] - output[0]);
+            count += 1;
+        }
+    }
+    Ok(total_cost / count as Weight)
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = parse_args(args)?;
+    let mut rng = pcg::Pcg::default();
+    let series = generate_series(args.length, &mut rng);
+    let split_ix = args.length * 4 / 5;
+    let mut train = windows(&series[..split_ix], args.window);
+    let test = windows(&series[split_ix..], args.window);
+    println!(
+        "Training a {:?} network with hidden layers of {:?} on {} windows of {} timesteps",
+        args.cell_type,
+        args.hidden_layer_sizes,
+        train.len(),
+        args.window
+    );
+
+    let baseline_cost = test
+        .iter()
+        .flat_map(|window| window.inputs.iter().zip(&window.targets))
+        .map(|(input, target)| (target[0] - input[0]) * (target[0] - input[0]))
+        .sum::<Weight>()
+        / (test.len() * args.window) as Weight;
+    println!("Predicting the last value: test MSE={:.5}", baseline_cost);
+
+    let mut network = build_sequence_network(&args, &mut rng)?;
+    for epoch in 1..=args.epochs {
+        let start = Instant::now();
+        train.shuffle(&mut rng);
+        let mut total_cost = 0.;
+        for window in &train {
+            total_cost += network
+                .train_sequence(&window.inputs, &window.targets, args.learning_rate)
+                .map_err(|err| err.to_string())?;
+        }
+        let test_cost = evaluate(&mut network, &test)?;
+        println!(
+            "Epoch {}: training MSE={:.5}, test MSE={:.5} ({:.1}s)",
+            epoch,
+            total_cost / train.len() as Weight,
+            test_cost,
+            start.elapsed().as_secs_f32()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+#![feature(test)]
+
+extern crate test;
+
+use libnn::*;
+use rand::Rng;
+use test::Bencher;
+
+/// Roughly the shape of a network used for MNIST
+const LAYER_SIZES: [usize; 3] = [784, 128, 10];
+
+fn build_network() -> (Network, Vec<Weight>, Vec<Weight>) {
+    let mut rng = pcg::Pcg::default();
+    let network = Network {
+        feature_layers: Vec::new(),
+        hidden_layers: vec![DenseLayer::new(
+            LAYER_SIZES[1],
+            LAYER_SIZES[0],
+            &mut |_, _| rng.gen_range(-0.05, 0.05),
+            &mut |_| 0.,
+            &LeakyReLU,
+        )],
+        outputs: Box::new(OutputLayer::new(
+            &Identity,
+            &MeanSquaredError,
+            &mut |_, _| rng.gen_range(-0.1, 0.1),
+            LAYER_SIZES[1],
+            LAYER_SIZES[2],
+        )),
+        learning_rate: 0.001,
+    };
+    let inputs = (0..LAYER_SIZES[0]).map(|_| rng.gen_range(0., 1.)).collect();
+    let mut expected = vec![0.; LAYER_SIZES[2]];
+    expected[3] = 1.;
+
+    (network, inputs, expected)
+}
+
+#[bench]
+fn bench_forward_propagate(b: &mut Bencher) {
+    let (mut network, inputs, _) = build_network();
+    b.iter(|| {
+        network.forward_propagate(test::black_box(&inputs));
+    });
+}
+
+#[bench]
+fn bench_train_one_example(b: &mut Bencher) {
+    let (mut network, inputs, expected) = build_network();
+    b.iter(|| network.train_one_example(test::black_box(&inputs), &expected, 0.001));
+}
+
+/// The number of examples run through the network per iteration by the batch benchmarks
+const BATCH_SIZE: usize = 64;
+
+fn build_batch(rng: &mut pcg::Pcg) -> Vec<Weight> {
+    (0..BATCH_SIZE * LAYER_SIZES[0])
+        .map(|_| rng.gen_range(0., 1.))
+        .collect()
+}
+
+#[bench]
+fn bench_forward_propagate_one_at_a_time(b: &mut Bencher) {
+    let (mut network, ..) = build_network();
+    let examples = build_batch(&mut pcg::Pcg::default());
+    b.iter(|| {
+        for example in test::black_box(&examples).chunks_exact(LAYER_SIZES[0]) {
+            network.forward_propagate(example);
+        }
+    });
+}
+
+#[bench]
+fn bench_forward_propagate_batch(b: &mut Bencher) {
+    let (network, ..) = build_network();
+    let examples = build_batch(&mut pcg::Pcg::default());
+    let mut activations = BatchActivations::new();
+    b.iter(|| {
+        network
+            .forward_propagate_batch(test::black_box(&examples), &mut activations)
+            .unwrap();
+    });
+}
+
+/// Prunes the network to the given sparsity and converts it to a `SparseNetwork`.  Compare against
+/// `bench_forward_propagate` to find the sparsity at which the sparse layers start winning.
+fn bench_sparse_forward_propagate(b: &mut Bencher, sparsity: f32) {
+    let (mut network, inputs, _) = build_network();
+    network.prune_by_magnitude(sparsity, PruningScope::PerLayer);
+    let mut sparse = SparseNetwork::from_network(&network, 0.).unwrap();
+    b.iter(|| {
+        sparse.compute(test::black_box(&inputs)).unwrap();
+    });
+}
+
+#[bench]
+fn bench_sparse_forward_propagate_50(b: &mut Bencher) { bench_sparse_forward_propagate(b, 0.5) }
+
+#[bench]
+fn bench_sparse_forward_propagate_70(b: &mut Bencher) { bench_sparse_forward_propagate(b, 0.7) }
+
+#[bench]
+fn bench_sparse_forward_propagate_80(b: &mut Bencher) { bench_sparse_forward_propagate(b, 0.8) }
+
+#[bench]
+fn bench_sparse_forward_propagate_90(b: &mut Bencher) { bench_sparse_forward_propagate(b, 0.9) }
+
+#[bench]
+fn bench_sparse_forward_propagate_95(b: &mut Bencher) { bench_sparse_forward_propagate(b, 0.95) }
+
+#[bench]
+fn bench_sparse_forward_propagate_99(b: &mut Bencher) { bench_sparse_forward_propagate(b, 0.99) }
+
+#[bench]
+fn bench_sparse_train_one_example_90(b: &mut Bencher) {
+    let (mut network, inputs, expected) = build_network();
+    network.prune_by_magnitude(0.9, PruningScope::PerLayer);
+    let mut sparse = SparseNetwork::from_network(&network, 0.).unwrap();
+    b.iter(|| sparse.train_one_example(test::black_box(&inputs), &expected, 0.001));
+}
@@ -0,0 +1,549 @@
+//! Scaled dot-product self-attention and the pieces needed to stack it into a transformer encoder.
+//!
+//! Everything here works on a whole sequence at once, held in a `WeightMatrix` with one row per position and one
+//! column per feature.  Each layer caches what it saw during its last forward pass so that `backpropagate` can be
+//! called with the errors of its outputs afterwards; the gradients are accumulated until `update_weights` is called.
+//! Same as the rest of the crate, the values passed backwards are errors (the negated gradients of the cost) and the
+//! weights are updated by adding them.
+//!
+//! The encoder blocks use pre-layer normalization: each sub-layer normalizes its inputs and adds its outputs back onto
+//! them through a residual connection, so the residual stream itself is never normalized.  This trains much more
+//! reliably than normalizing after the residual connection without needing a learning rate warm-up.
+
+use crate::{
+    average_cost,
+    recurrent::{backpropagate_readout, target_for_step, validate_sequence, validate_targets},
+    validate_hidden_activation, ActivationFunction, Float, NnError, OutputLayer, Weight, WeightMatrix,
+};
+
+/// Added to the variance in layer normalization to avoid dividing by zero for constant inputs.
+const LAYER_NORM_EPSILON: f64 = 1e-5;
+
+/// The sinusoidal position encodings from "Attention Is All You Need": column `2i` of row `pos` holds
+/// `sin(pos / 10000^(2i / dim))` and column `2i + 1` holds the cosine of the same value.
+pub fn sinusoidal_positional_encoding<T: Float>(position_count: usize, dim: usize) -> WeightMatrix<T> {
+    WeightMatrix::from_fn(position_count, dim, |pos, col| {
+        let frequency = 10_000f64.powf(-((col / 2 * 2) as f64) / dim as f64);
+        let angle = pos as f64 * frequency;
+        T::from_f64(if col % 2 == 0 { angle.sin() } else { angle.cos() })
+    })
+}
+
+/// `dst += src`
+fn add_assign<T: Float>(dst: &mut WeightMatrix<T>, src: &WeightMatrix<T>) {
+    debug_assert_eq!((dst.row_count(), dst.col_count()), (src.row_count(), src.col_count()));
+    T::axpy(dst.as_mut_slice(), T::ONE, src.as_slice());
+}
+
+/// A dense layer without an activation function that's applied separately to every position of a sequence, with the
+/// same weights for each one.
+pub struct PositionwiseDense<T: Float = Weight> {
+    /// One row per output and one column per input.
+    pub weights: WeightMatrix<T>,
+    pub biases: Vec<T>,
+    inputs: WeightMatrix<T>,
+    weight_gradients: WeightMatrix<T>,
+    bias_gradients: Vec<T>,
+}
+
+impl<T: Float> PositionwiseDense<T> {
+    /// `init_weights` is called with the output and input index of each weight.  Biases start at zero.
+    pub fn new(input_count: usize, output_count: usize, init_weights: &mut impl FnMut(usize, usize) -> T) -> Self {
+        PositionwiseDense {
+            weights: WeightMatrix::from_fn(output_count, input_count, init_weights),
+            biases: vec![T::ZERO; output_count],
+            inputs: WeightMatrix::default(),
+            weight_gradients: WeightMatrix::new(output_count, input_count),
+            bias_gradients: vec![T::ZERO; output_count],
+        }
+    }
+
+    pub fn input_count(&self) -> usize { self.weights.col_count() }
+
+    pub fn output_count(&self) -> usize { self.weights.row_count() }
+
+    pub fn forward_propagate(&mut self, inputs: &WeightMatrix<T>) -> WeightMatrix<T> {
+        debug_assert_eq!(inputs.col_count(), self.input_count());
+        self.inputs.clone_from(inputs);
+        WeightMatrix::from_fn(inputs.row_count(), self.output_count(), |pos, neuron_ix| {
+            T::dot(&self.weights[neuron_ix], &inputs[pos]) + self.biases[neuron_ix]
+        })
+    }
+
+    /// Accumulates gradients for the last sequence passed to `forward_propagate` and returns the errors of its inputs.
+    pub fn backpropagate(&mut self, output_errors: &WeightMatrix<T>) -> WeightMatrix<T> {
+        let mut input_errors = WeightMatrix::new(self.inputs.row_count(), self.input_count());
+        for pos in 0..output_errors.row_count() {
+            for (neuron_ix, &error) in output_errors[pos].iter().enumerate() {
+                T::axpy(&mut input_errors[pos], error, &self.weights[neuron_ix]);
+                T::axpy(&mut self.weight_gradients[neuron_ix], error, &self.inputs[pos]);
+                self.bias_gradients[neuron_ix] += error;
+            }
+        }
+        input_errors
+    }
+
+    /// Applies the gradients accumulated by `backpropagate` and clears them.
+    pub fn update_weights(&mut self, learning_rate: T) {
+        T::axpy(
+            self.weights.as_mut_slice(),
+            learning_rate,
+            self.weight_gradients.as_slice(),
+        );
+        T::axpy(&mut self.biases, learning_rate, &self.bias_gradients);
+        self.weight_gradients.as_mut_slice().fill(T::ZERO);
+        self.bias_gradients.fill(T::ZERO);
+    }
+}
+
+/// Normalizes each position to zero mean and unit variance across its features, then scales and shifts every feature
+/// by a learned gain and bias.
+pub struct LayerNorm<T: Float = Weight> {
+    pub gains: Vec<T>,
+    pub biases: Vec<T>,
+    /// The normalized inputs from the last forward pass, before the gains and biases were applied.
+    normalized: WeightMatrix<T>,
+    inverse_std_devs: Vec<T>,
+    gain_gradients: Vec<T>,
+    bias_gradients: Vec<T>,
+}
+
+impl<T: Float> LayerNorm<T> {
+    /// Gains start at one and biases at zero, so the layer initially just normalizes.
+    pub fn new(dim: usize) -> Self {
+        LayerNorm {
+            gains: vec![T::ONE; dim],
+            biases: vec![T::ZERO; dim],
+            normalized: WeightMatrix::default(),
+            inverse_std_devs: Vec::new(),
+            gain_gradients: vec![T::ZERO; dim],
+            bias_gradients: vec![T::ZERO; dim],
+        }
+    }
+
+    pub fn forward_propagate(&mut self, inputs: &WeightMatrix<T>) -> WeightMatrix<T> {
+        let dim = T::from_f64(self.gains.len() as f64);
+        self.normalized.resize(inputs.row_count(), inputs.col_count());
+        self.inverse_std_devs.clear();
+        let mut outputs = WeightMatrix::new(inputs.row_count(), inputs.col_count());
+        for pos in 0..inputs.row_count() {
+            let row = &inputs[pos];
+            let mean = row.iter().copied().sum::<T>() / dim;
+            let variance = row.iter().map(|&x| (x - mean) * (x - mean)).sum::<T>() / dim;
+            let inverse_std_dev = T::ONE / (variance + T::from_f64(LAYER_NORM_EPSILON)).sqrt();
+            self.inverse_std_devs.push(inverse_std_dev);
+
+            for (col, &x) in row.iter().enumerate() {
+                let normalized = (x - mean) * inverse_std_dev;
+                self.normalized[pos][col] = normalized;
+                outputs[pos][col] = normalized * self.gains[col] + self.biases[col];
+            }
+        }
+        outputs
+    }
+
+    pub fn backpropagate(&mut self, output_errors: &WeightMatrix<T>) -> WeightMatrix<T> {
+        let dim = T::from_f64(self.gains.len() as f64);
+        let mut input_errors = WeightMatrix::new(output_errors.row_count(), output_errors.col_count());
+        for pos in 0..output_errors.row_count() {
+            let normalized = &self.normalized[pos];
+            let normalized_errors: Vec<T> = output_errors[pos]
+                .iter()
+                .zip(&self.gains)
+                .map(|(&error, &gain)| error * gain)
+                .collect();
+            for (col, &error) in output_errors[pos].iter().enumerate() {
+                self.gain_gradients[col] += error * normalized[col];
+                self.bias_gradients[col] += error;
+            }
+
+            // Every input affects the mean and variance, which every output depends on
+            let mean_error = normalized_errors.iter().copied().sum::<T>() / dim;
+            let mean_scaled_error = T::dot(&normalized_errors, normalized) / dim;
+            for (col, input_error) in input_errors[pos].iter_mut().enumerate() {
+                *input_error = self.inverse_std_devs[pos]
+                    * (normalized_errors[col] - mean_error - normalized[col] * mean_scaled_error);
+            }
+        }
+        input_errors
+    }
+
+    pub fn update_weights(&mut self, learning_rate: T) {
+        T::axpy(&mut self.gains, learning_rate, &self.gain_gradients);
+        T::axpy(&mut self.biases, learning_rate, &self.bias_gradients);
+        self.gain_gradients.fill(T::ZERO);
+        self.bias_gradients.fill(T::ZERO);
+    }
+}
+
+/// Multi-head scaled dot-product self-attention.  The inputs are projected into queries, keys and values, which are
+/// split into `head_count` equal slices of features.  In each head, every position takes a weighted average of the
+/// values of the positions it attends to, weighted by the softmax of the dot products of its query with their keys
+/// divided by the square root of the head's size.  The averages from all heads are concatenated and projected back to
+/// the model's size.
+pub struct MultiHeadAttention<T: Float = Weight> {
+    pub head_count: usize,
+    /// Only lets each position attend to itself and the positions before it.
+    pub causal: bool,
+    pub query_projection: PositionwiseDense<T>,
+    pub key_projection: PositionwiseDense<T>,
+    pub value_projection: PositionwiseDense<T>,
+    pub output_projection: PositionwiseDense<T>,
+    queries: WeightMatrix<T>,
+    keys: WeightMatrix<T>,
+    values: WeightMatrix<T>,
+    /// For each head, row `i` holds how much position `i` attended to each position in the last forward pass.
+    attention_weights: Vec<WeightMatrix<T>>,
+}
+
+impl<T: Float> MultiHeadAttention<T> {
+    /// `init_weights` is called with the output and input index of each weight of the four projections, which are all
+    /// `model_dim` by `model_dim`.  Panics if `model_dim` isn't a multiple of `head_count`.
+    pub fn new(
+        model_dim: usize,
+        head_count: usize,
+        causal: bool,
+        init_weights: &mut impl FnMut(usize, usize) -> T,
+    ) -> Self {
+        assert!(
+            head_count > 0 && model_dim / head_count * head_count == model_dim,
+            "a model dimension of {} can't be split into {} heads",
+            model_dim,
+            head_count
+        );
+
+        MultiHeadAttention {
+            head_count,
+            causal,
+            query_projection: PositionwiseDense::new(model_dim, model_dim, init_weights),
+            key_projection: PositionwiseDense::new(model_dim, model_dim, init_weights),
+            value_projection: PositionwiseDense::new(model_dim, model_dim, init_weights),
+            output_projection: PositionwiseDense::new(model_dim, model_dim, init_weights),
+            queries: WeightMatrix::default(),
+            keys: WeightMatrix::default(),
+            values: WeightMatrix::default(),
+            attention_weights: Vec::new(),
+        }
+    }
+
+    pub fn model_dim(&self) -> usize { self.query_projection.input_count() }
+
+    pub fn head_dim(&self) -> usize { self.model_dim() / self.head_count }
+
+    /// The attention weights of each head from the last forward pass.  Row `i` of each matrix holds how much position
+    /// `i` attended to every position, and sums to one.
+    pub fn attention_weights(&self) -> &[WeightMatrix<T>] { &self.attention_weights }
+
+    pub fn forward_propagate(&mut self, inputs: &WeightMatrix<T>) -> WeightMatrix<T> {
+        let len = inputs.row_count();
+        let head_dim = self.head_dim();
+        let scale = T::ONE / T::from_f64(head_dim as f64).sqrt();
+        self.queries = self.query_projection.forward_propagate(inputs);
+        self.keys = self.key_projection.forward_propagate(inputs);
+        self.values = self.value_projection.forward_propagate(inputs);
+
+        let mut mixed_values = WeightMatrix::new(len, self.model_dim());
+        self.attention_weights
+            .resize_with(self.head_count, WeightMatrix::default);
+        for head_ix in 0..self.head_count {
+            let cols = head_ix * head_dim..(head_ix + 1) * head_dim;
+            let weights = &mut self.attention_weights[head_ix];
+            weights.resize(len, len);
+            for pos in 0..len {
+                let visible = if self.causal { pos + 1 } else { len };
+                let scores = &mut weights[pos][..visible];
+                for (other_pos, score) in scores.iter_mut().enumerate() {
+                    *score = T::dot(&self.queries[pos][cols.clone()], &self.keys[other_pos][cols.clone()]) * scale;
+                }
+
+                let max_score = scores.iter().fold(T::NEG_INFINITY, |acc, &score| acc.max(score));
+                for score in scores.iter_mut() {
+                    *score = (*score - max_score).exp();
+                }
+                let total = scores.iter().copied().sum::<T>();
+                for (other_pos, score) in scores.iter_mut().enumerate() {
+                    *score /= total;
+                    T::axpy(
+                        &mut mixed_values[pos][cols.clone()],
+                        *score,
+                        &self.values[other_pos][cols.clone()],
+                    );
+                }
+            }
+        }
+
+        self.output_projection.forward_propagate(&mixed_values)
+    }
+
+    pub fn backpropagate(&mut self, output_errors: &WeightMatrix<T>) -> WeightMatrix<T> {
+        let len = output_errors.row_count();
+        let head_dim = self.head_dim();
+        let scale = T::ONE / T::from_f64(head_dim as f64).sqrt();
+        let mixed_errors = self.output_projection.backpropagate(output_errors);
+
+        let mut query_errors = WeightMatrix::new(len, self.model_dim());
+        let mut key_errors = WeightMatrix::new(len, self.model_dim());
+        let mut value_errors = WeightMatrix::new(len, self.model_dim());
+        let mut weight_errors = vec![T::ZERO; len];
+        for head_ix in 0..self.head_count {
+            let cols = head_ix * head_dim..(head_ix + 1) * head_dim;
+            let weights = &self.attention_weights[head_ix];
+            for pos in 0..len {
+                let visible = if self.causal { pos + 1 } else { len };
+                let errors = &mixed_errors[pos][cols.clone()];
+                for other_pos in 0..visible {
+                    weight_errors[other_pos] = T::dot(errors, &self.values[other_pos][cols.clone()]);
+                    T::axpy(
+                        &mut value_errors[other_pos][cols.clone()],
+                        weights[pos][other_pos],
+                        errors,
+                    );
+                }
+
+                // Backpropagate through the softmax, then the scaled dot products
+                let weighted_error = T::dot(&weights[pos][..visible], &weight_errors[..visible]);
+                for other_pos in 0..visible {
+                    let score_error = weights[pos][other_pos] * (weight_errors[other_pos] - weighted_error) * scale;
+                    T::axpy(
+                        &mut query_errors[pos][cols.clone()],
+                        score_error,
+                        &self.keys[other_pos][cols.clone()],
+                    );
+                    T::axpy(
+                        &mut key_errors[other_pos][cols.clone()],
+                        score_error,
+                        &self.queries[pos][cols.clone()],
+                    );
+                }
+            }
+        }
+
+        let mut input_errors = self.query_projection.backpropagate(&query_errors);
+        add_assign(&mut input_errors, &self.key_projection.backpropagate(&key_errors));
+        add_assign(&mut input_errors, &self.value_projection.backpropagate(&value_errors));
+        input_errors
+    }
+
+    pub fn update_weights(&mut self, learning_rate: T) {
+        self.query_projection.update_weights(learning_rate);
+        self.key_projection.update_weights(learning_rate);
+        self.value_projection.update_weights(learning_rate);
+        self.output_projection.update_weights(learning_rate);
+    }
+}
+
+/// Self-attention followed by a two-layer feed-forward network applied to each position, each with layer
+/// normalization on its inputs and a residual connection around it:
+///
+/// ```text
+/// x = x + attention(norm(x))
+/// x = x + feed_forward_output(activation(feed_forward_hidden(norm(x))))
+/// ```
+pub struct TransformerEncoderBlock<T: Float = Weight> {
+    pub attention_norm: LayerNorm<T>,
+    pub attention: MultiHeadAttention<T>,
+    pub feed_forward_norm: LayerNorm<T>,
+    pub feed_forward_hidden: PositionwiseDense<T>,
+    pub feed_forward_activation: &'static dyn ActivationFunction<T>,
+    pub feed_forward_output: PositionwiseDense<T>,
+    hidden_before_activation: WeightMatrix<T>,
+}
+
+impl<T: Float> TransformerEncoderBlock<T> {
+    /// Panics if the feed-forward layers don't map from the attention's model dimension back to it.
+    pub fn new(
+        attention: MultiHeadAttention<T>,
+        feed_forward_hidden: PositionwiseDense<T>,
+        feed_forward_activation: &'static dyn ActivationFunction<T>,
+        feed_forward_output: PositionwiseDense<T>,
+    ) -> Self {
+        let model_dim = attention.model_dim();
+        assert_eq!(feed_forward_hidden.input_count(), model_dim);
+        assert_eq!(feed_forward_hidden.output_count(), feed_forward_output.input_count());
+        assert_eq!(feed_forward_output.output_count(), model_dim);
+
+        TransformerEncoderBlock {
+            attention_norm: LayerNorm::new(model_dim),
+            attention,
+            feed_forward_norm: LayerNorm::new(model_dim),
+            feed_forward_hidden,
+            feed_forward_activation,
+            feed_forward_output,
+            hidden_before_activation: WeightMatrix::default(),
+        }
+    }
+
+    pub fn model_dim(&self) -> usize { self.attention.model_dim() }
+
+    pub fn forward_propagate(&mut self, inputs: &WeightMatrix<T>) -> WeightMatrix<T> {
+        let mut residual = inputs.clone();
+        let normalized = self.attention_norm.forward_propagate(inputs);
+        add_assign(&mut residual, &self.attention.forward_propagate(&normalized));
+
+        let normalized = self.feed_forward_norm.forward_propagate(&residual);
+        self.hidden_before_activation = self.feed_forward_hidden.forward_propagate(&normalized);
+        let mut hidden = WeightMatrix::new(residual.row_count(), self.feed_forward_hidden.output_count());
+        for pos in 0..residual.row_count() {
+            self.feed_forward_activation
+                .apply_batch(&mut hidden[pos], &self.hidden_before_activation[pos]);
+        }
+        add_assign(&mut residual, &self.feed_forward_output.forward_propagate(&hidden));
+        residual
+    }
+
+    pub fn backpropagate(&mut self, output_errors: &WeightMatrix<T>) -> WeightMatrix<T> {
+        // The residual connections pass the errors straight through as well as into each sub-layer
+        let mut errors = output_errors.clone();
+        let hidden_errors = self.feed_forward_output.backpropagate(output_errors);
+        let mut hidden_before_activation_errors =
+            WeightMatrix::new(hidden_errors.row_count(), hidden_errors.col_count());
+        for pos in 0..hidden_errors.row_count() {
+            self.feed_forward_activation.apply_derivative_batch(
+                &mut hidden_before_activation_errors[pos],
+                &hidden_errors[pos],
+                &self.hidden_before_activation[pos],
+            );
+        }
+        let normalized_errors = self.feed_forward_hidden.backpropagate(&hidden_before_activation_errors);
+        add_assign(&mut errors, &self.feed_forward_norm.backpropagate(&normalized_errors));
+
+        let normalized_errors = self.attention.backpropagate(&errors);
+        let attention_norm_errors = self.attention_norm.backpropagate(&normalized_errors);
+        add_assign(&mut errors, &attention_norm_errors);
+        errors
+    }
+
+    pub fn update_weights(&mut self, learning_rate: T) {
+        self.attention_norm.update_weights(learning_rate);
+        self.attention.update_weights(learning_rate);
+        self.feed_forward_norm.update_weights(learning_rate);
+        self.feed_forward_hidden.update_weights(learning_rate);
+        self.feed_forward_output.update_weights(learning_rate);
+    }
+}
+
+/// Projects each timestep of a sequence up to the model dimension, adds positional encodings, runs it through a stack
+/// of encoder blocks and then applies an output layer at every position that has a target.  Sequences are passed as
+/// one slice of features per timestep, the same as for `SequenceNetwork`.
+pub struct TransformerEncoder<T: Float = Weight> {
+    pub input_projection: PositionwiseDense<T>,
+    /// Whether to add `sinusoidal_positional_encoding` to the projected inputs.  Without it, attention can't tell
+    /// positions apart unless the blocks are causal.
+    pub positional_encoding: bool,
+    pub blocks: Vec<TransformerEncoderBlock<T>>,
+    pub outputs: Box<OutputLayer<T>>,
+    output_weight_gradients: WeightMatrix<T>,
+}
+
+impl<T: Float> TransformerEncoder<T> {
+    /// Panics if the sizes of the layers don't line up.  Returns an error if `Softmax` is used anywhere other than on
+    /// the output layer together with `CrossEntropy`; the output layer is numbered after the blocks.
+    pub fn new(
+        input_projection: PositionwiseDense<T>,
+        blocks: Vec<TransformerEncoderBlock<T>>,
+        outputs: Box<OutputLayer<T>>,
+    ) -> Result<Self, NnError> {
+        let model_dim = input_projection.output_count();
+        for block in &blocks {
+            assert_eq!(
+                block.model_dim(),
+                model_dim,
+                "every block must have the same model dimension"
+            );
+        }
+        assert_eq!(
+            outputs.weights.col_count(),
+            model_dim,
+            "the output layer must take the model dimension"
+        );
+
+        for (block_ix, block) in blocks.iter().enumerate() {
+            validate_hidden_activation(block.feed_forward_activation, block_ix)?;
+        }
+        outputs.validate_activation(blocks.len())?;
+
+        let output_weight_gradients = WeightMatrix::new(outputs.weights.row_count(), outputs.weights.col_count());
+        Ok(TransformerEncoder {
+            input_projection,
+            positional_encoding: true,
+            blocks,
+            outputs,
+            output_weight_gradients,
+        })
+    }
+
+    pub fn input_count(&self) -> usize { self.input_projection.input_count() }
+
+    pub fn output_count(&self) -> usize { self.outputs.outputs.len() }
+
+    pub fn model_dim(&self) -> usize { self.input_projection.output_count() }
+
+    /// Runs a sequence through everything but the output layer.
+    fn encode<R: AsRef<[T]>>(&mut self, inputs: &[R]) -> WeightMatrix<T> {
+        let mut encoded = self
+            .input_projection
+            .forward_propagate(&WeightMatrix::from_rows(inputs));
+        if self.positional_encoding {
+            add_assign(
+                &mut encoded,
+                &sinusoidal_positional_encoding(inputs.len(), self.model_dim()),
+            );
+        }
+        for block in &mut self.blocks {
+            encoded = block.forward_propagate(&encoded);
+        }
+        encoded
+    }
+
+    /// Returns the outputs at each position of the sequence.
+    pub fn compute_sequence<R: AsRef<[T]>>(&mut self, inputs: &[R]) -> Result<Vec<Vec<T>>, NnError> {
+        validate_sequence(inputs, self.input_count())?;
+        let encoded = self.encode(inputs);
+        Ok(encoded
+            .iter_rows()
+            .map(|row| {
+                self.outputs.forward_propagate(row);
+                self.outputs.outputs.clone()
+            })
+            .collect())
+    }
+
+    /// Trains on one sequence.  `targets` holds either the expected outputs for every position, or a single expected
+    /// output for the last position.  Returns the average cost over the targets before updating weights.
+    pub fn train_sequence<R: AsRef<[T]>, S: AsRef<[T]>>(
+        &mut self,
+        inputs: &[R],
+        targets: &[S],
+        learning_rate: T,
+    ) -> Result<T, NnError> {
+        validate_sequence(inputs, self.input_count())?;
+        validate_targets(targets, inputs.len(), self.output_count())?;
+
+        let encoded = self.encode(inputs);
+        let mut errors = WeightMatrix::new(inputs.len(), self.model_dim());
+        let mut total_cost = T::ZERO;
+        for pos in 0..inputs.len() {
+            if let Some(target) = target_for_step(targets, inputs.len(), pos) {
+                total_cost += backpropagate_readout(
+                    &mut self.outputs,
+                    &encoded[pos],
+                    target,
+                    &mut errors[pos],
+                    &mut self.output_weight_gradients,
+                );
+            }
+        }
+
+        for block in self.blocks.iter_mut().rev() {
+            errors = block.backpropagate(&errors);
+            block.update_weights(learning_rate);
+        }
+        self.input_projection.backpropagate(&errors);
+        self.input_projection.update_weights(learning_rate);
+        self.outputs
+            .apply_weight_gradients(&self.output_weight_gradients, learning_rate);
+        self.output_weight_gradients.as_mut_slice().fill(T::ZERO);
+
+        average_cost(total_cost, targets.len())
+    }
+}
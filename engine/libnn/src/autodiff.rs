@@ -0,0 +1,336 @@
+//! A small reverse-mode automatic differentiation engine for trying out custom losses and layers without deriving
+//! their backward passes by hand.
+//!
+//! Operations on matrices are recorded on a `Tape` as they're run, each one producing a `Var` that can be fed into
+//! later operations.  Calling `Tape::backward` on a 1x1 result then walks the tape in reverse, accumulating the
+//! gradient of that result with respect to every value recorded before it.
+//!
+//! Unlike the rest of the crate, which passes errors (the negated gradients of the cost) backwards, the tape holds the
+//! actual gradients, so parameters are trained by subtracting them.
+
+use crate::{ActivationFunction, Float, Weight, WeightMatrix, SOFTMAX};
+
+/// A value recorded on a `Tape`.  Only valid for the tape that created it, and only until that tape is cleared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Var(usize);
+
+#[derive(Clone, Copy)]
+enum Op<T: Float> {
+    /// An input or parameter that isn't computed from anything else on the tape.
+    Leaf,
+    Add(Var, Var),
+    Sub(Var, Var),
+    Mul(Var, Var),
+    Scale(Var, T),
+    AddRow(Var, Var),
+    MatMul(Var, Var),
+    Transpose(Var),
+    Activation(Var, &'static dyn ActivationFunction<T>),
+    Softmax(Var),
+    Exp(Var),
+    Ln(Var),
+    Sum(Var),
+    Mean(Var),
+    SumRows(Var),
+}
+
+pub struct Tape<T: Float = Weight> {
+    ops: Vec<Op<T>>,
+    values: Vec<WeightMatrix<T>>,
+    /// Filled in by `backward`, with one gradient for each of `values`.
+    grads: Vec<WeightMatrix<T>>,
+}
+
+/// Adds `f(row_ix, col_ix)` onto each value of `dst`.
+fn accumulate<T: Float>(dst: &mut WeightMatrix<T>, mut f: impl FnMut(usize, usize) -> T) {
+    for row_ix in 0..dst.row_count() {
+        for (col_ix, dst) in dst[row_ix].iter_mut().enumerate() {
+            *dst += f(row_ix, col_ix);
+        }
+    }
+}
+
+fn map<T: Float>(src: &WeightMatrix<T>, f: impl Fn(T) -> T) -> WeightMatrix<T> {
+    WeightMatrix::from_fn(
+        src.row_count(),
+        src.col_count(),
+        |row_ix, col_ix| f(src[row_ix][col_ix]),
+    )
+}
+
+fn assert_same_shape<T: Float>(a: &WeightMatrix<T>, b: &WeightMatrix<T>) {
+    assert_eq!(
+        (a.row_count(), a.col_count()),
+        (b.row_count(), b.col_count()),
+        "shapes don't match"
+    );
+}
+
+impl<T: Float> Default for Tape<T> {
+    fn default() -> Self {
+        Tape {
+            ops: Vec::new(),
+            values: Vec::new(),
+            grads: Vec::new(),
+        }
+    }
+}
+
+impl<T: Float> Tape<T> {
+    pub fn new() -> Self { Self::default() }
+
+    /// Removes everything from the tape so it can be reused for the next example.
+    pub fn clear(&mut self) {
+        self.ops.clear();
+        self.values.clear();
+        self.grads.clear();
+    }
+
+    fn push(&mut self, op: Op<T>, value: WeightMatrix<T>) -> Var {
+        self.ops.push(op);
+        self.values.push(value);
+        Var(self.values.len() - 1)
+    }
+
+    /// Records an input or parameter.
+    pub fn leaf(&mut self, value: WeightMatrix<T>) -> Var { self.push(Op::Leaf, value) }
+
+    pub fn value(&self, var: Var) -> &WeightMatrix<T> { &self.values[var.0] }
+
+    /// The value of a 1x1 result.
+    pub fn scalar(&self, var: Var) -> T { self.values[var.0][0][0] }
+
+    /// The gradient computed for `var` by the last call to `backward`.  Panics if `backward` hasn't been called since
+    /// `var` was recorded.
+    pub fn grad(&self, var: Var) -> &WeightMatrix<T> {
+        assert!(var.0 < self.grads.len(), "call `backward` before reading gradients");
+        &self.grads[var.0]
+    }
+
+    pub fn add(&mut self, a: Var, b: Var) -> Var {
+        let (a_value, b_value) = (self.value(a), self.value(b));
+        assert_same_shape(a_value, b_value);
+        let value = WeightMatrix::from_fn(a_value.row_count(), a_value.col_count(), |row_ix, col_ix| {
+            a_value[row_ix][col_ix] + b_value[row_ix][col_ix]
+        });
+        self.push(Op::Add(a, b), value)
+    }
+
+    pub fn sub(&mut self, a: Var, b: Var) -> Var {
+        let (a_value, b_value) = (self.value(a), self.value(b));
+        assert_same_shape(a_value, b_value);
+        let value = WeightMatrix::from_fn(a_value.row_count(), a_value.col_count(), |row_ix, col_ix| {
+            a_value[row_ix][col_ix] - b_value[row_ix][col_ix]
+        });
+        self.push(Op::Sub(a, b), value)
+    }
+
+    /// Multiplies `a` and `b` elementwise.
+    pub fn mul(&mut self, a: Var, b: Var) -> Var {
+        let (a_value, b_value) = (self.value(a), self.value(b));
+        assert_same_shape(a_value, b_value);
+        let value = WeightMatrix::from_fn(a_value.row_count(), a_value.col_count(), |row_ix, col_ix| {
+            a_value[row_ix][col_ix] * b_value[row_ix][col_ix]
+        });
+        self.push(Op::Mul(a, b), value)
+    }
+
+    pub fn scale(&mut self, a: Var, factor: T) -> Var {
+        let value = map(self.value(a), |x| x * factor);
+        self.push(Op::Scale(a, factor), value)
+    }
+
+    /// Adds the single row in `row` onto every row of `a`, like adding biases to a batch of outputs.
+    pub fn add_row(&mut self, a: Var, row: Var) -> Var {
+        let (a_value, row_value) = (self.value(a), self.value(row));
+        assert_eq!(row_value.row_count(), 1, "`row` must have a single row");
+        assert_eq!(a_value.col_count(), row_value.col_count(), "shapes don't match");
+        let value = WeightMatrix::from_fn(a_value.row_count(), a_value.col_count(), |row_ix, col_ix| {
+            a_value[row_ix][col_ix] + row_value[0][col_ix]
+        });
+        self.push(Op::AddRow(a, row), value)
+    }
+
+    /// The matrix product of `a` and `b`.
+    pub fn matmul(&mut self, a: Var, b: Var) -> Var {
+        let (a_value, b_value) = (self.value(a), self.value(b));
+        assert_eq!(
+            a_value.col_count(),
+            b_value.row_count(),
+            "can't multiply a matrix with {} columns by one with {} rows",
+            a_value.col_count(),
+            b_value.row_count()
+        );
+        let value = WeightMatrix::from_fn(a_value.row_count(), b_value.col_count(), |row_ix, col_ix| {
+            a_value[row_ix]
+                .iter()
+                .zip(b_value.column(col_ix))
+                .fold(T::ZERO, |acc, (&a, b)| acc + a * b)
+        });
+        self.push(Op::MatMul(a, b), value)
+    }
+
+    pub fn transpose(&mut self, a: Var) -> Var {
+        let a_value = self.value(a);
+        let value = WeightMatrix::from_fn(a_value.col_count(), a_value.row_count(), |row_ix, col_ix| {
+            a_value[col_ix][row_ix]
+        });
+        self.push(Op::Transpose(a), value)
+    }
+
+    /// Applies `activation_fn` to each row of `a`.  `Softmax` is recorded as `softmax`, since its derivative can't be
+    /// computed one value at a time.
+    pub fn activation(&mut self, a: Var, activation_fn: &'static dyn ActivationFunction<T>) -> Var {
+        if activation_fn.is_softmax() {
+            return self.softmax(a);
+        }
+        let a_value = self.value(a);
+        let mut value = WeightMatrix::new(a_value.row_count(), a_value.col_count());
+        for row_ix in 0..a_value.row_count() {
+            activation_fn.apply_batch(&mut value[row_ix], &a_value[row_ix]);
+        }
+        self.push(Op::Activation(a, activation_fn), value)
+    }
+
+    /// Applies softmax to each row of `a`.
+    pub fn softmax(&mut self, a: Var) -> Var {
+        let a_value = self.value(a);
+        let mut value = WeightMatrix::new(a_value.row_count(), a_value.col_count());
+        for row_ix in 0..a_value.row_count() {
+            ActivationFunction::<T>::apply_batch(&SOFTMAX, &mut value[row_ix], &a_value[row_ix]);
+        }
+        self.push(Op::Softmax(a), value)
+    }
+
+    pub fn exp(&mut self, a: Var) -> Var {
+        let value = map(self.value(a), T::exp);
+        self.push(Op::Exp(a), value)
+    }
+
+    /// The natural log of each value of `a`.
+    pub fn ln(&mut self, a: Var) -> Var {
+        let value = map(self.value(a), T::ln);
+        self.push(Op::Ln(a), value)
+    }
+
+    /// The sum of every value of `a`, as a 1x1 matrix.
+    pub fn sum(&mut self, a: Var) -> Var {
+        let total = self.value(a).iter_rows().flatten().fold(T::ZERO, |acc, &x| acc + x);
+        self.push(Op::Sum(a), WeightMatrix::from_fn(1, 1, |_, _| total))
+    }
+
+    /// The mean of every value of `a`, as a 1x1 matrix.
+    pub fn mean(&mut self, a: Var) -> Var {
+        let a_value = self.value(a);
+        let count = T::from_f64((a_value.row_count() * a_value.col_count()) as f64);
+        let total = a_value.iter_rows().flatten().fold(T::ZERO, |acc, &x| acc + x);
+        self.push(Op::Mean(a), WeightMatrix::from_fn(1, 1, |_, _| total / count))
+    }
+
+    /// Sums the rows of `a` into a single row.
+    pub fn sum_rows(&mut self, a: Var) -> Var {
+        let a_value = self.value(a);
+        let value = WeightMatrix::from_fn(1, a_value.col_count(), |_, col_ix| {
+            a_value.column(col_ix).fold(T::ZERO, |acc, x| acc + x)
+        });
+        self.push(Op::SumRows(a), value)
+    }
+
+    /// Computes the gradient of `output` with respect to everything recorded before it.  `output` must be 1x1.
+    pub fn backward(&mut self, output: Var) {
+        let output_value = self.value(output);
+        assert_eq!(
+            (output_value.row_count(), output_value.col_count()),
+            (1, 1),
+            "can only differentiate 1x1 results"
+        );
+
+        self.grads.clear();
+        self.grads.extend(
+            self.values
+                .iter()
+                .map(|value| WeightMatrix::new(value.row_count(), value.col_count())),
+        );
+        self.grads[output.0][0][0] = T::ONE;
+
+        let values = &self.values;
+        for ix in (0..=output.0).rev() {
+            let (grads, later_grads) = self.grads.split_at_mut(ix);
+            let (grad, value) = (&later_grads[0], &values[ix]);
+            match self.ops[ix] {
+                Op::Leaf => (),
+                Op::Add(a, b) => {
+                    accumulate(&mut grads[a.0], |row_ix, col_ix| grad[row_ix][col_ix]);
+                    accumulate(&mut grads[b.0], |row_ix, col_ix| grad[row_ix][col_ix]);
+                },
+                Op::Sub(a, b) => {
+                    accumulate(&mut grads[a.0], |row_ix, col_ix| grad[row_ix][col_ix]);
+                    accumulate(&mut grads[b.0], |row_ix, col_ix| -grad[row_ix][col_ix]);
+                },
+                Op::Mul(a, b) => {
+                    let (a_value, b_value) = (&values[a.0], &values[b.0]);
+                    accumulate(&mut grads[a.0], |row_ix, col_ix| {
+                        grad[row_ix][col_ix] * b_value[row_ix][col_ix]
+                    });
+                    accumulate(&mut grads[b.0], |row_ix, col_ix| {
+                        grad[row_ix][col_ix] * a_value[row_ix][col_ix]
+                    });
+                },
+                Op::Scale(a, factor) => accumulate(&mut grads[a.0], |row_ix, col_ix| grad[row_ix][col_ix] * factor),
+                Op::AddRow(a, row) => {
+                    accumulate(&mut grads[a.0], |row_ix, col_ix| grad[row_ix][col_ix]);
+                    accumulate(&mut grads[row.0], |_, col_ix| {
+                        grad.column(col_ix).fold(T::ZERO, |acc, x| acc + x)
+                    });
+                },
+                Op::MatMul(a, b) => {
+                    let (a_value, b_value) = (&values[a.0], &values[b.0]);
+                    // d/dA = grad * B^T and d/dB = A^T * grad
+                    accumulate(&mut grads[a.0], |row_ix, col_ix| {
+                        T::dot(&grad[row_ix], &b_value[col_ix])
+                    });
+                    accumulate(&mut grads[b.0], |row_ix, col_ix| {
+                        a_value
+                            .column(row_ix)
+                            .zip(grad.column(col_ix))
+                            .fold(T::ZERO, |acc, (a, grad)| acc + a * grad)
+                    });
+                },
+                Op::Transpose(a) => accumulate(&mut grads[a.0], |row_ix, col_ix| grad[col_ix][row_ix]),
+                Op::Activation(a, activation_fn) => {
+                    let a_value = &values[a.0];
+                    let mut row_grads = vec![T::ZERO; a_value.col_count()];
+                    for row_ix in 0..a_value.row_count() {
+                        activation_fn.apply_derivative_batch(&mut row_grads, &grad[row_ix], &a_value[row_ix]);
+                        T::axpy(&mut grads[a.0][row_ix], T::ONE, &row_grads);
+                    }
+                },
+                Op::Softmax(a) => {
+                    // Each output depends on every input in its row: d/dx_i = y_i * (grad_i - sum_j(grad_j * y_j))
+                    for row_ix in 0..value.row_count() {
+                        let weighted_sum = T::dot(&grad[row_ix], &value[row_ix]);
+                        for (col_ix, a_grad) in grads[a.0][row_ix].iter_mut().enumerate() {
+                            *a_grad += value[row_ix][col_ix] * (grad[row_ix][col_ix] - weighted_sum);
+                        }
+                    }
+                },
+                Op::Exp(a) => accumulate(&mut grads[a.0], |row_ix, col_ix| {
+                    grad[row_ix][col_ix] * value[row_ix][col_ix]
+                }),
+                Op::Ln(a) => {
+                    let a_value = &values[a.0];
+                    accumulate(&mut grads[a.0], |row_ix, col_ix| {
+                        grad[row_ix][col_ix] / a_value[row_ix][col_ix]
+                    });
+                },
+                Op::Sum(a) => accumulate(&mut grads[a.0], |_, _| grad[0][0]),
+                Op::Mean(a) => {
+                    let a_grad = &mut grads[a.0];
+                    let count = T::from_f64((a_grad.row_count() * a_grad.col_count()) as f64);
+                    accumulate(a_grad, |_, _| grad[0][0] / count);
+                },
+                Op::SumRows(a) => accumulate(&mut grads[a.0], |_, col_ix| grad[0][col_ix]),
+            }
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Mini-batch training.  Instead of updating the weights after every example, the gradients for all of the examples
+//! in a batch are summed up and applied in a single update at the end.
+//!
+//! The layers' own `outputs`, `errors_scratch` and `neuron_gradients` buffers only have room for one example at a
+//! time, so batches are run through the network using separate `NetworkWorkspace`s instead.  That leaves the network
+//! itself untouched until the update, which lets `BatchTrainer` split a batch across several threads that share it.
+
+use crate::{dataset::MiniBatch, kernels, Dataset, DenseLayer, Network, NnError, OutputLayer, Weight, WeightMatrix};
+
+/// Scratch buffers for running a single example through a `DenseLayer`.  Mirrors the buffers stored on the layer
+/// itself.
+#[derive(Clone, Debug, Default)]
+pub struct LayerWorkspace {
+    pub outputs_before_activation: Vec<Weight>,
+    pub outputs: Vec<Weight>,
+    pub errors_scratch: Vec<Weight>,
+    pub neuron_gradients: Vec<Weight>,
+}
+
+impl LayerWorkspace {
+    pub fn new(neuron_count: usize) -> Self {
+        LayerWorkspace {
+            outputs_before_activation: vec![0.; neuron_count],
+            outputs: vec![0.; neuron_count],
+            errors_scratch: vec![0.; neuron_count],
+            neuron_gradients: vec![0.; neuron_count],
+        }
+    }
+}
+
+/// Scratch buffers for running a single example through an `OutputLayer`.
+#[derive(Clone, Debug, Default)]
+pub struct OutputLayerWorkspace {
+    pub outputs_before_activation: Vec<Weight>,
+    pub outputs: Vec<Weight>,
+    pub neuron_gradients: Vec<Weight>,
+}
+
+impl OutputLayerWorkspace {
+    pub fn new(neuron_count: usize) -> Self {
+        OutputLayerWorkspace {
+            outputs_before_activation: vec![0.; neuron_count],
+            outputs: vec![0.; neuron_count],
+            neuron_gradients: vec![0.; neuron_count],
+        }
+    }
+}
+
+/// Scratch buffers for running a single example through every layer of a `Network` without modifying it.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkWorkspace {
+    pub hidden_layers: Vec<LayerWorkspace>,
+    pub outputs: OutputLayerWorkspace,
+}
+
+impl NetworkWorkspace {
+    /// Returns an error if `network` has feature layers, which the batched and Hogwild trainers don't support.
+    pub fn new(network: &Network) -> Result<Self, NnError> {
+        if !network.feature_layers.is_empty() {
+            return Err(NnError::UnsupportedFeatureLayers);
+        }
+        Ok(NetworkWorkspace {
+            hidden_layers: network
+                .hidden_layers
+                .iter()
+                .map(|layer| LayerWorkspace::new(layer.weights.row_count()))
+                .collect(),
+            outputs: OutputLayerWorkspace::new(network.output_count()),
+        })
+    }
+}
+
+/// The gradients of every weight and bias in a network, summed over any number of examples.  Has the same shape as a
+/// `NetworkSnapshot`.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkGradients {
+    pub hidden_layer_weights: Vec<WeightMatrix>,
+    pub hidden_layer_biases: Vec<Vec<Weight>>,
+    pub output_weights: WeightMatrix,
+}
+
+impl NetworkGradients {
+    /// Creates zeroed gradients with the same shape as `network`.
+    pub fn new(network: &Network) -> Self {
+        NetworkGradients {
+            hidden_layer_weights: network
+                .hidden_layers
+                .iter()
+                .map(|layer| WeightMatrix::new(layer.weights.row_count(), layer.weights.col_count()))
+                .collect(),
+            hidden_layer_biases: network
+                .hidden_layers
+                .iter()
+                .map(|layer| vec![0.; layer.biases.len()])
+                .collect(),
+            output_weights: WeightMatrix::new(network.outputs.weights.row_count(), network.outputs.weights.col_count()),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for weights in &mut self.hidden_layer_weights {
+            weights.as_mut_slice().fill(0.);
+        }
+        for biases in &mut self.hidden_layer_biases {
+            biases.fill(0.);
+        }
+        self.output_weights.as_mut_slice().fill(0.);
+    }
+
+    /// Adds the gradients in `other`, which must have the same shape, to these ones.
+    pub fn add(&mut self, other: &NetworkGradients) {
+        for (weights, other_weights) in self.hidden_layer_weights.iter_mut().zip(&other.hidden_layer_weights) {
+            kernels::axpy(weights.as_mut_slice(), 1., other_weights.as_slice());
+        }
+        for (biases, other_biases) in self.hidden_layer_biases.iter_mut().zip(&other.hidden_layer_biases) {
+            kernels::axpy(biases, 1., other_biases);
+        }
+        kernels::axpy(self.output_weights.as_mut_slice(), 1., other.output_weights.as_slice());
+    }
+}
+
+impl DenseLayer {
+    /// Same as `forward_propagate`, but writes the outputs into `workspace` instead of the layer.
+    pub fn forward_propagate_with(&self, inputs: &[Weight], workspace: &mut LayerWorkspace) {
+        debug_assert_eq!(self.weights.col_count(), inputs.len());
+        for neuron_ix in 0..self.weights.row_count() {
+            workspace.outputs_before_activation[neuron_ix] =
+                kernels::dot(&self.weights[neuron_ix], inputs) + self.biases[neuron_ix];
+        }
+
+        (self.activation_fn).apply_batch(&mut workspace.outputs, &workspace.outputs_before_activation);
+    }
+
+    /// Same as `compute_gradients`, but reads and writes `workspace` instead of the layer.
+    pub fn compute_gradients_with(
+        &self,
+        output_weights: &WeightMatrix,
+        gradient_of_output_neurons: &[Weight],
+        workspace: &mut LayerWorkspace,
+    ) {
+        debug_assert_eq!(output_weights.row_count(), gradient_of_output_neurons.len());
+
+        workspace.errors_scratch.fill(0.);
+        for (output_neuron_ix, &gradient_of_output_neuron) in gradient_of_output_neurons.iter().enumerate() {
+            kernels::axpy(
+                &mut workspace.errors_scratch,
+                gradient_of_output_neuron,
+                &output_weights[output_neuron_ix],
+            );
+        }
+
+        (self.activation_fn).apply_derivative_batch(
+            &mut workspace.neuron_gradients,
+            &workspace.errors_scratch,
+            &workspace.outputs_before_activation,
+        );
+    }
+}
+
+impl OutputLayer {
+    /// Same as `forward_propagate`, but writes the outputs into `workspace` instead of the layer.
+    pub fn forward_propagate_with(&self, inputs: &[Weight], workspace: &mut OutputLayerWorkspace) {
+        debug_assert_eq!(self.weights.col_count(), inputs.len());
+        for neuron_ix in 0..self.weights.row_count() {
+            workspace.outputs_before_activation[neuron_ix] = kernels::dot(&self.weights[neuron_ix], inputs);
+        }
+
+        (self.activation_fn).apply_batch(&mut workspace.outputs, &workspace.outputs_before_activation);
+    }
+
+    /// Does the work of both `compute_costs` and `compute_gradients` using the outputs in `workspace`, writing the
+    /// gradients back into it.  Returns the average cost.
+    pub fn compute_gradients_with(&self, expected: &[Weight], workspace: &mut OutputLayerWorkspace) -> Weight {
+        debug_assert_eq!(expected.len(), workspace.outputs.len());
+        let mut total_cost = 0.;
+        for (neuron_ix, &output) in workspace.outputs.iter().enumerate() {
+            let error = expected[neuron_ix] - output;
+            total_cost += self.cost_fn.get_cost(error);
+            workspace.neuron_gradients[neuron_ix] =
+                self.compute_neuron_gradient(workspace.outputs_before_activation[neuron_ix], error);
+        }
+        total_cost / workspace.outputs.len() as Weight
+    }
+}
+
+/// Returns the outputs of the last of `layers`, which are the inputs to the layer after them, or `example` if there
+/// aren't any.
+pub(crate) fn last_outputs<'a>(layers: &'a [LayerWorkspace], example: &'a [Weight]) -> &'a [Weight] {
+    layers.last().map(|ws| ws.outputs.as_slice()).unwrap_or(example)
+}
+
+impl Network {
+    /// Runs one example through the network using `workspace` and adds the resulting gradients to `gradients`.  The
+    /// network itself isn't modified.  Returns the average cost for the example.
+    ///
+    /// The example must match the size of the network, and `workspace` and `gradients` must have been created for a
+    /// network with the same shape.
+    pub fn accumulate_gradients(
+        &self,
+        example: &[Weight],
+        expected: &[Weight],
+        workspace: &mut NetworkWorkspace,
+        gradients: &mut NetworkGradients,
+    ) -> Weight {
+        let layer_count = self.hidden_layers.len();
+
+        for layer_ix in 0..layer_count {
+            let (previous, rest) = workspace.hidden_layers.split_at_mut(layer_ix);
+            self.hidden_layers[layer_ix].forward_propagate_with(last_outputs(previous, example), &mut rest[0]);
+        }
+        self.outputs
+            .forward_propagate_with(last_outputs(&workspace.hidden_layers, example), &mut workspace.outputs);
+
+        let cost = self.outputs.compute_gradients_with(expected, &mut workspace.outputs);
+        for layer_ix in (0..layer_count).rev() {
+            let (current, next) = workspace.hidden_layers.split_at_mut(layer_ix + 1);
+            let (output_weights, gradient_of_output_neurons) = match next.first() {
+                Some(next) => (
+                    &self.hidden_layers[layer_ix + 1].weights,
+                    next.neuron_gradients.as_slice(),
+                ),
+                None => (&self.outputs.weights, workspace.outputs.neuron_gradients.as_slice()),
+            };
+            self.hidden_layers[layer_ix].compute_gradients_with(
+                output_weights,
+                gradient_of_output_neurons,
+                &mut current[layer_ix],
+            );
+        }
+
+        let last_hidden_outputs = last_outputs(&workspace.hidden_layers, example);
+        for (neuron_ix, &neuron_gradient) in workspace.outputs.neuron_gradients.iter().enumerate() {
+            kernels::axpy(
+                &mut gradients.output_weights[neuron_ix],
+                neuron_gradient,
+                last_hidden_outputs,
+            );
+        }
+        for layer_ix in 0..layer_count {
+            let inputs = last_outputs(&workspace.hidden_layers[..layer_ix], example);
+            let neuron_gradients = &workspace.hidden_layers[layer_ix].neuron_gradients;
+            let weight_gradients = &mut gradients.hidden_layer_weights[layer_ix];
+            for (neuron_ix, &neuron_gradient) in neuron_gradients.iter().enumerate() {
+                kernels::axpy(&mut weight_gradients[neuron_ix], neuron_gradient, inputs);
+            }
+            kernels::axpy(&mut gradients.hidden_layer_biases[layer_ix], 1., neuron_gradients);
+        }
+
+        cost
+    }
+
+    /// Adds `learning_rate` times `gradients` to every weight and bias, leaving pruned weights at zero.  Each layer
+    /// applies its learning rate multiplier, and frozen ones aren't updated.
+    pub fn apply_gradients(&mut self, gradients: &NetworkGradients, learning_rate: Weight) {
+        for (layer_ix, layer) in self.hidden_layers.iter_mut().enumerate() {
+            if layer.frozen {
+                continue;
+            }
+            let layer_learning_rate = layer.layer_learning_rate(learning_rate);
+            kernels::axpy(
+                layer.weights.as_mut_slice(),
+                layer_learning_rate,
+                gradients.hidden_layer_weights[layer_ix].as_slice(),
+            );
+            kernels::axpy(
+                &mut layer.biases,
+                layer_learning_rate,
+                &gradients.hidden_layer_biases[layer_ix],
+            );
+        }
+        self.outputs
+            .apply_weight_gradients(&gradients.output_weights, learning_rate);
+        self.apply_weight_masks();
+    }
+}
+
+struct Worker {
+    workspace: NetworkWorkspace,
+    gradients: NetworkGradients,
+    total_cost: Weight,
+}
+
+impl Worker {
+    fn run<D: Dataset>(&mut self, network: &Network, batch: &MiniBatch<'_, D>) {
+        self.gradients.clear();
+        self.total_cost = 0.;
+        for (inputs, targets) in batch.iter() {
+            self.total_cost += network.accumulate_gradients(inputs, targets, &mut self.workspace, &mut self.gradients);
+        }
+    }
+}
+
+/// Trains a network one mini-batch at a time, averaging the gradients of all of the examples in a batch and applying
+/// them in a single update.
+///
+/// Each batch is split evenly between `thread_count` workers, which each have their own scratch buffers and
+/// gradients; the gradients are summed once all workers have finished.  The result is the same no matter how many
+/// threads are used, apart from differences in floating point rounding caused by summing in a different order.  On
+/// wasm32, where threads aren't available, the workers are run one after another on the current thread.
+///
+/// The buffers are allocated up front and re-used for every batch, so a trainer should only be used with the network
+/// that it was created for (or others with the same shape).
+pub struct BatchTrainer {
+    workers: Vec<Worker>,
+}
+
+impl BatchTrainer {
+    /// Returns an error if `network` has feature layers.
+    pub fn new(network: &Network, thread_count: usize) -> Result<Self, NnError> {
+        assert!(thread_count > 0, "thread count must be greater than zero");
+        Ok(BatchTrainer {
+            workers: (0..thread_count)
+                .map(|_| {
+                    Ok(Worker {
+                        workspace: NetworkWorkspace::new(network)?,
+                        gradients: NetworkGradients::new(network),
+                        total_cost: 0.,
+                    })
+                })
+                .collect::<Result<_, NnError>>()?,
+        })
+    }
+
+    pub fn thread_count(&self) -> usize { self.workers.len() }
+
+    /// Computes the gradients for every example in `batch`, then updates the network's weights and biases using their
+    /// average.  Returns the average cost across the batch from before the update.
+    ///
+    /// Like `try_train_one_example`, returns an error if the cost or any of the updated weights or biases are NaN or
+    /// infinite.
+    pub fn train_batch<D: Dataset + Sync>(
+        &mut self,
+        network: &mut Network,
+        batch: &MiniBatch<'_, D>,
+        learning_rate: Weight,
+    ) -> Result<Weight, NnError> {
+        network.validate_dataset(batch.dataset())?;
+        if batch.is_empty() {
+            return Ok(0.);
+        }
+
+        let chunk_size = batch.len().div_ceil(self.workers.len());
+        let chunks: Vec<MiniBatch<'_, D>> = batch.chunks(chunk_size).collect();
+        let workers = &mut self.workers[..chunks.len()];
+        let shared_network: &Network = network;
+        if workers.len() == 1 || cfg!(target_arch = "wasm32") {
+            for (worker, chunk) in workers.iter_mut().zip(&chunks) {
+                worker.run(shared_network, chunk);
+            }
+        } else {
+            std::thread::scope(|scope| {
+                let (first_worker, other_workers) = workers.split_first_mut().unwrap();
+                for (worker, chunk) in other_workers.iter_mut().zip(&chunks[1..]) {
+                    scope.spawn(move || worker.run(shared_network, chunk));
+                }
+                first_worker.run(shared_network, &chunks[0]);
+            });
+        }
+
+        let (first_worker, other_workers) = workers.split_first_mut().unwrap();
+        let mut total_cost = first_worker.total_cost;
+        for worker in other_workers.iter() {
+            first_worker.gradients.add(&worker.gradients);
+            total_cost += worker.total_cost;
+        }
+
+        let cost = total_cost / batch.len() as Weight;
+        if !cost.is_finite() {
+            return Err(NnError::NonFiniteCost { cost });
+        }
+        network.apply_gradients(&first_worker.gradients, learning_rate / batch.len() as Weight);
+        network.check_weights_finite()?;
+
+        Ok(cost)
+    }
+}
@@ -0,0 +1,109 @@
+//! Runs many examples through a network at once.  Each layer's outputs for the whole batch are computed with a
+//! single matrix multiplication, so every weight is loaded from memory once per block of examples rather than once per
+//! example.
+
+use crate::{kernels, ActivationFunction, Network, NnError, Weight, WeightMatrix};
+
+/// The outputs of every layer for a batch of examples, one row per example.  Can be re-used between batches to avoid
+/// re-allocating.
+#[derive(Clone, Debug, Default)]
+pub struct BatchActivations {
+    pub inputs: WeightMatrix,
+    pub hidden_layers: Vec<WeightMatrix>,
+    pub outputs: WeightMatrix,
+    outputs_before_activation: WeightMatrix,
+}
+
+impl BatchActivations {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn example_count(&self) -> usize { self.inputs.row_count() }
+}
+
+/// Multiplies `inputs` by the layer's weights, adds the biases if there are any, and applies the activation function
+/// to each example's outputs.
+fn forward_propagate_layer(
+    weights: &WeightMatrix,
+    biases: Option<&[Weight]>,
+    activation_fn: &dyn ActivationFunction,
+    inputs: &WeightMatrix,
+    outputs_before_activation: &mut WeightMatrix,
+    outputs: &mut WeightMatrix,
+) {
+    outputs_before_activation.resize(inputs.row_count(), weights.row_count());
+    outputs.resize(inputs.row_count(), weights.row_count());
+    kernels::matmul_transposed(inputs, weights, outputs_before_activation);
+
+    for example_ix in 0..inputs.row_count() {
+        let row = &mut outputs_before_activation[example_ix];
+        if let Some(biases) = biases {
+            kernels::axpy(row, 1., biases);
+        }
+        // Softmax needs to see all of an example's outputs at once, so activations are applied a row at a time
+        activation_fn.apply_batch(&mut outputs[example_ix], row);
+    }
+}
+
+impl Network {
+    /// Runs all of the examples packed back-to-back in `examples` through the network, storing the outputs of every
+    /// layer in `activations`.  Returns the outputs of the output layer, one row per example.
+    ///
+    /// Unlike `compute`, this doesn't touch the buffers stored on the layers.
+    pub fn forward_propagate_batch<'a>(
+        &self,
+        examples: &[Weight],
+        activations: &'a mut BatchActivations,
+    ) -> Result<&'a WeightMatrix, NnError> {
+        if !self.feature_layers.is_empty() {
+            return Err(NnError::UnsupportedFeatureLayers);
+        }
+        let example_count = self.count_inputs(examples)?;
+        let input_dims = self.input_count();
+
+        let BatchActivations {
+            inputs,
+            hidden_layers,
+            outputs,
+            outputs_before_activation,
+        } = activations;
+        inputs.resize(example_count, input_dims);
+        for (example_ix, example) in examples.chunks_exact(input_dims).enumerate() {
+            inputs[example_ix].copy_from_slice(example);
+        }
+        hidden_layers.resize_with(self.hidden_layers.len(), WeightMatrix::default);
+
+        let mut layer_inputs: &WeightMatrix = inputs;
+        for (layer, layer_outputs) in self.hidden_layers.iter().zip(hidden_layers.iter_mut()) {
+            forward_propagate_layer(
+                &layer.weights,
+                Some(&layer.biases),
+                layer.activation_fn,
+                layer_inputs,
+                outputs_before_activation,
+                layer_outputs,
+            );
+            layer_inputs = layer_outputs;
+        }
+
+        // No bias on the output layer.
+        forward_propagate_layer(
+            &self.outputs.weights,
+            None,
+            self.outputs.activation_fn,
+            layer_inputs,
+            outputs_before_activation,
+            outputs,
+        );
+
+        Ok(outputs)
+    }
+
+    /// Same as `compute`, but for any number of examples packed back-to-back in `examples`.  Returns the outputs for
+    /// each example back-to-back in the same order.
+    pub fn compute_batch(&self, examples: &[Weight]) -> Result<Vec<Weight>, NnError> {
+        let mut activations = BatchActivations::new();
+        let outputs = self.forward_propagate_batch(examples, &mut activations)?;
+
+        Ok(outputs.iter_rows().flatten().copied().collect())
+    }
+}
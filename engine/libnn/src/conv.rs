@@ -0,0 +1,520 @@
+//! Convolution and pooling layers for image inputs.  Images are stored flattened in channel-major order (all of the
+//! rows of the first channel, then all of the rows of the second, and so on), which is also how the pixels of a
+//! grayscale IDX image are laid out.  A stack of these layers is kept in `Network::feature_layers`, and the values it
+//! produces are fed into the first dense layer.
+//!
+//! Everything is computed with plain loops over the receptive field of each output pixel.  That's plenty for the small
+//! CNNs these are meant for and keeps the backward passes easy to follow.
+
+use crate::{ActivationFunction, EmbeddingLayer, Float, Network, Weight, WeightMatrix};
+
+/// The dimensions of an image, or of the output of a convolution or pooling layer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageShape {
+    pub channels: usize,
+    pub height: usize,
+    pub width: usize,
+}
+
+impl ImageShape {
+    pub fn new(channels: usize, height: usize, width: usize) -> Self {
+        ImageShape {
+            channels,
+            height,
+            width,
+        }
+    }
+
+    /// The number of values in an image of this shape.
+    pub fn len(&self) -> usize { self.channels * self.height * self.width }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    fn index(&self, channel: usize, y: usize, x: usize) -> usize { (channel * self.height + y) * self.width + x }
+}
+
+/// The number of positions a window of `window_size` fits in along an axis of `input_size` values padded with
+/// `padding` zeros on each side.
+fn output_size(input_size: usize, window_size: usize, stride: usize, padding: usize) -> usize {
+    assert!(stride > 0, "stride must be greater than zero");
+    assert!(
+        window_size > 0 && window_size <= input_size + 2 * padding,
+        "a window of size {} doesn't fit in {} values with {} padding",
+        window_size,
+        input_size,
+        padding
+    );
+    (input_size + 2 * padding - window_size) / stride + 1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Conv2DConfig {
+    pub out_channels: usize,
+    pub kernel_height: usize,
+    pub kernel_width: usize,
+    /// How far the kernel moves between output pixels, in both directions.
+    pub stride: usize,
+    /// The number of rows and columns of zeros added around each side of the input.
+    pub padding: usize,
+}
+
+/// Calls `f(weight_ix, input_ix)` for every weight of a kernel that lines up with an input pixel when computing the
+/// output pixel at `(out_y, out_x)`.  Weights that line up with padding are skipped since they'd be multiplied by zero.
+fn for_each_tap(
+    input_shape: &ImageShape,
+    config: &Conv2DConfig,
+    out_y: usize,
+    out_x: usize,
+    mut f: impl FnMut(usize, usize),
+) {
+    for in_channel in 0..input_shape.channels {
+        for kernel_y in 0..config.kernel_height {
+            // Positions in the padding to the top or left wrap around to huge values, so one check covers both sides
+            let y = (out_y * config.stride + kernel_y).wrapping_sub(config.padding);
+            if y >= input_shape.height {
+                continue;
+            }
+            for kernel_x in 0..config.kernel_width {
+                let x = (out_x * config.stride + kernel_x).wrapping_sub(config.padding);
+                if x >= input_shape.width {
+                    continue;
+                }
+                let weight_ix = (in_channel * config.kernel_height + kernel_y) * config.kernel_width + kernel_x;
+                f(weight_ix, input_shape.index(in_channel, y, x));
+            }
+        }
+    }
+}
+
+pub struct Conv2DLayer<T: Float = Weight> {
+    pub input_shape: ImageShape,
+    pub output_shape: ImageShape,
+    pub config: Conv2DConfig,
+    /// One row per output channel holding its kernel for every input channel.  The weight for input channel `c` at
+    /// `(y, x)` in the kernel is at `(c * kernel_height + y) * kernel_width + x`.
+    pub weights: WeightMatrix<T>,
+    /// One per output channel, shared by all of its pixels.
+    pub biases: Vec<T>,
+    pub activation_fn: &'static dyn ActivationFunction<T>,
+    pub neuron_gradients: Vec<T>,
+    /// The errors of this layer's outputs, filled in by the layer after it during backpropagation.
+    pub errors_scratch: Vec<T>,
+    pub outputs_before_activation: Vec<T>,
+    pub outputs: Vec<T>,
+    /// Same as `DenseLayer::learning_rate_multiplier`.
+    pub learning_rate_multiplier: T,
+    /// Same as `DenseLayer::frozen`.
+    pub frozen: bool,
+}
+
+impl<T: Float> Conv2DLayer<T> {
+    /// `init_weights` is called with the output channel and the index of the weight within its kernel.
+    pub fn new(
+        input_shape: ImageShape,
+        config: Conv2DConfig,
+        init_weights: &mut impl FnMut(usize, usize) -> T,
+        init_biases: &mut impl FnMut(usize) -> T,
+        activation_fn: &'static dyn ActivationFunction<T>,
+    ) -> Self {
+        let output_shape = ImageShape::new(
+            config.out_channels,
+            output_size(input_shape.height, config.kernel_height, config.stride, config.padding),
+            output_size(input_shape.width, config.kernel_width, config.stride, config.padding),
+        );
+        let kernel_len = input_shape.channels * config.kernel_height * config.kernel_width;
+
+        Conv2DLayer {
+            input_shape,
+            output_shape,
+            config,
+            weights: WeightMatrix::from_fn(config.out_channels, kernel_len, init_weights),
+            biases: (0..config.out_channels).map(init_biases).collect(),
+            activation_fn,
+            neuron_gradients: vec![T::ZERO; output_shape.len()],
+            errors_scratch: vec![T::ZERO; output_shape.len()],
+            outputs_before_activation: vec![T::ZERO; output_shape.len()],
+            outputs: vec![T::ZERO; output_shape.len()],
+            learning_rate_multiplier: T::ONE,
+            frozen: false,
+        }
+    }
+
+    /// Same as `DenseLayer::layer_learning_rate`.
+    pub fn layer_learning_rate(&self, learning_rate: T) -> T { learning_rate * self.learning_rate_multiplier }
+
+    /// Same as `forward_propagate`, but writing into the given buffers rather than the layer's own.
+    pub fn forward_propagate_into(&self, inputs: &[T], outputs_before_activation: &mut Vec<T>, outputs: &mut Vec<T>) {
+        debug_assert_eq!(inputs.len(), self.input_shape.len());
+        outputs_before_activation.resize(self.output_shape.len(), T::ZERO);
+        outputs.resize(self.output_shape.len(), T::ZERO);
+
+        for out_channel in 0..self.output_shape.channels {
+            let kernel = &self.weights[out_channel];
+            for out_y in 0..self.output_shape.height {
+                for out_x in 0..self.output_shape.width {
+                    let mut sum = self.biases[out_channel];
+                    for_each_tap(&self.input_shape, &self.config, out_y, out_x, |weight_ix, input_ix| {
+                        sum += kernel[weight_ix] * inputs[input_ix];
+                    });
+                    outputs_before_activation[self.output_shape.index(out_channel, out_y, out_x)] = sum;
+                }
+            }
+        }
+
+        (self.activation_fn).apply_batch(outputs, outputs_before_activation);
+    }
+
+    pub fn forward_propagate(&mut self, inputs: &[T]) {
+        let mut outputs_before_activation = std::mem::take(&mut self.outputs_before_activation);
+        let mut outputs = std::mem::take(&mut self.outputs);
+        self.forward_propagate_into(inputs, &mut outputs_before_activation, &mut outputs);
+        self.outputs_before_activation = outputs_before_activation;
+        self.outputs = outputs;
+    }
+
+    /// Populates `self.neuron_gradients` from the errors in `self.errors_scratch`.
+    pub fn compute_gradients(&mut self) {
+        (self.activation_fn).apply_derivative_batch(
+            &mut self.neuron_gradients,
+            &self.errors_scratch,
+            &self.outputs_before_activation,
+        );
+    }
+
+    /// Once `compute_gradients()` has been called, fills `input_errors` with the errors of the values that were fed
+    /// into this layer.
+    pub fn backpropagate_errors(&self, input_errors: &mut [T]) {
+        input_errors.fill(T::ZERO);
+        for out_channel in 0..self.output_shape.channels {
+            let kernel = &self.weights[out_channel];
+            for out_y in 0..self.output_shape.height {
+                for out_x in 0..self.output_shape.width {
+                    let gradient = self.neuron_gradients[self.output_shape.index(out_channel, out_y, out_x)];
+                    for_each_tap(&self.input_shape, &self.config, out_y, out_x, |weight_ix, input_ix| {
+                        input_errors[input_ix] += gradient * kernel[weight_ix];
+                    });
+                }
+            }
+        }
+    }
+
+    /// Each kernel weight is shared by every output pixel of its channel, so its update is summed over all of them.
+    /// Applies `learning_rate_multiplier`, and does nothing if the layer is frozen.
+    pub fn update_weights(&mut self, inputs: &[T], learning_rate: T) {
+        if self.frozen {
+            return;
+        }
+        let learning_rate = self.layer_learning_rate(learning_rate);
+        for out_channel in 0..self.output_shape.channels {
+            let kernel = &mut self.weights[out_channel];
+            for out_y in 0..self.output_shape.height {
+                for out_x in 0..self.output_shape.width {
+                    let gradient = self.neuron_gradients[self.output_shape.index(out_channel, out_y, out_x)];
+                    let alpha = learning_rate * gradient;
+                    for_each_tap(&self.input_shape, &self.config, out_y, out_x, |weight_ix, input_ix| {
+                        kernel[weight_ix] += alpha * inputs[input_ix];
+                    });
+                }
+            }
+        }
+    }
+
+    /// Same as `update_weights`, applies `learning_rate_multiplier` and does nothing if the layer is frozen.
+    pub fn update_biases(&mut self, learning_rate: T) {
+        if self.frozen {
+            return;
+        }
+        let learning_rate = self.layer_learning_rate(learning_rate);
+        let pixel_count = self.output_shape.height * self.output_shape.width;
+        for (bias, gradients) in self
+            .biases
+            .iter_mut()
+            .zip(self.neuron_gradients.chunks_exact(pixel_count))
+        {
+            *bias += learning_rate * gradients.iter().fold(T::ZERO, |acc, &gradient| acc + gradient);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolingMode {
+    Max,
+    Average,
+}
+
+/// Downsamples each channel separately by taking the largest value or the average of each window.
+pub struct Pool2DLayer<T: Float = Weight> {
+    pub input_shape: ImageShape,
+    pub output_shape: ImageShape,
+    pub mode: PoolingMode,
+    /// The width and height of the windows.
+    pub size: usize,
+    pub stride: usize,
+    pub errors_scratch: Vec<T>,
+    pub outputs: Vec<T>,
+}
+
+impl<T: Float> Pool2DLayer<T> {
+    pub fn new(input_shape: ImageShape, mode: PoolingMode, size: usize, stride: usize) -> Self {
+        let output_shape = ImageShape::new(
+            input_shape.channels,
+            output_size(input_shape.height, size, stride, 0),
+            output_size(input_shape.width, size, stride, 0),
+        );
+
+        Pool2DLayer {
+            input_shape,
+            output_shape,
+            mode,
+            size,
+            stride,
+            errors_scratch: vec![T::ZERO; output_shape.len()],
+            outputs: vec![T::ZERO; output_shape.len()],
+        }
+    }
+
+    /// The indices of the inputs in the window for the given output pixel.
+    fn window(&self, channel: usize, out_y: usize, out_x: usize) -> impl Iterator<Item = usize> + '_ {
+        let (top, left) = (out_y * self.stride, out_x * self.stride);
+        (top..top + self.size)
+            .flat_map(move |y| (left..left + self.size).map(move |x| self.input_shape.index(channel, y, x)))
+    }
+
+    /// Calls `f(output_ix, window)` for every output pixel.
+    fn for_each_window(&self, mut f: impl FnMut(usize, &mut dyn Iterator<Item = usize>)) {
+        for channel in 0..self.output_shape.channels {
+            for out_y in 0..self.output_shape.height {
+                for out_x in 0..self.output_shape.width {
+                    f(
+                        self.output_shape.index(channel, out_y, out_x),
+                        &mut self.window(channel, out_y, out_x),
+                    );
+                }
+            }
+        }
+    }
+
+    /// The index of the largest input in a window, picking the first one in case of ties.
+    fn argmax(inputs: &[T], window: &mut dyn Iterator<Item = usize>) -> usize {
+        let first = window.next().unwrap();
+        window.fold(first, |best, ix| if inputs[ix] > inputs[best] { ix } else { best })
+    }
+
+    /// Same as `forward_propagate`, but writing into `outputs` rather than the layer's own buffer.
+    pub fn forward_propagate_into(&self, inputs: &[T], outputs: &mut Vec<T>) {
+        debug_assert_eq!(inputs.len(), self.input_shape.len());
+        outputs.resize(self.output_shape.len(), T::ZERO);
+        let window_len = T::from_f64((self.size * self.size) as f64);
+        self.for_each_window(|output_ix, window| {
+            outputs[output_ix] = match self.mode {
+                PoolingMode::Max => inputs[Self::argmax(inputs, window)],
+                PoolingMode::Average => window.fold(T::ZERO, |acc, ix| acc + inputs[ix]) / window_len,
+            };
+        });
+    }
+
+    pub fn forward_propagate(&mut self, inputs: &[T]) {
+        let mut outputs = std::mem::take(&mut self.outputs);
+        self.forward_propagate_into(inputs, &mut outputs);
+        self.outputs = outputs;
+    }
+
+    /// Fills `input_errors` with the errors of the values that were fed into this layer.  Max pooling passes each
+    /// error back to the input that was picked, and average pooling spreads it evenly over the window.
+    pub fn backpropagate_errors(&self, inputs: &[T], input_errors: &mut [T]) {
+        input_errors.fill(T::ZERO);
+        let window_len = T::from_f64((self.size * self.size) as f64);
+        self.for_each_window(|output_ix, window| {
+            let error = self.errors_scratch[output_ix];
+            match self.mode {
+                PoolingMode::Max => input_errors[Self::argmax(inputs, window)] += error,
+                PoolingMode::Average =>
+                    for ix in window {
+                        input_errors[ix] += error / window_len;
+                    },
+            }
+        });
+    }
+}
+
+/// Marks the point where images are fed into the dense layers as flat vectors.  Images are already stored flattened,
+/// so values pass through unchanged in both directions.
+pub struct FlattenLayer<T: Float = Weight> {
+    pub input_shape: ImageShape,
+    pub errors_scratch: Vec<T>,
+    pub outputs: Vec<T>,
+}
+
+impl<T: Float> FlattenLayer<T> {
+    pub fn new(input_shape: ImageShape) -> Self {
+        FlattenLayer {
+            input_shape,
+            errors_scratch: vec![T::ZERO; input_shape.len()],
+            outputs: vec![T::ZERO; input_shape.len()],
+        }
+    }
+}
+
+/// A layer that sits in front of the dense layers of a `Network`.
+pub enum FeatureLayer<T: Float = Weight> {
+    Conv2D(Conv2DLayer<T>),
+    Pool2D(Pool2DLayer<T>),
+    Flatten(FlattenLayer<T>),
+    /// Replaces category IDs with learned vectors.  Only works as the first feature layer, since it needs the raw
+    /// category IDs from the examples; `Network::new` returns `NnError::MisplacedEmbedding` if it's anywhere else.
+    Embedding(EmbeddingLayer<T>),
+}
+
+impl<T: Float> FeatureLayer<T> {
+    pub fn input_shape(&self) -> ImageShape {
+        match self {
+            FeatureLayer::Conv2D(layer) => layer.input_shape,
+            FeatureLayer::Pool2D(layer) => layer.input_shape,
+            FeatureLayer::Flatten(layer) => layer.input_shape,
+            FeatureLayer::Embedding(layer) => ImageShape::new(1, 1, layer.input_count),
+        }
+    }
+
+    pub fn output_shape(&self) -> ImageShape {
+        match self {
+            FeatureLayer::Conv2D(layer) => layer.output_shape,
+            FeatureLayer::Pool2D(layer) => layer.output_shape,
+            FeatureLayer::Flatten(layer) => ImageShape::new(1, 1, layer.input_shape.len()),
+            FeatureLayer::Embedding(layer) => ImageShape::new(1, 1, layer.output_count()),
+        }
+    }
+
+    pub fn outputs(&self) -> &[T] {
+        match self {
+            FeatureLayer::Conv2D(layer) => &layer.outputs,
+            FeatureLayer::Pool2D(layer) => &layer.outputs,
+            FeatureLayer::Flatten(layer) => &layer.outputs,
+            FeatureLayer::Embedding(layer) => &layer.outputs,
+        }
+    }
+
+    fn outputs_and_errors_mut(&mut self) -> (&[T], &mut [T]) {
+        match self {
+            FeatureLayer::Conv2D(layer) => (&layer.outputs, &mut layer.errors_scratch),
+            FeatureLayer::Pool2D(layer) => (&layer.outputs, &mut layer.errors_scratch),
+            FeatureLayer::Flatten(layer) => (&layer.outputs, &mut layer.errors_scratch),
+            FeatureLayer::Embedding(layer) => (&layer.outputs, &mut layer.errors_scratch),
+        }
+    }
+
+    /// The kernels and biases of convolution layers and the vectors of embedding layers, which don't have biases.  The
+    /// other layers don't have any weights.
+    pub fn weights_and_biases(&self) -> Option<(&WeightMatrix<T>, &[T])> {
+        match self {
+            FeatureLayer::Conv2D(layer) => Some((&layer.weights, &layer.biases)),
+            FeatureLayer::Embedding(layer) => Some((&layer.vectors, &[])),
+            _ => None,
+        }
+    }
+
+    pub fn weights_and_biases_mut(&mut self) -> Option<(&mut WeightMatrix<T>, &mut [T])> {
+        match self {
+            FeatureLayer::Conv2D(layer) => Some((&mut layer.weights, &mut layer.biases)),
+            FeatureLayer::Embedding(layer) => Some((&mut layer.vectors, &mut [])),
+            _ => None,
+        }
+    }
+
+    pub fn forward_propagate(&mut self, inputs: &[T]) {
+        match self {
+            FeatureLayer::Conv2D(layer) => layer.forward_propagate(inputs),
+            FeatureLayer::Pool2D(layer) => layer.forward_propagate(inputs),
+            FeatureLayer::Flatten(layer) => layer.outputs.copy_from_slice(inputs),
+            FeatureLayer::Embedding(layer) => layer.forward_propagate(inputs),
+        }
+    }
+
+    /// Same as `forward_propagate`, but writing into `outputs` rather than the layer's own buffer.
+    /// `outputs_before_activation` is used as scratch space.
+    pub fn forward_propagate_into(&self, inputs: &[T], outputs_before_activation: &mut Vec<T>, outputs: &mut Vec<T>) {
+        match self {
+            FeatureLayer::Conv2D(layer) => layer.forward_propagate_into(inputs, outputs_before_activation, outputs),
+            FeatureLayer::Pool2D(layer) => layer.forward_propagate_into(inputs, outputs),
+            FeatureLayer::Flatten(_) => {
+                outputs.clear();
+                outputs.extend_from_slice(inputs);
+            },
+            FeatureLayer::Embedding(layer) => layer.forward_propagate_into(inputs, outputs),
+        }
+    }
+
+    /// Once the layer after this one has filled in the errors of this layer's outputs, computes this layer's
+    /// gradients and, if `input_errors` is provided, fills it with the errors of the values fed into this layer.
+    fn backpropagate(&mut self, inputs: &[T], input_errors: Option<&mut [T]>) {
+        match self {
+            FeatureLayer::Conv2D(layer) => {
+                layer.compute_gradients();
+                if let Some(input_errors) = input_errors {
+                    layer.backpropagate_errors(input_errors);
+                }
+            },
+            FeatureLayer::Pool2D(layer) =>
+                if let Some(input_errors) = input_errors {
+                    layer.backpropagate_errors(inputs, input_errors);
+                },
+            FeatureLayer::Flatten(layer) =>
+                if let Some(input_errors) = input_errors {
+                    input_errors.copy_from_slice(&layer.errors_scratch);
+                },
+            FeatureLayer::Embedding(layer) =>
+                if let Some(input_errors) = input_errors {
+                    layer.backpropagate_errors(input_errors);
+                },
+        }
+    }
+}
+
+impl<T: Float> Network<T> {
+    /// Once the gradients of the dense layers have been computed, backpropagates through the feature layers and
+    /// computes their gradients.  Has to be called before the first dense layer's weights are updated.
+    pub(crate) fn backpropagate_feature_layers(&mut self, example: &[T]) {
+        let last_layer = match self.feature_layers.last_mut() {
+            Some(layer) => layer,
+            None => return,
+        };
+
+        // Same as the error accumulation in `DenseLayer::compute_gradients`
+        let (next_weights, next_gradients) = match self.hidden_layers.first() {
+            Some(layer) => (&layer.weights, &layer.neuron_gradients),
+            None => (&self.outputs.weights, &self.outputs.neuron_gradients),
+        };
+        let (_, errors) = last_layer.outputs_and_errors_mut();
+        errors.fill(T::ZERO);
+        for (neuron_ix, &gradient) in next_gradients.iter().enumerate() {
+            T::axpy(errors, gradient, &next_weights[neuron_ix]);
+        }
+
+        for layer_ix in (0..self.feature_layers.len()).rev() {
+            let (previous_layers, layers) = self.feature_layers.split_at_mut(layer_ix);
+            match previous_layers.last_mut() {
+                Some(previous_layer) => {
+                    let (inputs, input_errors) = previous_layer.outputs_and_errors_mut();
+                    layers[0].backpropagate(inputs, Some(input_errors));
+                },
+                None => layers[0].backpropagate(example, None),
+            }
+        }
+    }
+
+    /// Updates the kernels and biases of every convolution layer and the vectors used by every embedding layer once
+    /// `backpropagate_feature_layers` has been called.
+    pub(crate) fn update_feature_layers(&mut self, example: &[T], learning_rate: T) {
+        for layer_ix in 0..self.feature_layers.len() {
+            let (previous_layers, layers) = self.feature_layers.split_at_mut(layer_ix);
+            let inputs = previous_layers.last().map_or(example, |layer| layer.outputs());
+            match &mut layers[0] {
+                FeatureLayer::Conv2D(layer) => {
+                    layer.update_weights(inputs, self.learning_rate);
+                    layer.update_biases(learning_rate);
+                },
+                FeatureLayer::Embedding(layer) => layer.update_weights(self.learning_rate),
+                _ => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::{io::BufRead, str::FromStr};
+
+use crate::{InMemoryDataset, NnError, Weight};
+
+/// Identifies a CSV column either by its zero-based position or by its name in the header row.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ColumnSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for ColumnSelector {
+    type Err = std::convert::Infallible;
+
+    /// Anything that parses as an integer is treated as a column index; everything else is a column name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().parse() {
+            Ok(ix) => ColumnSelector::Index(ix),
+            Err(_) => ColumnSelector::Name(s.trim().to_owned()),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CsvOptions {
+    /// If set, the first non-empty line is treated as a header containing column names.
+    pub has_header: bool,
+    pub delimiter: char,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            has_header: true,
+            delimiter: ',',
+        }
+    }
+}
+
+/// A dataset loaded from a CSV file along with the names of the columns that its inputs and targets came from.
+/// Columns in files without a header are named after their index.
+#[derive(Clone, Debug)]
+pub struct CsvData {
+    pub dataset: InMemoryDataset,
+    pub feature_names: Vec<String>,
+    pub target_names: Vec<String>,
+}
+
+/// Splits a single line of CSV into fields.  Fields can be wrapped in double quotes in order to contain the delimiter,
+/// and a doubled quote inside of a quoted field is an escaped quote.
+fn split_line(line: &str, delimiter: char) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                field.push(c);
+            }
+        } else if c == '"' && field.trim().is_empty() {
+            field.clear();
+            in_quotes = true;
+        } else if c == delimiter {
+            fields.push(std::mem::take(&mut field));
+        } else {
+            field.push(c);
+        }
+    }
+    if in_quotes {
+        return Err("unterminated quoted field".to_owned());
+    }
+    fields.push(field);
+
+    Ok(fields)
+}
+
+fn resolve_column(selector: &ColumnSelector, column_names: &[String], line: usize) -> Result<usize, NnError> {
+    match selector {
+        ColumnSelector::Index(ix) if *ix < column_names.len() => Ok(*ix),
+        ColumnSelector::Index(ix) => Err(NnError::Csv {
+            line,
+            message: format!(
+                "column {} is out of range; there are {} columns",
+                ix,
+                column_names.len()
+            ),
+        }),
+        ColumnSelector::Name(name) => column_names
+            .iter()
+            .position(|column_name| column_name.trim() == name)
+            .ok_or_else(|| NnError::UnknownColumn(name.clone())),
+    }
+}
+
+/// Reads numeric columns out of CSV data into a dataset.  `features` selects the columns used as inputs; if it's
+/// `None`, every column that isn't a target is used.  `targets` can be empty when loading data to make predictions on.
+///
+/// Only the selected columns have to be numeric.  Blank lines are skipped.  Line numbers in errors start at 1.
+pub fn read_csv<R: BufRead>(
+    reader: R,
+    options: &CsvOptions,
+    features: Option<&[ColumnSelector]>,
+    targets: &[ColumnSelector],
+) -> Result<CsvData, NnError> {
+    let mut lines = reader
+        .lines()
+        .enumerate()
+        .map(|(ix, line)| (ix + 1, line))
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()));
+
+    // We need to know how many columns there are in order to resolve column selectors, so the first row is
+    // read up front even if it isn't a header.
+    let (first_line_number, first_line) = match lines.next() {
+        Some((line_number, line)) => (line_number, line.map_err(|err| NnError::Io(err.to_string()))?),
+        None =>
+            return Err(NnError::Csv {
+                line: 1,
+                message: "file is empty".to_owned(),
+            }),
+    };
+    let first_row = split_line(&first_line, options.delimiter).map_err(|message| NnError::Csv {
+        line: first_line_number,
+        message,
+    })?;
+    let column_names: Vec<String> = if options.has_header {
+        first_row.iter().map(|name| name.trim().to_owned()).collect()
+    } else {
+        (0..first_row.len()).map(|ix| ix.to_string()).collect()
+    };
+
+    let target_ixs = targets
+        .iter()
+        .map(|selector| resolve_column(selector, &column_names, first_line_number))
+        .collect::<Result<Vec<_>, _>>()?;
+    let feature_ixs = match features {
+        Some(features) => features
+            .iter()
+            .map(|selector| resolve_column(selector, &column_names, first_line_number))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => (0..column_names.len()).filter(|ix| !target_ixs.contains(ix)).collect(),
+    };
+    if feature_ixs.is_empty() {
+        return Err(NnError::Csv {
+            line: first_line_number,
+            message: "no feature columns were selected".to_owned(),
+        });
+    }
+
+    let mut dataset = InMemoryDataset::empty(feature_ixs.len(), target_ixs.len());
+    let mut feature_buf: Vec<Weight> = Vec::with_capacity(feature_ixs.len());
+    let mut target_buf: Vec<Weight> = Vec::with_capacity(target_ixs.len());
+    let mut parse_row = |line_number: usize, row: &[String]| -> Result<(), NnError> {
+        if row.len() != column_names.len() {
+            return Err(NnError::Csv {
+                line: line_number,
+                message: format!("expected {} columns but found {}", column_names.len(), row.len()),
+            });
+        }
+
+        let parse_field = |column_ix: usize| -> Result<Weight, NnError> {
+            let field = row[column_ix].trim();
+            field.parse().map_err(|_| NnError::Csv {
+                line: line_number,
+                message: format!(
+                    "couldn't parse {:?} in column {:?} as a number",
+                    field, column_names[column_ix]
+                ),
+            })
+        };
+        feature_buf.clear();
+        for &column_ix in &feature_ixs {
+            feature_buf.push(parse_field(column_ix)?);
+        }
+        target_buf.clear();
+        for &column_ix in &target_ixs {
+            target_buf.push(parse_field(column_ix)?);
+        }
+        dataset.push(&feature_buf, &target_buf)
+    };
+
+    if !options.has_header {
+        parse_row(first_line_number, &first_row)?;
+    }
+    for (line_number, line) in lines {
+        let line = line.map_err(|err| NnError::Io(err.to_string()))?;
+        let row = split_line(&line, options.delimiter).map_err(|message| NnError::Csv {
+            line: line_number,
+            message,
+        })?;
+        parse_row(line_number, &row)?;
+    }
+
+    Ok(CsvData {
+        dataset,
+        feature_names: feature_ixs.iter().map(|&ix| column_names[ix].clone()).collect(),
+        target_names: target_ixs.iter().map(|&ix| column_names[ix].clone()).collect(),
+    })
+}
@@ -0,0 +1,332 @@
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{Network, NnError, Weight};
+
+/// A collection of training examples, each made up of `input_dims` input values and `output_dims` expected output
+/// values.
+pub trait Dataset {
+    fn input_dims(&self) -> usize;
+
+    fn output_dims(&self) -> usize;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Returns the inputs and expected outputs for the example at `ix`.
+    fn get(&self, ix: usize) -> (&[Weight], &[Weight]);
+
+    /// Iterates over the dataset in order, `batch_size` examples at a time.  The last batch will be smaller if the
+    /// dataset doesn't divide evenly.
+    fn batches(&self, batch_size: usize) -> MiniBatches<'_, Self>
+    where
+        Self: Sized,
+    {
+        MiniBatches::new(self, (0..self.len()).collect(), batch_size)
+    }
+
+    /// Same as `batches`, but visits the examples in a random order.  Call this once per epoch to re-shuffle.
+    fn shuffled_batches<R: Rng + ?Sized>(&self, batch_size: usize, rng: &mut R) -> MiniBatches<'_, Self>
+    where
+        Self: Sized,
+    {
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        order.shuffle(rng);
+        MiniBatches::new(self, order, batch_size)
+    }
+}
+
+pub struct MiniBatch<'a, D: Dataset> {
+    dataset: &'a D,
+    indices: &'a [usize],
+}
+
+impl<'a, D: Dataset> MiniBatch<'a, D> {
+    pub fn len(&self) -> usize { self.indices.len() }
+
+    pub fn is_empty(&self) -> bool { self.indices.is_empty() }
+
+    /// The indices into the underlying dataset of the examples in this batch
+    pub fn indices(&self) -> &'a [usize] { self.indices }
+
+    /// The dataset that the examples in this batch come from
+    pub fn dataset(&self) -> &'a D { self.dataset }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [Weight], &'a [Weight])> + 'a {
+        let dataset = self.dataset;
+        self.indices.iter().map(move |&ix| dataset.get(ix))
+    }
+
+    /// Splits this batch into smaller batches of `chunk_size` examples.  The last one will be smaller if the batch
+    /// doesn't divide evenly.
+    pub fn chunks(&self, chunk_size: usize) -> impl Iterator<Item = MiniBatch<'a, D>> + 'a {
+        let dataset = self.dataset;
+        self.indices
+            .chunks(chunk_size)
+            .map(move |indices| MiniBatch { dataset, indices })
+    }
+}
+
+pub struct MiniBatches<'a, D: Dataset> {
+    dataset: &'a D,
+    order: Vec<usize>,
+    batch_size: usize,
+}
+
+impl<'a, D: Dataset> MiniBatches<'a, D> {
+    fn new(dataset: &'a D, order: Vec<usize>, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "batch size must be greater than zero");
+        MiniBatches {
+            dataset,
+            order,
+            batch_size,
+        }
+    }
+
+    pub fn batch_count(&self) -> usize { self.order.len().div_ceil(self.batch_size) }
+
+    pub fn iter(&self) -> impl Iterator<Item = MiniBatch<'_, D>> + '_ {
+        let dataset = self.dataset;
+        self.order
+            .chunks(self.batch_size)
+            .map(move |indices| MiniBatch { dataset, indices })
+    }
+}
+
+/// A dataset that holds all of its examples in memory, packed back-to-back in flat buffers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InMemoryDataset {
+    input_dims: usize,
+    output_dims: usize,
+    inputs: Vec<Weight>,
+    targets: Vec<Weight>,
+}
+
+pub struct DatasetSplits {
+    pub train: InMemoryDataset,
+    pub validation: InMemoryDataset,
+    pub test: InMemoryDataset,
+}
+
+impl InMemoryDataset {
+    pub fn new(
+        input_dims: usize,
+        output_dims: usize,
+        inputs: Vec<Weight>,
+        targets: Vec<Weight>,
+    ) -> Result<Self, NnError> {
+        let len = inputs.len().checked_div(input_dims).unwrap_or(0);
+        if input_dims == 0 || len * input_dims != inputs.len() {
+            return Err(NnError::InvalidBatchLength {
+                example_len: input_dims,
+                buffer_len: inputs.len(),
+            });
+        }
+        if targets.len() != len * output_dims {
+            return Err(NnError::InvalidTargetLength {
+                expected: len * output_dims,
+                actual: targets.len(),
+            });
+        }
+
+        Ok(InMemoryDataset {
+            input_dims,
+            output_dims,
+            inputs,
+            targets,
+        })
+    }
+
+    pub fn empty(input_dims: usize, output_dims: usize) -> Self {
+        InMemoryDataset {
+            input_dims,
+            output_dims,
+            inputs: Vec::new(),
+            targets: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, inputs: &[Weight], targets: &[Weight]) -> Result<(), NnError> {
+        if inputs.len() != self.input_dims {
+            return Err(NnError::InvalidInputLength {
+                expected: self.input_dims,
+                actual: inputs.len(),
+            });
+        }
+        if targets.len() != self.output_dims {
+            return Err(NnError::InvalidTargetLength {
+                expected: self.output_dims,
+                actual: targets.len(),
+            });
+        }
+
+        self.inputs.extend_from_slice(inputs);
+        self.targets.extend_from_slice(targets);
+        Ok(())
+    }
+
+    /// All inputs packed back-to-back, suitable for passing to APIs like `Network::evaluate`
+    pub fn inputs(&self) -> &[Weight] { &self.inputs }
+
+    /// All expected outputs packed back-to-back
+    pub fn targets(&self) -> &[Weight] { &self.targets }
+
+    /// Builds a new dataset out of the examples at `indices`, in that order.
+    pub fn subset(&self, indices: &[usize]) -> Self {
+        let mut subset = InMemoryDataset::empty(self.input_dims, self.output_dims);
+        subset.inputs.reserve(indices.len() * self.input_dims);
+        subset.targets.reserve(indices.len() * self.output_dims);
+        for &ix in indices {
+            let (inputs, targets) = self.get(ix);
+            subset.inputs.extend_from_slice(inputs);
+            subset.targets.extend_from_slice(targets);
+        }
+        subset
+    }
+
+    /// Shuffles the order of the examples in place.
+    pub fn shuffle<R: Rng + ?Sized>(&mut self, rng: &mut R) {
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        order.shuffle(rng);
+        *self = self.subset(&order);
+    }
+
+    /// Splits the dataset into train, validation and test sets.  `train_fraction` and `validation_fraction` of the
+    /// examples go to the first two and the rest go to the test set.  Examples are taken in order, so call `shuffle`
+    /// first if the dataset is sorted in any way.
+    pub fn split(&self, train_fraction: f32, validation_fraction: f32) -> DatasetSplits {
+        assert!(train_fraction >= 0. && validation_fraction >= 0. && train_fraction + validation_fraction <= 1.);
+        let len = self.len();
+        let train_len = ((len as f32 * train_fraction) as usize).min(len);
+        let validation_len = ((len as f32 * validation_fraction) as usize).min(len - train_len);
+
+        let indices: Vec<usize> = (0..len).collect();
+        DatasetSplits {
+            train: self.subset(&indices[..train_len]),
+            validation: self.subset(&indices[train_len..train_len + validation_len]),
+            test: self.subset(&indices[train_len + validation_len..]),
+        }
+    }
+
+    /// Iterates over `k` (train, validation) pairs for k-fold cross validation.  Each example shows up in exactly one
+    /// of the validation sets; fold sizes differ by at most one example.
+    pub fn k_folds(&self, k: usize) -> impl Iterator<Item = (InMemoryDataset, InMemoryDataset)> + '_ {
+        assert!(
+            k >= 2 && k <= self.len(),
+            "k must be between 2 and the number of examples"
+        );
+        let len = self.len();
+        (0..k).map(move |fold_ix| {
+            let start = fold_ix * len / k;
+            let end = (fold_ix + 1) * len / k;
+            let train_indices: Vec<usize> = (0..start).chain(end..len).collect();
+            let validation_indices: Vec<usize> = (start..end).collect();
+            (self.subset(&train_indices), self.subset(&validation_indices))
+        })
+    }
+}
+
+impl Dataset for InMemoryDataset {
+    fn input_dims(&self) -> usize { self.input_dims }
+
+    fn output_dims(&self) -> usize { self.output_dims }
+
+    fn len(&self) -> usize { self.inputs.len() / self.input_dims.max(1) }
+
+    fn get(&self, ix: usize) -> (&[Weight], &[Weight]) {
+        (
+            &self.inputs[ix * self.input_dims..(ix + 1) * self.input_dims],
+            &self.targets[ix * self.output_dims..(ix + 1) * self.output_dims],
+        )
+    }
+}
+
+/// Returns the index of the largest value, picking the first one in case of ties.  Returns 0 for an empty slice.
+pub fn argmax(vals: &[Weight]) -> usize {
+    let mut best_ix = 0;
+    for (ix, &val) in vals.iter().enumerate() {
+        if val > vals[best_ix] {
+            best_ix = ix;
+        }
+    }
+    best_ix
+}
+
+impl Network {
+    pub(crate) fn validate_dataset<D: Dataset + ?Sized>(&self, dataset: &D) -> Result<(), NnError> {
+        if dataset.input_dims() != self.input_count() {
+            return Err(NnError::InvalidInputLength {
+                expected: self.input_count(),
+                actual: dataset.input_dims(),
+            });
+        }
+        if dataset.output_dims() != self.output_count() {
+            return Err(NnError::InvalidTargetLength {
+                expected: self.output_count(),
+                actual: dataset.output_dims(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the average cost across every example in `dataset` without updating any weights.  Returns
+    /// `NnError::EmptyDataset` if `dataset` is empty, since there's no cost to average.
+    pub fn evaluate_dataset<D: Dataset + ?Sized>(&mut self, dataset: &D) -> Result<Weight, NnError> {
+        self.validate_dataset(dataset)?;
+        if dataset.is_empty() {
+            return Err(NnError::EmptyDataset);
+        }
+
+        let mut total_cost = 0.;
+        for ix in 0..dataset.len() {
+            let (inputs, targets) = dataset.get(ix);
+            self.try_compute(inputs)?;
+            self.outputs.compute_costs(targets);
+            total_cost += self.outputs.costs.iter().sum::<Weight>() / self.output_count() as Weight;
+        }
+        Ok(total_cost / dataset.len() as Weight)
+    }
+
+    /// Returns the fraction of examples in `dataset` for which the network's largest output is at the same index as
+    /// the largest expected output.  Meant for classification datasets with one-hot targets.
+    pub fn classification_accuracy<D: Dataset + ?Sized>(&mut self, dataset: &D) -> Result<Weight, NnError> {
+        self.validate_dataset(dataset)?;
+        if dataset.is_empty() {
+            return Ok(0.);
+        }
+
+        let mut correct_count = 0;
+        for ix in 0..dataset.len() {
+            let (inputs, targets) = dataset.get(ix);
+            if argmax(self.try_compute(inputs)?) == argmax(targets) {
+                correct_count += 1;
+            }
+        }
+        Ok(correct_count as Weight / dataset.len() as Weight)
+    }
+
+    /// Trains on every example in `dataset` once per epoch, re-shuffling the order each epoch.  Weights are updated
+    /// after every example.  Returns the average training cost for each epoch.
+    pub fn fit<D: Dataset, R: Rng + ?Sized>(
+        &mut self,
+        dataset: &D,
+        epochs: usize,
+        rng: &mut R,
+    ) -> Result<Vec<Weight>, NnError> {
+        self.validate_dataset(dataset)?;
+        let learning_rate = self.learning_rate;
+
+        let mut epoch_costs = Vec::with_capacity(epochs);
+        for _ in 0..epochs {
+            let mut total_cost = 0.;
+            for batch in dataset.shuffled_batches(dataset.len().max(1), rng).iter() {
+                for (inputs, targets) in batch.iter() {
+                    total_cost += self.try_train_one_example(inputs, targets, learning_rate)?;
+                }
+            }
+            epoch_costs.push(total_cost / dataset.len().max(1) as Weight);
+        }
+
+        Ok(epoch_costs)
+    }
+}
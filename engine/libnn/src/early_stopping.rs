@@ -0,0 +1,145 @@
+use crate::{InMemoryDataset, Network, NnError, Weight};
+
+#[derive(Clone, Debug)]
+pub struct EarlyStoppingConfig {
+    /// The validation set is evaluated every time this many training examples have been processed.
+    pub eval_interval: usize,
+    /// How many evaluations in a row are allowed to go by without the validation cost improving before training is
+    /// stopped.
+    pub patience: usize,
+    /// The validation cost has to drop by more than this to count as an improvement.
+    pub min_delta: Weight,
+    /// Training stops after this many training examples regardless of how the validation cost is doing.
+    pub max_iterations: usize,
+}
+
+impl Default for EarlyStoppingConfig {
+    fn default() -> Self {
+        EarlyStoppingConfig {
+            eval_interval: 10_000,
+            patience: 10,
+            min_delta: 0.,
+            max_iterations: 2_000_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// The validation cost didn't improve for `patience` evaluations in a row.
+    PatienceExhausted,
+    /// `max_iterations` training examples were processed.
+    MaxIterations,
+    /// Training diverged.  The best weights seen before that happened have been restored.
+    Diverged(NnError),
+}
+
+#[derive(Clone, Debug)]
+pub struct TrainingReport {
+    pub stop_reason: StopReason,
+    /// The number of training examples that were processed
+    pub iterations: usize,
+    pub best_validation_cost: Weight,
+    /// The number of training examples that had been processed when the best validation cost was recorded
+    pub best_iteration: usize,
+    /// Every validation cost that was computed, starting with the one for the untrained network.
+    pub validation_costs: Vec<Weight>,
+}
+
+impl Network {
+    /// Trains on the training examples in order, cycling back to the start when they run out, and periodically checks
+    /// the cost on the validation set.  When training stops, the weights that produced the lowest validation cost are
+    /// restored.  Returns `NnError::EmptyDataset` if there aren't any validation examples.
+    pub fn train_with_early_stopping(
+        &mut self,
+        training_examples: &[Weight],
+        training_expected: &[Weight],
+        validation_examples: &[Weight],
+        validation_expected: &[Weight],
+        learning_rate: Weight,
+        config: &EarlyStoppingConfig,
+    ) -> Result<TrainingReport, NnError> {
+        let training_example_count = self.count_examples(training_examples, training_expected)?;
+        if training_example_count == 0 {
+            return Err(NnError::InvalidBatchLength {
+                example_len: self.input_count(),
+                buffer_len: 0,
+            });
+        }
+        self.count_examples(validation_examples, validation_expected)?;
+        let eval_interval = config.eval_interval.max(1);
+        let input_dims = self.input_count();
+        let output_dims = self.output_count();
+        // Checked up front so that bad inputs, such as invalid category IDs, aren't reported as training diverging
+        for example in training_examples.chunks_exact(input_dims) {
+            self.validate_inputs(example)?;
+        }
+        let validation = InMemoryDataset::new(
+            input_dims,
+            output_dims,
+            validation_examples.to_vec(),
+            validation_expected.to_vec(),
+        )?;
+
+        let mut best_validation_cost = self.evaluate_dataset(&validation)?;
+        let mut best_iteration = 0;
+        let mut best_weights = self.snapshot();
+        let mut validation_costs = vec![best_validation_cost];
+        let mut evals_without_improvement = 0;
+
+        let mut iteration = 0;
+        let stop_reason = loop {
+            if iteration >= config.max_iterations {
+                // Make sure the weights from the tail end of training get a chance to be picked as the best
+                if iteration % eval_interval != 0 {
+                    let validation_cost = self.evaluate_dataset(&validation)?;
+                    validation_costs.push(validation_cost);
+                    if validation_cost < best_validation_cost - config.min_delta {
+                        best_validation_cost = validation_cost;
+                        best_iteration = iteration;
+                        self.snapshot_into(&mut best_weights);
+                    }
+                }
+                break StopReason::MaxIterations;
+            }
+
+            let example_ix = iteration % training_example_count;
+            let res = self.try_train_one_example(
+                &training_examples[example_ix * input_dims..(example_ix + 1) * input_dims],
+                &training_expected[example_ix * output_dims..(example_ix + 1) * output_dims],
+                learning_rate,
+            );
+            iteration += 1;
+            if let Err(err) = res {
+                break StopReason::Diverged(err);
+            }
+
+            if iteration % eval_interval != 0 {
+                continue;
+            }
+
+            let validation_cost = self.evaluate_dataset(&validation)?;
+            validation_costs.push(validation_cost);
+            if validation_cost < best_validation_cost - config.min_delta {
+                best_validation_cost = validation_cost;
+                best_iteration = iteration;
+                self.snapshot_into(&mut best_weights);
+                evals_without_improvement = 0;
+            } else {
+                evals_without_improvement += 1;
+                if evals_without_improvement >= config.patience {
+                    break StopReason::PatienceExhausted;
+                }
+            }
+        };
+
+        self.restore(&best_weights);
+        Ok(TrainingReport {
+            stop_reason,
+            iterations: iteration,
+            best_validation_cost,
+            best_iteration,
+            validation_costs,
+        })
+    }
+}
@@ -0,0 +1,180 @@
+//! Learned embeddings for categorical inputs.  Category IDs are passed in as ordinary input values, and an
+//! `EmbeddingLayer` at the front of `Network::feature_layers` replaces each of them with a learned vector while
+//! passing the numeric inputs through untouched.  This replaces one-hot encoding, which needs an input per category
+//! and a column of weights in the first dense layer for each of them.
+//!
+//! Only the vectors of the categories seen in an example are used, so only those rows of `vectors` are updated after
+//! each example; the rest of the table isn't touched at all.
+
+use std::ops::Range;
+
+use crate::{Float, NnError, Weight, WeightMatrix};
+
+/// An input that holds a category ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CategoricalInput {
+    pub input_ix: usize,
+    /// IDs must be whole numbers in `0..category_count`.
+    pub category_count: usize,
+}
+
+pub struct EmbeddingLayer<T: Float = Weight> {
+    pub input_count: usize,
+    /// Sorted by `input_ix`.
+    pub categorical_inputs: Vec<CategoricalInput>,
+    /// One row per category of each categorical input, with the rows for each input following the ones for the input
+    /// before it.
+    pub vectors: WeightMatrix<T>,
+    pub errors_scratch: Vec<T>,
+    pub outputs: Vec<T>,
+    /// For each categorical input, the row of `vectors` holding the vector for its first category.
+    first_rows: Vec<usize>,
+    /// For each categorical input, where its vector starts in the outputs.
+    output_ixs: Vec<usize>,
+    /// Each run of numeric inputs, along with where it starts in the outputs.
+    numeric_spans: Vec<(Range<usize>, usize)>,
+    /// The rows of `vectors` used in the last forward pass.
+    rows: Vec<usize>,
+    /// Same as `DenseLayer::learning_rate_multiplier`.
+    pub learning_rate_multiplier: T,
+    /// Same as `DenseLayer::frozen`.
+    pub frozen: bool,
+}
+
+impl<T: Float> EmbeddingLayer<T> {
+    /// Every categorical input gets vectors of `dim` values, which take its place in the outputs.  `init_weights` is
+    /// called with the row and column of each value in `vectors`.  Panics if any of the categorical inputs are out of
+    /// range or listed more than once.
+    pub fn new(
+        input_count: usize,
+        mut categorical_inputs: Vec<CategoricalInput>,
+        dim: usize,
+        init_weights: &mut impl FnMut(usize, usize) -> T,
+    ) -> Self {
+        categorical_inputs.sort_by_key(|input| input.input_ix);
+        for pair in categorical_inputs.windows(2) {
+            assert_ne!(
+                pair[0].input_ix, pair[1].input_ix,
+                "input {} is listed twice",
+                pair[0].input_ix
+            );
+        }
+        if let Some(input) = categorical_inputs.last() {
+            assert!(
+                input.input_ix < input_count,
+                "input {} is out of range for {} inputs",
+                input.input_ix,
+                input_count
+            );
+        }
+
+        let mut first_rows = Vec::with_capacity(categorical_inputs.len());
+        let mut output_ixs = Vec::with_capacity(categorical_inputs.len());
+        let mut numeric_spans = Vec::with_capacity(categorical_inputs.len() + 1);
+        let (mut row_count, mut input_ix, mut output_ix) = (0, 0, 0);
+        for input in &categorical_inputs {
+            first_rows.push(row_count);
+            row_count += input.category_count;
+            numeric_spans.push((input_ix..input.input_ix, output_ix));
+            output_ix += input.input_ix - input_ix;
+            output_ixs.push(output_ix);
+            output_ix += dim;
+            input_ix = input.input_ix + 1;
+        }
+        numeric_spans.push((input_ix..input_count, output_ix));
+        let output_count = output_ix + input_count - input_ix;
+
+        EmbeddingLayer {
+            input_count,
+            rows: vec![0; categorical_inputs.len()],
+            categorical_inputs,
+            vectors: WeightMatrix::from_fn(row_count, dim, init_weights),
+            errors_scratch: vec![T::ZERO; output_count],
+            outputs: vec![T::ZERO; output_count],
+            first_rows,
+            output_ixs,
+            numeric_spans,
+            learning_rate_multiplier: T::ONE,
+            frozen: false,
+        }
+    }
+
+    /// Same as `DenseLayer::layer_learning_rate`.
+    pub fn layer_learning_rate(&self, learning_rate: T) -> T { learning_rate * self.learning_rate_multiplier }
+
+    pub fn dim(&self) -> usize { self.vectors.col_count() }
+
+    pub fn output_count(&self) -> usize {
+        self.input_count - self.categorical_inputs.len() + self.categorical_inputs.len() * self.dim()
+    }
+
+    /// Checks that every categorical input holds a valid category ID.
+    pub fn validate_inputs(&self, inputs: &[T]) -> Result<(), NnError> {
+        for input in &self.categorical_inputs {
+            let id = inputs[input.input_ix].to_f64();
+            if !(id >= 0. && id < input.category_count as f64 && id.fract() == 0.) {
+                return Err(NnError::InvalidCategory {
+                    input_ix: input.input_ix,
+                    value: id as Weight,
+                    category_count: input.category_count,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn row(&self, categorical_ix: usize, inputs: &[T]) -> usize {
+        let input_ix = self.categorical_inputs[categorical_ix].input_ix;
+        self.first_rows[categorical_ix] + inputs[input_ix].to_f64() as usize
+    }
+
+    pub fn forward_propagate(&mut self, inputs: &[T]) {
+        let mut outputs = std::mem::take(&mut self.outputs);
+        self.forward_propagate_into(inputs, &mut outputs);
+        self.outputs = outputs;
+        for categorical_ix in 0..self.categorical_inputs.len() {
+            self.rows[categorical_ix] = self.row(categorical_ix, inputs);
+        }
+    }
+
+    /// Same as `forward_propagate`, but writing into `outputs` rather than the layer's own buffer.
+    pub fn forward_propagate_into(&self, inputs: &[T], outputs: &mut Vec<T>) {
+        debug_assert_eq!(inputs.len(), self.input_count);
+        outputs.resize(self.output_count(), T::ZERO);
+        for (inputs_range, output_ix) in &self.numeric_spans {
+            outputs[*output_ix..*output_ix + inputs_range.len()].copy_from_slice(&inputs[inputs_range.clone()]);
+        }
+        let dim = self.dim();
+        for (categorical_ix, &output_ix) in self.output_ixs.iter().enumerate() {
+            let row = self.row(categorical_ix, inputs);
+            outputs[output_ix..output_ix + dim].copy_from_slice(&self.vectors[row]);
+        }
+    }
+
+    /// Passes the errors of the numeric outputs back to the inputs they came from.  Category IDs can't be
+    /// differentiated, so their errors are zero.
+    pub fn backpropagate_errors(&self, input_errors: &mut [T]) {
+        input_errors.fill(T::ZERO);
+        for (inputs_range, output_ix) in &self.numeric_spans {
+            let errors = &self.errors_scratch[*output_ix..*output_ix + inputs_range.len()];
+            input_errors[inputs_range.clone()].copy_from_slice(errors);
+        }
+    }
+
+    /// Updates the vectors used in the last forward pass once `errors_scratch` holds the errors of the outputs.
+    /// Applies `learning_rate_multiplier`, and does nothing if the layer is frozen.
+    pub fn update_weights(&mut self, learning_rate: T) {
+        if self.frozen {
+            return;
+        }
+        let learning_rate = self.layer_learning_rate(learning_rate);
+        let dim = self.dim();
+        for (&row, &output_ix) in self.rows.iter().zip(&self.output_ixs) {
+            T::axpy(
+                &mut self.vectors[row],
+                learning_rate,
+                &self.errors_scratch[output_ix..output_ix + dim],
+            );
+        }
+    }
+}
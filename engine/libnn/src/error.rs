@@ -0,0 +1,125 @@
+use std::fmt;
+
+use crate::{KernelBackend, Weight};
+
+/// Errors that can be produced while training or evaluating a network.
+///
+/// Layer indices follow the same convention used by the visualization code: `0..hidden_layers.len()` refer to hidden
+/// layers and `hidden_layers.len()` refers to the output layer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NnError {
+    /// The cost computed for a training example was NaN or infinite.
+    NonFiniteCost { cost: Weight },
+    /// A weight or bias in the given layer became NaN or infinite after being updated.
+    NonFiniteWeights { layer_ix: usize },
+    /// The kernels or biases of a convolution layer became NaN or infinite after being updated.  `layer_ix` indexes
+    /// into `Network::feature_layers`.
+    NonFiniteFeatureLayerWeights { layer_ix: usize },
+    /// The given layer produced a NaN or infinite output.
+    NonFiniteOutputs { layer_ix: usize },
+    /// A categorical input held something other than one of its category IDs.
+    InvalidCategory {
+        input_ix: usize,
+        value: Weight,
+        category_count: usize,
+    },
+    /// An example had a different number of values than the network has inputs.
+    InvalidInputLength { expected: usize, actual: usize },
+    /// The expected outputs for an example had a different number of values than the network has outputs.
+    InvalidTargetLength { expected: usize, actual: usize },
+    /// A multi-head network was given targets for a different number of heads than it has.
+    InvalidHeadCount { expected: usize, actual: usize },
+    /// A flat buffer holding many examples back-to-back can't be evenly split into examples of `example_len` values.
+    InvalidBatchLength { example_len: usize, buffer_len: usize },
+    /// Reading from a file or stream failed.  Holds the message of the underlying IO error.
+    Io(String),
+    /// A line of CSV data couldn't be parsed.  Line numbers start at 1.
+    Csv { line: usize, message: String },
+    /// A CSV column was selected by a name that isn't in the header.
+    UnknownColumn(String),
+    /// An IDX file was malformed or its contents couldn't be used as requested.
+    InvalidIdx(String),
+    /// The requested kernel backend isn't supported on this CPU.
+    KernelBackendUnavailable(KernelBackend),
+    /// An operation that needs at least one example was given an empty dataset.
+    EmptyDataset,
+    /// A sequence passed to a recurrent network didn't have any timesteps.
+    EmptySequence,
+    /// The operation only supports networks made up of dense layers, but the network has feature layers.
+    UnsupportedFeatureLayers,
+    /// `Softmax` was used somewhere other than an output layer using `CrossEntropy`, or `CrossEntropy` was used
+    /// without it.
+    UnpairedSoftmax { layer_ix: usize },
+    /// An embedding layer was used somewhere other than as the first feature layer.  `layer_ix` indexes into
+    /// `Network::feature_layers`.
+    MisplacedEmbedding { layer_ix: usize },
+}
+
+impl fmt::Display for NnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NnError::NonFiniteCost { cost } => write!(f, "training diverged: cost is {}", cost),
+            NnError::NonFiniteWeights { layer_ix } => write!(
+                f,
+                "training diverged: layer {} has non-finite weights or biases",
+                layer_ix
+            ),
+            NnError::NonFiniteFeatureLayerWeights { layer_ix } => write!(
+                f,
+                "training diverged: feature layer {} has non-finite weights or biases",
+                layer_ix
+            ),
+            NnError::NonFiniteOutputs { layer_ix } => write!(f, "layer {} produced non-finite outputs", layer_ix),
+            NnError::InvalidCategory {
+                input_ix,
+                value,
+                category_count,
+            } => write!(
+                f,
+                "input {} holds {}, which isn't a category ID between 0 and {}",
+                input_ix,
+                value,
+                category_count - 1
+            ),
+            NnError::InvalidInputLength { expected, actual } =>
+                write!(f, "expected {} input values but got {}", expected, actual),
+            NnError::InvalidTargetLength { expected, actual } =>
+                write!(f, "expected {} target values but got {}", expected, actual),
+            NnError::InvalidHeadCount { expected, actual } =>
+                write!(f, "expected targets for {} output heads but got {}", expected, actual),
+            NnError::InvalidBatchLength {
+                example_len,
+                buffer_len,
+            } => write!(
+                f,
+                "a buffer of {} values can't be split into examples of {} values each",
+                buffer_len, example_len
+            ),
+            NnError::Io(message) => write!(f, "IO error: {}", message),
+            NnError::Csv { line, message } => write!(f, "line {}: {}", line, message),
+            NnError::UnknownColumn(name) => write!(f, "no column named {:?}", name),
+            NnError::InvalidIdx(message) => write!(f, "invalid IDX data: {}", message),
+            NnError::KernelBackendUnavailable(backend) =>
+                write!(f, "the {:?} kernel backend isn't supported on this CPU", backend),
+            NnError::EmptyDataset => write!(f, "the dataset doesn't contain any examples"),
+            NnError::EmptySequence => write!(f, "the sequence doesn't contain any timesteps"),
+            NnError::UnsupportedFeatureLayers => write!(
+                f,
+                "convolution, pooling and embedding layers aren't supported by this operation"
+            ),
+            NnError::UnpairedSoftmax { layer_ix } => write!(
+                f,
+                "layer {} uses softmax or cross-entropy without the other; they're only supported together on an \
+                 output layer",
+                layer_ix
+            ),
+            NnError::MisplacedEmbedding { layer_ix } => write!(
+                f,
+                "feature layer {} is an embedding layer, but embeddings are only supported as the first feature layer",
+                layer_ix
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NnError {}
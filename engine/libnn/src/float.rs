@@ -0,0 +1,174 @@
+//! The floating point types that networks can be built out of.  `f32` is the default everywhere and is the only type
+//! with SIMD kernels; `f64` is there for when precision matters more than speed, such as checking gradients
+//! numerically.
+//!
+//! Only the core network types (`Network`, the layers, `WeightMatrix`, activation and cost functions) and single
+//! example inference with `Network::predict` are generic.
+//! Datasets, batched and multi-threaded training, and batched inference all work with `f32` networks only.
+
+use std::{
+    fmt::{Debug, Display},
+    iter::Sum,
+    ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign},
+};
+
+use crate::{fast_math::sigmoid_approx, kernels, kernels::scalar};
+
+pub trait Float:
+    Copy
+    + Default
+    + PartialEq
+    + PartialOrd
+    + Debug
+    + Display
+    + Send
+    + Sync
+    + 'static
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + MulAssign
+    + DivAssign
+    + Sum
+{
+    const ZERO: Self;
+    const ONE: Self;
+    const E: Self;
+    const PI: Self;
+    const NEG_INFINITY: Self;
+
+    /// Converts a constant into this type, rounding if needed.
+    fn from_f64(x: f64) -> Self;
+
+    fn to_f64(self) -> f64;
+
+    fn exp(self) -> Self;
+
+    fn ln(self) -> Self;
+
+    fn powi(self, n: i32) -> Self;
+
+    fn powf(self, n: Self) -> Self;
+
+    fn sqrt(self) -> Self;
+
+    fn tanh(self) -> Self;
+
+    fn cos(self) -> Self;
+
+    fn sin(self) -> Self;
+
+    fn abs(self) -> Self;
+
+    fn max(self, other: Self) -> Self;
+
+    fn is_nan(self) -> bool;
+
+    fn is_finite(self) -> bool;
+
+    /// `1 / (1 + e^-x)`.  May be approximated if that's much faster.
+    fn sigmoid(self) -> Self;
+
+    /// `cos(x)` for `x` in `[-PI, PI]`.  May be approximated if that's much faster.
+    fn approx_cos(self) -> Self;
+
+    /// `sin(x)` for `x` in `[-PI, PI]`.  May be approximated if that's much faster.
+    fn approx_sin(self) -> Self;
+
+    // The kernels from the `kernels` module.  These default to the plain scalar versions.
+
+    fn dot(a: &[Self], b: &[Self]) -> Self { scalar::dot(a, b) }
+
+    fn axpy(dst: &mut [Self], alpha: Self, x: &[Self]) { scalar::axpy(dst, alpha, x) }
+
+    fn relu(dst: &mut [Self], src: &[Self]) { scalar::relu(dst, src) }
+
+    fn relu_derivative(dst: &mut [Self], errors: &[Self], outputs_before_activation: &[Self]) {
+        scalar::relu_derivative(dst, errors, outputs_before_activation)
+    }
+
+    fn leaky_relu(dst: &mut [Self], src: &[Self]) { scalar::leaky_relu(dst, src) }
+
+    fn leaky_relu_derivative(dst: &mut [Self], errors: &[Self], outputs_before_activation: &[Self]) {
+        scalar::leaky_relu_derivative(dst, errors, outputs_before_activation)
+    }
+}
+
+/// Forwards the methods that `f32` and `f64` both have built in.
+macro_rules! impl_float_common {
+    ($ty:ident) => {
+        const ZERO: Self = 0.;
+        const ONE: Self = 1.;
+        const E: Self = std::$ty::consts::E;
+        const PI: Self = std::$ty::consts::PI;
+        const NEG_INFINITY: Self = $ty::NEG_INFINITY;
+
+        fn from_f64(x: f64) -> Self { x as $ty }
+
+        fn to_f64(self) -> f64 { self as f64 }
+
+        fn exp(self) -> Self { $ty::exp(self) }
+
+        fn ln(self) -> Self { $ty::ln(self) }
+
+        fn powi(self, n: i32) -> Self { $ty::powi(self, n) }
+
+        fn powf(self, n: Self) -> Self { $ty::powf(self, n) }
+
+        fn sqrt(self) -> Self { $ty::sqrt(self) }
+
+        fn tanh(self) -> Self { $ty::tanh(self) }
+
+        fn cos(self) -> Self { $ty::cos(self) }
+
+        fn sin(self) -> Self { $ty::sin(self) }
+
+        fn abs(self) -> Self { $ty::abs(self) }
+
+        fn max(self, other: Self) -> Self { $ty::max(self, other) }
+
+        fn is_nan(self) -> bool { $ty::is_nan(self) }
+
+        fn is_finite(self) -> bool { $ty::is_finite(self) }
+    };
+}
+
+impl Float for f32 {
+    impl_float_common!(f32);
+
+    fn sigmoid(self) -> Self { sigmoid_approx(self) }
+
+    fn approx_cos(self) -> Self { fastapprox::fast::cos(self) }
+
+    fn approx_sin(self) -> Self { fastapprox::fast::sin(self) }
+
+    fn dot(a: &[Self], b: &[Self]) -> Self { kernels::dot(a, b) }
+
+    fn axpy(dst: &mut [Self], alpha: Self, x: &[Self]) { kernels::axpy(dst, alpha, x) }
+
+    fn relu(dst: &mut [Self], src: &[Self]) { kernels::relu(dst, src) }
+
+    fn relu_derivative(dst: &mut [Self], errors: &[Self], outputs_before_activation: &[Self]) {
+        kernels::relu_derivative(dst, errors, outputs_before_activation)
+    }
+
+    fn leaky_relu(dst: &mut [Self], src: &[Self]) { kernels::leaky_relu(dst, src) }
+
+    fn leaky_relu_derivative(dst: &mut [Self], errors: &[Self], outputs_before_activation: &[Self]) {
+        kernels::leaky_relu_derivative(dst, errors, outputs_before_activation)
+    }
+}
+
+impl Float for f64 {
+    impl_float_common!(f64);
+
+    fn sigmoid(self) -> Self { 1. / (1. + (-self).exp()) }
+
+    fn approx_cos(self) -> Self { self.cos() }
+
+    fn approx_sin(self) -> Self { self.sin() }
+}
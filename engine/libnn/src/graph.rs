@@ -0,0 +1,323 @@
+//! Networks shaped like a directed acyclic graph rather than a stack of layers, which allows for skip connections and
+//! parallel branches.  Graphs are put together with `GraphNetworkBuilder`: each node takes its inputs from nodes that
+//! were added before it, so nodes are always stored in an order where everything a node depends on comes first.
+//!
+//! The forward pass runs the nodes in that order and the backward pass runs them in reverse.  Every node has a buffer
+//! of errors for its outputs that each of the nodes using them adds into, so a node that fans out to several others
+//! gets the sum of the errors from all of them before its own errors are passed on.
+//!
+//! Residual MLPs are built by adding the output of a couple of dense layers back onto their inputs; the errors flow
+//! back through the add without passing through any activation functions, so they don't shrink layer after layer
+//! like they do in a deep stack of dense layers.
+
+use crate::{
+    average_cost, validate_hidden_activation, ActivationFunction, DenseLayer, Float, NnError, OutputLayer, Weight,
+    IDENTITY,
+};
+
+/// Refers to a node of a graph network.  These are only handed out by `GraphNetworkBuilder`, so a node can only take
+/// its inputs from nodes added before it and graphs can't contain cycles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+pub enum GraphNode<T: Float = Weight> {
+    /// The inputs of the network.  Always the first node.
+    Input,
+    Dense {
+        input: NodeId,
+        layer: Box<DenseLayer<T>>,
+    },
+    /// Sums the outputs of its inputs, which all have to be the same size.
+    Add {
+        inputs: Vec<NodeId>,
+    },
+    /// Joins the outputs of its inputs end to end.
+    Concat {
+        inputs: Vec<NodeId>,
+    },
+}
+
+struct Node<T: Float> {
+    op: GraphNode<T>,
+    /// Unused by dense nodes, which keep their outputs in the layer.
+    outputs: Vec<T>,
+    /// The errors of this node's outputs, summed over every node that uses them.
+    errors: Vec<T>,
+}
+
+impl<T: Float> Node<T> {
+    fn new(op: GraphNode<T>, size: usize) -> Self {
+        let outputs = match op {
+            GraphNode::Dense { .. } => Vec::new(),
+            _ => vec![T::ZERO; size],
+        };
+        Node {
+            op,
+            outputs,
+            errors: vec![T::ZERO; size],
+        }
+    }
+
+    fn outputs(&self) -> &[T] {
+        match &self.op {
+            GraphNode::Dense { layer, .. } => &layer.outputs,
+            _ => &self.outputs,
+        }
+    }
+}
+
+pub struct GraphNetworkBuilder<T: Float = Weight> {
+    nodes: Vec<Node<T>>,
+}
+
+impl<T: Float> GraphNetworkBuilder<T> {
+    pub fn new(input_count: usize) -> Self {
+        GraphNetworkBuilder {
+            nodes: vec![Node::new(GraphNode::Input, input_count)],
+        }
+    }
+
+    /// The node holding the inputs of the network.
+    pub fn input(&self) -> NodeId { NodeId(0) }
+
+    /// The number of values output by `node`.
+    pub fn size(&self, node: NodeId) -> usize { self.nodes[node.0].errors.len() }
+
+    fn push(&mut self, op: GraphNode<T>, size: usize) -> NodeId {
+        self.nodes.push(Node::new(op, size));
+        NodeId(self.nodes.len() - 1)
+    }
+
+    pub fn dense(
+        &mut self,
+        input: NodeId,
+        neuron_count: usize,
+        init_weights: &mut impl FnMut(usize, usize) -> T,
+        init_biases: &mut impl FnMut(usize) -> T,
+        activation_fn: &'static dyn ActivationFunction<T>,
+    ) -> NodeId {
+        let layer = DenseLayer::new(neuron_count, self.size(input), init_weights, init_biases, activation_fn);
+        self.push(
+            GraphNode::Dense {
+                input,
+                layer: Box::new(layer),
+            },
+            neuron_count,
+        )
+    }
+
+    /// Panics if `inputs` is empty or the inputs aren't all the same size.
+    pub fn add(&mut self, inputs: &[NodeId]) -> NodeId {
+        assert!(!inputs.is_empty(), "need at least one node to add");
+        let size = self.size(inputs[0]);
+        for &input in &inputs[1..] {
+            assert_eq!(
+                self.size(input),
+                size,
+                "can't add nodes with {} and {} outputs",
+                size,
+                self.size(input)
+            );
+        }
+        self.push(
+            GraphNode::Add {
+                inputs: inputs.to_owned(),
+            },
+            size,
+        )
+    }
+
+    /// Panics if `inputs` is empty.
+    pub fn concat(&mut self, inputs: &[NodeId]) -> NodeId {
+        assert!(!inputs.is_empty(), "need at least one node to concatenate");
+        let size = inputs.iter().map(|&input| self.size(input)).sum();
+        self.push(
+            GraphNode::Concat {
+                inputs: inputs.to_owned(),
+            },
+            size,
+        )
+    }
+
+    /// Adds a dense layer of `hidden_size` neurons with `activation_fn` followed by one without an activation function
+    /// that maps back to the size of `input`, and then adds `input` onto the result.  Returns the add node.
+    pub fn residual_block(
+        &mut self,
+        input: NodeId,
+        hidden_size: usize,
+        init_weights: &mut impl FnMut(usize, usize) -> T,
+        init_biases: &mut impl FnMut(usize) -> T,
+        activation_fn: &'static dyn ActivationFunction<T>,
+    ) -> NodeId {
+        let hidden = self.dense(input, hidden_size, init_weights, init_biases, activation_fn);
+        let projection = self.dense(hidden, self.size(input), init_weights, init_biases, &IDENTITY);
+        self.add(&[input, projection])
+    }
+
+    /// Feeds the outputs of `output` into `outputs`.  Panics if the output layer expects a different number of inputs.
+    /// Returns an error if `Softmax` is used anywhere other than on the output layer together with `CrossEntropy`;
+    /// dense layers are numbered by their node and the output layer comes after the last node.
+    pub fn build(self, output: NodeId, outputs: Box<OutputLayer<T>>) -> Result<GraphNetwork<T>, NnError> {
+        assert_eq!(
+            self.size(output),
+            outputs.weights.col_count(),
+            "node with {} outputs feeds into an output layer with {} inputs",
+            self.size(output),
+            outputs.weights.col_count()
+        );
+        for (node_ix, node) in self.nodes.iter().enumerate() {
+            if let GraphNode::Dense { layer, .. } = &node.op {
+                validate_hidden_activation(layer.activation_fn, node_ix)?;
+            }
+        }
+        outputs.validate_activation(self.nodes.len())?;
+
+        Ok(GraphNetwork {
+            nodes: self.nodes,
+            output_node: output,
+            outputs,
+        })
+    }
+}
+
+pub struct GraphNetwork<T: Float = Weight> {
+    nodes: Vec<Node<T>>,
+    output_node: NodeId,
+    pub outputs: Box<OutputLayer<T>>,
+}
+
+impl<T: Float> GraphNetwork<T> {
+    pub fn input_count(&self) -> usize { self.nodes[0].outputs.len() }
+
+    pub fn output_count(&self) -> usize { self.outputs.outputs.len() }
+
+    pub fn node(&self, node: NodeId) -> &GraphNode<T> { &self.nodes[node.0].op }
+
+    pub fn node_mut(&mut self, node: NodeId) -> &mut GraphNode<T> { &mut self.nodes[node.0].op }
+
+    /// The outputs of `node` from the last forward pass.
+    pub fn node_outputs(&self, node: NodeId) -> &[T] { self.nodes[node.0].outputs() }
+
+    /// Every node of the graph in the order they were added.
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeId, &GraphNode<T>)> {
+        self.nodes.iter().enumerate().map(|(ix, node)| (NodeId(ix), &node.op))
+    }
+
+    /// The dense layers of the graph in the order they were added.
+    pub fn dense_layers_mut(&mut self) -> impl Iterator<Item = &mut DenseLayer<T>> {
+        self.nodes.iter_mut().filter_map(|node| match &mut node.op {
+            GraphNode::Dense { layer, .. } => Some(layer.as_mut()),
+            _ => None,
+        })
+    }
+
+    fn validate_inputs(&self, inputs: &[T]) -> Result<(), NnError> {
+        if inputs.len() != self.input_count() {
+            return Err(NnError::InvalidInputLength {
+                expected: self.input_count(),
+                actual: inputs.len(),
+            });
+        }
+        Ok(())
+    }
+
+    fn forward_propagate(&mut self, inputs: &[T]) {
+        self.nodes[0].outputs.copy_from_slice(inputs);
+        for node_ix in 1..self.nodes.len() {
+            let (earlier_nodes, later_nodes) = self.nodes.split_at_mut(node_ix);
+            let Node { op, outputs, .. } = &mut later_nodes[0];
+            match op {
+                GraphNode::Input => unreachable!("only the first node holds the inputs"),
+                GraphNode::Dense { input, layer } => layer.forward_propagate(earlier_nodes[input.0].outputs()),
+                GraphNode::Add { inputs } => {
+                    outputs.copy_from_slice(earlier_nodes[inputs[0].0].outputs());
+                    for input in &inputs[1..] {
+                        T::axpy(outputs, T::ONE, earlier_nodes[input.0].outputs());
+                    }
+                },
+                GraphNode::Concat { inputs } => {
+                    let mut offset = 0;
+                    for input in inputs.iter() {
+                        let input_outputs = earlier_nodes[input.0].outputs();
+                        outputs[offset..offset + input_outputs.len()].copy_from_slice(input_outputs);
+                        offset += input_outputs.len();
+                    }
+                },
+            }
+        }
+
+        self.outputs.forward_propagate(self.nodes[self.output_node.0].outputs());
+    }
+
+    pub fn compute<'a>(&'a mut self, inputs: &[T]) -> Result<&'a [T], NnError> {
+        self.validate_inputs(inputs)?;
+        self.forward_propagate(inputs);
+        Ok(&self.outputs.outputs)
+    }
+
+    /// Returns the average cost of the outputs before updating weights.
+    pub fn train_one_example(&mut self, example: &[T], expected: &[T], learning_rate: T) -> Result<T, NnError> {
+        self.validate_inputs(example)?;
+        if expected.len() != self.output_count() {
+            return Err(NnError::InvalidTargetLength {
+                expected: self.output_count(),
+                actual: expected.len(),
+            });
+        }
+        self.forward_propagate(example);
+
+        self.outputs.compute_costs(expected);
+        self.outputs.compute_gradients();
+        for node in &mut self.nodes {
+            node.errors.fill(T::ZERO);
+        }
+        let output_node = &mut self.nodes[self.output_node.0];
+        for (neuron_ix, &neuron_gradient) in self.outputs.neuron_gradients.iter().enumerate() {
+            T::axpy(
+                &mut output_node.errors,
+                neuron_gradient,
+                &self.outputs.weights[neuron_ix],
+            );
+        }
+        self.outputs.update_weights(output_node.outputs(), learning_rate);
+
+        // Each node's errors are complete by the time it's reached since everything that uses its outputs comes after
+        // it, so its weights can be updated right away.
+        for node_ix in (1..self.nodes.len()).rev() {
+            let (earlier_nodes, later_nodes) = self.nodes.split_at_mut(node_ix);
+            let Node { op, errors, .. } = &mut later_nodes[0];
+            match op {
+                GraphNode::Input => unreachable!("only the first node holds the inputs"),
+                GraphNode::Dense { input, layer } => {
+                    layer.activation_fn.apply_derivative_batch(
+                        &mut layer.neuron_gradients,
+                        errors,
+                        &layer.outputs_before_activation,
+                    );
+                    let input = &mut earlier_nodes[input.0];
+                    for (neuron_ix, &neuron_gradient) in layer.neuron_gradients.iter().enumerate() {
+                        T::axpy(&mut input.errors, neuron_gradient, &layer.weights[neuron_ix]);
+                    }
+                    layer.update_weights(input.outputs(), learning_rate);
+                    layer.update_biases(learning_rate);
+                },
+                GraphNode::Add { inputs } =>
+                    for input in inputs.iter() {
+                        T::axpy(&mut earlier_nodes[input.0].errors, T::ONE, errors);
+                    },
+                GraphNode::Concat { inputs } => {
+                    let mut offset = 0;
+                    for input in inputs.iter() {
+                        let input_errors = &mut earlier_nodes[input.0].errors;
+                        let len = input_errors.len();
+                        T::axpy(input_errors, T::ONE, &errors[offset..offset + len]);
+                        offset += len;
+                    }
+                },
+            }
+        }
+
+        let total_cost = self.outputs.costs.iter().fold(T::ZERO, |acc, &cost| acc + cost);
+        average_cost(total_cost, self.output_count())
+    }
+}
@@ -0,0 +1,348 @@
+//! Lock-free asynchronous SGD, as described in "Hogwild!: A Lock-Free Approach to Parallelizing Stochastic Gradient
+//! Descent" (Niu et al., 2011).
+//!
+//! Several threads each train on their own share of the examples, one example at a time, reading and updating a single
+//! shared copy of the weights without any locking.  Updates from different threads can overwrite each other, but when
+//! each example only touches a small fraction of the weights (sparse inputs, or ReLU layers where many outputs are
+//! zero) that's rare enough that training converges about as well as it does on a single thread.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use rand::Rng;
+
+use crate::{
+    batch::last_outputs, dataset::MiniBatch, Dataset, Network, NetworkWorkspace, NnError, Weight, WeightMatrix,
+};
+
+/// A weight that can be read and written by several threads at once.  Adding to it is a separate load and store rather
+/// than an atomic read-modify-write, so concurrent updates can be lost; that's the trade-off Hogwild makes.
+struct SharedWeight(AtomicU32);
+
+impl SharedWeight {
+    fn new(value: Weight) -> Self { SharedWeight(AtomicU32::new(value.to_bits())) }
+
+    #[inline]
+    fn get(&self) -> Weight { Weight::from_bits(self.0.load(Ordering::Relaxed)) }
+
+    #[inline]
+    fn set(&self, value: Weight) { self.0.store(value.to_bits(), Ordering::Relaxed) }
+
+    #[inline]
+    fn add(&self, delta: Weight) { self.set(self.get() + delta) }
+}
+
+/// The weights of one layer stored row-major, like a `WeightMatrix` without the padding.
+struct SharedMatrix {
+    col_count: usize,
+    weights: Vec<SharedWeight>,
+}
+
+impl SharedMatrix {
+    fn new(matrix: &WeightMatrix) -> Self {
+        SharedMatrix {
+            col_count: matrix.col_count(),
+            weights: matrix
+                .iter_rows()
+                .flatten()
+                .map(|&weight| SharedWeight::new(weight))
+                .collect(),
+        }
+    }
+
+    fn row(&self, row_ix: usize) -> &[SharedWeight] {
+        &self.weights[row_ix * self.col_count..(row_ix + 1) * self.col_count]
+    }
+
+    fn load_from(&self, matrix: &WeightMatrix) {
+        for (shared, &weight) in self.weights.iter().zip(matrix.iter_rows().flatten()) {
+            shared.set(weight);
+        }
+    }
+
+    fn store_into(&self, matrix: &mut WeightMatrix) {
+        for (row_ix, shared_row) in self.weights.chunks(self.col_count.max(1)).enumerate() {
+            for (weight, shared) in matrix[row_ix].iter_mut().zip(shared_row) {
+                *weight = shared.get();
+            }
+        }
+    }
+}
+
+/// Returns the sum of the products of `weights` and `inputs`.  Zero inputs are skipped so that sparse examples don't
+/// need to read most of the weights.
+fn shared_dot(weights: &[SharedWeight], inputs: &[Weight]) -> Weight {
+    let mut sum = 0.;
+    for (weight, &input) in weights.iter().zip(inputs) {
+        if input != 0. {
+            sum += weight.get() * input;
+        }
+    }
+    sum
+}
+
+/// Adds `alpha * x` to `weights`, skipping the weights that wouldn't change.  Weights that are zero in `mask` have
+/// been pruned and are left alone.
+fn shared_axpy(weights: &[SharedWeight], alpha: Weight, x: &[Weight], mask: Option<&[Weight]>) {
+    if alpha == 0. {
+        return;
+    }
+    match mask {
+        Some(mask) =>
+            for ((weight, &x), &keep) in weights.iter().zip(x).zip(mask) {
+                if x != 0. && keep != 0. {
+                    weight.add(alpha * x);
+                }
+            },
+        None =>
+            for (weight, &x) in weights.iter().zip(x) {
+                if x != 0. {
+                    weight.add(alpha * x);
+                }
+            },
+    }
+}
+
+/// A copy of all of a network's weights and biases that can be trained by several threads at once.  Activation and
+/// cost functions still come from the `Network` itself, and each thread keeps its outputs and gradients in its own
+/// `NetworkWorkspace`.
+pub struct SharedWeights {
+    hidden_layer_weights: Vec<SharedMatrix>,
+    hidden_layer_biases: Vec<Vec<SharedWeight>>,
+    output_weights: SharedMatrix,
+}
+
+impl SharedWeights {
+    pub fn new(network: &Network) -> Self {
+        SharedWeights {
+            hidden_layer_weights: network
+                .hidden_layers
+                .iter()
+                .map(|layer| SharedMatrix::new(&layer.weights))
+                .collect(),
+            hidden_layer_biases: network
+                .hidden_layers
+                .iter()
+                .map(|layer| layer.biases.iter().map(|&bias| SharedWeight::new(bias)).collect())
+                .collect(),
+            output_weights: SharedMatrix::new(&network.outputs.weights),
+        }
+    }
+
+    /// Overwrites these weights with the ones in `network`, which must have the same shape.
+    pub fn load_from(&self, network: &Network) {
+        for (layer_ix, layer) in network.hidden_layers.iter().enumerate() {
+            self.hidden_layer_weights[layer_ix].load_from(&layer.weights);
+            for (shared, &bias) in self.hidden_layer_biases[layer_ix].iter().zip(&layer.biases) {
+                shared.set(bias);
+            }
+        }
+        self.output_weights.load_from(&network.outputs.weights);
+    }
+
+    /// Copies these weights into `network`, which must have the same shape.
+    pub fn store_into(&self, network: &mut Network) {
+        for (layer_ix, layer) in network.hidden_layers.iter_mut().enumerate() {
+            self.hidden_layer_weights[layer_ix].store_into(&mut layer.weights);
+            for (bias, shared) in layer.biases.iter_mut().zip(&self.hidden_layer_biases[layer_ix]) {
+                *bias = shared.get();
+            }
+        }
+        self.output_weights.store_into(&mut network.outputs.weights);
+    }
+
+    /// The same as `Network::train_one_example`, but reads and updates these weights instead of the network's and
+    /// keeps all intermediate values in `workspace`.  `network` only supplies the activation and cost functions along
+    /// with which layers are frozen and their learning rate multipliers.
+    fn train_one_example(
+        &self,
+        network: &Network,
+        example: &[Weight],
+        expected: &[Weight],
+        learning_rate: Weight,
+        workspace: &mut NetworkWorkspace,
+    ) -> Weight {
+        let layer_count = network.hidden_layers.len();
+
+        for layer_ix in 0..layer_count {
+            let (previous, rest) = workspace.hidden_layers.split_at_mut(layer_ix);
+            let inputs = last_outputs(previous, example);
+            let layer_workspace = &mut rest[0];
+            let weights = &self.hidden_layer_weights[layer_ix];
+            for (neuron_ix, bias) in self.hidden_layer_biases[layer_ix].iter().enumerate() {
+                layer_workspace.outputs_before_activation[neuron_ix] =
+                    shared_dot(weights.row(neuron_ix), inputs) + bias.get();
+            }
+            network.hidden_layers[layer_ix]
+                .activation_fn
+                .apply_batch(&mut layer_workspace.outputs, &layer_workspace.outputs_before_activation);
+        }
+        let output_workspace = &mut workspace.outputs;
+        let inputs = last_outputs(&workspace.hidden_layers, example);
+        for neuron_ix in 0..output_workspace.outputs.len() {
+            output_workspace.outputs_before_activation[neuron_ix] =
+                shared_dot(self.output_weights.row(neuron_ix), inputs);
+        }
+        network.outputs.activation_fn.apply_batch(
+            &mut output_workspace.outputs,
+            &output_workspace.outputs_before_activation,
+        );
+
+        let cost = network.outputs.compute_gradients_with(expected, &mut workspace.outputs);
+        for layer_ix in (0..layer_count).rev() {
+            let (current, next) = workspace.hidden_layers.split_at_mut(layer_ix + 1);
+            let (output_weights, gradient_of_output_neurons) = match next.first() {
+                Some(next) => (
+                    &self.hidden_layer_weights[layer_ix + 1],
+                    next.neuron_gradients.as_slice(),
+                ),
+                None => (&self.output_weights, workspace.outputs.neuron_gradients.as_slice()),
+            };
+            let layer_workspace = &mut current[layer_ix];
+            layer_workspace.errors_scratch.fill(0.);
+            for (output_neuron_ix, &gradient_of_output_neuron) in gradient_of_output_neurons.iter().enumerate() {
+                let output_neuron_weights = output_weights.row(output_neuron_ix);
+                for (error, weight) in layer_workspace.errors_scratch.iter_mut().zip(output_neuron_weights) {
+                    *error += gradient_of_output_neuron * weight.get();
+                }
+            }
+            network.hidden_layers[layer_ix].activation_fn.apply_derivative_batch(
+                &mut layer_workspace.neuron_gradients,
+                &layer_workspace.errors_scratch,
+                &layer_workspace.outputs_before_activation,
+            );
+        }
+
+        if !network.outputs.frozen {
+            let learning_rate = network.outputs.layer_learning_rate(learning_rate);
+            let inputs = last_outputs(&workspace.hidden_layers, example);
+            for (neuron_ix, &neuron_gradient) in workspace.outputs.neuron_gradients.iter().enumerate() {
+                shared_axpy(
+                    self.output_weights.row(neuron_ix),
+                    learning_rate * neuron_gradient,
+                    inputs,
+                    network.outputs.weight_mask.as_ref().map(|mask| &mask[neuron_ix]),
+                );
+            }
+        }
+        for (layer_ix, layer) in network.hidden_layers.iter().enumerate() {
+            if layer.frozen {
+                continue;
+            }
+            let learning_rate = layer.layer_learning_rate(learning_rate);
+            let inputs = last_outputs(&workspace.hidden_layers[..layer_ix], example);
+            let neuron_gradients = &workspace.hidden_layers[layer_ix].neuron_gradients;
+            for (neuron_ix, &neuron_gradient) in neuron_gradients.iter().enumerate() {
+                shared_axpy(
+                    self.hidden_layer_weights[layer_ix].row(neuron_ix),
+                    learning_rate * neuron_gradient,
+                    inputs,
+                    layer.weight_mask.as_ref().map(|mask| &mask[neuron_ix]),
+                );
+                self.hidden_layer_biases[layer_ix][neuron_ix].add(learning_rate * neuron_gradient);
+            }
+        }
+
+        cost
+    }
+}
+
+struct Worker {
+    workspace: NetworkWorkspace,
+    total_cost: Weight,
+}
+
+impl Worker {
+    fn run<D: Dataset>(
+        &mut self,
+        network: &Network,
+        weights: &SharedWeights,
+        examples: &MiniBatch<'_, D>,
+        learning_rate: Weight,
+    ) {
+        self.total_cost = 0.;
+        for (inputs, targets) in examples.iter() {
+            self.total_cost += weights.train_one_example(network, inputs, targets, learning_rate, &mut self.workspace);
+        }
+    }
+}
+
+/// Trains a network with Hogwild-style lock-free SGD: each epoch, the shuffled examples are split between
+/// `thread_count` threads which all update the same `SharedWeights` after every example without synchronizing with
+/// each other.
+///
+/// With a single thread this is equivalent to `Network::fit`.  With more, the result depends on how the threads'
+/// updates happen to interleave, so it isn't deterministic.  On wasm32, where threads aren't available, the workers run
+/// one after another on the current thread.
+pub struct HogwildTrainer {
+    weights: SharedWeights,
+    workers: Vec<Worker>,
+}
+
+impl HogwildTrainer {
+    /// Returns an error if `network` has feature layers.
+    pub fn new(network: &Network, thread_count: usize) -> Result<Self, NnError> {
+        assert!(thread_count > 0, "thread count must be greater than zero");
+        Ok(HogwildTrainer {
+            weights: SharedWeights::new(network),
+            workers: (0..thread_count)
+                .map(|_| {
+                    Ok(Worker {
+                        workspace: NetworkWorkspace::new(network)?,
+                        total_cost: 0.,
+                    })
+                })
+                .collect::<Result<_, NnError>>()?,
+        })
+    }
+
+    pub fn thread_count(&self) -> usize { self.workers.len() }
+
+    /// Trains on every example in `dataset` once, in a random order, then copies the trained weights back into
+    /// `network`.  Returns the average training cost.
+    ///
+    /// Like `try_train_one_example`, returns an error if the cost or any of the updated weights or biases are NaN or
+    /// infinite; the diverged weights are still copied into `network`.
+    pub fn train_epoch<D: Dataset + Sync, R: Rng + ?Sized>(
+        &mut self,
+        network: &mut Network,
+        dataset: &D,
+        learning_rate: Weight,
+        rng: &mut R,
+    ) -> Result<Weight, NnError> {
+        network.validate_dataset(dataset)?;
+        if dataset.is_empty() {
+            return Ok(0.);
+        }
+
+        self.weights.load_from(network);
+        let batches = dataset.shuffled_batches(dataset.len(), rng);
+        let all_examples = batches.iter().next().unwrap();
+        let chunk_size = all_examples.len().div_ceil(self.workers.len());
+        let chunks: Vec<MiniBatch<'_, D>> = all_examples.chunks(chunk_size).collect();
+        let workers = &mut self.workers[..chunks.len()];
+        let (shared_network, weights): (&Network, _) = (network, &self.weights);
+        if workers.len() == 1 || cfg!(target_arch = "wasm32") {
+            for (worker, chunk) in workers.iter_mut().zip(&chunks) {
+                worker.run(shared_network, weights, chunk, learning_rate);
+            }
+        } else {
+            std::thread::scope(|scope| {
+                let (first_worker, other_workers) = workers.split_first_mut().unwrap();
+                for (worker, chunk) in other_workers.iter_mut().zip(&chunks[1..]) {
+                    scope.spawn(move || worker.run(shared_network, weights, chunk, learning_rate));
+                }
+                first_worker.run(shared_network, weights, &chunks[0], learning_rate);
+            });
+        }
+
+        self.weights.store_into(network);
+        let total_cost: Weight = workers.iter().map(|worker| worker.total_cost).sum();
+        let cost = total_cost / dataset.len() as Weight;
+        if !cost.is_finite() {
+            return Err(NnError::NonFiniteCost { cost });
+        }
+        network.check_weights_finite()?;
+
+        Ok(cost)
+    }
+}
@@ -0,0 +1,123 @@
+//! Reader for the IDX file format used by MNIST, Fashion-MNIST and friends.  Files are expected to already be
+//! decompressed.
+
+use std::io::Read;
+
+use crate::{InMemoryDataset, NnError, Weight};
+
+/// An n-dimensional array read from an IDX file.  Values are converted to `Weight` regardless of how they're stored in
+/// the file and kept in row-major order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdxArray {
+    pub dims: Vec<usize>,
+    pub data: Vec<Weight>,
+}
+
+impl IdxArray {
+    /// The number of entries along the first dimension, such as the number of images in an MNIST image file
+    pub fn len(&self) -> usize { self.dims.first().copied().unwrap_or(0) }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// The number of values in each entry along the first dimension, such as the number of pixels in an image
+    pub fn entry_size(&self) -> usize { self.dims.iter().skip(1).product() }
+}
+
+fn invalid(message: impl Into<String>) -> NnError { NnError::InvalidIdx(message.into()) }
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), NnError> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => invalid("file ended before all of the data was read"),
+        _ => NnError::Io(err.to_string()),
+    })
+}
+
+pub fn read_idx<R: Read>(mut reader: R) -> Result<IdxArray, NnError> {
+    let mut magic = [0u8; 4];
+    read_exact(&mut reader, &mut magic)?;
+    if magic[..2] == [0x1f, 0x8b] {
+        return Err(invalid("file is gzip-compressed; decompress it first"));
+    }
+    if magic[..2] != [0, 0] {
+        return Err(invalid(format!("bad magic number {:02x?}", magic)));
+    }
+    let value_size = match magic[2] {
+        0x08 | 0x09 => 1,
+        0x0B => 2,
+        0x0C | 0x0D => 4,
+        0x0E => 8,
+        data_type => return Err(invalid(format!("unknown data type 0x{:02x}", data_type))),
+    };
+
+    let mut dims = Vec::with_capacity(magic[3] as usize);
+    for _ in 0..magic[3] {
+        let mut dim = [0u8; 4];
+        read_exact(&mut reader, &mut dim)?;
+        dims.push(u32::from_be_bytes(dim) as usize);
+    }
+    let value_count = dims
+        .iter()
+        .try_fold(1usize, |acc, &dim| acc.checked_mul(dim))
+        .ok_or_else(|| invalid(format!("dimensions {:?} are too large", dims)))?;
+
+    let mut raw = vec![0u8; value_count * value_size];
+    read_exact(&mut reader, &mut raw)?;
+    let data = match magic[2] {
+        0x08 => raw.iter().map(|&val| val as Weight).collect(),
+        0x09 => raw.iter().map(|&val| val as i8 as Weight).collect(),
+        0x0B => raw
+            .chunks_exact(2)
+            .map(|val| i16::from_be_bytes([val[0], val[1]]) as Weight)
+            .collect(),
+        0x0C => raw
+            .chunks_exact(4)
+            .map(|val| i32::from_be_bytes([val[0], val[1], val[2], val[3]]) as Weight)
+            .collect(),
+        0x0D => raw
+            .chunks_exact(4)
+            .map(|val| f32::from_be_bytes([val[0], val[1], val[2], val[3]]) as Weight)
+            .collect(),
+        _ => raw
+            .chunks_exact(8)
+            .map(|val| f64::from_be_bytes([val[0], val[1], val[2], val[3], val[4], val[5], val[6], val[7]]) as Weight)
+            .collect(),
+    };
+
+    Ok(IdxArray { dims, data })
+}
+
+/// Builds a classification dataset out of an array of inputs (such as MNIST images) and an array of class labels.
+/// Every input value is multiplied by `input_scale`; use `1. / 255.` to get pixels between 0 and 1.  Labels are
+/// one-hot encoded into `class_count` outputs.
+pub fn idx_classification_dataset(
+    inputs: &IdxArray,
+    labels: &IdxArray,
+    class_count: usize,
+    input_scale: Weight,
+) -> Result<InMemoryDataset, NnError> {
+    if inputs.len() != labels.len() || labels.entry_size() != 1 {
+        return Err(invalid(format!(
+            "can't match {} inputs up with labels of shape {:?}",
+            inputs.len(),
+            labels.dims
+        )));
+    }
+
+    let mut targets = vec![0.; labels.len() * class_count];
+    for (example_ix, &label) in labels.data.iter().enumerate() {
+        if label < 0. || label as usize >= class_count || label.fract() != 0. {
+            return Err(invalid(format!(
+                "label {} of example {} isn't a valid class index",
+                label, example_ix
+            )));
+        }
+        targets[example_ix * class_count + label as usize] = 1.;
+    }
+
+    InMemoryDataset::new(
+        inputs.entry_size(),
+        class_count,
+        inputs.data.iter().map(|&val| val * input_scale).collect(),
+        targets,
+    )
+}
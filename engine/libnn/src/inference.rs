@@ -0,0 +1,88 @@
+//! Runs single examples through a network without mutating it.  `Network::compute` writes each layer's outputs into
+//! buffers stored on the layers themselves, which means it needs `&mut self` and clobbers the values that training
+//! reads back.  `Network::predict` writes them into a caller-owned `InferenceWorkspace` instead, so any number of
+//! threads can run inference on a shared network at once as long as each has its own workspace.
+
+use crate::{ActivationFunction, Float, Network, NnError, Weight, WeightMatrix};
+
+/// The outputs of every layer from the last call to `Network::predict`.  Can be re-used between calls, and between
+/// networks of different sizes, to avoid re-allocating.
+#[derive(Clone, Debug, Default)]
+pub struct InferenceWorkspace<T: Float = Weight> {
+    pub feature_layers: Vec<Vec<T>>,
+    pub hidden_layers: Vec<Vec<T>>,
+    pub outputs: Vec<T>,
+    outputs_before_activation: Vec<T>,
+}
+
+impl<T: Float> InferenceWorkspace<T> {
+    pub fn new() -> Self { Self::default() }
+}
+
+/// Same as `DenseLayer::forward_propagate`, but writing into the given buffers rather than the layer's own.
+fn forward_propagate_layer<T: Float>(
+    weights: &WeightMatrix<T>,
+    biases: Option<&[T]>,
+    activation_fn: &dyn ActivationFunction<T>,
+    inputs: &[T],
+    outputs_before_activation: &mut Vec<T>,
+    outputs: &mut Vec<T>,
+) {
+    debug_assert_eq!(weights.col_count(), inputs.len());
+    outputs_before_activation.resize(weights.row_count(), T::ZERO);
+    outputs.resize(weights.row_count(), T::ZERO);
+    for (neuron_ix, output) in outputs_before_activation.iter_mut().enumerate() {
+        *output = T::dot(&weights[neuron_ix], inputs);
+        if let Some(biases) = biases {
+            *output += biases[neuron_ix];
+        }
+    }
+
+    activation_fn.apply_batch(outputs, outputs_before_activation);
+}
+
+impl<T: Float> Network<T> {
+    /// Same as `try_compute`, but stores the outputs of every layer in `workspace` rather than on the layers so that
+    /// the network itself isn't modified.
+    pub fn predict<'a>(&self, inputs: &[T], workspace: &'a mut InferenceWorkspace<T>) -> Result<&'a [T], NnError> {
+        self.validate_inputs(inputs)?;
+
+        let InferenceWorkspace {
+            feature_layers,
+            hidden_layers,
+            outputs,
+            outputs_before_activation,
+        } = workspace;
+        feature_layers.resize_with(self.feature_layers.len(), Vec::new);
+        hidden_layers.resize_with(self.hidden_layers.len(), Vec::new);
+
+        let mut layer_inputs = inputs;
+        for (layer, layer_outputs) in self.feature_layers.iter().zip(feature_layers.iter_mut()) {
+            layer.forward_propagate_into(layer_inputs, outputs_before_activation, layer_outputs);
+            layer_inputs = layer_outputs;
+        }
+        for (layer, layer_outputs) in self.hidden_layers.iter().zip(hidden_layers.iter_mut()) {
+            forward_propagate_layer(
+                &layer.weights,
+                Some(&layer.biases),
+                layer.activation_fn,
+                layer_inputs,
+                outputs_before_activation,
+                layer_outputs,
+            );
+            layer_inputs = layer_outputs;
+        }
+
+        // No bias on the output layer.
+        forward_propagate_layer(
+            &self.outputs.weights,
+            None,
+            self.outputs.activation_fn,
+            layer_inputs,
+            outputs_before_activation,
+            outputs,
+        );
+
+        Ok(outputs)
+    }
+}
@@ -0,0 +1,225 @@
+//! 4-lane float and integer vectors with the handful of operations that the `simd128` kernels need.  When building for
+//! wasm32 with the `simd128` target feature enabled, each operation compiles to one or a few wasm SIMD instructions.
+//! Everywhere else the lanes are processed one at a time, with the same semantics as the wasm instructions, so that
+//! the `simd128` kernels can be run and tested on any machine.
+
+#[cfg(not(all(target_arch = "wasm32", target_feature = "simd128")))]
+pub use self::emulated::*;
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+pub use self::native::*;
+
+/// Whether the operations compile to real SIMD instructions rather than being emulated.
+pub const IS_NATIVE: bool = cfg!(all(target_arch = "wasm32", target_feature = "simd128"));
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+mod native {
+    use core::arch::wasm32::*;
+
+    use crate::Weight;
+
+    #[derive(Clone, Copy)]
+    pub struct F32x4(v128);
+
+    /// The result of comparing two `F32x4`s lane by lane.
+    #[derive(Clone, Copy)]
+    pub struct Mask(v128);
+
+    impl F32x4 {
+        #[inline(always)]
+        pub fn splat(value: Weight) -> Self { F32x4(f32x4_splat(value)) }
+
+        /// Loads the first 4 values of `src`, which doesn't need to be aligned.
+        #[inline(always)]
+        pub fn load(src: &[Weight]) -> Self {
+            assert!(src.len() >= 4);
+            F32x4(unsafe { v128_load(src.as_ptr() as *const v128) })
+        }
+
+        /// Stores the lanes into the first 4 values of `dst`.
+        #[inline(always)]
+        pub fn store(self, dst: &mut [Weight]) {
+            assert!(dst.len() >= 4);
+            unsafe { v128_store(dst.as_mut_ptr() as *mut v128, self.0) }
+        }
+
+        #[inline(always)]
+        pub fn add(self, other: Self) -> Self { F32x4(f32x4_add(self.0, other.0)) }
+
+        #[inline(always)]
+        pub fn mul(self, other: Self) -> Self { F32x4(f32x4_mul(self.0, other.0)) }
+
+        /// Pseudo-maximum: `if self < other { other } else { self }` in each lane.
+        #[inline(always)]
+        pub fn pmax(self, other: Self) -> Self { F32x4(f32x4_pmax(self.0, other.0)) }
+
+        #[inline(always)]
+        pub fn gt(self, other: Self) -> Mask { Mask(f32x4_gt(self.0, other.0)) }
+
+        #[inline(always)]
+        pub fn ge(self, other: Self) -> Mask { Mask(f32x4_ge(self.0, other.0)) }
+
+        /// Adds up the lanes from first to last.
+        #[inline(always)]
+        pub fn sum(self) -> Weight {
+            f32x4_extract_lane::<0>(self.0)
+                + f32x4_extract_lane::<1>(self.0)
+                + f32x4_extract_lane::<2>(self.0)
+                + f32x4_extract_lane::<3>(self.0)
+        }
+    }
+
+    impl Mask {
+        /// Picks lanes from `if_true` where the comparison held and from `if_false` everywhere else.
+        #[inline(always)]
+        pub fn select(self, if_true: F32x4, if_false: F32x4) -> F32x4 {
+            F32x4(v128_bitselect(if_true.0, if_false.0, self.0))
+        }
+    }
+
+    /// Four 32-bit integer lanes, used to accumulate products of 8-bit integers.
+    #[derive(Clone, Copy)]
+    pub struct I32x4(v128);
+
+    impl I32x4 {
+        #[inline(always)]
+        pub fn splat(value: i32) -> Self { I32x4(i32x4_splat(value)) }
+
+        /// Multiplies the first 16 values of `a` and `b` together and adds up pairs of neighboring products from each
+        /// half, so that lane `i` holds the products at `2i`, `2i + 1`, `2i + 8` and `2i + 9`.
+        #[inline(always)]
+        pub fn dot_i8x16(a: &[i8], b: &[i8]) -> Self {
+            assert!(a.len() >= 16 && b.len() >= 16);
+            let (a, b) = unsafe {
+                (
+                    v128_load(a.as_ptr() as *const v128),
+                    v128_load(b.as_ptr() as *const v128),
+                )
+            };
+            let low = i32x4_dot_i16x8(i16x8_extend_low_i8x16(a), i16x8_extend_low_i8x16(b));
+            let high = i32x4_dot_i16x8(i16x8_extend_high_i8x16(a), i16x8_extend_high_i8x16(b));
+            I32x4(i32x4_add(low, high))
+        }
+
+        #[inline(always)]
+        pub fn add(self, other: Self) -> Self { I32x4(i32x4_add(self.0, other.0)) }
+
+        #[inline(always)]
+        pub fn sum(self) -> i32 {
+            i32x4_extract_lane::<0>(self.0)
+                .wrapping_add(i32x4_extract_lane::<1>(self.0))
+                .wrapping_add(i32x4_extract_lane::<2>(self.0))
+                .wrapping_add(i32x4_extract_lane::<3>(self.0))
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_feature = "simd128")))]
+mod emulated {
+    use crate::Weight;
+
+    #[derive(Clone, Copy)]
+    pub struct F32x4([Weight; 4]);
+
+    /// The result of comparing two `F32x4`s lane by lane.
+    #[derive(Clone, Copy)]
+    pub struct Mask([bool; 4]);
+
+    impl F32x4 {
+        #[inline(always)]
+        fn map(self, other: Self, f: impl Fn(Weight, Weight) -> Weight) -> Self {
+            F32x4([
+                f(self.0[0], other.0[0]),
+                f(self.0[1], other.0[1]),
+                f(self.0[2], other.0[2]),
+                f(self.0[3], other.0[3]),
+            ])
+        }
+
+        #[inline(always)]
+        fn compare(self, other: Self, f: impl Fn(Weight, Weight) -> bool) -> Mask {
+            Mask([
+                f(self.0[0], other.0[0]),
+                f(self.0[1], other.0[1]),
+                f(self.0[2], other.0[2]),
+                f(self.0[3], other.0[3]),
+            ])
+        }
+
+        #[inline(always)]
+        pub fn splat(value: Weight) -> Self { F32x4([value; 4]) }
+
+        /// Loads the first 4 values of `src`, which doesn't need to be aligned.
+        #[inline(always)]
+        pub fn load(src: &[Weight]) -> Self { F32x4([src[0], src[1], src[2], src[3]]) }
+
+        /// Stores the lanes into the first 4 values of `dst`.
+        #[inline(always)]
+        pub fn store(self, dst: &mut [Weight]) { dst[..4].copy_from_slice(&self.0) }
+
+        #[inline(always)]
+        pub fn add(self, other: Self) -> Self { self.map(other, |a, b| a + b) }
+
+        #[inline(always)]
+        pub fn mul(self, other: Self) -> Self { self.map(other, |a, b| a * b) }
+
+        /// Pseudo-maximum: `if self < other { other } else { self }` in each lane.
+        #[inline(always)]
+        pub fn pmax(self, other: Self) -> Self { self.map(other, |a, b| if a < b { b } else { a }) }
+
+        #[inline(always)]
+        pub fn gt(self, other: Self) -> Mask { self.compare(other, |a, b| a > b) }
+
+        #[inline(always)]
+        pub fn ge(self, other: Self) -> Mask { self.compare(other, |a, b| a >= b) }
+
+        /// Adds up the lanes from first to last.
+        #[inline(always)]
+        pub fn sum(self) -> Weight { self.0[0] + self.0[1] + self.0[2] + self.0[3] }
+    }
+
+    impl Mask {
+        /// Picks lanes from `if_true` where the comparison held and from `if_false` everywhere else.
+        #[inline(always)]
+        pub fn select(self, if_true: F32x4, if_false: F32x4) -> F32x4 {
+            F32x4([0, 1, 2, 3].map(|lane| {
+                if self.0[lane] {
+                    if_true.0[lane]
+                } else {
+                    if_false.0[lane]
+                }
+            }))
+        }
+    }
+
+    /// Four 32-bit integer lanes, used to accumulate products of 8-bit integers.
+    #[derive(Clone, Copy)]
+    pub struct I32x4([i32; 4]);
+
+    impl I32x4 {
+        #[inline(always)]
+        pub fn splat(value: i32) -> Self { I32x4([value; 4]) }
+
+        /// Multiplies the first 16 values of `a` and `b` together and adds up pairs of neighboring products from each
+        /// half, so that lane `i` holds the products at `2i`, `2i + 1`, `2i + 8` and `2i + 9`.
+        #[inline(always)]
+        pub fn dot_i8x16(a: &[i8], b: &[i8]) -> Self {
+            let product = |ix: usize| a[ix] as i32 * b[ix] as i32;
+            I32x4([0, 1, 2, 3].map(|lane| {
+                (product(2 * lane) + product(2 * lane + 1)).wrapping_add(product(2 * lane + 8) + product(2 * lane + 9))
+            }))
+        }
+
+        #[inline(always)]
+        pub fn add(self, other: Self) -> Self {
+            I32x4([0, 1, 2, 3].map(|lane| self.0[lane].wrapping_add(other.0[lane])))
+        }
+
+        #[inline(always)]
+        pub fn sum(self) -> i32 {
+            self.0[0]
+                .wrapping_add(self.0[1])
+                .wrapping_add(self.0[2])
+                .wrapping_add(self.0[3])
+        }
+    }
+}
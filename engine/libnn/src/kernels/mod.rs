@@ -0,0 +1,243 @@
+//! Low-level vector operations that the layers are built out of.  Each one has several implementations, or backends:
+//! a plain scalar one, a 4-wide SIMD one written for wasm32, and SSE2 and AVX2 ones for x86_64.  The fastest backend
+//! that the current CPU supports is used by default, but a different one can be picked at runtime with
+//! `set_kernel_backend`, which is mostly useful for testing and benchmarking.
+//!
+//! All slices passed to a kernel must have the same length.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{NnError, Weight, WeightMatrix};
+
+mod f32x4;
+pub(crate) mod scalar;
+pub(crate) mod simd128;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod x86;
+
+/// A set of kernel implementations.  All backends compute the same results, apart from differences in floating point
+/// rounding caused by summing values in a different order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KernelBackend {
+    /// Plain loops with no explicit SIMD.
+    Scalar,
+    /// The 4-wide kernels used by the wasm build.  Outside of wasm32 builds with the `simd128` target feature enabled,
+    /// the vector instructions are emulated one lane at a time; that's slower than `Scalar`, but it means that these
+    /// kernels can be tested on any machine.
+    Simd128,
+    /// 4-wide SSE2 kernels.  Only available on x86_64.
+    Sse2,
+    /// 8-wide AVX2 kernels that use fused multiply-add.  Only available on x86_64 CPUs with both AVX2 and FMA.
+    Avx2,
+}
+
+impl KernelBackend {
+    pub const ALL: [KernelBackend; 4] = [
+        KernelBackend::Scalar,
+        KernelBackend::Simd128,
+        KernelBackend::Sse2,
+        KernelBackend::Avx2,
+    ];
+
+    /// Whether this backend can be used on the current CPU.
+    pub fn is_available(self) -> bool {
+        match self {
+            KernelBackend::Scalar | KernelBackend::Simd128 => true,
+            #[cfg(target_arch = "x86_64")]
+            KernelBackend::Sse2 => true,
+            #[cfg(target_arch = "x86_64")]
+            KernelBackend::Avx2 => x86::avx2_fma_available(),
+            #[cfg(not(target_arch = "x86_64"))]
+            KernelBackend::Sse2 | KernelBackend::Avx2 => false,
+        }
+    }
+
+    /// Every backend that can be used on the current CPU.
+    pub fn available() -> impl Iterator<Item = KernelBackend> {
+        Self::ALL.into_iter().filter(|backend| backend.is_available())
+    }
+
+    /// The fastest backend available on the current CPU.  This is the one used unless `set_kernel_backend` is called.
+    pub fn best() -> Self {
+        if KernelBackend::Avx2.is_available() {
+            KernelBackend::Avx2
+        } else if KernelBackend::Sse2.is_available() {
+            KernelBackend::Sse2
+        } else if f32x4::IS_NATIVE {
+            KernelBackend::Simd128
+        } else {
+            KernelBackend::Scalar
+        }
+    }
+}
+
+/// Marks that no backend has been picked yet, in which case `KernelBackend::best()` is used.
+const UNSELECTED: u8 = u8::MAX;
+
+static SELECTED_BACKEND: AtomicU8 = AtomicU8::new(UNSELECTED);
+
+/// Returns the backend that all kernels are currently dispatched to.
+pub fn kernel_backend() -> KernelBackend {
+    match SELECTED_BACKEND.load(Ordering::Relaxed) {
+        0 => KernelBackend::Scalar,
+        1 => KernelBackend::Simd128,
+        2 => KernelBackend::Sse2,
+        3 => KernelBackend::Avx2,
+        _ => {
+            let backend = KernelBackend::best();
+            SELECTED_BACKEND.store(backend as u8, Ordering::Relaxed);
+            backend
+        },
+    }
+}
+
+/// Switches every kernel over to `backend` for all threads.  Returns an error without changing anything if the
+/// backend isn't supported by the current CPU.
+pub fn set_kernel_backend(backend: KernelBackend) -> Result<(), NnError> {
+    if !backend.is_available() {
+        return Err(NnError::KernelBackendUnavailable(backend));
+    }
+    SELECTED_BACKEND.store(backend as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Defines a method on `KernelBackend` for each kernel that checks that the slices listed in brackets have the same
+/// length and then runs that backend's implementation, along with a function of the same name that uses the currently
+/// selected backend.
+macro_rules! dispatch {
+    ($($(#[$attr:meta])* fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)? [$first:ident $(, $same_len:ident)*];)*) => {
+        impl KernelBackend {
+            $(
+                $(#[$attr])*
+                #[inline]
+                pub(crate) fn $name(self, $($arg: $ty),*) $(-> $ret)? {
+                    $(assert_eq!($first.len(), $same_len.len());)*
+                    // The x86 kernels are only sound to call if the CPU supports them, which is checked before a
+                    // backend can be selected
+                    debug_assert!(self.is_available());
+
+                    match self {
+                        KernelBackend::Scalar => scalar::$name($($arg),*),
+                        KernelBackend::Simd128 => simd128::$name($($arg),*),
+                        #[cfg(target_arch = "x86_64")]
+                        KernelBackend::Sse2 => unsafe { x86::sse::$name($($arg),*) },
+                        #[cfg(target_arch = "x86_64")]
+                        KernelBackend::Avx2 => unsafe { x86::avx2::$name($($arg),*) },
+                        #[cfg(not(target_arch = "x86_64"))]
+                        KernelBackend::Sse2 | KernelBackend::Avx2 => unreachable!(),
+                    }
+                }
+            )*
+        }
+
+        $(
+            $(#[$attr])*
+            #[inline]
+            pub fn $name($($arg: $ty),*) $(-> $ret)? { kernel_backend().$name($($arg),*) }
+        )*
+    };
+}
+
+dispatch! {
+    /// Returns the sum of the products of the values in `a` and `b`.
+    fn dot(a: &[Weight], b: &[Weight]) -> Weight [a, b];
+
+    /// Returns the sum of the products of the values in `a` and `b`.  Integer sums are exact, so every backend returns
+    /// the same result.
+    fn dot_i8(a: &[i8], b: &[i8]) -> i32 [a, b];
+
+    /// Adds `alpha * x` to `dst`.
+    fn axpy(dst: &mut [Weight], alpha: Weight, x: &[Weight]) [dst, x];
+
+    fn relu(dst: &mut [Weight], src: &[Weight]) [dst, src];
+
+    /// Multiplies each error by the derivative of ReLU at the corresponding output.
+    fn relu_derivative(dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight])
+        [dst, errors, outputs_before_activation];
+
+    fn leaky_relu(dst: &mut [Weight], src: &[Weight]) [dst, src];
+
+    /// Multiplies each error by the derivative of leaky ReLU at the corresponding output.
+    fn leaky_relu_derivative(dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight])
+        [dst, errors, outputs_before_activation];
+}
+
+/// The number of rows from each matrix that `dot_tile` multiplies together at once.  Each value loaded from memory
+/// gets used `TILE_ROWS` or `TILE_COLS` times, and all of the partial sums fit in registers.
+pub(crate) const TILE_ROWS: usize = 4;
+pub(crate) const TILE_COLS: usize = 2;
+
+/// The dot product of every row in `a_rows` with every row in `b_rows`.
+pub(crate) type Tile = [[Weight; TILE_COLS]; TILE_ROWS];
+
+/// How many columns of the inputs are processed at a time by `matmul_transposed`.  This keeps the block of `b` that's
+/// being multiplied small enough to stay in cache while every row of `a` passes over it.
+const BLOCK_LEN: usize = 1024;
+
+impl KernelBackend {
+    /// `a_rows` and `b_rows` must all have the same length, which must be a multiple of 4.
+    fn dot_tile(self, a_rows: [&[Weight]; TILE_ROWS], b_rows: [&[Weight]; TILE_COLS]) -> Tile {
+        debug_assert!(a_rows.iter().chain(&b_rows).all(|row| row.len() == a_rows[0].len()));
+        debug_assert_eq!(a_rows[0].len() % 4, 0);
+
+        match self {
+            KernelBackend::Scalar => scalar::dot_tile(a_rows, b_rows),
+            KernelBackend::Simd128 => simd128::dot_tile(a_rows, b_rows),
+            #[cfg(target_arch = "x86_64")]
+            KernelBackend::Sse2 => unsafe { x86::sse::dot_tile(a_rows, b_rows) },
+            #[cfg(target_arch = "x86_64")]
+            KernelBackend::Avx2 => unsafe { x86::avx2::dot_tile(a_rows, b_rows) },
+            #[cfg(not(target_arch = "x86_64"))]
+            KernelBackend::Sse2 | KernelBackend::Avx2 => unreachable!(),
+        }
+    }
+
+    pub(crate) fn matmul_transposed(self, a: &WeightMatrix, b: &WeightMatrix, out: &mut WeightMatrix) {
+        assert_eq!(a.col_count(), b.col_count());
+        assert_eq!((out.row_count(), out.col_count()), (a.row_count(), b.row_count()));
+        debug_assert!(self.is_available());
+
+        out.as_mut_slice().fill(0.);
+        if a.row_count() == 0 || b.row_count() == 0 {
+            return;
+        }
+
+        // Rows are padded with zeros up to the stride, so whole strides can be multiplied without affecting the
+        // result.  Tiles that run past the last row re-use the last row and the extra results are thrown away.
+        let stride = a.stride();
+        for block_start in (0..stride).step_by(BLOCK_LEN) {
+            let block = block_start..(block_start + BLOCK_LEN).min(stride);
+            for a_row_start in (0..a.row_count()).step_by(TILE_ROWS) {
+                let a_rows: [&[Weight]; TILE_ROWS] =
+                    std::array::from_fn(|ix| &a.padded_row((a_row_start + ix).min(a.row_count() - 1))[block.clone()]);
+                let a_row_count = TILE_ROWS.min(a.row_count() - a_row_start);
+
+                for b_row_start in (0..b.row_count()).step_by(TILE_COLS) {
+                    let b_rows: [&[Weight]; TILE_COLS] = std::array::from_fn(|ix| {
+                        &b.padded_row((b_row_start + ix).min(b.row_count() - 1))[block.clone()]
+                    });
+                    let b_row_count = TILE_COLS.min(b.row_count() - b_row_start);
+
+                    let tile = self.dot_tile(a_rows, b_rows);
+                    for (row_offset, tile_row) in tile.iter().enumerate().take(a_row_count) {
+                        let out_row = &mut out[a_row_start + row_offset][b_row_start..b_row_start + b_row_count];
+                        for (out, &value) in out_row.iter_mut().zip(tile_row) {
+                            *out += value;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sets each value in `out` to the dot product of the corresponding row of `a` and row of `b`: `out[i][j]` is
+/// `a[i] . b[j]`.  That's the product of `a` and the transpose of `b`, which is what a layer computes for a batch of
+/// examples when `a` holds one example per row and `b` holds the layer's weights.
+///
+/// `a` and `b` must have the same number of columns, and `out` must have a row for each row of `a` and a column for
+/// each row of `b`.
+pub fn matmul_transposed(a: &WeightMatrix, b: &WeightMatrix, out: &mut WeightMatrix) {
+    kernel_backend().matmul_transposed(a, b, out)
+}
@@ -0,0 +1,65 @@
+//! Plain implementations of every kernel.  Used on targets without a SIMD implementation and as the reference that the
+//! SIMD versions are tested against.  Apart from `dot_tile`, these are generic so that they can also be used for `f64`
+//! networks.
+
+use super::{Tile, TILE_COLS, TILE_ROWS};
+use crate::{Float, Weight};
+
+pub fn dot<T: Float>(a: &[T], b: &[T]) -> T {
+    let mut sum = T::ZERO;
+    for (&a, &b) in a.iter().zip(b) {
+        sum += a * b;
+    }
+    sum
+}
+
+pub fn axpy<T: Float>(dst: &mut [T], alpha: T, x: &[T]) {
+    for (dst, &x) in dst.iter_mut().zip(x) {
+        *dst += alpha * x;
+    }
+}
+
+pub fn relu<T: Float>(dst: &mut [T], src: &[T]) {
+    for (dst, &src) in dst.iter_mut().zip(src) {
+        *dst = if src > T::ZERO { src } else { T::ZERO };
+    }
+}
+
+pub fn relu_derivative<T: Float>(dst: &mut [T], errors: &[T], outputs_before_activation: &[T]) {
+    for ((dst, &error), &output) in dst.iter_mut().zip(errors).zip(outputs_before_activation) {
+        *dst = if output > T::ZERO { error } else { T::ZERO };
+    }
+}
+
+pub fn leaky_relu<T: Float>(dst: &mut [T], src: &[T]) {
+    let slope = T::from_f64(0.01);
+    for (dst, &src) in dst.iter_mut().zip(src) {
+        *dst = if src < T::ZERO { slope * src } else { src };
+    }
+}
+
+pub fn leaky_relu_derivative<T: Float>(dst: &mut [T], errors: &[T], outputs_before_activation: &[T]) {
+    let slope = T::from_f64(0.01);
+    for ((dst, &error), &output) in dst.iter_mut().zip(errors).zip(outputs_before_activation) {
+        *dst = if output < T::ZERO { slope * error } else { error };
+    }
+}
+
+/// Returns the sum of the products of the values in `a` and `b`, accumulated without rounding.
+pub fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+    let mut sum = 0i32;
+    for (&a, &b) in a.iter().zip(b) {
+        sum = sum.wrapping_add(a as i32 * b as i32);
+    }
+    sum
+}
+
+pub fn dot_tile(a_rows: [&[Weight]; TILE_ROWS], b_rows: [&[Weight]; TILE_COLS]) -> Tile {
+    let mut tile = [[0.; TILE_COLS]; TILE_ROWS];
+    for (tile_row, a_row) in tile.iter_mut().zip(a_rows) {
+        for (sum, b_row) in tile_row.iter_mut().zip(b_rows) {
+            *sum = dot(a_row, b_row);
+        }
+    }
+    tile
+}
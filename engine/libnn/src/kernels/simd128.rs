@@ -0,0 +1,144 @@
+//! 4-wide kernels written against `F32x4`.  These are the kernels used by the wasm build, but since `F32x4` is
+//! emulated on other targets they can be run anywhere.
+
+use super::{
+    f32x4::{F32x4, I32x4},
+    scalar, Tile, TILE_COLS, TILE_ROWS,
+};
+use crate::Weight;
+
+pub fn dot(a: &[Weight], b: &[Weight]) -> Weight {
+    let split_ix = a.len() / 4 * 4;
+    let (a_chunks, a_tail) = a.split_at(split_ix);
+    let (b_chunks, b_tail) = b.split_at(split_ix);
+
+    let mut sum_v = F32x4::splat(0.);
+    for (a, b) in a_chunks.chunks_exact(4).zip(b_chunks.chunks_exact(4)) {
+        sum_v = sum_v.add(F32x4::load(a).mul(F32x4::load(b)));
+    }
+    sum_v.sum() + scalar::dot(a_tail, b_tail)
+}
+
+pub fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+    let split_ix = a.len() / 16 * 16;
+    let (a_chunks, a_tail) = a.split_at(split_ix);
+    let (b_chunks, b_tail) = b.split_at(split_ix);
+
+    let mut sum_v = I32x4::splat(0);
+    for (a, b) in a_chunks.chunks_exact(16).zip(b_chunks.chunks_exact(16)) {
+        sum_v = sum_v.add(I32x4::dot_i8x16(a, b));
+    }
+    sum_v.sum().wrapping_add(scalar::dot_i8(a_tail, b_tail))
+}
+
+pub fn axpy(dst: &mut [Weight], alpha: Weight, x: &[Weight]) {
+    let split_ix = dst.len() / 4 * 4;
+    let (dst_chunks, dst_tail) = dst.split_at_mut(split_ix);
+    let (x_chunks, x_tail) = x.split_at(split_ix);
+    let alpha_v = F32x4::splat(alpha);
+
+    for (dst, x) in dst_chunks.chunks_exact_mut(4).zip(x_chunks.chunks_exact(4)) {
+        F32x4::load(dst).add(alpha_v.mul(F32x4::load(x))).store(dst);
+    }
+    scalar::axpy(dst_tail, alpha, x_tail);
+}
+
+/// Applies `f` to each group of 4 values in `src` and stores the results into `dst`, then hands the remaining values
+/// to `tail`.
+#[inline(always)]
+fn map(dst: &mut [Weight], src: &[Weight], f: impl Fn(F32x4) -> F32x4, tail: fn(&mut [Weight], &[Weight])) {
+    let split_ix = dst.len() / 4 * 4;
+    let (dst_chunks, dst_tail) = dst.split_at_mut(split_ix);
+    let (src_chunks, src_tail) = src.split_at(split_ix);
+
+    for (dst, src) in dst_chunks.chunks_exact_mut(4).zip(src_chunks.chunks_exact(4)) {
+        f(F32x4::load(src)).store(dst);
+    }
+    tail(dst_tail, src_tail);
+}
+
+/// Like `map` but for kernels that combine errors with the outputs they were computed from.
+#[inline(always)]
+fn map_derivative(
+    dst: &mut [Weight],
+    errors: &[Weight],
+    outputs_before_activation: &[Weight],
+    f: impl Fn(F32x4, F32x4) -> F32x4,
+    tail: fn(&mut [Weight], &[Weight], &[Weight]),
+) {
+    let split_ix = dst.len() / 4 * 4;
+    let (dst_chunks, dst_tail) = dst.split_at_mut(split_ix);
+    let (errors_chunks, errors_tail) = errors.split_at(split_ix);
+    let (outputs_chunks, outputs_tail) = outputs_before_activation.split_at(split_ix);
+
+    for ((dst, errors), outputs) in dst_chunks
+        .chunks_exact_mut(4)
+        .zip(errors_chunks.chunks_exact(4))
+        .zip(outputs_chunks.chunks_exact(4))
+    {
+        f(F32x4::load(errors), F32x4::load(outputs)).store(dst);
+    }
+    tail(dst_tail, errors_tail, outputs_tail);
+}
+
+pub fn relu(dst: &mut [Weight], src: &[Weight]) {
+    let zero_v = F32x4::splat(0.);
+    map(dst, src, |src| zero_v.pmax(src), scalar::relu);
+}
+
+pub fn relu_derivative(dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight]) {
+    let zero_v = F32x4::splat(0.);
+    map_derivative(
+        dst,
+        errors,
+        outputs_before_activation,
+        |errors, outputs| outputs.gt(zero_v).select(errors, zero_v),
+        scalar::relu_derivative,
+    );
+}
+
+pub fn leaky_relu(dst: &mut [Weight], src: &[Weight]) {
+    let zero_v = F32x4::splat(0.);
+    let negative_multiplier_v = F32x4::splat(0.01);
+    map(
+        dst,
+        src,
+        |src| src.ge(zero_v).select(src, src.mul(negative_multiplier_v)),
+        scalar::leaky_relu,
+    );
+}
+
+pub fn leaky_relu_derivative(dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight]) {
+    let zero_v = F32x4::splat(0.);
+    let negative_derivative_v = F32x4::splat(0.01);
+    map_derivative(
+        dst,
+        errors,
+        outputs_before_activation,
+        |errors, outputs| outputs.ge(zero_v).select(errors, errors.mul(negative_derivative_v)),
+        scalar::leaky_relu_derivative,
+    );
+}
+
+pub fn dot_tile(a_rows: [&[Weight]; TILE_ROWS], b_rows: [&[Weight]; TILE_COLS]) -> Tile {
+    let split_ix = a_rows[0].len() / 4 * 4;
+
+    let mut sums = [[F32x4::splat(0.); TILE_COLS]; TILE_ROWS];
+    for k in (0..split_ix).step_by(4) {
+        let b = b_rows.map(|b_row| F32x4::load(&b_row[k..]));
+        for (row_sums, a_row) in sums.iter_mut().zip(a_rows) {
+            let a = F32x4::load(&a_row[k..]);
+            for (sum, &b) in row_sums.iter_mut().zip(&b) {
+                *sum = sum.add(a.mul(b));
+            }
+        }
+    }
+
+    let mut tile = scalar::dot_tile(a_rows.map(|row| &row[split_ix..]), b_rows.map(|row| &row[split_ix..]));
+    for (tile_row, row_sums) in tile.iter_mut().zip(&sums) {
+        for (value, sum) in tile_row.iter_mut().zip(row_sums) {
+            *value += sum.sum();
+        }
+    }
+    tile
+}
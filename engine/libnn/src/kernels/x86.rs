@@ -0,0 +1,390 @@
+//! x86_64 SIMD kernels.  SSE2 is part of the x86_64 baseline so the `sse` kernels can always be used; the `avx2`
+//! ones also need FMA and are only used if the CPU supports both.
+//!
+//! Safety: every slice passed to a kernel must be at least as long as the first one, and the `avx2` kernels must only
+//! be called if `avx2_fma_available()` returns true.
+
+/// Whether the CPU supports the instructions needed by the `avx2` kernels.  The result is cached by the standard
+/// library after the first call.
+pub fn avx2_fma_available() -> bool { is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") }
+
+pub mod sse {
+    use core::arch::x86_64::*;
+
+    use crate::{
+        kernels::{scalar, Tile, TILE_COLS, TILE_ROWS},
+        Weight,
+    };
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn hsum(v: __m128) -> Weight {
+        let mut lanes = [0.; 4];
+        _mm_storeu_ps(lanes.as_mut_ptr(), v);
+        lanes[0] + lanes[1] + lanes[2] + lanes[3]
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn hsum_i32(v: __m128i) -> i32 {
+        let mut lanes = [0i32; 4];
+        _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, v);
+        lanes[0]
+            .wrapping_add(lanes[1])
+            .wrapping_add(lanes[2])
+            .wrapping_add(lanes[3])
+    }
+
+    /// Picks values from `if_true` where `mask` is set and from `if_false` everywhere else.
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn select(mask: __m128, if_true: __m128, if_false: __m128) -> __m128 {
+        _mm_or_ps(_mm_and_ps(mask, if_true), _mm_andnot_ps(mask, if_false))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn dot(a: &[Weight], b: &[Weight]) -> Weight {
+        let len = a.len();
+        let chunk_count = len / 4;
+        let (a_ptr, b_ptr) = (a.as_ptr(), b.as_ptr());
+
+        let mut sum_v = _mm_setzero_ps();
+        for chunk_ix in 0..chunk_count {
+            let a = _mm_loadu_ps(a_ptr.add(chunk_ix * 4));
+            let b = _mm_loadu_ps(b_ptr.add(chunk_ix * 4));
+            sum_v = _mm_add_ps(sum_v, _mm_mul_ps(a, b));
+        }
+
+        hsum(sum_v) + scalar::dot(&a[chunk_count * 4..], &b[chunk_count * 4..len])
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+        let len = a.len();
+        let chunk_count = len / 16;
+        let (a_ptr, b_ptr) = (a.as_ptr() as *const __m128i, b.as_ptr() as *const __m128i);
+
+        let mut sum_v = _mm_setzero_si128();
+        for chunk_ix in 0..chunk_count {
+            let a = _mm_loadu_si128(a_ptr.add(chunk_ix));
+            let b = _mm_loadu_si128(b_ptr.add(chunk_ix));
+            // SSE2 can't sign-extend directly, so each byte is duplicated into both halves of a 16-bit lane and then
+            // shifted back down
+            let (a_low, a_high) = (
+                _mm_srai_epi16(_mm_unpacklo_epi8(a, a), 8),
+                _mm_srai_epi16(_mm_unpackhi_epi8(a, a), 8),
+            );
+            let (b_low, b_high) = (
+                _mm_srai_epi16(_mm_unpacklo_epi8(b, b), 8),
+                _mm_srai_epi16(_mm_unpackhi_epi8(b, b), 8),
+            );
+            sum_v = _mm_add_epi32(sum_v, _mm_madd_epi16(a_low, b_low));
+            sum_v = _mm_add_epi32(sum_v, _mm_madd_epi16(a_high, b_high));
+        }
+
+        hsum_i32(sum_v).wrapping_add(scalar::dot_i8(&a[chunk_count * 16..], &b[chunk_count * 16..len]))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn axpy(dst: &mut [Weight], alpha: Weight, x: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 4;
+        let alpha_v = _mm_set1_ps(alpha);
+        let (dst_ptr, x_ptr) = (dst.as_mut_ptr(), x.as_ptr());
+
+        for chunk_ix in 0..chunk_count {
+            let dst_v = _mm_loadu_ps(dst_ptr.add(chunk_ix * 4));
+            let x_v = _mm_loadu_ps(x_ptr.add(chunk_ix * 4));
+            _mm_storeu_ps(dst_ptr.add(chunk_ix * 4), _mm_add_ps(dst_v, _mm_mul_ps(alpha_v, x_v)));
+        }
+        scalar::axpy(&mut dst[chunk_count * 4..], alpha, &x[chunk_count * 4..len]);
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn relu(dst: &mut [Weight], src: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 4;
+        let zero_v = _mm_setzero_ps();
+
+        for chunk_ix in 0..chunk_count {
+            let src_v = _mm_loadu_ps(src.as_ptr().add(chunk_ix * 4));
+            // `_mm_max_ps` returns its second argument if either is NaN, which matches the scalar version
+            _mm_storeu_ps(dst.as_mut_ptr().add(chunk_ix * 4), _mm_max_ps(src_v, zero_v));
+        }
+        scalar::relu(&mut dst[chunk_count * 4..], &src[chunk_count * 4..len]);
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn relu_derivative(dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 4;
+        let zero_v = _mm_setzero_ps();
+
+        for chunk_ix in 0..chunk_count {
+            let outputs = _mm_loadu_ps(outputs_before_activation.as_ptr().add(chunk_ix * 4));
+            let errors = _mm_loadu_ps(errors.as_ptr().add(chunk_ix * 4));
+            let gt_mask = _mm_cmpgt_ps(outputs, zero_v);
+            _mm_storeu_ps(dst.as_mut_ptr().add(chunk_ix * 4), _mm_and_ps(gt_mask, errors));
+        }
+        scalar::relu_derivative(
+            &mut dst[chunk_count * 4..],
+            &errors[chunk_count * 4..len],
+            &outputs_before_activation[chunk_count * 4..len],
+        );
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn leaky_relu(dst: &mut [Weight], src: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 4;
+        let zero_v = _mm_setzero_ps();
+        let negative_multiplier_v = _mm_set1_ps(0.01);
+
+        for chunk_ix in 0..chunk_count {
+            let src_v = _mm_loadu_ps(src.as_ptr().add(chunk_ix * 4));
+            let ge_mask = _mm_cmpge_ps(src_v, zero_v);
+            let negatives = _mm_mul_ps(src_v, negative_multiplier_v);
+            _mm_storeu_ps(dst.as_mut_ptr().add(chunk_ix * 4), select(ge_mask, src_v, negatives));
+        }
+        scalar::leaky_relu(&mut dst[chunk_count * 4..], &src[chunk_count * 4..len]);
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn leaky_relu_derivative(dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 4;
+        let zero_v = _mm_setzero_ps();
+        let negative_derivative_v = _mm_set1_ps(0.01);
+
+        for chunk_ix in 0..chunk_count {
+            let outputs = _mm_loadu_ps(outputs_before_activation.as_ptr().add(chunk_ix * 4));
+            let errors = _mm_loadu_ps(errors.as_ptr().add(chunk_ix * 4));
+            let ge_mask = _mm_cmpge_ps(outputs, zero_v);
+            let negative_derivatives = _mm_mul_ps(errors, negative_derivative_v);
+            _mm_storeu_ps(
+                dst.as_mut_ptr().add(chunk_ix * 4),
+                select(ge_mask, errors, negative_derivatives),
+            );
+        }
+        scalar::leaky_relu_derivative(
+            &mut dst[chunk_count * 4..],
+            &errors[chunk_count * 4..len],
+            &outputs_before_activation[chunk_count * 4..len],
+        );
+    }
+
+    /// Safety: every row must have the same length, which must be a multiple of 4.
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn dot_tile(a_rows: [&[Weight]; TILE_ROWS], b_rows: [&[Weight]; TILE_COLS]) -> Tile {
+        let len = a_rows[0].len();
+        debug_assert_eq!(len % 4, 0);
+
+        let mut sums = [[_mm_setzero_ps(); TILE_COLS]; TILE_ROWS];
+        let mut ix = 0;
+        while ix < len {
+            let b = [
+                _mm_loadu_ps(b_rows[0].as_ptr().add(ix)),
+                _mm_loadu_ps(b_rows[1].as_ptr().add(ix)),
+            ];
+            for row_ix in 0..TILE_ROWS {
+                let a = _mm_loadu_ps(a_rows[row_ix].as_ptr().add(ix));
+                for col_ix in 0..TILE_COLS {
+                    sums[row_ix][col_ix] = _mm_add_ps(sums[row_ix][col_ix], _mm_mul_ps(a, b[col_ix]));
+                }
+            }
+            ix += 4;
+        }
+
+        let mut tile = [[0.; TILE_COLS]; TILE_ROWS];
+        for row_ix in 0..TILE_ROWS {
+            for col_ix in 0..TILE_COLS {
+                tile[row_ix][col_ix] = hsum(sums[row_ix][col_ix]);
+            }
+        }
+        tile
+    }
+}
+
+pub mod avx2 {
+    use core::arch::x86_64::*;
+
+    use super::sse;
+    use crate::{
+        kernels::{Tile, TILE_COLS, TILE_ROWS},
+        Weight,
+    };
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn hsum(v: __m256) -> Weight {
+        let halves = _mm_add_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps(v, 1));
+        let mut lanes = [0.; 4];
+        _mm_storeu_ps(lanes.as_mut_ptr(), halves);
+        lanes[0] + lanes[1] + lanes[2] + lanes[3]
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot(a: &[Weight], b: &[Weight]) -> Weight {
+        let len = a.len();
+        let (a_ptr, b_ptr) = (a.as_ptr(), b.as_ptr());
+
+        // Two accumulators so that consecutive FMAs don't have to wait on each other
+        let mut sum_0 = _mm256_setzero_ps();
+        let mut sum_1 = _mm256_setzero_ps();
+        let mut ix = 0;
+        while ix + 16 <= len {
+            sum_0 = _mm256_fmadd_ps(_mm256_loadu_ps(a_ptr.add(ix)), _mm256_loadu_ps(b_ptr.add(ix)), sum_0);
+            sum_1 = _mm256_fmadd_ps(
+                _mm256_loadu_ps(a_ptr.add(ix + 8)),
+                _mm256_loadu_ps(b_ptr.add(ix + 8)),
+                sum_1,
+            );
+            ix += 16;
+        }
+        if ix + 8 <= len {
+            sum_0 = _mm256_fmadd_ps(_mm256_loadu_ps(a_ptr.add(ix)), _mm256_loadu_ps(b_ptr.add(ix)), sum_0);
+            ix += 8;
+        }
+
+        hsum(_mm256_add_ps(sum_0, sum_1)) + sse::dot(&a[ix..], &b[ix..len])
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+        let len = a.len();
+        let (a_ptr, b_ptr) = (a.as_ptr(), b.as_ptr());
+
+        let mut sum_v = _mm256_setzero_si256();
+        let mut ix = 0;
+        while ix + 16 <= len {
+            let a = _mm256_cvtepi8_epi16(_mm_loadu_si128(a_ptr.add(ix) as *const __m128i));
+            let b = _mm256_cvtepi8_epi16(_mm_loadu_si128(b_ptr.add(ix) as *const __m128i));
+            sum_v = _mm256_add_epi32(sum_v, _mm256_madd_epi16(a, b));
+            ix += 16;
+        }
+
+        let halves = _mm_add_epi32(_mm256_castsi256_si128(sum_v), _mm256_extracti128_si256(sum_v, 1));
+        sse::hsum_i32(halves).wrapping_add(sse::dot_i8(&a[ix..], &b[ix..len]))
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn axpy(dst: &mut [Weight], alpha: Weight, x: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 8;
+        let alpha_v = _mm256_set1_ps(alpha);
+        let (dst_ptr, x_ptr) = (dst.as_mut_ptr(), x.as_ptr());
+
+        for chunk_ix in 0..chunk_count {
+            let dst_v = _mm256_loadu_ps(dst_ptr.add(chunk_ix * 8));
+            let x_v = _mm256_loadu_ps(x_ptr.add(chunk_ix * 8));
+            _mm256_storeu_ps(dst_ptr.add(chunk_ix * 8), _mm256_fmadd_ps(alpha_v, x_v, dst_v));
+        }
+        sse::axpy(&mut dst[chunk_count * 8..], alpha, &x[chunk_count * 8..len]);
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn relu(dst: &mut [Weight], src: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 8;
+        let zero_v = _mm256_setzero_ps();
+
+        for chunk_ix in 0..chunk_count {
+            let src_v = _mm256_loadu_ps(src.as_ptr().add(chunk_ix * 8));
+            _mm256_storeu_ps(dst.as_mut_ptr().add(chunk_ix * 8), _mm256_max_ps(src_v, zero_v));
+        }
+        sse::relu(&mut dst[chunk_count * 8..], &src[chunk_count * 8..len]);
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn relu_derivative(dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 8;
+        let zero_v = _mm256_setzero_ps();
+
+        for chunk_ix in 0..chunk_count {
+            let outputs = _mm256_loadu_ps(outputs_before_activation.as_ptr().add(chunk_ix * 8));
+            let errors = _mm256_loadu_ps(errors.as_ptr().add(chunk_ix * 8));
+            let gt_mask = _mm256_cmp_ps(outputs, zero_v, _CMP_GT_OQ);
+            _mm256_storeu_ps(dst.as_mut_ptr().add(chunk_ix * 8), _mm256_and_ps(gt_mask, errors));
+        }
+        sse::relu_derivative(
+            &mut dst[chunk_count * 8..],
+            &errors[chunk_count * 8..len],
+            &outputs_before_activation[chunk_count * 8..len],
+        );
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn leaky_relu(dst: &mut [Weight], src: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 8;
+        let zero_v = _mm256_setzero_ps();
+        let negative_multiplier_v = _mm256_set1_ps(0.01);
+
+        for chunk_ix in 0..chunk_count {
+            let src_v = _mm256_loadu_ps(src.as_ptr().add(chunk_ix * 8));
+            let ge_mask = _mm256_cmp_ps(src_v, zero_v, _CMP_GE_OQ);
+            let negatives = _mm256_mul_ps(src_v, negative_multiplier_v);
+            _mm256_storeu_ps(
+                dst.as_mut_ptr().add(chunk_ix * 8),
+                _mm256_blendv_ps(negatives, src_v, ge_mask),
+            );
+        }
+        sse::leaky_relu(&mut dst[chunk_count * 8..], &src[chunk_count * 8..len]);
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn leaky_relu_derivative(dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight]) {
+        let len = dst.len();
+        let chunk_count = len / 8;
+        let zero_v = _mm256_setzero_ps();
+        let negative_derivative_v = _mm256_set1_ps(0.01);
+
+        for chunk_ix in 0..chunk_count {
+            let outputs = _mm256_loadu_ps(outputs_before_activation.as_ptr().add(chunk_ix * 8));
+            let errors = _mm256_loadu_ps(errors.as_ptr().add(chunk_ix * 8));
+            let ge_mask = _mm256_cmp_ps(outputs, zero_v, _CMP_GE_OQ);
+            let negative_derivatives = _mm256_mul_ps(errors, negative_derivative_v);
+            _mm256_storeu_ps(
+                dst.as_mut_ptr().add(chunk_ix * 8),
+                _mm256_blendv_ps(negative_derivatives, errors, ge_mask),
+            );
+        }
+        sse::leaky_relu_derivative(
+            &mut dst[chunk_count * 8..],
+            &errors[chunk_count * 8..len],
+            &outputs_before_activation[chunk_count * 8..len],
+        );
+    }
+
+    /// Safety: every row must have the same length, which must be a multiple of 4.
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot_tile(a_rows: [&[Weight]; TILE_ROWS], b_rows: [&[Weight]; TILE_COLS]) -> Tile {
+        let len = a_rows[0].len();
+        debug_assert_eq!(len % 4, 0);
+
+        let mut sums = [[_mm256_setzero_ps(); TILE_COLS]; TILE_ROWS];
+        let mut ix = 0;
+        while ix + 8 <= len {
+            let b = [
+                _mm256_loadu_ps(b_rows[0].as_ptr().add(ix)),
+                _mm256_loadu_ps(b_rows[1].as_ptr().add(ix)),
+            ];
+            for row_ix in 0..TILE_ROWS {
+                let a = _mm256_loadu_ps(a_rows[row_ix].as_ptr().add(ix));
+                for col_ix in 0..TILE_COLS {
+                    sums[row_ix][col_ix] = _mm256_fmadd_ps(a, b[col_ix], sums[row_ix][col_ix]);
+                }
+            }
+            ix += 8;
+        }
+
+        let mut tile = sse::dot_tile(a_rows.map(|row| &row[ix..]), b_rows.map(|row| &row[ix..]));
+        for row_ix in 0..TILE_ROWS {
+            for col_ix in 0..TILE_COLS {
+                tile[row_ix][col_ix] += hsum(sums[row_ix][col_ix]);
+            }
+        }
+        tile
+    }
+}
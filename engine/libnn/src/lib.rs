@@ -1,29 +1,75 @@
 #![feature(array_methods)]
 
-#[cfg(target_arch = "wasm32")]
-use core::arch::wasm32::*;
-
-use fast_math::sigmoid_approx;
-
+mod attention;
+mod autodiff;
+mod batch;
+mod batch_forward;
+mod conv;
+mod csv;
+mod dataset;
+mod early_stopping;
+mod embedding;
+mod error;
 mod fast_math;
+mod float;
+mod graph;
+mod hogwild;
+mod idx;
+mod inference;
+mod kernels;
+mod matrix;
+mod multi_head;
+mod prune;
+mod quantize;
+mod recurrent;
+mod sparse;
 #[cfg(test)]
 mod tests;
 
+pub use attention::{
+    sinusoidal_positional_encoding, LayerNorm, MultiHeadAttention, PositionwiseDense, TransformerEncoder,
+    TransformerEncoderBlock,
+};
+pub use autodiff::{Tape, Var};
+pub use batch::{BatchTrainer, LayerWorkspace, NetworkGradients, NetworkWorkspace, OutputLayerWorkspace};
+pub use batch_forward::BatchActivations;
+pub use conv::{Conv2DConfig, Conv2DLayer, FeatureLayer, FlattenLayer, ImageShape, Pool2DLayer, PoolingMode};
+pub use csv::{read_csv, ColumnSelector, CsvData, CsvOptions};
+pub use dataset::{argmax, Dataset, DatasetSplits, InMemoryDataset, MiniBatch, MiniBatches};
+pub use early_stopping::{EarlyStoppingConfig, StopReason, TrainingReport};
+pub use embedding::{CategoricalInput, EmbeddingLayer};
+pub use error::NnError;
+pub use float::Float;
+pub use graph::{GraphNetwork, GraphNetworkBuilder, GraphNode, NodeId};
+pub use hogwild::{HogwildTrainer, SharedWeights};
+pub use idx::{idx_classification_dataset, read_idx, IdxArray};
+pub use inference::InferenceWorkspace;
+pub use kernels::{kernel_backend, set_kernel_backend, KernelBackend};
+pub use matrix::WeightMatrix;
+pub use multi_head::{MultiHeadNetwork, OutputHead};
+pub use prune::{LayerSparsity, PruningRound, PruningSchedule, PruningScope};
+pub use quantize::{QuantizationParams, QuantizationReport, QuantizedLayer, QuantizedNetwork, WeightGranularity};
+pub use recurrent::{CellType, RecurrentLayer, SequenceNetwork};
+pub use sparse::{SparseLayer, SparseMatrix, SparseNetwork};
+
 pub type Weight = f32;
 
-pub trait ActivationFunction {
-    fn get_output(&self, x: Weight) -> Weight;
+pub trait ActivationFunction<T: Float = Weight>: Send + Sync {
+    fn get_output(&self, x: T) -> T;
 
-    fn derivative(&self, x: Weight) -> Weight;
+    fn derivative(&self, x: T) -> T;
 
-    fn apply_batch(&self, dst: &mut [Weight], src: &[Weight]) {
+    /// Only true for `Softmax`, whose derivative can't be computed one value at a time.
+    fn is_softmax(&self) -> bool { false }
+
+    fn apply_batch(&self, dst: &mut [T], src: &[T]) {
         debug_assert_eq!(src.len(), dst.len());
         for i in 0..dst.len() {
             unsafe { *dst.get_unchecked_mut(i) = self.get_output(*src.get_unchecked(i)) };
         }
     }
 
-    fn apply_derivative_batch(&self, dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight]) {
+    fn apply_derivative_batch(&self, dst: &mut [T], errors: &[T], outputs_before_activation: &[T]) {
         debug_assert_eq!(dst.len(), errors.len());
         debug_assert_eq!(errors.len(), outputs_before_activation.len());
         for i in 0..dst.len() {
@@ -38,197 +84,104 @@ pub trait ActivationFunction {
 pub struct Sigmoid;
 pub static SIGMOID: Sigmoid = Sigmoid;
 
-impl ActivationFunction for Sigmoid {
-    fn get_output(&self, x: Weight) -> Weight {
+impl<T: Float> ActivationFunction<T> for Sigmoid {
+    fn get_output(&self, x: T) -> T {
         // 1. / (1. + std::f32::consts::E.powf(-x))
-        sigmoid_approx(x)
+        x.sigmoid()
     }
 
-    fn derivative(&self, x: Weight) -> Weight {
+    fn derivative(&self, x: T) -> T {
         let y = self.get_output(x);
-        y * (1. - y)
+        y * (T::ONE - y)
     }
 }
 
 pub struct Tanh;
 pub static TANH: Tanh = Tanh;
 
-impl ActivationFunction for Tanh {
-    fn get_output(&self, x: Weight) -> Weight { x.tanh() }
+impl<T: Float> ActivationFunction<T> for Tanh {
+    fn get_output(&self, x: T) -> T { x.tanh() }
 
-    fn derivative(&self, x: Weight) -> Weight { 1. - x.tanh().powi(2) }
+    fn derivative(&self, x: T) -> T { T::ONE - x.tanh().powi(2) }
 }
 
 pub struct Identity;
 pub static IDENTITY: Identity = Identity;
 
-impl ActivationFunction for Identity {
-    fn get_output(&self, x: Weight) -> Weight { x }
+impl<T: Float> ActivationFunction<T> for Identity {
+    fn get_output(&self, x: T) -> T { x }
 
-    fn derivative(&self, _x: Weight) -> Weight { 1. }
+    fn derivative(&self, _x: T) -> T { T::ONE }
 }
 
 pub struct ReLU;
 pub static RELU: ReLU = ReLU;
 
-impl ActivationFunction for ReLU {
-    fn get_output(&self, x: Weight) -> Weight {
-        if x > 0. {
+impl<T: Float> ActivationFunction<T> for ReLU {
+    fn get_output(&self, x: T) -> T {
+        if x > T::ZERO {
             x
         } else {
-            0.
+            T::ZERO
         }
     }
 
-    fn derivative(&self, x: Weight) -> Weight {
-        if x > 0. {
-            1.
+    fn derivative(&self, x: T) -> T {
+        if x > T::ZERO {
+            T::ONE
         } else {
-            0.
-        }
-    }
-
-    #[cfg(target_arch = "wasm32")]
-    fn apply_batch(&self, dst: &mut [Weight], src: &[Weight]) {
-        debug_assert_eq!(src.len(), dst.len());
-        let remainder = src.len() % 4;
-        let chunk_count = (src.len() - remainder) / 4;
-        let zero_v = f32x4_splat(0.);
-
-        for chunk_ix in 0..chunk_count {
-            let src = unsafe { v128_load(src.as_ptr().add(chunk_ix * 4) as *const _) };
-            unsafe { v128_store(dst.as_mut_ptr().add(chunk_ix * 4) as *mut _, f32x4_pmax(zero_v, src)) }
-        }
-        for remainder_ix in (chunk_count * 4)..src.len() {
-            unsafe { *dst.get_unchecked_mut(remainder_ix) = self.get_output(*src.get_unchecked(remainder_ix)) };
+            T::ZERO
         }
     }
 
-    #[cfg(target_arch = "wasm32")]
-    fn apply_derivative_batch(&self, dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight]) {
-        debug_assert_eq!(dst.len(), errors.len());
-        debug_assert_eq!(errors.len(), outputs_before_activation.len());
-
-        let remainder = dst.len() % 4;
-        let chunk_count = (dst.len() - remainder) / 4;
-        let zero_v = f32x4_splat(0.);
-
-        debug_assert!(dst.len() == chunk_count * 4 + remainder);
-        for chunk_ix in 0..chunk_count {
-            let outputs = unsafe { v128_load(outputs_before_activation.as_ptr().add(chunk_ix * 4) as *const _) };
-            let errors = unsafe { v128_load(errors.as_ptr().add(chunk_ix * 4) as *const _) };
-            let gt_mask = f32x4_gt(outputs, zero_v);
+    fn apply_batch(&self, dst: &mut [T], src: &[T]) { T::relu(dst, src) }
 
-            unsafe {
-                v128_store(
-                    dst.as_mut_ptr().add(chunk_ix * 4) as *mut _,
-                    v128_bitselect(errors, zero_v, gt_mask),
-                );
-            }
-        }
-        for remainder_ix in (chunk_count * 4)..dst.len() {
-            unsafe {
-                *dst.get_unchecked_mut(remainder_ix) = if *outputs_before_activation.get_unchecked(remainder_ix) > 0. {
-                    *errors.get_unchecked(remainder_ix)
-                } else {
-                    0.
-                }
-            };
-        }
+    fn apply_derivative_batch(&self, dst: &mut [T], errors: &[T], outputs_before_activation: &[T]) {
+        T::relu_derivative(dst, errors, outputs_before_activation)
     }
 }
 
 pub struct LeakyReLU;
 pub static LEAKY_RELU: LeakyReLU = LeakyReLU;
 
-impl ActivationFunction for LeakyReLU {
-    fn get_output(&self, x: Weight) -> Weight {
-        if x < 0. {
-            0.01 * x
+impl<T: Float> ActivationFunction<T> for LeakyReLU {
+    fn get_output(&self, x: T) -> T {
+        if x < T::ZERO {
+            T::from_f64(0.01) * x
         } else {
             x
         }
     }
 
-    fn derivative(&self, x: Weight) -> Weight {
-        if x < 0. {
-            0.01
+    fn derivative(&self, x: T) -> T {
+        if x < T::ZERO {
+            T::from_f64(0.01)
         } else {
-            1.
+            T::ONE
         }
     }
 
-    #[cfg(target_arch = "wasm32")]
-    fn apply_batch(&self, dst: &mut [Weight], src: &[Weight]) {
-        debug_assert_eq!(src.len(), dst.len());
-        let remainder = src.len() % 4;
-        let chunk_count = (src.len() - remainder) / 4;
-        let negative_multiplier_v = f32x4_splat(0.01);
-        let zero_v = f32x4_splat(0.);
+    fn apply_batch(&self, dst: &mut [T], src: &[T]) { T::leaky_relu(dst, src) }
 
-        for chunk_ix in 0..chunk_count {
-            let src = unsafe { v128_load(src.as_ptr().add(chunk_ix * 4) as *const _) };
-            let mask = f32x4_ge(src, zero_v);
-            let negatives = f32x4_mul(src, negative_multiplier_v);
-            let val = v128_bitselect(src, negatives, mask);
-            unsafe { v128_store(dst.as_mut_ptr().add(chunk_ix * 4) as *mut _, val) }
-        }
-        for remainder_ix in (chunk_count * 4)..src.len() {
-            unsafe { *dst.get_unchecked_mut(remainder_ix) = self.get_output(*src.get_unchecked(remainder_ix)) };
-        }
-    }
-
-    #[cfg(target_arch = "wasm32")]
-    fn apply_derivative_batch(&self, dst: &mut [Weight], errors: &[Weight], outputs_before_activation: &[Weight]) {
-        debug_assert_eq!(dst.len(), errors.len());
-        debug_assert_eq!(errors.len(), outputs_before_activation.len());
-
-        let remainder = dst.len() % 4;
-        let chunk_count = (dst.len() - remainder) / 4;
-        let zero_v = f32x4_splat(0.);
-        let negative_derivative_v = f32x4_splat(0.01);
-
-        debug_assert!(dst.len() == chunk_count * 4 + remainder);
-        for chunk_ix in 0..chunk_count {
-            let outputs = unsafe { v128_load(outputs_before_activation.as_ptr().add(chunk_ix * 4) as *const _) };
-            let errors = unsafe { v128_load(errors.as_ptr().add(chunk_ix * 4) as *const _) };
-            let ge_mask = f32x4_ge(outputs, zero_v);
-
-            unsafe {
-                let negative_derivatives = f32x4_mul(errors, negative_derivative_v);
-                v128_store(
-                    dst.as_mut_ptr().add(chunk_ix * 4) as *mut _,
-                    v128_bitselect(errors, negative_derivatives, ge_mask),
-                );
-            }
-        }
-        for remainder_ix in (chunk_count * 4)..dst.len() {
-            unsafe {
-                let error = *errors.get_unchecked(remainder_ix);
-                *dst.get_unchecked_mut(remainder_ix) = if *outputs_before_activation.get_unchecked(remainder_ix) >= 0. {
-                    error
-                } else {
-                    0.01 * error
-                }
-            };
-        }
+    fn apply_derivative_batch(&self, dst: &mut [T], errors: &[T], outputs_before_activation: &[T]) {
+        T::leaky_relu_derivative(dst, errors, outputs_before_activation)
     }
 }
 
 pub struct GrowingCosineUnit;
 pub static GCU: GrowingCosineUnit = GrowingCosineUnit;
 
-impl ActivationFunction for GrowingCosineUnit {
-    fn get_output(&self, x: Weight) -> Weight {
-        if x >= -std::f32::consts::PI && x <= std::f32::consts::PI {
-            return x * fastapprox::fast::cos(x);
+impl<T: Float> ActivationFunction<T> for GrowingCosineUnit {
+    fn get_output(&self, x: T) -> T {
+        if x >= -T::PI && x <= T::PI {
+            return x * x.approx_cos();
         }
         return x * x.cos();
     }
 
-    fn derivative(&self, x: Weight) -> Weight {
-        if x >= -std::f32::consts::PI && x <= std::f32::consts::PI {
-            return fastapprox::fast::cos(x) - (x * fastapprox::fast::sin(x));
+    fn derivative(&self, x: T) -> T {
+        if x >= -T::PI && x <= T::PI {
+            return x.approx_cos() - (x * x.approx_sin());
         }
         return x.cos() - (x * x.sin());
     }
@@ -239,11 +192,11 @@ impl ActivationFunction for GrowingCosineUnit {
 pub struct Gaussian;
 pub static GAUSSIAN: Gaussian = Gaussian;
 
-impl ActivationFunction for Gaussian {
+impl<T: Float> ActivationFunction<T> for Gaussian {
     // TODO: Fastmath
-    fn get_output(&self, x: Weight) -> Weight { std::f32::consts::E.powf(-x * x) }
+    fn get_output(&self, x: T) -> T { T::E.powf(-x * x) }
 
-    fn derivative(&self, x: Weight) -> Weight { -2. * x * std::f32::consts::E.powf(-x * x) }
+    fn derivative(&self, x: T) -> T { T::from_f64(-2.) * x * T::E.powf(-x * x) }
 
     // TODO: Batch Application
 }
@@ -251,30 +204,29 @@ impl ActivationFunction for Gaussian {
 pub struct Swish;
 pub static SWISH: Swish = Swish;
 
-impl ActivationFunction for Swish {
+impl<T: Float> ActivationFunction<T> for Swish {
     // TODO: Fastmath
-    fn get_output(&self, x: Weight) -> Weight { x / (1. + std::f32::consts::E.powf(-x)) }
+    fn get_output(&self, x: T) -> T { x / (T::ONE + T::E.powf(-x)) }
 
-    fn derivative(&self, x: Weight) -> Weight {
-        (1. + std::f32::consts::E.powf(-x) + (x * std::f32::consts::E.powf(-x)))
-            / (1. + std::f32::consts::E.powf(-x)).powi(2)
+    fn derivative(&self, x: T) -> T {
+        (T::ONE + T::E.powf(-x) + (x * T::E.powf(-x))) / (T::ONE + T::E.powf(-x)).powi(2)
     }
 }
 
 pub struct Ameo;
 pub static AMEO: Ameo = Ameo;
 
-impl ActivationFunction for Ameo {
-    fn get_output(&self, x: Weight) -> Weight {
-        if x >= 0. {
+impl<T: Float> ActivationFunction<T> for Ameo {
+    fn get_output(&self, x: T) -> T {
+        if x >= T::ZERO {
             GCU.get_output(x)
         } else {
             TANH.get_output(x)
         }
     }
 
-    fn derivative(&self, x: Weight) -> Weight {
-        if x >= 0. {
+    fn derivative(&self, x: T) -> T {
+        if x >= T::ZERO {
             GCU.derivative(x)
         } else {
             TANH.derivative(x)
@@ -282,49 +234,143 @@ impl ActivationFunction for Ameo {
     }
 }
 
-pub trait CostFunction {
-    fn get_cost(&self, error: Weight) -> Weight;
+/// Normalizes a layer's outputs into a probability distribution.  Unlike the other activation functions, each output
+/// depends on every value in the layer, so this only makes sense through `apply_batch`; `get_output` treats `x` as a
+/// layer with a single neuron.
+///
+/// The derivative is reported as 1 because this is meant to be used on the output layer together with `CrossEntropy`,
+/// whose derivative already accounts for the softmax.  It wouldn't train correctly with any other cost function or in
+/// a hidden layer, so `Network::new` and the constructors of the other networks return `NnError::UnpairedSoftmax` if
+/// it's used anywhere else.
+pub struct Softmax;
+pub static SOFTMAX: Softmax = Softmax;
+
+impl<T: Float> ActivationFunction<T> for Softmax {
+    fn get_output(&self, _x: T) -> T { T::ONE }
 
-    fn derivative(&self, error: Weight) -> Weight;
+    fn derivative(&self, _x: T) -> T { T::ONE }
+
+    fn is_softmax(&self) -> bool { true }
+
+    fn apply_batch(&self, dst: &mut [T], src: &[T]) {
+        debug_assert_eq!(src.len(), dst.len());
+        // Subtracting the max keeps `exp` from overflowing without changing the result
+        let max = src.iter().fold(T::NEG_INFINITY, |acc, &x| acc.max(x));
+        let mut sum = T::ZERO;
+        for (dst, &src) in dst.iter_mut().zip(src) {
+            *dst = (src - max).exp();
+            sum += *dst;
+        }
+        for dst in dst.iter_mut() {
+            *dst /= sum;
+        }
+    }
+}
+
+pub trait CostFunction<T: Float = Weight>: Send + Sync {
+    fn get_cost(&self, error: T) -> T;
+
+    fn derivative(&self, error: T) -> T;
+
+    /// Whether `derivative` already includes the derivative of `Softmax`, in which case the output layer has to use it.
+    fn includes_softmax_derivative(&self) -> bool { false }
+}
+
+/// Returns an error if `activation_fn` is `Softmax`, which is only supported on output layers.
+pub(crate) fn validate_hidden_activation<T: Float>(
+    activation_fn: &dyn ActivationFunction<T>,
+    layer_ix: usize,
+) -> Result<(), NnError> {
+    if activation_fn.is_softmax() {
+        return Err(NnError::UnpairedSoftmax { layer_ix });
+    }
+    Ok(())
+}
+
+/// Divides a cost summed over `target_count` targets by their count, failing if training diverged.
+pub(crate) fn average_cost<T: Float>(total_cost: T, target_count: usize) -> Result<T, NnError> {
+    let cost = total_cost / T::from_f64(target_count as f64);
+    if !cost.is_finite() {
+        return Err(NnError::NonFiniteCost {
+            cost: cost.to_f64() as Weight,
+        });
+    }
+    Ok(cost)
 }
 
 pub struct MeanSquaredError;
 pub static MEAN_SQUARED_ERROR: MeanSquaredError = MeanSquaredError;
 
-impl CostFunction for MeanSquaredError {
-    fn get_cost(&self, error: Weight) -> Weight { error * error }
+impl<T: Float> CostFunction<T> for MeanSquaredError {
+    fn get_cost(&self, error: T) -> T { error * error }
 
-    fn derivative(&self, error: Weight) -> Weight { error * 2. }
+    fn derivative(&self, error: T) -> T { error * T::from_f64(2.) }
+}
+
+/// Cross-entropy loss for one-hot targets.  Must be paired with `Softmax` on the output layer: the derivative of the
+/// two combined with respect to the outputs before activation is just the error, which is what this returns.
+///
+/// Costs are computed from the error alone, so only the output for the correct class (the one with a positive error)
+/// contributes to the cost.
+pub struct CrossEntropy;
+pub static CROSS_ENTROPY: CrossEntropy = CrossEntropy;
+
+impl<T: Float> CostFunction<T> for CrossEntropy {
+    fn get_cost(&self, error: T) -> T {
+        if error.is_nan() {
+            return error;
+        }
+
+        if error > T::ZERO {
+            // The output for the correct class is `1 - error`.  It's clamped to avoid an infinite cost when it
+            // underflows to zero.
+            -(T::ONE - error).max(T::from_f64(1e-7)).ln()
+        } else {
+            T::ZERO
+        }
+    }
+
+    fn derivative(&self, error: T) -> T { error }
+
+    fn includes_softmax_derivative(&self) -> bool { true }
 }
 
 pub struct MeanSquaredErrorMultiplied(pub f32);
 
-impl CostFunction for MeanSquaredErrorMultiplied {
-    fn get_cost(&self, error: Weight) -> Weight { error * error * self.0 }
+impl<T: Float> CostFunction<T> for MeanSquaredErrorMultiplied {
+    fn get_cost(&self, error: T) -> T { error * error * T::from_f64(self.0.into()) }
 
-    fn derivative(&self, error: Weight) -> Weight { error * self.0 }
+    fn derivative(&self, error: T) -> T { error * T::from_f64(self.0.into()) }
 }
 
-pub struct DenseLayer {
-    pub weights: Vec<Vec<Weight>>,
-    pub biases: Vec<Weight>,
-    pub neuron_gradients: Vec<Weight>,
-    pub activation_fn: &'static dyn ActivationFunction,
-    pub errors_scratch: Vec<Weight>,
-    pub outputs_before_activation: Vec<Weight>,
-    pub outputs: Vec<Weight>,
+pub struct DenseLayer<T: Float = Weight> {
+    pub weights: WeightMatrix<T>,
+    pub biases: Vec<T>,
+    pub neuron_gradients: Vec<T>,
+    pub activation_fn: &'static dyn ActivationFunction<T>,
+    pub errors_scratch: Vec<T>,
+    pub outputs_before_activation: Vec<T>,
+    pub outputs: Vec<T>,
+    /// Set once the layer has been pruned.  Holds 1 for each weight that's kept and 0 for each one that's been pruned;
+    /// pruned weights are kept at zero by `update_weights`.
+    pub weight_mask: Option<WeightMatrix<T>>,
+    /// Scales the learning rate used to update this layer's weights and biases.  Defaults to 1.
+    pub learning_rate_multiplier: T,
+    /// Frozen layers still pass errors back to the layers before them, but their own weights and biases are left
+    /// as-is when training.
+    pub frozen: bool,
 }
 
-impl DenseLayer {
+impl<T: Float> DenseLayer<T> {
     pub fn new(
         neuron_count: usize,
         input_count: usize,
-        init_weights: &mut impl FnMut(usize, usize) -> Weight,
-        init_biases: &mut impl FnMut(usize) -> Weight,
-        activation_fn: &'static dyn ActivationFunction,
+        init_weights: &mut impl FnMut(usize, usize) -> T,
+        init_biases: &mut impl FnMut(usize) -> T,
+        activation_fn: &'static dyn ActivationFunction<T>,
     ) -> Self {
-        let mut weights = vec![vec![0.; input_count]; neuron_count];
-        let mut biases = vec![0.; neuron_count];
+        let mut weights = WeightMatrix::new(neuron_count, input_count);
+        let mut biases = vec![T::ZERO; neuron_count];
 
         for neuron_ix in 0..neuron_count {
             for input_ix in 0..input_count {
@@ -336,90 +382,43 @@ impl DenseLayer {
         DenseLayer {
             weights,
             biases,
-            neuron_gradients: vec![0.; neuron_count],
+            neuron_gradients: vec![T::ZERO; neuron_count],
             activation_fn,
-            errors_scratch: vec![0.; neuron_count],
-            outputs_before_activation: vec![0.; neuron_count],
-            outputs: vec![0.; neuron_count],
+            errors_scratch: vec![T::ZERO; neuron_count],
+            outputs_before_activation: vec![T::ZERO; neuron_count],
+            outputs: vec![T::ZERO; neuron_count],
+            weight_mask: None,
+            learning_rate_multiplier: T::ONE,
+            frozen: false,
         }
     }
 
+    /// The learning rate to update this layer with when training with `learning_rate`.  Doesn't account for `frozen`;
+    /// frozen layers shouldn't be updated at all.
+    pub fn layer_learning_rate(&self, learning_rate: T) -> T { learning_rate * self.learning_rate_multiplier }
+
     pub fn compute_neuron_gradient(
         &self,
-        neuron_output_before_activation: Weight,
-        connected_output_neuron_gradient_sum: Weight,
-    ) -> Weight {
+        neuron_output_before_activation: T,
+        connected_output_neuron_gradient_sum: T,
+    ) -> T {
         connected_output_neuron_gradient_sum * (self.activation_fn).derivative(neuron_output_before_activation)
     }
 
     /// Calculates the gradients for each neuron and populates `self.neuron_gradients`.
-    #[cfg(not(target_arch = "wasm32"))]
-    pub fn compute_gradients(&mut self, output_weights: &[Vec<Weight>], gradient_of_output_neurons: &[Weight]) {
-        debug_assert_eq!(output_weights.len(), gradient_of_output_neurons.len());
-
-        for neuron_ix in 0..self.weights.len() {
-            let output_before_activation = self.outputs_before_activation[neuron_ix];
-            let mut error = 0.;
-
-            for output_ix in 0..gradient_of_output_neurons.len() {
-                let output_neuron = &output_weights[output_ix];
-                let output_weight = output_neuron[neuron_ix];
-                // How much the output weight we're connected to contributes to the gradient of the
-                // neuron it's connected to.
-                error += output_weight * gradient_of_output_neurons[output_ix];
-            }
-
-            let gradient = self.compute_neuron_gradient(output_before_activation, error);
-            self.neuron_gradients[neuron_ix] = gradient;
-        }
-    }
-
-    /// Calculates the gradients for each neuron and populates `self.neuron_gradients`.
-    #[cfg(target_arch = "wasm32")]
-    pub fn compute_gradients(&mut self, output_weights: &[Vec<Weight>], gradient_of_output_neurons: &[Weight]) {
-        debug_assert_eq!(output_weights.len(), gradient_of_output_neurons.len());
+    pub fn compute_gradients(&mut self, output_weights: &WeightMatrix<T>, gradient_of_output_neurons: &[T]) {
+        debug_assert_eq!(output_weights.row_count(), gradient_of_output_neurons.len());
 
         // Accumulate errors into the scratch buffer
-        let remainder = self.errors_scratch.len() % 4;
-        let chunk_count = (self.errors_scratch.len() - remainder) / 4;
-        self.errors_scratch.fill(0.);
-
-        debug_assert_eq!(self.errors_scratch.len(), chunk_count * 4 + remainder);
-        for output_neuron_ix in 0..gradient_of_output_neurons.len() {
-            let output_weights_for_neuron = if cfg!(debug_assertions) {
-                &output_weights[output_neuron_ix]
-            } else {
-                unsafe { &output_weights.get_unchecked(output_neuron_ix) }
-            };
-            let gradient_of_output_neuron =
-                unsafe { v128_load32_splat(gradient_of_output_neurons.as_ptr().add(output_neuron_ix) as *const _) };
-
-            for chunk_ix in 0..chunk_count {
-                let errors = unsafe { v128_load(self.errors_scratch.as_ptr().add(chunk_ix * 4) as *const _) };
-                let output_weights =
-                    unsafe { v128_load(output_weights_for_neuron.as_ptr().add(chunk_ix * 4) as *const _) };
-
-                unsafe {
-                    v128_store(
-                        self.errors_scratch.as_mut_ptr().add(chunk_ix * 4) as *mut _,
-                        f32x4_add(errors, f32x4_mul(output_weights, gradient_of_output_neuron)),
-                    )
-                }
-            }
-
-            // remainders
-            for neuron_ix in (chunk_count * 4)..self.errors_scratch.len() {
-                unsafe {
-                    if cfg!(debug_assertions) {
-                        self.errors_scratch[neuron_ix] +=
-                            output_weights_for_neuron[neuron_ix] * gradient_of_output_neurons[output_neuron_ix];
-                    } else {
-                        *self.errors_scratch.get_unchecked_mut(neuron_ix) += *output_weights_for_neuron
-                            .get_unchecked(neuron_ix)
-                            * *gradient_of_output_neurons.get_unchecked(output_neuron_ix);
-                    }
-                }
-            }
+        self.errors_scratch.fill(T::ZERO);
+        for (output_neuron_ix, &gradient_of_output_neuron) in gradient_of_output_neurons.iter().enumerate() {
+            // How much the output weights we're connected to contribute to the gradient of the neuron they're
+            // connected to.
+            T::axpy(
+                &mut self.errors_scratch,
+                gradient_of_output_neuron,
+                &output_weights[output_neuron_ix],
+            );
         }
 
         (self.activation_fn).apply_derivative_batch(
@@ -429,178 +428,71 @@ impl DenseLayer {
         );
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
-    pub fn update_weights(&mut self, inputs: &[Weight], learning_rate: Weight) {
-        for (neuron_ix, &neuron_gradient) in self.neuron_gradients.iter().enumerate() {
-            for (weight_ix, weight) in self.weights[neuron_ix].iter_mut().enumerate() {
-                *weight += learning_rate * neuron_gradient * inputs[weight_ix];
-            }
+    /// Applies `learning_rate_multiplier`, and does nothing if the layer is frozen.
+    pub fn update_weights(&mut self, inputs: &[T], learning_rate: T) {
+        if self.frozen {
+            return;
         }
-    }
-
-    #[cfg(target_arch = "wasm32")]
-    pub fn update_weights(&mut self, inputs: &[Weight], learning_rate: Weight) {
-        let input_count = inputs.len();
-        let remainder = input_count % 4;
-        let chunk_count = (input_count - remainder) / 4;
-        let learning_rate_v = f32x4_splat(learning_rate);
-        let inputs_ptr = inputs.as_ptr();
-
+        let learning_rate = self.layer_learning_rate(learning_rate);
         for (neuron_ix, &neuron_gradient) in self.neuron_gradients.iter().enumerate() {
-            let neuron_gradient_v = f32x4_splat(neuron_gradient);
-            let weights_for_neuron = if cfg!(debug_assertions) {
-                &mut self.weights[neuron_ix]
-            } else {
-                unsafe { self.weights.get_unchecked_mut(neuron_ix) }
-            };
-            let weights_for_neuron_ptr = weights_for_neuron.as_ptr();
-
-            for chunk_ix in 0..chunk_count {
-                unsafe {
-                    let weights_v = v128_load(weights_for_neuron_ptr.add(chunk_ix * 4) as *const _);
-                    let inputs_v = v128_load(inputs_ptr.add(chunk_ix * 4) as *const _);
-                    let updated_weights = f32x4_add(
-                        weights_v,
-                        f32x4_mul(learning_rate_v, f32x4_mul(neuron_gradient_v, inputs_v)),
-                    );
-                    v128_store(weights_for_neuron_ptr.add(chunk_ix * 4) as *mut _, updated_weights)
-                }
-            }
-            for weight_ix in (chunk_count * 4)..input_count {
-                let (weight, input) = if cfg!(debug_assertions) {
-                    (&mut weights_for_neuron[weight_ix], inputs[weight_ix])
-                } else {
-                    unsafe {
-                        (
-                            weights_for_neuron.get_unchecked_mut(weight_ix),
-                            *inputs.get_unchecked(weight_ix),
-                        )
-                    }
-                };
-
-                *weight += learning_rate * neuron_gradient * input;
+            T::axpy(&mut self.weights[neuron_ix], learning_rate * neuron_gradient, inputs);
+            if let Some(mask) = &self.weight_mask {
+                prune::apply_mask(&mut self.weights[neuron_ix], &mask[neuron_ix]);
             }
         }
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
-    pub fn update_biases(&mut self, learning_rate: Weight) {
-        for neuron_ix in 0..self.biases.len() {
-            // Each of these biases is added directly to what is fed into our activation function.
-            // The impact that it will have on the output of this neuron is equal to
-            // whatever the derivative of the activation function is.  We want to update the bias to
-            // whatever value minimizes the gradient/error of this neuron.
-            self.biases[neuron_ix] += self.neuron_gradients[neuron_ix] * learning_rate;
+    /// Same as `update_weights`, applies `learning_rate_multiplier` and does nothing if the layer is frozen.
+    pub fn update_biases(&mut self, learning_rate: T) {
+        if self.frozen {
+            return;
         }
+        let learning_rate = self.layer_learning_rate(learning_rate);
+        // Each of these biases is added directly to what is fed into our activation function.
+        // The impact that it will have on the output of this neuron is equal to
+        // whatever the derivative of the activation function is.  We want to update the bias to
+        // whatever value minimizes the gradient/error of this neuron.
+        T::axpy(&mut self.biases, learning_rate, &self.neuron_gradients);
     }
 
-    #[cfg(target_arch = "wasm32")]
-    pub fn update_biases(&mut self, learning_rate: Weight) {
-        let remainder = self.biases.len() % 4;
-        let chunk_count = (self.biases.len() - remainder) / 4;
-        let learning_rate_v = f32x4_splat(learning_rate);
-
-        for chunk_ix in 0..chunk_count {
-            let biases = unsafe { v128_load(self.biases.as_ptr().add(chunk_ix * 4) as *const _) };
-            let gradients = unsafe { v128_load(self.neuron_gradients.as_ptr().add(chunk_ix * 4) as *const _) };
-
-            unsafe {
-                v128_store(
-                    self.biases.as_mut_ptr().add(chunk_ix * 4) as *mut _,
-                    f32x4_add(biases, f32x4_mul(gradients, learning_rate_v)),
-                )
-            }
-        }
-        for remainder_ix in (chunk_count * 4)..self.biases.len() {
-            unsafe {
-                *self.biases.get_unchecked_mut(remainder_ix) +=
-                    *self.neuron_gradients.get_unchecked(remainder_ix) * learning_rate;
-            }
-        }
-    }
-
-    #[cfg(not(target_arch = "wasm32"))]
-    pub fn forward_propagate(&mut self, inputs: &[Weight]) {
-        debug_assert_eq!(self.weights[0].len(), inputs.len());
-        for neuron_ix in 0..self.weights.len() {
-            let mut weight_sum = 0.;
-            for (weight_ix, &weight) in unsafe { self.weights.get_unchecked(neuron_ix) }.iter().enumerate() {
-                let input = inputs[weight_ix];
-                weight_sum += input * weight;
-            }
-
-            unsafe {
-                *self.outputs_before_activation.get_unchecked_mut(neuron_ix) = weight_sum + self.biases[neuron_ix]
-            };
-        }
-
-        (self.activation_fn).apply_batch(&mut self.outputs, &self.outputs_before_activation);
-    }
-
-    #[cfg(target_arch = "wasm32")]
-    pub fn forward_propagate(&mut self, inputs: &[Weight]) {
-        debug_assert_eq!(self.weights[0].len(), inputs.len());
-
-        let input_count = self.weights[0].len();
-        let remainder = input_count % 4;
-        let chunk_count = (input_count - remainder) / 4;
-        let inputs_ptr = inputs.as_ptr();
-
-        for neuron_ix in 0..self.weights.len() {
-            let weights_ptr = unsafe { (*self.weights.get_unchecked_mut(neuron_ix)).as_ptr() };
-            let mut weight_sum_v = f32x4_splat(0.);
-            let mut weight_sum_v_stored: [f32; 4] = [0.; 4];
-
-            unsafe {
-                for chunk_ix in 0..chunk_count {
-                    let inputs = v128_load(inputs_ptr.add(4 * chunk_ix) as *const _);
-                    let weights = v128_load(weights_ptr.add(4 * chunk_ix) as *const _);
-                    weight_sum_v = f32x4_add(weight_sum_v, f32x4_mul(inputs, weights));
-                }
-
-                v128_store((&mut weight_sum_v_stored) as *mut _ as *mut _, weight_sum_v);
-            }
-            let mut weight_sum = 0.;
-            for &partial_sum in weight_sum_v_stored.iter() {
-                weight_sum += partial_sum;
-            }
-            for weight_ix in (chunk_count * 4)..input_count {
-                let input = unsafe { *inputs.get_unchecked(weight_ix) };
-                let weight = unsafe { *self.weights.get_unchecked(neuron_ix).get_unchecked(weight_ix) };
-                weight_sum += input * weight;
-            }
-
-            unsafe {
-                *self.outputs_before_activation.get_unchecked_mut(neuron_ix) =
-                    weight_sum + *self.biases.get_unchecked(neuron_ix);
-            }
+    pub fn forward_propagate(&mut self, inputs: &[T]) {
+        debug_assert_eq!(self.weights.col_count(), inputs.len());
+        for neuron_ix in 0..self.weights.row_count() {
+            self.outputs_before_activation[neuron_ix] =
+                T::dot(&self.weights[neuron_ix], inputs) + self.biases[neuron_ix];
         }
 
         (self.activation_fn).apply_batch(&mut self.outputs, &self.outputs_before_activation);
     }
 }
 
-pub struct OutputLayer {
-    pub weights: Vec<Vec<Weight>>,
-    pub activation_fn: &'static dyn ActivationFunction,
-    pub outputs_before_activation: Vec<Weight>,
-    pub outputs: Vec<Weight>,
-    pub errors: Vec<Weight>,
-    pub costs: Vec<Weight>,
-    pub cost_fn: &'static dyn CostFunction,
-    pub neuron_gradients: Vec<Weight>,
+pub struct OutputLayer<T: Float = Weight> {
+    pub weights: WeightMatrix<T>,
+    pub activation_fn: &'static dyn ActivationFunction<T>,
+    pub outputs_before_activation: Vec<T>,
+    pub outputs: Vec<T>,
+    pub errors: Vec<T>,
+    pub costs: Vec<T>,
+    pub cost_fn: &'static dyn CostFunction<T>,
+    pub neuron_gradients: Vec<T>,
+    /// Same as `DenseLayer::weight_mask`.
+    pub weight_mask: Option<WeightMatrix<T>>,
+    /// Same as `DenseLayer::learning_rate_multiplier`.
+    pub learning_rate_multiplier: T,
+    /// Same as `DenseLayer::frozen`.  Errors are still passed back to the hidden layers.
+    pub frozen: bool,
 }
 
-impl OutputLayer {
+impl<T: Float> OutputLayer<T> {
     pub fn new(
-        activation_fn: &'static dyn ActivationFunction,
-        cost_fn: &'static dyn CostFunction,
-        init_weights: &mut impl FnMut(usize, usize) -> Weight,
+        activation_fn: &'static dyn ActivationFunction<T>,
+        cost_fn: &'static dyn CostFunction<T>,
+        init_weights: &mut impl FnMut(usize, usize) -> T,
         input_count: usize,
         neuron_count: usize,
     ) -> Self {
-        let mut weights: Vec<Vec<Weight>> = vec![vec![0.; input_count]; neuron_count];
-        for i in 0..weights.len() {
+        let mut weights = WeightMatrix::new(neuron_count, input_count);
+        for i in 0..neuron_count {
             let neuron_weights = &mut weights[i];
             for j in 0..neuron_weights.len() {
                 neuron_weights[j] = init_weights(i, j);
@@ -610,28 +502,36 @@ impl OutputLayer {
         OutputLayer {
             weights,
             activation_fn,
-            outputs_before_activation: vec![0.; neuron_count],
-            outputs: vec![0.; neuron_count],
-            errors: vec![0.; neuron_count],
-            costs: vec![0.; neuron_count],
+            outputs_before_activation: vec![T::ZERO; neuron_count],
+            outputs: vec![T::ZERO; neuron_count],
+            errors: vec![T::ZERO; neuron_count],
+            costs: vec![T::ZERO; neuron_count],
             cost_fn,
-            neuron_gradients: vec![0.; neuron_count],
+            neuron_gradients: vec![T::ZERO; neuron_count],
+            weight_mask: None,
+            learning_rate_multiplier: T::ONE,
+            frozen: false,
+        }
+    }
+
+    /// Same as `DenseLayer::layer_learning_rate`.
+    pub fn layer_learning_rate(&self, learning_rate: T) -> T { learning_rate * self.learning_rate_multiplier }
+
+    /// Returns an error unless the layer uses both `Softmax` and `CrossEntropy` or neither of them, since their
+    /// derivatives are only correct together.
+    pub fn validate_activation(&self, layer_ix: usize) -> Result<(), NnError> {
+        if self.activation_fn.is_softmax() != self.cost_fn.includes_softmax_derivative() {
+            return Err(NnError::UnpairedSoftmax { layer_ix });
         }
+        Ok(())
     }
 
     /// Fills `self.outputs` with output values given the outputs from the previous layer in
     /// `inputs`.
-    pub fn compute(&mut self, inputs: &[Weight]) {
+    pub fn compute(&mut self, inputs: &[T]) {
         for neuron_ix in 0..self.outputs.len() {
-            let mut sum: Weight = 0.;
-            let weights = &self.weights[neuron_ix];
-            debug_assert_eq!(inputs.len(), weights.len());
-            for (&weight, &input) in weights.iter().zip(inputs.iter()) {
-                sum += weight * input;
-            }
-
             // No bias on the output layer.
-            self.outputs_before_activation[neuron_ix] = sum;
+            self.outputs_before_activation[neuron_ix] = T::dot(&self.weights[neuron_ix], inputs);
         }
 
         (self.activation_fn).apply_batch(&mut self.outputs, &self.outputs_before_activation);
@@ -639,7 +539,7 @@ impl OutputLayer {
 
     /// Once `compute()` has been called, calculates the cost using the error for each output value
     /// and populates `self.costs.
-    pub fn compute_costs(&mut self, expected: &[Weight]) {
+    pub fn compute_costs(&mut self, expected: &[T]) {
         debug_assert_eq!(expected.len(), self.outputs.len());
         // Assumes that outputs have already been computed.
         for (i, &output) in self.outputs.iter().enumerate() {
@@ -649,7 +549,7 @@ impl OutputLayer {
         }
     }
 
-    pub fn compute_neuron_gradient(&self, neuron_output_before_activation: Weight, neuron_error: Weight) -> Weight {
+    pub fn compute_neuron_gradient(&self, neuron_output_before_activation: T, neuron_error: T) -> T {
         (self.cost_fn).derivative(neuron_error) * (self.activation_fn).derivative(neuron_output_before_activation)
     }
 
@@ -665,38 +565,176 @@ impl OutputLayer {
         }
     }
 
-    pub fn update_weights(&mut self, inputs: &[Weight], learning_rate: Weight) {
+    /// Same as `DenseLayer::update_weights`.
+    pub fn update_weights(&mut self, inputs: &[T], learning_rate: T) {
+        if self.frozen {
+            return;
+        }
+        let learning_rate = self.layer_learning_rate(learning_rate);
         for (neuron_ix, &neuron_gradient) in self.neuron_gradients.iter().enumerate() {
-            for (weight_ix, weight) in self.weights[neuron_ix].iter_mut().enumerate() {
-                *weight += learning_rate * neuron_gradient * inputs[weight_ix];
+            T::axpy(&mut self.weights[neuron_ix], learning_rate * neuron_gradient, inputs);
+            if let Some(mask) = &self.weight_mask {
+                prune::apply_mask(&mut self.weights[neuron_ix], &mask[neuron_ix]);
             }
         }
     }
 
-    pub fn forward_propagate(&mut self, inputs: &[Weight]) {
-        debug_assert_eq!(self.weights[0].len(), inputs.len());
-        for neuron_ix in 0..self.weights.len() {
-            let mut weight_sum = 0.;
-            for (weight_ix, &weight) in self.weights[neuron_ix].iter().enumerate() {
-                let input = inputs[weight_ix];
-                weight_sum += input * weight;
-            }
-            self.outputs_before_activation[neuron_ix] = weight_sum;
+    /// Adds `learning_rate` times `weight_gradients` to the weights, for trainers that accumulate gradients before
+    /// applying them.  Like `update_weights`, applies `learning_rate_multiplier` and does nothing if the layer is
+    /// frozen.  Doesn't apply `weight_mask`.
+    pub fn apply_weight_gradients(&mut self, weight_gradients: &WeightMatrix<T>, learning_rate: T) {
+        if self.frozen {
+            return;
+        }
+        let learning_rate = self.layer_learning_rate(learning_rate);
+        T::axpy(self.weights.as_mut_slice(), learning_rate, weight_gradients.as_slice());
+    }
+
+    pub fn forward_propagate(&mut self, inputs: &[T]) {
+        debug_assert_eq!(self.weights.col_count(), inputs.len());
+        for neuron_ix in 0..self.weights.row_count() {
+            self.outputs_before_activation[neuron_ix] = T::dot(&self.weights[neuron_ix], inputs);
         }
 
         (self.activation_fn).apply_batch(&mut self.outputs, &self.outputs_before_activation);
     }
 }
 
-pub struct Network {
-    pub hidden_layers: Vec<DenseLayer>,
-    pub outputs: Box<OutputLayer>,
-    pub learning_rate: Weight,
+pub struct Network<T: Float = Weight> {
+    /// Convolution, pooling, flatten and embedding layers that inputs are run through before the dense layers.
+    /// Usually empty.
+    pub feature_layers: Vec<FeatureLayer<T>>,
+    pub hidden_layers: Vec<DenseLayer<T>>,
+    pub outputs: Box<OutputLayer<T>>,
+    pub learning_rate: T,
+}
+
+/// A copy of all of the trainable parameters of a `Network`, used to restore it to an earlier state.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkSnapshot<T: Float = Weight> {
+    /// Empty for feature layers that don't have any weights.
+    pub feature_layer_weights: Vec<WeightMatrix<T>>,
+    pub feature_layer_biases: Vec<Vec<T>>,
+    pub hidden_layer_weights: Vec<WeightMatrix<T>>,
+    pub hidden_layer_biases: Vec<Vec<T>>,
+    pub output_weights: WeightMatrix<T>,
 }
 
-impl Network {
-    pub fn forward_propagate(&mut self, inputs: &[Weight]) {
-        let mut inputs: &[Weight] = inputs;
+fn all_finite<T: Float>(vals: &[T]) -> bool { vals.iter().all(|val| val.is_finite()) }
+
+impl<T: Float> Network<T> {
+    /// Returns an error if the layers can't be trained together; see `validate_layers`.  Doesn't check that the sizes
+    /// of the layers line up.
+    pub fn new(
+        feature_layers: Vec<FeatureLayer<T>>,
+        hidden_layers: Vec<DenseLayer<T>>,
+        outputs: Box<OutputLayer<T>>,
+        learning_rate: T,
+    ) -> Result<Self, NnError> {
+        let network = Network {
+            feature_layers,
+            hidden_layers,
+            outputs,
+            learning_rate,
+        };
+        network.validate_layers()?;
+        Ok(network)
+    }
+
+    /// Checks that `Softmax` is only used on the output layer, together with `CrossEntropy`, and that an
+    /// `EmbeddingLayer` is only used as the first feature layer.
+    pub fn validate_layers(&self) -> Result<(), NnError> {
+        for (layer_ix, layer) in self.feature_layers.iter().enumerate().skip(1) {
+            if let FeatureLayer::Embedding(_) = layer {
+                return Err(NnError::MisplacedEmbedding { layer_ix });
+            }
+        }
+        for (layer_ix, layer) in self.hidden_layers.iter().enumerate() {
+            validate_hidden_activation(layer.activation_fn, layer_ix)?;
+        }
+        self.outputs.validate_activation(self.hidden_layers.len())
+    }
+
+    /// The number of values in each example fed into the network.
+    pub fn input_count(&self) -> usize {
+        if let Some(layer) = self.feature_layers.first() {
+            return layer.input_shape().len();
+        }
+        match self.hidden_layers.first() {
+            Some(layer) => layer.weights.col_count(),
+            None => self.outputs.weights.col_count(),
+        }
+    }
+
+    pub fn output_count(&self) -> usize { self.outputs.outputs.len() }
+
+    pub fn validate_inputs(&self, inputs: &[T]) -> Result<(), NnError> {
+        let expected = self.input_count();
+        if inputs.len() != expected {
+            return Err(NnError::InvalidInputLength {
+                expected,
+                actual: inputs.len(),
+            });
+        }
+        if let Some(FeatureLayer::Embedding(layer)) = self.feature_layers.first() {
+            layer.validate_inputs(inputs)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate_example(&self, example: &[T], expected: &[T]) -> Result<(), NnError> {
+        self.validate_inputs(example)?;
+        if expected.len() != self.output_count() {
+            return Err(NnError::InvalidTargetLength {
+                expected: self.output_count(),
+                actual: expected.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the number of examples packed back-to-back in `examples`.
+    pub fn count_inputs(&self, examples: &[T]) -> Result<usize, NnError> {
+        let input_dims = self.input_count();
+        let example_count = examples.len().checked_div(input_dims).unwrap_or(0);
+        if input_dims == 0 || example_count * input_dims != examples.len() {
+            return Err(NnError::InvalidBatchLength {
+                example_len: input_dims,
+                buffer_len: examples.len(),
+            });
+        }
+        Ok(example_count)
+    }
+
+    /// Returns the number of examples packed back-to-back in `examples` after checking that `expected` holds the
+    /// same number of expected outputs.
+    pub fn count_examples(&self, examples: &[T], expected: &[T]) -> Result<usize, NnError> {
+        let output_dims = self.output_count();
+        let example_count = self.count_inputs(examples)?;
+        if expected.len() != output_dims * example_count {
+            return Err(NnError::InvalidTargetLength {
+                expected: output_dims * example_count,
+                actual: expected.len(),
+            });
+        }
+        Ok(example_count)
+    }
+
+    /// Panics if `inputs` doesn't match the size of the network.  The layers themselves only check dimensions in
+    /// debug builds, so this keeps bad input from reaching their unchecked fast paths.
+    pub fn forward_propagate(&mut self, inputs: &[T]) {
+        if let Err(err) = self.validate_inputs(inputs) {
+            panic!("{}", err);
+        }
+        self.forward_propagate_unchecked(inputs);
+    }
+
+    fn forward_propagate_unchecked(&mut self, inputs: &[T]) {
+        let mut inputs: &[T] = inputs;
+        for layer in &mut self.feature_layers {
+            layer.forward_propagate(inputs);
+            inputs = layer.outputs();
+        }
         for layer in &mut self.hidden_layers {
             layer.forward_propagate(inputs);
             inputs = &layer.outputs;
@@ -707,22 +745,33 @@ impl Network {
 
     /// Returns the average cost of the output before updating weights.  It would be better to compute again after, but
     /// that would be too expensive
-    pub fn train_one_example(&mut self, example: &[Weight], expected: &[Weight], learning_rate: Weight) -> Weight {
+    ///
+    /// Panics if the lengths of `example` or `expected` don't match the size of the network; use
+    /// `try_train_one_example` to get an error instead.
+    pub fn train_one_example(&mut self, example: &[T], expected: &[T], learning_rate: T) -> T {
+        if let Err(err) = self.validate_example(example, expected) {
+            panic!("{}", err);
+        }
+        self.train_one_example_unchecked(example, expected, learning_rate)
+    }
+
+    fn train_one_example_unchecked(&mut self, example: &[T], expected: &[T], learning_rate: T) -> T {
         // Run the example all the way through the network, populating outputs in the output layer.
-        self.forward_propagate(example);
+        self.forward_propagate_unchecked(example);
 
         // Compute gradients + costs for the output layer based off the generated outputs
         self.outputs.compute_costs(expected);
         self.outputs.compute_gradients();
 
         // Then compute gradients for the hidden layers
-        let mut output_weights = self.outputs.weights.as_slice();
+        let mut output_weights = &self.outputs.weights;
         let mut gradient_of_output_neurons = self.outputs.neuron_gradients.as_slice();
         for hidden_layer in self.hidden_layers.iter_mut().rev() {
             hidden_layer.compute_gradients(output_weights, gradient_of_output_neurons);
-            output_weights = hidden_layer.weights.as_slice();
+            output_weights = &hidden_layer.weights;
             gradient_of_output_neurons = &hidden_layer.neuron_gradients.as_slice();
         }
+        self.backpropagate_feature_layers(example);
 
         // Using the gradients computed before, update weights on the output layer
         let inputs = self.hidden_layers.last().unwrap().outputs.as_slice();
@@ -731,7 +780,7 @@ impl Network {
         // then update weights + biases for all hidden layers
         for hidden_layer_ix in (0..self.hidden_layers.len()).rev() {
             let inputs = if hidden_layer_ix == 0 {
-                example
+                self.feature_layers.last().map_or(example, |layer| layer.outputs())
             } else {
                 // I don't care about lifetimes here, we only mutate the output
                 let slice = self.hidden_layers[hidden_layer_ix - 1].outputs.as_slice();
@@ -741,10 +790,11 @@ impl Network {
             hidden_layer.update_weights(inputs, self.learning_rate);
             hidden_layer.update_biases(learning_rate);
         }
+        self.update_feature_layers(example, learning_rate);
 
         // That's it, we've successfully "learned"
-        let total_cost = self.outputs.costs.iter().fold(0., |acc, cost| acc + *cost);
-        total_cost / self.outputs.costs.len() as Weight
+        let total_cost = self.outputs.costs.iter().fold(T::ZERO, |acc, cost| acc + *cost);
+        total_cost / T::from_f64(self.outputs.costs.len() as f64)
     }
 
     // pub fn train_batch(
@@ -816,8 +866,126 @@ impl Network {
     //     total_cost / self.outputs.costs.len() as Weight
     // }
 
-    pub fn compute<'a>(&'a mut self, inputs: &[Weight]) -> &'a [Weight] {
+    /// Panics if `inputs` doesn't match the size of the network; use `try_compute` to get an error instead.  Use
+    /// `predict` to run inference without modifying the network.
+    pub fn compute<'a>(&'a mut self, inputs: &[T]) -> &'a [T] {
         self.forward_propagate(inputs);
         &self.outputs.outputs
     }
+
+    pub fn try_compute<'a>(&'a mut self, inputs: &[T]) -> Result<&'a [T], NnError> {
+        self.validate_inputs(inputs)?;
+        self.forward_propagate_unchecked(inputs);
+        Ok(&self.outputs.outputs)
+    }
+
+    /// Same as `train_one_example`, but returns an error if the example doesn't match the size of the network or if
+    /// the cost or any of the updated weights or biases are NaN or infinite.  The weights are left as-is when training
+    /// diverges; use `try_train_one_example_with_rollback` to undo the diverged step instead.
+    pub fn try_train_one_example(&mut self, example: &[T], expected: &[T], learning_rate: T) -> Result<T, NnError> {
+        self.validate_example(example, expected)?;
+        let cost = self.train_one_example_unchecked(example, expected, learning_rate);
+        if !cost.is_finite() {
+            return Err(NnError::NonFiniteCost {
+                cost: cost.to_f64() as Weight,
+            });
+        }
+        self.check_weights_finite()?;
+
+        Ok(cost)
+    }
+
+    /// Same as `try_train_one_example`, but restores all weights and biases to what they were before the step if it
+    /// diverged.  `scratch` is used to hold the pre-step parameters so that it can be re-used between calls without
+    /// re-allocating.
+    pub fn try_train_one_example_with_rollback(
+        &mut self,
+        example: &[T],
+        expected: &[T],
+        learning_rate: T,
+        scratch: &mut NetworkSnapshot<T>,
+    ) -> Result<T, NnError> {
+        self.validate_example(example, expected)?;
+        self.snapshot_into(scratch);
+        let res = self.try_train_one_example(example, expected, learning_rate);
+        if res.is_err() {
+            self.restore(scratch);
+        }
+        res
+    }
+
+    /// Returns an error identifying the first layer that has a NaN or infinite weight or bias.
+    pub fn check_weights_finite(&self) -> Result<(), NnError> {
+        for (layer_ix, layer) in self.feature_layers.iter().enumerate() {
+            if let Some((weights, biases)) = layer.weights_and_biases() {
+                if !all_finite(weights.as_slice()) || !all_finite(biases) {
+                    return Err(NnError::NonFiniteFeatureLayerWeights { layer_ix });
+                }
+            }
+        }
+        for (layer_ix, layer) in self.hidden_layers.iter().enumerate() {
+            if !all_finite(layer.weights.as_slice()) || !all_finite(&layer.biases) {
+                return Err(NnError::NonFiniteWeights { layer_ix });
+            }
+        }
+        if !all_finite(self.outputs.weights.as_slice()) {
+            return Err(NnError::NonFiniteWeights {
+                layer_ix: self.hidden_layers.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> NetworkSnapshot<T> {
+        let mut snapshot = NetworkSnapshot::default();
+        self.snapshot_into(&mut snapshot);
+        snapshot
+    }
+
+    /// Copies all weights and biases into `snapshot`, re-using its existing allocations where possible.
+    pub fn snapshot_into(&self, snapshot: &mut NetworkSnapshot<T>) {
+        snapshot
+            .feature_layer_weights
+            .resize_with(self.feature_layers.len(), WeightMatrix::default);
+        snapshot
+            .feature_layer_biases
+            .resize_with(self.feature_layers.len(), Vec::new);
+        for (layer_ix, layer) in self.feature_layers.iter().enumerate() {
+            if let Some((weights, biases)) = layer.weights_and_biases() {
+                snapshot.feature_layer_weights[layer_ix].clone_from(weights);
+                snapshot.feature_layer_biases[layer_ix].clear();
+                snapshot.feature_layer_biases[layer_ix].extend_from_slice(biases);
+            }
+        }
+        snapshot
+            .hidden_layer_weights
+            .resize_with(self.hidden_layers.len(), WeightMatrix::default);
+        snapshot
+            .hidden_layer_biases
+            .resize_with(self.hidden_layers.len(), Vec::new);
+        for (layer_ix, layer) in self.hidden_layers.iter().enumerate() {
+            snapshot.hidden_layer_weights[layer_ix].clone_from(&layer.weights);
+            snapshot.hidden_layer_biases[layer_ix].clone_from(&layer.biases);
+        }
+        snapshot.output_weights.clone_from(&self.outputs.weights);
+    }
+
+    /// Overwrites all weights and biases with the ones stored in `snapshot`, which must have been taken from a
+    /// network with the same shape.
+    pub fn restore(&mut self, snapshot: &NetworkSnapshot<T>) {
+        assert_eq!(snapshot.feature_layer_weights.len(), self.feature_layers.len());
+        assert_eq!(snapshot.hidden_layer_weights.len(), self.hidden_layers.len());
+        for (layer_ix, layer) in self.feature_layers.iter_mut().enumerate() {
+            if let Some((weights, biases)) = layer.weights_and_biases_mut() {
+                weights.clone_from(&snapshot.feature_layer_weights[layer_ix]);
+                biases.copy_from_slice(&snapshot.feature_layer_biases[layer_ix]);
+            }
+        }
+        for (layer_ix, layer) in self.hidden_layers.iter_mut().enumerate() {
+            layer.weights.clone_from(&snapshot.hidden_layer_weights[layer_ix]);
+            layer.biases.clone_from(&snapshot.hidden_layer_biases[layer_ix]);
+        }
+        self.outputs.weights.clone_from(&snapshot.output_weights);
+    }
 }
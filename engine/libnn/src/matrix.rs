@@ -0,0 +1,153 @@
+use std::{
+    fmt,
+    ops::{Index, IndexMut},
+};
+
+use crate::{Float, Weight};
+
+/// Four weights aligned to 16 bytes so that every row of a `WeightMatrix` can be loaded straight into SIMD registers.
+#[derive(Clone, Copy, Default, PartialEq)]
+#[repr(C, align(16))]
+struct Chunk<T>([T; 4]);
+
+/// The weights for a layer stored in a single contiguous row-major buffer, one row per neuron and one column per
+/// input.  Rows are padded with zeros up to `stride()` values so that each one starts on a 16-byte boundary.
+///
+/// Also used to hold values for a batch of examples, with one row per example.
+///
+/// Indexing with a row index returns that row's weights without the padding, so `weights[neuron_ix][input_ix]` works
+/// the same as it would for a `Vec<Vec<Weight>>`.
+#[derive(Default, PartialEq)]
+pub struct WeightMatrix<T: Float = Weight> {
+    row_count: usize,
+    col_count: usize,
+    data: Vec<Chunk<T>>,
+}
+
+impl<T: Float> WeightMatrix<T> {
+    /// Creates a matrix with all weights set to zero.
+    pub fn new(row_count: usize, col_count: usize) -> Self {
+        WeightMatrix {
+            row_count,
+            col_count,
+            data: vec![Chunk::default(); row_count * Self::stride_for(col_count) / 4],
+        }
+    }
+
+    pub fn from_fn(row_count: usize, col_count: usize, mut f: impl FnMut(usize, usize) -> T) -> Self {
+        let mut matrix = Self::new(row_count, col_count);
+        for row_ix in 0..row_count {
+            for (col_ix, weight) in matrix.row_mut(row_ix).iter_mut().enumerate() {
+                *weight = f(row_ix, col_ix);
+            }
+        }
+        matrix
+    }
+
+    /// Builds a matrix out of a list of rows, which must all have the same length.
+    pub fn from_rows<R: AsRef<[T]>>(rows: &[R]) -> Self {
+        let col_count = rows.first().map(|row| row.as_ref().len()).unwrap_or(0);
+        let mut matrix = Self::new(rows.len(), col_count);
+        for (row_ix, row) in rows.iter().enumerate() {
+            assert_eq!(row.as_ref().len(), col_count, "all rows must have the same length");
+            matrix.row_mut(row_ix).copy_from_slice(row.as_ref());
+        }
+        matrix
+    }
+
+    /// Changes the shape of the matrix and sets all of its values to zero, re-using the existing allocation if it's
+    /// big enough.
+    pub fn resize(&mut self, row_count: usize, col_count: usize) {
+        self.row_count = row_count;
+        self.col_count = col_count;
+        self.data.clear();
+        self.data
+            .resize(row_count * Self::stride_for(col_count) / 4, Chunk::default());
+    }
+
+    fn stride_for(col_count: usize) -> usize { col_count.div_ceil(4) * 4 }
+
+    pub fn row_count(&self) -> usize { self.row_count }
+
+    pub fn col_count(&self) -> usize { self.col_count }
+
+    /// The distance between the starts of two consecutive rows in the underlying buffer.  Always a multiple of 4.
+    pub fn stride(&self) -> usize { Self::stride_for(self.col_count) }
+
+    /// The whole underlying buffer, including the zero padding at the end of each row
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr() as *const T, self.data.len() * 4) }
+    }
+
+    /// The whole underlying buffer, including the zero padding at the end of each row.  The padding must be left as
+    /// zeros.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut T, self.data.len() * 4) }
+    }
+
+    /// Returns a pointer to the start of the row at `row_ix`, which is aligned to 16 bytes.
+    pub fn row_ptr(&self, row_ix: usize) -> *const T {
+        debug_assert!(row_ix < self.row_count);
+        unsafe { (self.data.as_ptr() as *const T).add(row_ix * self.stride()) }
+    }
+
+    pub fn row(&self, row_ix: usize) -> &[T] {
+        let start = row_ix * self.stride();
+        &self.as_slice()[start..start + self.col_count]
+    }
+
+    /// The row at `row_ix` including its zero padding, which makes it `stride()` values long.
+    pub fn padded_row(&self, row_ix: usize) -> &[T] {
+        let start = row_ix * self.stride();
+        &self.as_slice()[start..start + self.stride()]
+    }
+
+    pub fn row_mut(&mut self, row_ix: usize) -> &mut [T] {
+        let start = row_ix * self.stride();
+        let col_count = self.col_count;
+        &mut self.as_mut_slice()[start..start + col_count]
+    }
+
+    pub fn iter_rows(&self) -> impl Iterator<Item = &[T]> + '_ { (0..self.row_count).map(move |ix| self.row(ix)) }
+
+    /// Iterates over the weight at `col_ix` in every row; for a layer, that's the weight each neuron applies to one of
+    /// its inputs.
+    pub fn column(&self, col_ix: usize) -> impl Iterator<Item = T> + '_ {
+        assert!(col_ix < self.col_count, "column index out of range");
+        let stride = self.stride();
+        self.as_slice().iter().skip(col_ix).step_by(stride).copied()
+    }
+
+    /// Copies the weights out into one `Vec` per row.
+    pub fn to_rows(&self) -> Vec<Vec<T>> { self.iter_rows().map(<[T]>::to_vec).collect() }
+}
+
+impl<T: Float> Clone for WeightMatrix<T> {
+    fn clone(&self) -> Self {
+        WeightMatrix {
+            row_count: self.row_count,
+            col_count: self.col_count,
+            data: self.data.clone(),
+        }
+    }
+
+    fn clone_from(&mut self, source: &Self) {
+        self.row_count = source.row_count;
+        self.col_count = source.col_count;
+        self.data.clone_from(&source.data);
+    }
+}
+
+impl<T: Float> Index<usize> for WeightMatrix<T> {
+    type Output = [T];
+
+    fn index(&self, row_ix: usize) -> &[T] { self.row(row_ix) }
+}
+
+impl<T: Float> IndexMut<usize> for WeightMatrix<T> {
+    fn index_mut(&mut self, row_ix: usize) -> &mut [T] { self.row_mut(row_ix) }
+}
+
+impl<T: Float> fmt::Debug for WeightMatrix<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.debug_list().entries(self.iter_rows()).finish() }
+}
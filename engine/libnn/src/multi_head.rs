@@ -0,0 +1,189 @@
+//! Multi-task networks: several output heads sharing one stack of hidden layers.  Each head is an `OutputLayer` with
+//! its own activation and cost function, so a network can e.g. have a regression head trained with mean squared error
+//! next to a classification head using softmax and cross-entropy.
+//!
+//! The network is trained on the weighted sum of the heads' costs.  Each head's gradients are scaled by its loss
+//! weight, and the errors that the heads pass back to the last hidden layer are summed across all of them.
+
+use crate::{average_cost, validate_hidden_activation, DenseLayer, Float, NnError, OutputLayer, Weight};
+
+pub struct OutputHead<T: Float = Weight> {
+    pub layer: OutputLayer<T>,
+    /// How much this head's cost counts towards the cost the network is trained on.  A weight of zero leaves the head
+    /// and its effect on the hidden layers untrained.
+    pub loss_weight: T,
+}
+
+impl<T: Float> OutputHead<T> {
+    pub fn new(layer: OutputLayer<T>, loss_weight: T) -> Self { OutputHead { layer, loss_weight } }
+
+    pub fn output_count(&self) -> usize { self.layer.outputs.len() }
+}
+
+pub struct MultiHeadNetwork<T: Float = Weight> {
+    pub hidden_layers: Vec<DenseLayer<T>>,
+    pub heads: Vec<OutputHead<T>>,
+    /// Errors passed back to the last hidden layer, summed over every head.
+    trunk_errors: Vec<T>,
+    head_costs: Vec<T>,
+}
+
+impl<T: Float> MultiHeadNetwork<T> {
+    /// Panics if there aren't any heads or the sizes of the layers don't line up.  Returns an error if `Softmax` is
+    /// used anywhere other than on a head using `CrossEntropy`; heads are numbered after the hidden layers.
+    pub fn new(hidden_layers: Vec<DenseLayer<T>>, heads: Vec<OutputHead<T>>) -> Result<Self, NnError> {
+        assert!(!heads.is_empty(), "need at least one output head");
+        for pair in hidden_layers.windows(2) {
+            assert_eq!(
+                pair[0].weights.row_count(),
+                pair[1].weights.col_count(),
+                "layer with {} neurons feeds into one with {} inputs",
+                pair[0].weights.row_count(),
+                pair[1].weights.col_count()
+            );
+        }
+        let trunk_output_count = match hidden_layers.last() {
+            Some(layer) => layer.weights.row_count(),
+            None => heads[0].layer.weights.col_count(),
+        };
+        for head in &heads {
+            assert_eq!(
+                head.layer.weights.col_count(),
+                trunk_output_count,
+                "head with {} inputs is fed by {} values",
+                head.layer.weights.col_count(),
+                trunk_output_count
+            );
+        }
+
+        for (layer_ix, layer) in hidden_layers.iter().enumerate() {
+            validate_hidden_activation(layer.activation_fn, layer_ix)?;
+        }
+        for (head_ix, head) in heads.iter().enumerate() {
+            head.layer.validate_activation(hidden_layers.len() + head_ix)?;
+        }
+
+        Ok(MultiHeadNetwork {
+            hidden_layers,
+            trunk_errors: vec![T::ZERO; trunk_output_count],
+            head_costs: vec![T::ZERO; heads.len()],
+            heads,
+        })
+    }
+
+    pub fn input_count(&self) -> usize {
+        match self.hidden_layers.first() {
+            Some(layer) => layer.weights.col_count(),
+            None => self.heads[0].layer.weights.col_count(),
+        }
+    }
+
+    pub fn validate_inputs(&self, inputs: &[T]) -> Result<(), NnError> {
+        if inputs.len() != self.input_count() {
+            return Err(NnError::InvalidInputLength {
+                expected: self.input_count(),
+                actual: inputs.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// `expected` holds the expected outputs of each head.
+    pub fn validate_example<R: AsRef<[T]>>(&self, example: &[T], expected: &[R]) -> Result<(), NnError> {
+        self.validate_inputs(example)?;
+        if expected.len() != self.heads.len() {
+            return Err(NnError::InvalidHeadCount {
+                expected: self.heads.len(),
+                actual: expected.len(),
+            });
+        }
+        for (head, expected) in self.heads.iter().zip(expected) {
+            if expected.as_ref().len() != head.output_count() {
+                return Err(NnError::InvalidTargetLength {
+                    expected: head.output_count(),
+                    actual: expected.as_ref().len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn forward_propagate(&mut self, inputs: &[T]) {
+        let mut inputs = inputs;
+        for layer in &mut self.hidden_layers {
+            layer.forward_propagate(inputs);
+            inputs = &layer.outputs;
+        }
+        for head in &mut self.heads {
+            head.layer.forward_propagate(inputs);
+        }
+    }
+
+    /// Returns the outputs of each head.
+    pub fn compute(&mut self, inputs: &[T]) -> Result<Vec<&[T]>, NnError> {
+        self.validate_inputs(inputs)?;
+        self.forward_propagate(inputs);
+        Ok(self.heads.iter().map(|head| head.layer.outputs.as_slice()).collect())
+    }
+
+    /// Trains on one example, where `expected` holds the expected outputs of each head.  Returns the average cost of
+    /// each head's outputs before updating weights, without the loss weights applied.
+    pub fn train_one_example<R: AsRef<[T]>>(
+        &mut self,
+        example: &[T],
+        expected: &[R],
+        learning_rate: T,
+    ) -> Result<&[T], NnError> {
+        self.validate_example(example, expected)?;
+        self.forward_propagate(example);
+
+        self.trunk_errors.fill(T::ZERO);
+        for (head_ix, (head, expected)) in self.heads.iter_mut().zip(expected).enumerate() {
+            let layer = &mut head.layer;
+            layer.compute_costs(expected.as_ref());
+            layer.compute_gradients();
+            for neuron_gradient in &mut layer.neuron_gradients {
+                *neuron_gradient *= head.loss_weight;
+            }
+            for (neuron_ix, &neuron_gradient) in layer.neuron_gradients.iter().enumerate() {
+                T::axpy(&mut self.trunk_errors, neuron_gradient, &layer.weights[neuron_ix]);
+            }
+
+            let total_cost = layer.costs.iter().fold(T::ZERO, |acc, &cost| acc + cost);
+            self.head_costs[head_ix] = average_cost(total_cost, layer.costs.len())?;
+        }
+
+        // The last hidden layer gets the errors summed over every head, and the rest get them from the layer after
+        if let Some((last_layer, layers)) = self.hidden_layers.split_last_mut() {
+            last_layer.activation_fn.apply_derivative_batch(
+                &mut last_layer.neuron_gradients,
+                &self.trunk_errors,
+                &last_layer.outputs_before_activation,
+            );
+            let mut output_weights = &last_layer.weights;
+            let mut gradient_of_output_neurons = last_layer.neuron_gradients.as_slice();
+            for layer in layers.iter_mut().rev() {
+                layer.compute_gradients(output_weights, gradient_of_output_neurons);
+                output_weights = &layer.weights;
+                gradient_of_output_neurons = &layer.neuron_gradients;
+            }
+        }
+
+        let trunk_outputs = self
+            .hidden_layers
+            .last()
+            .map_or(example, |layer| layer.outputs.as_slice());
+        for head in &mut self.heads {
+            head.layer.update_weights(trunk_outputs, learning_rate);
+        }
+        for layer_ix in (0..self.hidden_layers.len()).rev() {
+            let (earlier_layers, later_layers) = self.hidden_layers.split_at_mut(layer_ix);
+            let inputs = earlier_layers.last().map_or(example, |layer| layer.outputs.as_slice());
+            let layer = &mut later_layers[0];
+            layer.update_weights(inputs, learning_rate);
+            layer.update_biases(learning_rate);
+        }
+
+        Ok(&self.head_costs)
+    }
+}
@@ -0,0 +1,231 @@
+//! Magnitude pruning.  The weights with the smallest absolute values are removed from the network by setting them to
+//! zero and masking them out so that training can't bring them back.  Pruning a little at a time and re-training in
+//! between, optionally rewinding the surviving weights to their initial values each time, is the procedure used to
+//! find "winning tickets" in lottery ticket experiments.
+
+use std::cmp::Ordering;
+
+use rand::Rng;
+
+use crate::{Dataset, Float, Network, NetworkSnapshot, NnError, Weight, WeightMatrix};
+
+/// Which weights the smallest ones are picked from when pruning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruningScope {
+    /// Ranks the weights of all layers together, so layers with many small weights end up sparser than the rest.
+    Global,
+    /// Prunes the same fraction of each layer's weights.
+    PerLayer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerSparsity {
+    pub pruned_count: usize,
+    pub weight_count: usize,
+}
+
+impl LayerSparsity {
+    /// The fraction of the layer's weights that have been pruned.
+    pub fn sparsity(&self) -> f32 {
+        if self.weight_count == 0 {
+            return 0.;
+        }
+        self.pruned_count as f32 / self.weight_count as f32
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PruningSchedule {
+    /// How many times to prune.  The network is trained once before the first round and once after each one.
+    pub rounds: usize,
+    /// The fraction of the weights that should be pruned once all rounds are done.
+    pub target_sparsity: f32,
+    pub epochs_per_round: usize,
+    pub scope: PruningScope,
+    /// Whether to reset the surviving weights to the values they had before any training after each round.
+    pub rewind: bool,
+}
+
+impl Default for PruningSchedule {
+    fn default() -> Self {
+        PruningSchedule {
+            rounds: 5,
+            target_sparsity: 0.8,
+            epochs_per_round: 1,
+            scope: PruningScope::Global,
+            rewind: true,
+        }
+    }
+}
+
+impl PruningSchedule {
+    /// The fraction of the remaining weights to prune each round so that `target_sparsity` is reached after the last
+    /// one.
+    pub fn fraction_per_round(&self) -> f32 {
+        if self.rounds == 0 {
+            return 0.;
+        }
+        1. - (1. - self.target_sparsity).powf(1. / self.rounds as f32)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PruningRound {
+    /// The sparsity of each layer while this round was being trained, with the output layer last.
+    pub sparsity: Vec<LayerSparsity>,
+    /// The average training cost of each epoch in this round.
+    pub epoch_costs: Vec<Weight>,
+}
+
+/// Sets every weight whose entry in `mask` is zero to zero.
+pub(crate) fn apply_mask<T: Float>(weights: &mut [T], mask: &[T]) {
+    for (weight, &keep) in weights.iter_mut().zip(mask) {
+        if keep == T::ZERO {
+            *weight = T::ZERO;
+        }
+    }
+}
+
+impl<T: Float> Network<T> {
+    /// The weights and weight mask of every layer, with the output layer last.
+    fn weights_and_masks_mut(&mut self) -> Vec<(&mut WeightMatrix<T>, &mut Option<WeightMatrix<T>>)> {
+        self.hidden_layers
+            .iter_mut()
+            .map(|layer| (&mut layer.weights, &mut layer.weight_mask))
+            .chain(std::iter::once((
+                &mut self.outputs.weights,
+                &mut self.outputs.weight_mask,
+            )))
+            .collect()
+    }
+
+    /// Prunes `fraction` of the weights that haven't been pruned yet, picking the ones with the smallest magnitudes.
+    /// Biases, the kernels of convolution layers and the vectors of embedding layers are never pruned.
+    pub fn prune_by_magnitude(&mut self, fraction: f32, scope: PruningScope) {
+        assert!(
+            (0. ..=1.).contains(&fraction),
+            "can't prune {} of the weights",
+            fraction
+        );
+
+        let mut layers = self.weights_and_masks_mut();
+        for (weights, mask) in layers.iter_mut() {
+            mask.get_or_insert_with(|| WeightMatrix::from_fn(weights.row_count(), weights.col_count(), |_, _| T::ONE));
+        }
+        let groups: Vec<Vec<usize>> = match scope {
+            PruningScope::Global => vec![(0..layers.len()).collect()],
+            PruningScope::PerLayer => (0..layers.len()).map(|layer_ix| vec![layer_ix]).collect(),
+        };
+
+        // (magnitude, layer_ix, neuron_ix, input_ix) for every weight that's still unpruned
+        let mut candidates: Vec<(T, usize, usize, usize)> = Vec::new();
+        for group in groups {
+            candidates.clear();
+            for layer_ix in group {
+                let (weights, mask) = &layers[layer_ix];
+                let mask = mask.as_ref().unwrap();
+                for neuron_ix in 0..weights.row_count() {
+                    for input_ix in 0..weights.col_count() {
+                        if mask[neuron_ix][input_ix] != T::ZERO {
+                            candidates.push((weights[neuron_ix][input_ix].abs(), layer_ix, neuron_ix, input_ix));
+                        }
+                    }
+                }
+            }
+
+            let prune_count = ((candidates.len() as f64 * fraction as f64).round() as usize).min(candidates.len());
+            if prune_count == 0 {
+                continue;
+            }
+            if prune_count < candidates.len() {
+                candidates.select_nth_unstable_by(prune_count, |a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+            }
+            for &(_, layer_ix, neuron_ix, input_ix) in &candidates[..prune_count] {
+                let (weights, mask) = &mut layers[layer_ix];
+                weights[neuron_ix][input_ix] = T::ZERO;
+                mask.as_mut().unwrap()[neuron_ix][input_ix] = T::ZERO;
+            }
+        }
+    }
+
+    /// How many of each layer's weights have been pruned, with the output layer last.
+    pub fn weight_sparsity(&self) -> Vec<LayerSparsity> {
+        self.hidden_layers
+            .iter()
+            .map(|layer| (&layer.weights, &layer.weight_mask))
+            .chain(std::iter::once((&self.outputs.weights, &self.outputs.weight_mask)))
+            .map(|(weights, mask)| LayerSparsity {
+                pruned_count: mask.as_ref().map_or(0, |mask| {
+                    mask.iter_rows().flatten().filter(|&&keep| keep == T::ZERO).count()
+                }),
+                weight_count: weights.row_count() * weights.col_count(),
+            })
+            .collect()
+    }
+
+    /// The fraction of the weights of the whole network that have been pruned.
+    pub fn sparsity(&self) -> f32 {
+        let layers = self.weight_sparsity();
+        LayerSparsity {
+            pruned_count: layers.iter().map(|layer| layer.pruned_count).sum(),
+            weight_count: layers.iter().map(|layer| layer.weight_count).sum(),
+        }
+        .sparsity()
+    }
+
+    /// Sets all pruned weights back to zero.  Only needed after modifying weights directly.
+    pub fn apply_weight_masks(&mut self) {
+        for (weights, mask) in self.weights_and_masks_mut() {
+            if let Some(mask) = mask {
+                apply_mask(weights.as_mut_slice(), mask.as_slice());
+            }
+        }
+    }
+
+    /// Un-prunes every weight.  Pruned weights start out at zero but are free to be trained again.
+    pub fn clear_weight_masks(&mut self) {
+        for (_, mask) in self.weights_and_masks_mut() {
+            *mask = None;
+        }
+    }
+
+    /// Restores the weights and biases in `snapshot` and then zeroes out the pruned weights again.  With a snapshot
+    /// taken before training, this resets the surviving weights to their initial values.
+    pub fn rewind_weights(&mut self, snapshot: &NetworkSnapshot<T>) {
+        self.restore(snapshot);
+        self.apply_weight_masks();
+    }
+}
+
+impl Network {
+    /// Trains the network, then repeatedly prunes it and trains it again according to `schedule`.  When
+    /// `schedule.rewind` is set, the weights are rewound to the values they had when this was called after each round,
+    /// so the network should be freshly initialized for a lottery ticket experiment.
+    ///
+    /// Returns one entry for the initial training plus one for each round.
+    pub fn fit_with_pruning<D: Dataset, R: Rng + ?Sized>(
+        &mut self,
+        dataset: &D,
+        schedule: &PruningSchedule,
+        rng: &mut R,
+    ) -> Result<Vec<PruningRound>, NnError> {
+        let initial_weights = self.snapshot();
+        let fraction_per_round = schedule.fraction_per_round();
+
+        let mut rounds = Vec::with_capacity(schedule.rounds + 1);
+        for round_ix in 0..=schedule.rounds {
+            if round_ix > 0 {
+                self.prune_by_magnitude(fraction_per_round, schedule.scope);
+                if schedule.rewind {
+                    self.rewind_weights(&initial_weights);
+                }
+            }
+            rounds.push(PruningRound {
+                sparsity: self.weight_sparsity(),
+                epoch_costs: self.fit(dataset, schedule.epochs_per_round, rng)?,
+            });
+        }
+
+        Ok(rounds)
+    }
+}
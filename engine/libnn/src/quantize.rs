@@ -0,0 +1,353 @@
+//! Post-training quantization.  A trained `Network` can be converted into a `QuantizedNetwork`, which stores its
+//! weights as 8-bit integers and can only be used for inference.  That makes the weights a quarter of the size, which
+//! matters when shipping a model to the browser.
+//!
+//! Weights are quantized symmetrically, either with one scale per layer or one per neuron.  The values fed into each
+//! layer are quantized with a scale and zero point picked from the range of values seen while running a sample of
+//! examples through the float network.  Each neuron's inputs and weights are multiplied together and summed up as
+//! integers; biases and activation functions are applied in floating point.
+
+use crate::{
+    argmax, kernels, ActivationFunction, BatchActivations, Dataset, DenseLayer, Network, NnError, OutputLayer, Weight,
+    WeightMatrix,
+};
+
+/// How many examples are run through the float network at a time while calibrating or comparing.
+const BATCH_SIZE: usize = 256;
+
+/// Whether weights share one scale for the whole layer or get one per neuron.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeightGranularity {
+    PerLayer,
+    /// More accurate when the weights of different neurons in a layer have very different magnitudes, at the cost of
+    /// storing a scale for each neuron.
+    PerChannel,
+}
+
+/// Maps real values onto 8-bit integers: `real = scale * (quantized - zero_point)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuantizationParams {
+    pub scale: Weight,
+    pub zero_point: i8,
+}
+
+impl QuantizationParams {
+    /// Spreads the 256 possible values evenly over `[min, max]`.  The range is widened to include 0 if needed so that
+    /// 0 is represented exactly.
+    pub fn from_range(min: Weight, max: Weight) -> Self {
+        let (min, max) = (min.min(0.), max.max(0.));
+        let scale = if max > min { (max - min) / 255. } else { 1. };
+        let zero_point = (-128. - min / scale).round().clamp(-128., 127.) as i8;
+        QuantizationParams { scale, zero_point }
+    }
+
+    /// Picks a scale that covers `[-max_abs, max_abs]` with a zero point of 0, using -127 to 127 so that the range is
+    /// symmetric.
+    pub fn symmetric(max_abs: Weight) -> Self {
+        let scale = if max_abs > 0. { max_abs / 127. } else { 1. };
+        QuantizationParams { scale, zero_point: 0 }
+    }
+
+    /// Values outside of the range that the params cover are clamped to its ends.
+    pub fn quantize(&self, value: Weight) -> i8 {
+        ((value / self.scale).round() + self.zero_point as Weight).clamp(-128., 127.) as i8
+    }
+
+    pub fn dequantize(&self, value: i8) -> Weight { self.scale * (value as i32 - self.zero_point as i32) as Weight }
+}
+
+pub struct QuantizedLayer {
+    /// Row-major with one row per neuron, like `WeightMatrix` but without any padding.
+    weights: Vec<i8>,
+    input_count: usize,
+    /// The scale of each neuron's weights.  With `WeightGranularity::PerLayer`, they're all the same.
+    weight_scales: Vec<Weight>,
+    /// The sum of each neuron's quantized weights, used to cancel out the zero point of the inputs.
+    weight_sums: Vec<i32>,
+    biases: Vec<Weight>,
+    /// Used to quantize the values fed into the layer.
+    pub input_params: QuantizationParams,
+    pub activation_fn: &'static dyn ActivationFunction,
+    quantized_inputs: Vec<i8>,
+    pub outputs_before_activation: Vec<Weight>,
+    pub outputs: Vec<Weight>,
+}
+
+impl QuantizedLayer {
+    /// `biases` should be empty for layers that don't have any.
+    fn new(
+        weights: &WeightMatrix,
+        biases: &[Weight],
+        activation_fn: &'static dyn ActivationFunction,
+        input_params: QuantizationParams,
+        granularity: WeightGranularity,
+    ) -> Self {
+        let neuron_count = weights.row_count();
+        let max_abs = |row: &[Weight]| row.iter().fold(0., |acc: Weight, weight| acc.max(weight.abs()));
+        let layer_params = QuantizationParams::symmetric(weights.iter_rows().map(max_abs).fold(0., Weight::max));
+
+        let mut layer = QuantizedLayer {
+            weights: Vec::with_capacity(neuron_count * weights.col_count()),
+            input_count: weights.col_count(),
+            weight_scales: Vec::with_capacity(neuron_count),
+            weight_sums: Vec::with_capacity(neuron_count),
+            biases: if biases.is_empty() {
+                vec![0.; neuron_count]
+            } else {
+                biases.to_owned()
+            },
+            input_params,
+            activation_fn,
+            quantized_inputs: vec![0; weights.col_count()],
+            outputs_before_activation: vec![0.; neuron_count],
+            outputs: vec![0.; neuron_count],
+        };
+        for row in weights.iter_rows() {
+            let params = match granularity {
+                WeightGranularity::PerLayer => layer_params,
+                WeightGranularity::PerChannel => QuantizationParams::symmetric(max_abs(row)),
+            };
+            let start = layer.weights.len();
+            layer.weights.extend(row.iter().map(|&weight| params.quantize(weight)));
+            layer
+                .weight_sums
+                .push(layer.weights[start..].iter().map(|&weight| weight as i32).sum());
+            layer.weight_scales.push(params.scale);
+        }
+        layer
+    }
+
+    pub fn input_count(&self) -> usize { self.input_count }
+
+    pub fn neuron_count(&self) -> usize { self.outputs.len() }
+
+    /// The quantized weights of the neuron at `neuron_ix`.
+    pub fn neuron_weights(&self, neuron_ix: usize) -> &[i8] {
+        &self.weights[neuron_ix * self.input_count..(neuron_ix + 1) * self.input_count]
+    }
+
+    /// The weight at `input_ix` of the neuron at `neuron_ix` converted back into floating point.
+    pub fn dequantized_weight(&self, neuron_ix: usize, input_ix: usize) -> Weight {
+        self.weight_scales[neuron_ix] * self.neuron_weights(neuron_ix)[input_ix] as Weight
+    }
+
+    pub fn forward_propagate(&mut self, inputs: &[Weight]) {
+        debug_assert_eq!(inputs.len(), self.input_count);
+        for (quantized, &input) in self.quantized_inputs.iter_mut().zip(inputs) {
+            *quantized = self.input_params.quantize(input);
+        }
+
+        let input_scale = self.input_params.scale;
+        let input_zero_point = self.input_params.zero_point as i32;
+        for neuron_ix in 0..self.neuron_count() {
+            // sum(w * (q - z)) = sum(w * q) - z * sum(w)
+            let sum = kernels::dot_i8(self.neuron_weights(neuron_ix), &self.quantized_inputs)
+                - input_zero_point * self.weight_sums[neuron_ix];
+            self.outputs_before_activation[neuron_ix] =
+                sum as Weight * self.weight_scales[neuron_ix] * input_scale + self.biases[neuron_ix];
+        }
+
+        (self.activation_fn).apply_batch(&mut self.outputs, &self.outputs_before_activation);
+    }
+
+    /// The number of bytes taken up by the weights, scales and biases.
+    pub fn parameter_bytes(&self) -> usize {
+        self.weights.len() + (self.weight_scales.len() + self.biases.len()) * std::mem::size_of::<Weight>()
+    }
+}
+
+/// An inference-only copy of a `Network` with 8-bit weights.  Built with `QuantizedNetwork::quantize`.
+pub struct QuantizedNetwork {
+    pub hidden_layers: Vec<QuantizedLayer>,
+    pub outputs: QuantizedLayer,
+}
+
+/// How closely a `QuantizedNetwork` matches the network it was built from on some dataset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantizationReport {
+    /// The largest difference between any output of the quantized network and the float network.
+    pub max_output_error: Weight,
+    /// The average difference between the outputs of the quantized network and the float network.
+    pub mean_output_error: Weight,
+    /// The fraction of examples for which both networks' largest output is at the same index.
+    pub argmax_agreement: Weight,
+    /// Classification accuracy of the float network, as returned by `Network::classification_accuracy`.
+    pub float_accuracy: Weight,
+    /// Classification accuracy of the quantized network.
+    pub quantized_accuracy: Weight,
+}
+
+impl QuantizationReport {
+    /// How much accuracy was lost by quantizing.  Negative if the quantized network is less accurate.
+    pub fn accuracy_delta(&self) -> Weight { self.quantized_accuracy - self.float_accuracy }
+}
+
+/// Runs every example in `dataset` through `network` in batches, calling `f` with each batch's examples and outputs.
+fn for_each_batch<D: Dataset>(
+    network: &Network,
+    dataset: &D,
+    mut f: impl FnMut(&[usize], &BatchActivations),
+) -> Result<(), NnError> {
+    let mut examples = Vec::new();
+    let mut activations = BatchActivations::new();
+    for batch in dataset.batches(BATCH_SIZE).iter() {
+        examples.clear();
+        for (inputs, _) in batch.iter() {
+            examples.extend_from_slice(inputs);
+        }
+        network.forward_propagate_batch(&examples, &mut activations)?;
+        f(batch.indices(), &activations);
+    }
+    Ok(())
+}
+
+impl QuantizedNetwork {
+    /// Quantizes `network`, picking the ranges of the values fed into each layer by running every example in
+    /// `calibration_data` through it.  A few hundred examples representative of what the network will be used on are
+    /// usually enough.
+    pub fn quantize<D: Dataset>(
+        network: &Network,
+        calibration_data: &D,
+        granularity: WeightGranularity,
+    ) -> Result<Self, NnError> {
+        network.validate_dataset(calibration_data)?;
+        if calibration_data.is_empty() {
+            return Err(NnError::EmptyDataset);
+        }
+
+        // The range of the values fed into each layer, with the output layer last
+        let mut ranges = vec![(Weight::INFINITY, Weight::NEG_INFINITY); network.hidden_layers.len() + 1];
+        for_each_batch(network, calibration_data, |_, activations| {
+            let layer_inputs = std::iter::once(&activations.inputs).chain(&activations.hidden_layers);
+            for ((min, max), values) in ranges.iter_mut().zip(layer_inputs) {
+                for &value in values.iter_rows().flatten() {
+                    *min = min.min(value);
+                    *max = max.max(value);
+                }
+            }
+        })?;
+        // `min` and `max` ignore NaNs, so non-finite outputs only show up as infinite ranges
+        if let Some(layer_ix) = ranges
+            .iter()
+            .skip(1)
+            .position(|(min, max)| !min.is_finite() || !max.is_finite())
+        {
+            return Err(NnError::NonFiniteOutputs { layer_ix });
+        }
+        let mut input_params = ranges
+            .iter()
+            .map(|&(min, max)| QuantizationParams::from_range(min, max));
+
+        let quantize_layer = |layer: &DenseLayer, input_params| {
+            QuantizedLayer::new(
+                &layer.weights,
+                &layer.biases,
+                layer.activation_fn,
+                input_params,
+                granularity,
+            )
+        };
+        let hidden_layers = network
+            .hidden_layers
+            .iter()
+            .zip(&mut input_params)
+            .map(|(layer, input_params)| quantize_layer(layer, input_params))
+            .collect();
+        let OutputLayer {
+            weights, activation_fn, ..
+        } = &*network.outputs;
+        let outputs = QuantizedLayer::new(weights, &[], *activation_fn, input_params.next().unwrap(), granularity);
+
+        Ok(QuantizedNetwork { hidden_layers, outputs })
+    }
+
+    pub fn input_count(&self) -> usize {
+        match self.hidden_layers.first() {
+            Some(layer) => layer.input_count(),
+            None => self.outputs.input_count(),
+        }
+    }
+
+    pub fn output_count(&self) -> usize { self.outputs.neuron_count() }
+
+    /// Returns an error if `inputs` doesn't match the size of the network.
+    pub fn compute<'a>(&'a mut self, inputs: &[Weight]) -> Result<&'a [Weight], NnError> {
+        if inputs.len() != self.input_count() {
+            return Err(NnError::InvalidInputLength {
+                expected: self.input_count(),
+                actual: inputs.len(),
+            });
+        }
+        self.forward_propagate_unchecked(inputs);
+        Ok(&self.outputs.outputs)
+    }
+
+    fn forward_propagate_unchecked(&mut self, inputs: &[Weight]) {
+        let mut inputs = inputs;
+        for layer in &mut self.hidden_layers {
+            layer.forward_propagate(inputs);
+            inputs = &layer.outputs;
+        }
+        self.outputs.forward_propagate(inputs);
+    }
+
+    /// The number of bytes taken up by the weights, scales and biases of every layer.
+    pub fn parameter_bytes(&self) -> usize {
+        self.hidden_layers
+            .iter()
+            .chain(std::iter::once(&self.outputs))
+            .map(QuantizedLayer::parameter_bytes)
+            .sum()
+    }
+
+    /// Runs every example in `dataset` through both this network and `network`, which should be the one it was
+    /// quantized from, and reports how much their outputs differ.
+    pub fn compare<D: Dataset>(&mut self, network: &Network, dataset: &D) -> Result<QuantizationReport, NnError> {
+        network.validate_dataset(dataset)?;
+        if dataset.input_dims() != self.input_count() {
+            return Err(NnError::InvalidInputLength {
+                expected: self.input_count(),
+                actual: dataset.input_dims(),
+            });
+        }
+        if dataset.output_dims() != self.output_count() {
+            return Err(NnError::InvalidTargetLength {
+                expected: self.output_count(),
+                actual: dataset.output_dims(),
+            });
+        }
+        if dataset.is_empty() {
+            return Err(NnError::EmptyDataset);
+        }
+
+        let mut max_output_error: Weight = 0.;
+        let mut total_output_error = 0.;
+        let (mut agreed_count, mut float_correct_count, mut quantized_correct_count) = (0, 0, 0);
+        for_each_batch(network, dataset, |indices, activations| {
+            for (&example_ix, float_outputs) in indices.iter().zip(activations.outputs.iter_rows()) {
+                let (inputs, targets) = dataset.get(example_ix);
+                self.forward_propagate_unchecked(inputs);
+                let quantized_outputs = self.outputs.outputs.as_slice();
+
+                for (&quantized, &float) in quantized_outputs.iter().zip(float_outputs) {
+                    let error = (quantized - float).abs();
+                    max_output_error = max_output_error.max(error);
+                    total_output_error += error;
+                }
+                let (quantized_class, float_class) = (argmax(quantized_outputs), argmax(float_outputs));
+                agreed_count += (quantized_class == float_class) as usize;
+                float_correct_count += (float_class == argmax(targets)) as usize;
+                quantized_correct_count += (quantized_class == argmax(targets)) as usize;
+            }
+        })?;
+
+        let example_count = dataset.len() as Weight;
+        Ok(QuantizationReport {
+            max_output_error,
+            mean_output_error: total_output_error / (example_count * self.output_count() as Weight),
+            argmax_agreement: agreed_count as Weight / example_count,
+            float_accuracy: float_correct_count as Weight / example_count,
+            quantized_accuracy: quantized_correct_count as Weight / example_count,
+        })
+    }
+}
@@ -0,0 +1,483 @@
+//! Recurrent layers for sequences.  A `SequenceNetwork` runs a stack of recurrent layers over the timesteps of a
+//! sequence one at a time, with each layer carrying a hidden state from one timestep to the next, and feeds the hidden
+//! state of the last layer into an `OutputLayer` at every timestep that has a target.
+//!
+//! Training uses backpropagation through time: the whole sequence is run forwards while caching what each layer
+//! computed at each timestep, and then the errors are run backwards through the timesteps in reverse, accumulating
+//! gradients as they go.  With truncated BPTT the sequence is split into chunks of `bptt_steps` timesteps; the hidden
+//! state still carries over between chunks, but errors stop at the start of each chunk and the weights are updated
+//! after every chunk.
+//!
+//! Same as the rest of the crate, the values passed backwards are errors (the negated gradients of the cost) and the
+//! weights are updated by adding them.
+
+use crate::{average_cost, Float, NnError, OutputLayer, Weight, WeightMatrix};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellType {
+    /// `h = tanh(W [x, h_prev] + b)`
+    Rnn,
+    /// Long short-term memory, with input, forget and output gates and a separate cell state.
+    Lstm,
+    /// Gated recurrent unit, with update and reset gates.  The reset gate is applied to the previous hidden state
+    /// before it's multiplied by the candidate's weights, as in the original formulation.
+    Gru,
+}
+
+impl CellType {
+    /// The number of blocks of `hidden_size` rows in the weights of a layer with this type of cell.
+    pub fn gate_count(self) -> usize {
+        match self {
+            CellType::Rnn => 1,
+            CellType::Lstm => 4,
+            CellType::Gru => 3,
+        }
+    }
+}
+
+/// Everything computed by a layer for one timestep that's needed to backpropagate through it.
+struct StepCache<T: Float> {
+    /// The inputs followed by the previous hidden state.
+    inputs: Vec<T>,
+    /// For GRUs, the inputs followed by the previous hidden state multiplied by the reset gate.
+    candidate_inputs: Vec<T>,
+    /// The output of every gate after its activation function, in the same order as the rows of the weights.
+    gates: Vec<T>,
+    /// For LSTMs, the cell state before and after this timestep.
+    prev_cell_state: Vec<T>,
+    cell_state: Vec<T>,
+}
+
+pub struct RecurrentLayer<T: Float = Weight> {
+    pub cell_type: CellType,
+    pub input_count: usize,
+    pub hidden_size: usize,
+    /// One block of `hidden_size` rows for each gate: the input, forget, candidate and output gates for LSTMs and the
+    /// update, reset and candidate gates for GRUs.  Each row holds the weights for the layer's inputs followed by the
+    /// weights for the previous hidden state.
+    pub weights: WeightMatrix<T>,
+    pub biases: Vec<T>,
+    /// The hidden state after the last timestep, which is fed back in at the next one.
+    pub hidden_state: Vec<T>,
+    /// Only used by LSTMs.
+    pub cell_state: Vec<T>,
+    steps: Vec<StepCache<T>>,
+    weight_gradients: WeightMatrix<T>,
+    bias_gradients: Vec<T>,
+}
+
+impl<T: Float> RecurrentLayer<T> {
+    /// `init_weights` is called with the row and column of each weight.  Biases start at zero, apart from the
+    /// forget gate of LSTMs which starts at one so that the cell state is remembered by default.
+    pub fn new(
+        cell_type: CellType,
+        input_count: usize,
+        hidden_size: usize,
+        init_weights: &mut impl FnMut(usize, usize) -> T,
+    ) -> Self {
+        let row_count = cell_type.gate_count() * hidden_size;
+        let col_count = input_count + hidden_size;
+        let mut biases = vec![T::ZERO; row_count];
+        if cell_type == CellType::Lstm {
+            biases[hidden_size..2 * hidden_size].fill(T::ONE);
+        }
+
+        RecurrentLayer {
+            cell_type,
+            input_count,
+            hidden_size,
+            weights: WeightMatrix::from_fn(row_count, col_count, init_weights),
+            biases,
+            hidden_state: vec![T::ZERO; hidden_size],
+            cell_state: vec![T::ZERO; hidden_size],
+            steps: Vec::new(),
+            weight_gradients: WeightMatrix::new(row_count, col_count),
+            bias_gradients: vec![T::ZERO; row_count],
+        }
+    }
+
+    pub fn reset_state(&mut self) {
+        self.hidden_state.fill(T::ZERO);
+        self.cell_state.fill(T::ZERO);
+    }
+
+    /// `W[row] . inputs + b[row]` for each row in `rows`.
+    fn pre_activations(&self, rows: std::ops::Range<usize>, inputs: &[T]) -> Vec<T> {
+        rows.map(|row| T::dot(&self.weights[row], inputs) + self.biases[row])
+            .collect()
+    }
+
+    /// Runs one timestep, updating `self.hidden_state`.  With `cache`, stores what's needed to backpropagate through
+    /// the timestep.
+    pub fn forward_step(&mut self, inputs: &[T], cache: bool) {
+        debug_assert_eq!(inputs.len(), self.input_count);
+        let hidden_size = self.hidden_size;
+        let mut concatenated = Vec::with_capacity(self.input_count + hidden_size);
+        concatenated.extend_from_slice(inputs);
+        concatenated.extend_from_slice(&self.hidden_state);
+
+        let prev_cell_state = self.cell_state.clone();
+        let mut candidate_inputs = Vec::new();
+        let gates = match self.cell_type {
+            CellType::Rnn => {
+                let gates: Vec<T> = self
+                    .pre_activations(0..hidden_size, &concatenated)
+                    .into_iter()
+                    .map(T::tanh)
+                    .collect();
+                self.hidden_state.copy_from_slice(&gates);
+                gates
+            },
+            CellType::Lstm => {
+                let mut gates = self.pre_activations(0..4 * hidden_size, &concatenated);
+                for (ix, gate) in gates.iter_mut().enumerate() {
+                    let is_candidate = ix / hidden_size == 2;
+                    *gate = if is_candidate { gate.tanh() } else { gate.sigmoid() };
+                }
+                for unit in 0..hidden_size {
+                    let [input, forget, candidate, output] =
+                        [0, 1, 2, 3].map(|gate_ix| gates[gate_ix * hidden_size + unit]);
+                    self.cell_state[unit] = forget * prev_cell_state[unit] + input * candidate;
+                    self.hidden_state[unit] = output * self.cell_state[unit].tanh();
+                }
+                gates
+            },
+            CellType::Gru => {
+                let mut gates: Vec<T> = self
+                    .pre_activations(0..2 * hidden_size, &concatenated)
+                    .into_iter()
+                    .map(T::sigmoid)
+                    .collect();
+                candidate_inputs.extend_from_slice(inputs);
+                candidate_inputs
+                    .extend((0..hidden_size).map(|unit| gates[hidden_size + unit] * self.hidden_state[unit]));
+                gates.extend(
+                    self.pre_activations(2 * hidden_size..3 * hidden_size, &candidate_inputs)
+                        .into_iter()
+                        .map(T::tanh),
+                );
+                for unit in 0..hidden_size {
+                    let (update, candidate) = (gates[unit], gates[2 * hidden_size + unit]);
+                    self.hidden_state[unit] = (T::ONE - update) * candidate + update * self.hidden_state[unit];
+                }
+                gates
+            },
+        };
+
+        if cache {
+            self.steps.push(StepCache {
+                inputs: concatenated,
+                candidate_inputs,
+                gates,
+                prev_cell_state,
+                cell_state: self.cell_state.clone(),
+            });
+        }
+    }
+
+    /// Adds `errors` times the transpose of the given rows of the weights to `dst`, and accumulates the gradients of
+    /// those rows.
+    fn backpropagate_rows(&mut self, first_row: usize, errors: &[T], inputs: &[T], dst: &mut [T]) {
+        for (ix, &error) in errors.iter().enumerate() {
+            let row = first_row + ix;
+            T::axpy(dst, error, &self.weights[row]);
+            T::axpy(&mut self.weight_gradients[row], error, inputs);
+            self.bias_gradients[row] += error;
+        }
+    }
+
+    /// Backpropagates through every cached timestep, starting from the last one, and clears the cache.  `output_errors`
+    /// holds the errors of the hidden state at each timestep coming from the layers above.  Returns the errors of this
+    /// layer's inputs at each timestep.
+    pub fn backpropagate(&mut self, output_errors: &WeightMatrix<T>) -> WeightMatrix<T> {
+        debug_assert_eq!(output_errors.row_count(), self.steps.len());
+        let (input_count, hidden_size) = (self.input_count, self.hidden_size);
+        let steps = std::mem::take(&mut self.steps);
+        let mut input_errors = WeightMatrix::new(steps.len(), input_count);
+
+        // The errors flowing into the previous timestep through the hidden and cell states
+        let mut next_hidden_errors = vec![T::ZERO; hidden_size];
+        let mut next_cell_errors = vec![T::ZERO; hidden_size];
+        let mut concatenated_errors = vec![T::ZERO; input_count + hidden_size];
+        for (step_ix, step) in steps.iter().enumerate().rev() {
+            let hidden_errors: Vec<T> = (0..hidden_size)
+                .map(|unit| output_errors[step_ix][unit] + next_hidden_errors[unit])
+                .collect();
+            let prev_hidden_state = &step.inputs[input_count..];
+            concatenated_errors.fill(T::ZERO);
+
+            match self.cell_type {
+                CellType::Rnn => {
+                    let errors: Vec<T> = (0..hidden_size)
+                        .map(|unit| hidden_errors[unit] * (T::ONE - step.gates[unit] * step.gates[unit]))
+                        .collect();
+                    self.backpropagate_rows(0, &errors, &step.inputs, &mut concatenated_errors);
+                },
+                CellType::Lstm => {
+                    let mut errors = vec![T::ZERO; 4 * hidden_size];
+                    for unit in 0..hidden_size {
+                        let [input, forget, candidate, output] =
+                            [0, 1, 2, 3].map(|gate_ix| step.gates[gate_ix * hidden_size + unit]);
+                        let cell_tanh = step.cell_state[unit].tanh();
+                        let cell_error =
+                            hidden_errors[unit] * output * (T::ONE - cell_tanh * cell_tanh) + next_cell_errors[unit];
+
+                        errors[unit] = cell_error * candidate * input * (T::ONE - input);
+                        errors[hidden_size + unit] =
+                            cell_error * step.prev_cell_state[unit] * forget * (T::ONE - forget);
+                        errors[2 * hidden_size + unit] = cell_error * input * (T::ONE - candidate * candidate);
+                        errors[3 * hidden_size + unit] = hidden_errors[unit] * cell_tanh * output * (T::ONE - output);
+                        next_cell_errors[unit] = cell_error * forget;
+                    }
+                    self.backpropagate_rows(0, &errors, &step.inputs, &mut concatenated_errors);
+                },
+                CellType::Gru => {
+                    let mut candidate_errors = vec![T::ZERO; hidden_size];
+                    let mut gate_errors = vec![T::ZERO; 2 * hidden_size];
+                    for unit in 0..hidden_size {
+                        let (update, candidate) = (step.gates[unit], step.gates[2 * hidden_size + unit]);
+                        candidate_errors[unit] =
+                            hidden_errors[unit] * (T::ONE - update) * (T::ONE - candidate * candidate);
+                        gate_errors[unit] =
+                            hidden_errors[unit] * (prev_hidden_state[unit] - candidate) * update * (T::ONE - update);
+                    }
+
+                    let mut candidate_input_errors = vec![T::ZERO; input_count + hidden_size];
+                    self.backpropagate_rows(
+                        2 * hidden_size,
+                        &candidate_errors,
+                        &step.candidate_inputs,
+                        &mut candidate_input_errors,
+                    );
+                    for unit in 0..hidden_size {
+                        let reset = step.gates[hidden_size + unit];
+                        let reset_hidden_error = candidate_input_errors[input_count + unit];
+                        gate_errors[hidden_size + unit] =
+                            reset_hidden_error * prev_hidden_state[unit] * reset * (T::ONE - reset);
+                        // The previous hidden state is mixed directly into the new one by the update gate, and fed
+                        // into the candidate after being multiplied by the reset gate
+                        concatenated_errors[input_count + unit] =
+                            hidden_errors[unit] * step.gates[unit] + reset_hidden_error * reset;
+                    }
+                    T::axpy(
+                        &mut concatenated_errors[..input_count],
+                        T::ONE,
+                        &candidate_input_errors[..input_count],
+                    );
+                    self.backpropagate_rows(0, &gate_errors, &step.inputs, &mut concatenated_errors);
+                },
+            }
+
+            input_errors[step_ix].copy_from_slice(&concatenated_errors[..input_count]);
+            next_hidden_errors.copy_from_slice(&concatenated_errors[input_count..]);
+        }
+
+        input_errors
+    }
+
+    /// Applies the gradients accumulated by `backpropagate` and clears them.
+    pub fn update_weights(&mut self, learning_rate: T) {
+        T::axpy(
+            self.weights.as_mut_slice(),
+            learning_rate,
+            self.weight_gradients.as_slice(),
+        );
+        T::axpy(&mut self.biases, learning_rate, &self.bias_gradients);
+        self.weight_gradients.as_mut_slice().fill(T::ZERO);
+        self.bias_gradients.fill(T::ZERO);
+    }
+}
+
+/// A stack of recurrent layers followed by an output layer that's applied to the last layer's hidden state at each
+/// timestep.  Sequences are passed as one slice of features per timestep.
+pub struct SequenceNetwork<T: Float = Weight> {
+    pub recurrent_layers: Vec<RecurrentLayer<T>>,
+    pub outputs: Box<OutputLayer<T>>,
+    /// Truncates backpropagation through time to chunks of this many timesteps.  `None` backpropagates through the
+    /// whole sequence at once.
+    pub bptt_steps: Option<usize>,
+    output_weight_gradients: WeightMatrix<T>,
+}
+
+impl<T: Float> SequenceNetwork<T> {
+    /// Panics if the sizes of the layers don't line up.  Returns an error if the output layer uses only one of
+    /// `Softmax` and `CrossEntropy`.
+    pub fn new(recurrent_layers: Vec<RecurrentLayer<T>>, outputs: Box<OutputLayer<T>>) -> Result<Self, NnError> {
+        assert!(!recurrent_layers.is_empty(), "need at least one recurrent layer");
+        for (layer, next_layer_inputs) in recurrent_layers.iter().zip(
+            recurrent_layers
+                .iter()
+                .skip(1)
+                .map(|layer| layer.input_count)
+                .chain(std::iter::once(outputs.weights.col_count())),
+        ) {
+            assert_eq!(
+                layer.hidden_size, next_layer_inputs,
+                "layer with a hidden size of {} feeds into one with {} inputs",
+                layer.hidden_size, next_layer_inputs
+            );
+        }
+
+        outputs.validate_activation(recurrent_layers.len())?;
+
+        let output_weight_gradients = WeightMatrix::new(outputs.weights.row_count(), outputs.weights.col_count());
+        Ok(SequenceNetwork {
+            recurrent_layers,
+            outputs,
+            bptt_steps: None,
+            output_weight_gradients,
+        })
+    }
+
+    pub fn input_count(&self) -> usize { self.recurrent_layers[0].input_count }
+
+    pub fn output_count(&self) -> usize { self.outputs.outputs.len() }
+
+    pub fn reset_state(&mut self) {
+        for layer in &mut self.recurrent_layers {
+            layer.reset_state();
+        }
+    }
+
+    /// Runs one timestep through every recurrent layer.  Returns the hidden state of the last one.
+    fn forward_step(&mut self, inputs: &[T], cache: bool) -> &[T] {
+        let (first_layer, layers) = self.recurrent_layers.split_first_mut().unwrap();
+        first_layer.forward_step(inputs, cache);
+        let mut hidden_state = &first_layer.hidden_state;
+        for layer in layers {
+            layer.forward_step(hidden_state, cache);
+            hidden_state = &layer.hidden_state;
+        }
+        hidden_state
+    }
+
+    /// Runs a sequence through the network starting from an empty hidden state, returning the outputs at each
+    /// timestep.
+    pub fn compute_sequence<R: AsRef<[T]>>(&mut self, inputs: &[R]) -> Result<Vec<Vec<T>>, NnError> {
+        validate_sequence(inputs, self.input_count())?;
+        self.reset_state();
+        Ok(inputs
+            .iter()
+            .map(|step| {
+                let hidden_state = self.forward_step(step.as_ref(), false).to_vec();
+                self.outputs.forward_propagate(&hidden_state);
+                self.outputs.outputs.clone()
+            })
+            .collect())
+    }
+
+    /// Trains on one sequence starting from an empty hidden state.  `targets` holds either the expected outputs for
+    /// every timestep, or a single expected output for the last timestep.  Returns the average cost over the targets
+    /// before updating weights.
+    pub fn train_sequence<R: AsRef<[T]>, S: AsRef<[T]>>(
+        &mut self,
+        inputs: &[R],
+        targets: &[S],
+        learning_rate: T,
+    ) -> Result<T, NnError> {
+        validate_sequence(inputs, self.input_count())?;
+        validate_targets(targets, inputs.len(), self.output_count())?;
+
+        self.reset_state();
+        let chunk_len = self.bptt_steps.unwrap_or(inputs.len()).max(1);
+        let hidden_size = self.outputs.weights.col_count();
+        let mut total_cost = T::ZERO;
+        for chunk_start in (0..inputs.len()).step_by(chunk_len) {
+            let chunk = chunk_start..(chunk_start + chunk_len).min(inputs.len());
+            let mut errors = WeightMatrix::new(chunk.len(), hidden_size);
+            for step_ix in chunk.clone() {
+                let hidden_state = self.forward_step(inputs[step_ix].as_ref(), true).to_vec();
+                if let Some(target) = target_for_step(targets, inputs.len(), step_ix) {
+                    total_cost += backpropagate_readout(
+                        &mut self.outputs,
+                        &hidden_state,
+                        target,
+                        &mut errors[step_ix - chunk.start],
+                        &mut self.output_weight_gradients,
+                    );
+                }
+            }
+
+            for layer in self.recurrent_layers.iter_mut().rev() {
+                errors = layer.backpropagate(&errors);
+                layer.update_weights(learning_rate);
+            }
+            self.outputs
+                .apply_weight_gradients(&self.output_weight_gradients, learning_rate);
+            self.output_weight_gradients.as_mut_slice().fill(T::ZERO);
+        }
+
+        average_cost(total_cost, targets.len())
+    }
+}
+
+pub(crate) fn validate_sequence<T: Float, R: AsRef<[T]>>(inputs: &[R], input_count: usize) -> Result<(), NnError> {
+    if inputs.is_empty() {
+        return Err(NnError::EmptySequence);
+    }
+    for step in inputs {
+        if step.as_ref().len() != input_count {
+            return Err(NnError::InvalidInputLength {
+                expected: input_count,
+                actual: step.as_ref().len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Targets are either given for every timestep or only for the last one.
+pub(crate) fn validate_targets<T: Float, S: AsRef<[T]>>(
+    targets: &[S],
+    step_count: usize,
+    output_count: usize,
+) -> Result<(), NnError> {
+    if targets.len() != step_count && targets.len() != 1 {
+        return Err(NnError::InvalidTargetLength {
+            expected: step_count,
+            actual: targets.len(),
+        });
+    }
+    if let Some(target) = targets.iter().find(|target| target.as_ref().len() != output_count) {
+        return Err(NnError::InvalidTargetLength {
+            expected: output_count,
+            actual: target.as_ref().len(),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn target_for_step<T: Float, S: AsRef<[T]>>(
+    targets: &[S],
+    step_count: usize,
+    step_ix: usize,
+) -> Option<&[T]> {
+    if targets.len() == step_count {
+        Some(targets[step_ix].as_ref())
+    } else if step_ix == step_count - 1 {
+        Some(targets[0].as_ref())
+    } else {
+        None
+    }
+}
+
+/// Runs the output layer on the hidden state for one timestep and backpropagates `target` through it, adding the
+/// errors of the hidden state to `hidden_errors` and the output layer's gradients to `weight_gradients`.  Returns the
+/// average cost over the outputs.
+pub(crate) fn backpropagate_readout<T: Float>(
+    outputs: &mut OutputLayer<T>,
+    hidden_state: &[T],
+    target: &[T],
+    hidden_errors: &mut [T],
+    weight_gradients: &mut WeightMatrix<T>,
+) -> T {
+    outputs.forward_propagate(hidden_state);
+    outputs.compute_costs(target);
+    outputs.compute_gradients();
+    for (neuron_ix, &gradient) in outputs.neuron_gradients.iter().enumerate() {
+        T::axpy(hidden_errors, gradient, &outputs.weights[neuron_ix]);
+        T::axpy(&mut weight_gradients[neuron_ix], gradient, hidden_state);
+    }
+    let cost = outputs.costs.iter().fold(T::ZERO, |acc, &cost| acc + cost);
+    cost / T::from_f64(outputs.costs.len() as f64)
+}
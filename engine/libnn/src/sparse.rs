@@ -0,0 +1,447 @@
+//! Sparse layers for pruned networks.  Pruning zeroes out weights, but a pruned `Network` still stores and multiplies
+//! every one of them.  A `SparseNetwork` keeps only the weights that survived in compressed sparse row (CSR) form, so
+//! each neuron only touches its remaining inputs in both the forward and backward passes.
+//!
+//! The sparse kernels can't use SIMD since they have to gather inputs from scattered indices, so they only come out
+//! ahead once most of the weights are gone.  For the 784-128-10 network in `benches/dense_layers.rs` on an AVX2
+//! machine, the sparse forward pass breaks even with the dense one at around 85% sparsity, is about 3x faster at 95%,
+//! and about 10x faster at 99%.
+
+use crate::{ActivationFunction, CostFunction, DenseLayer, Float, Network, NnError, OutputLayer, Weight, WeightMatrix};
+
+/// A matrix stored in compressed sparse row form.  Only entries with a magnitude greater than the threshold it was
+/// built with are stored; everything else is implicitly zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SparseMatrix<T: Float = Weight> {
+    row_count: usize,
+    col_count: usize,
+    /// The entries of row `i` are stored at `row_starts[i]..row_starts[i + 1]` in `col_indices` and `values`.
+    row_starts: Vec<usize>,
+    col_indices: Vec<u32>,
+    values: Vec<T>,
+}
+
+impl<T: Float> SparseMatrix<T> {
+    /// Keeps every entry of `dense` whose magnitude is greater than `threshold`.  A threshold of zero keeps all
+    /// non-zero entries, which is what's wanted to convert a network that was pruned with
+    /// `Network::prune_by_magnitude`.
+    pub fn from_dense(dense: &WeightMatrix<T>, threshold: T) -> Self {
+        assert!(
+            dense.col_count() <= u32::MAX as usize,
+            "too many columns for a sparse matrix: {}",
+            dense.col_count()
+        );
+
+        let mut row_starts = Vec::with_capacity(dense.row_count() + 1);
+        let mut col_indices = Vec::new();
+        let mut values = Vec::new();
+        row_starts.push(0);
+        for row in dense.iter_rows() {
+            for (col_ix, &value) in row.iter().enumerate() {
+                if value.abs() > threshold {
+                    col_indices.push(col_ix as u32);
+                    values.push(value);
+                }
+            }
+            row_starts.push(values.len());
+        }
+
+        SparseMatrix {
+            row_count: dense.row_count(),
+            col_count: dense.col_count(),
+            row_starts,
+            col_indices,
+            values,
+        }
+    }
+
+    pub fn to_dense(&self) -> WeightMatrix<T> {
+        let mut dense = WeightMatrix::new(self.row_count, self.col_count);
+        for row_ix in 0..self.row_count {
+            let (col_indices, values) = self.row(row_ix);
+            for (&col_ix, &value) in col_indices.iter().zip(values) {
+                dense[row_ix][col_ix as usize] = value;
+            }
+        }
+        dense
+    }
+
+    /// A matrix the same shape as this one holding 1 for every stored entry and 0 everywhere else, in the format used
+    /// by `DenseLayer::weight_mask`.
+    pub fn mask(&self) -> WeightMatrix<T> {
+        let mut mask = WeightMatrix::new(self.row_count, self.col_count);
+        for row_ix in 0..self.row_count {
+            for &col_ix in self.row(row_ix).0 {
+                mask[row_ix][col_ix as usize] = T::ONE;
+            }
+        }
+        mask
+    }
+
+    pub fn row_count(&self) -> usize { self.row_count }
+
+    pub fn col_count(&self) -> usize { self.col_count }
+
+    /// The number of entries that are actually stored.
+    pub fn stored_count(&self) -> usize { self.values.len() }
+
+    /// The fraction of the matrix's entries that are stored.
+    pub fn density(&self) -> f32 {
+        let entry_count = self.row_count * self.col_count;
+        if entry_count == 0 {
+            return 0.;
+        }
+        self.stored_count() as f32 / entry_count as f32
+    }
+
+    /// The column indices and values of the stored entries in the given row.
+    pub fn row(&self, row_ix: usize) -> (&[u32], &[T]) {
+        let range = self.row_starts[row_ix]..self.row_starts[row_ix + 1];
+        (&self.col_indices[range.clone()], &self.values[range])
+    }
+
+    pub fn row_mut(&mut self, row_ix: usize) -> (&[u32], &mut [T]) {
+        let range = self.row_starts[row_ix]..self.row_starts[row_ix + 1];
+        (&self.col_indices[range.clone()], &mut self.values[range])
+    }
+
+    /// The number of bytes used to store the entries and their positions.
+    pub fn parameter_bytes(&self) -> usize {
+        self.values.len() * (std::mem::size_of::<T>() + std::mem::size_of::<u32>())
+            + self.row_starts.len() * std::mem::size_of::<usize>()
+    }
+}
+
+/// Sums `values[i] * x[col_indices[i]]`.  Uses four independent accumulators so that the additions don't all have to
+/// wait on each other.
+fn sparse_dot<T: Float>(col_indices: &[u32], values: &[T], x: &[T]) -> T {
+    debug_assert_eq!(col_indices.len(), values.len());
+    let mut sums = [T::ZERO; 4];
+    let mut index_chunks = col_indices.chunks_exact(4);
+    let mut value_chunks = values.chunks_exact(4);
+    for (indices, values) in (&mut index_chunks).zip(&mut value_chunks) {
+        for lane in 0..4 {
+            sums[lane] += values[lane] * x[indices[lane] as usize];
+        }
+    }
+    for (&col_ix, &value) in index_chunks.remainder().iter().zip(value_chunks.remainder()) {
+        sums[0] += value * x[col_ix as usize];
+    }
+    (sums[0] + sums[1]) + (sums[2] + sums[3])
+}
+
+/// `y[col_indices[i]] += alpha * values[i]`; the sparse equivalent of `Float::axpy` with a row of the matrix as `x`.
+fn sparse_scatter_axpy<T: Float>(y: &mut [T], alpha: T, col_indices: &[u32], values: &[T]) {
+    debug_assert_eq!(col_indices.len(), values.len());
+    for (&col_ix, &value) in col_indices.iter().zip(values) {
+        y[col_ix as usize] += alpha * value;
+    }
+}
+
+/// `values[i] += alpha * x[col_indices[i]]`; updates only the stored entries of a row.
+fn sparse_gather_axpy<T: Float>(values: &mut [T], alpha: T, col_indices: &[u32], x: &[T]) {
+    debug_assert_eq!(col_indices.len(), values.len());
+    for (value, &col_ix) in values.iter_mut().zip(col_indices) {
+        *value += alpha * x[col_ix as usize];
+    }
+}
+
+/// The sparse equivalent of a `DenseLayer` or an `OutputLayer`.  Weights that weren't stored when the layer was
+/// converted stay at zero through training.
+pub struct SparseLayer<T: Float = Weight> {
+    pub weights: SparseMatrix<T>,
+    /// Empty for layers converted from an `OutputLayer`, which don't have biases.
+    pub biases: Vec<T>,
+    pub activation_fn: &'static dyn ActivationFunction<T>,
+    pub neuron_gradients: Vec<T>,
+    pub errors_scratch: Vec<T>,
+    pub outputs_before_activation: Vec<T>,
+    pub outputs: Vec<T>,
+    /// Same as `DenseLayer::learning_rate_multiplier`.
+    pub learning_rate_multiplier: T,
+    /// Same as `DenseLayer::frozen`.
+    pub frozen: bool,
+}
+
+impl<T: Float> SparseLayer<T> {
+    fn new(weights: SparseMatrix<T>, biases: Vec<T>, activation_fn: &'static dyn ActivationFunction<T>) -> Self {
+        let neuron_count = weights.row_count();
+        SparseLayer {
+            weights,
+            biases,
+            activation_fn,
+            neuron_gradients: vec![T::ZERO; neuron_count],
+            errors_scratch: vec![T::ZERO; neuron_count],
+            outputs_before_activation: vec![T::ZERO; neuron_count],
+            outputs: vec![T::ZERO; neuron_count],
+            learning_rate_multiplier: T::ONE,
+            frozen: false,
+        }
+    }
+
+    /// Drops every weight with a magnitude less than or equal to `threshold`.
+    pub fn from_dense_layer(layer: &DenseLayer<T>, threshold: T) -> Self {
+        SparseLayer {
+            learning_rate_multiplier: layer.learning_rate_multiplier,
+            frozen: layer.frozen,
+            ..Self::new(
+                SparseMatrix::from_dense(&layer.weights, threshold),
+                layer.biases.clone(),
+                layer.activation_fn,
+            )
+        }
+    }
+
+    /// Same as `from_dense_layer`.  The cost function stays with the `SparseNetwork`.
+    pub fn from_output_layer(layer: &OutputLayer<T>, threshold: T) -> Self {
+        SparseLayer {
+            learning_rate_multiplier: layer.learning_rate_multiplier,
+            frozen: layer.frozen,
+            ..Self::new(
+                SparseMatrix::from_dense(&layer.weights, threshold),
+                Vec::new(),
+                layer.activation_fn,
+            )
+        }
+    }
+
+    pub fn input_count(&self) -> usize { self.weights.col_count() }
+
+    pub fn neuron_count(&self) -> usize { self.weights.row_count() }
+
+    /// Same as `DenseLayer::layer_learning_rate`.
+    pub fn layer_learning_rate(&self, learning_rate: T) -> T { learning_rate * self.learning_rate_multiplier }
+
+    pub fn forward_propagate(&mut self, inputs: &[T]) {
+        debug_assert_eq!(self.weights.col_count(), inputs.len());
+        for (neuron_ix, output) in self.outputs_before_activation.iter_mut().enumerate() {
+            let (col_indices, values) = self.weights.row(neuron_ix);
+            *output = sparse_dot(col_indices, values, inputs);
+            if let Some(&bias) = self.biases.get(neuron_ix) {
+                *output += bias;
+            }
+        }
+
+        (self.activation_fn).apply_batch(&mut self.outputs, &self.outputs_before_activation);
+    }
+
+    /// Same as `DenseLayer::compute_gradients`, using the weights and gradients of the layer this one feeds into.
+    pub fn compute_gradients(&mut self, next_layer: &SparseLayer<T>) {
+        debug_assert_eq!(next_layer.input_count(), self.neuron_count());
+
+        // Multiply the next layer's gradients by the transpose of its weights, one row at a time.
+        self.errors_scratch.fill(T::ZERO);
+        for (output_neuron_ix, &gradient_of_output_neuron) in next_layer.neuron_gradients.iter().enumerate() {
+            let (col_indices, values) = next_layer.weights.row(output_neuron_ix);
+            sparse_scatter_axpy(&mut self.errors_scratch, gradient_of_output_neuron, col_indices, values);
+        }
+
+        (self.activation_fn).apply_derivative_batch(
+            &mut self.neuron_gradients,
+            &self.errors_scratch,
+            &self.outputs_before_activation,
+        );
+    }
+
+    /// Same as `DenseLayer::update_weights`.
+    pub fn update_weights(&mut self, inputs: &[T], learning_rate: T) {
+        if self.frozen {
+            return;
+        }
+        let learning_rate = self.layer_learning_rate(learning_rate);
+        for (neuron_ix, &neuron_gradient) in self.neuron_gradients.iter().enumerate() {
+            let (col_indices, values) = self.weights.row_mut(neuron_ix);
+            sparse_gather_axpy(values, learning_rate * neuron_gradient, col_indices, inputs);
+        }
+    }
+
+    /// Same as `DenseLayer::update_biases`.
+    pub fn update_biases(&mut self, learning_rate: T) {
+        if self.frozen || self.biases.is_empty() {
+            return;
+        }
+        let learning_rate = self.layer_learning_rate(learning_rate);
+        T::axpy(&mut self.biases, learning_rate, &self.neuron_gradients);
+    }
+}
+
+/// A `Network` with all of its layers converted to `SparseLayer`s.  Supports the same single-example training and
+/// inference as the dense network.
+pub struct SparseNetwork<T: Float = Weight> {
+    pub hidden_layers: Vec<SparseLayer<T>>,
+    pub outputs: SparseLayer<T>,
+    pub cost_fn: &'static dyn CostFunction<T>,
+    costs: Vec<T>,
+}
+
+impl<T: Float> SparseNetwork<T> {
+    /// Converts every layer of `network`, dropping weights with a magnitude less than or equal to `threshold`.  Returns
+    /// an error if `network` has feature layers.
+    pub fn from_network(network: &Network<T>, threshold: T) -> Result<Self, NnError> {
+        if !network.feature_layers.is_empty() {
+            return Err(NnError::UnsupportedFeatureLayers);
+        }
+        let output_count = network.output_count();
+        Ok(SparseNetwork {
+            hidden_layers: network
+                .hidden_layers
+                .iter()
+                .map(|layer| SparseLayer::from_dense_layer(layer, threshold))
+                .collect(),
+            outputs: SparseLayer::from_output_layer(&network.outputs, threshold),
+            cost_fn: network.outputs.cost_fn,
+            costs: vec![T::ZERO; output_count],
+        })
+    }
+
+    /// Converts back to a dense network.  The weights that aren't stored are masked out so that they stay at zero if
+    /// the dense network is trained further, and every layer keeps its learning rate multiplier and whether it's
+    /// frozen.
+    pub fn to_network(&self, learning_rate: T) -> Network<T> {
+        let hidden_layers = self
+            .hidden_layers
+            .iter()
+            .map(|layer| {
+                let mut dense = DenseLayer::new(
+                    layer.neuron_count(),
+                    layer.input_count(),
+                    &mut |_, _| T::ZERO,
+                    &mut |neuron_ix| layer.biases[neuron_ix],
+                    layer.activation_fn,
+                );
+                dense.weights = layer.weights.to_dense();
+                dense.weight_mask = Some(layer.weights.mask());
+                dense.learning_rate_multiplier = layer.learning_rate_multiplier;
+                dense.frozen = layer.frozen;
+                dense
+            })
+            .collect();
+        let mut outputs = OutputLayer::new(
+            self.outputs.activation_fn,
+            self.cost_fn,
+            &mut |_, _| T::ZERO,
+            self.outputs.input_count(),
+            self.outputs.neuron_count(),
+        );
+        outputs.weights = self.outputs.weights.to_dense();
+        outputs.weight_mask = Some(self.outputs.weights.mask());
+        outputs.learning_rate_multiplier = self.outputs.learning_rate_multiplier;
+        outputs.frozen = self.outputs.frozen;
+
+        Network {
+            feature_layers: Vec::new(),
+            hidden_layers,
+            outputs: Box::new(outputs),
+            learning_rate,
+        }
+    }
+
+    pub fn input_count(&self) -> usize {
+        match self.hidden_layers.first() {
+            Some(layer) => layer.input_count(),
+            None => self.outputs.input_count(),
+        }
+    }
+
+    pub fn output_count(&self) -> usize { self.outputs.neuron_count() }
+
+    /// The fraction of the weights of the whole network that are stored.
+    pub fn density(&self) -> f32 {
+        let (stored, total) =
+            self.hidden_layers
+                .iter()
+                .chain(std::iter::once(&self.outputs))
+                .fold((0, 0), |(stored, total), layer| {
+                    (
+                        stored + layer.weights.stored_count(),
+                        total + layer.weights.row_count() * layer.weights.col_count(),
+                    )
+                });
+        if total == 0 {
+            return 0.;
+        }
+        stored as f32 / total as f32
+    }
+
+    fn validate_example(&self, example: &[T], expected: &[T]) -> Result<(), NnError> {
+        if example.len() != self.input_count() {
+            return Err(NnError::InvalidInputLength {
+                expected: self.input_count(),
+                actual: example.len(),
+            });
+        }
+        if expected.len() != self.output_count() {
+            return Err(NnError::InvalidTargetLength {
+                expected: self.output_count(),
+                actual: expected.len(),
+            });
+        }
+        Ok(())
+    }
+
+    fn forward_propagate_unchecked(&mut self, inputs: &[T]) {
+        let mut inputs = inputs;
+        for layer in &mut self.hidden_layers {
+            layer.forward_propagate(inputs);
+            inputs = &layer.outputs;
+        }
+
+        self.outputs.forward_propagate(inputs);
+    }
+
+    pub fn compute<'a>(&'a mut self, inputs: &[T]) -> Result<&'a [T], NnError> {
+        if inputs.len() != self.input_count() {
+            return Err(NnError::InvalidInputLength {
+                expected: self.input_count(),
+                actual: inputs.len(),
+            });
+        }
+        self.forward_propagate_unchecked(inputs);
+        Ok(&self.outputs.outputs)
+    }
+
+    /// Same as `Network::train_one_example`, except that `learning_rate` is used for both weights and biases.  Returns
+    /// the average cost of the output before updating weights.
+    pub fn train_one_example(&mut self, example: &[T], expected: &[T], learning_rate: T) -> Result<T, NnError> {
+        self.validate_example(example, expected)?;
+        self.forward_propagate_unchecked(example);
+
+        // Same as `OutputLayer::compute_costs` followed by `OutputLayer::compute_gradients`
+        for (neuron_ix, &output) in self.outputs.outputs.iter().enumerate() {
+            let error = expected[neuron_ix] - output;
+            self.costs[neuron_ix] = self.cost_fn.get_cost(error);
+            self.outputs.neuron_gradients[neuron_ix] = self.cost_fn.derivative(error)
+                * (self.outputs.activation_fn).derivative(self.outputs.outputs_before_activation[neuron_ix]);
+        }
+
+        for layer_ix in (0..self.hidden_layers.len()).rev() {
+            let (layers, next_layers) = self.hidden_layers.split_at_mut(layer_ix + 1);
+            let next_layer = next_layers.first().unwrap_or(&self.outputs);
+            layers[layer_ix].compute_gradients(next_layer);
+        }
+
+        let inputs = self
+            .hidden_layers
+            .last()
+            .map_or(example, |layer| layer.outputs.as_slice());
+        self.outputs.update_weights(inputs, learning_rate);
+        for layer_ix in (0..self.hidden_layers.len()).rev() {
+            let (previous_layers, layers) = self.hidden_layers.split_at_mut(layer_ix);
+            let inputs = previous_layers.last().map_or(example, |layer| layer.outputs.as_slice());
+            layers[0].update_weights(inputs, learning_rate);
+            layers[0].update_biases(learning_rate);
+        }
+
+        let total_cost = self.costs.iter().fold(T::ZERO, |acc, cost| acc + *cost);
+        Ok(total_cost / T::from_f64(self.costs.len() as f64))
+    }
+
+    /// The number of bytes used to store the weights and biases.
+    pub fn parameter_bytes(&self) -> usize {
+        self.hidden_layers
+            .iter()
+            .chain(std::iter::once(&self.outputs))
+            .map(|layer| layer.weights.parameter_bytes() + layer.biases.len() * std::mem::size_of::<T>())
+            .sum()
+    }
+}
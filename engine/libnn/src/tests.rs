@@ -7,13 +7,16 @@ fn test_dense_layer_forward_propagation() {
     let inputs = &[1.2, -2.0];
 
     let mut dense_layer = DenseLayer {
-        weights: vec![vec![-1.2, 0.4], vec![2.0, -1.0]],
+        weights: WeightMatrix::from_rows(&[[-1.2, 0.4], [2.0, -1.0]]),
         biases: vec![1.0, -2.0],
         neuron_gradients: vec![0.; 2],
         activation_fn: &Sigmoid,
         outputs_before_activation: vec![0., 0.],
         errors_scratch: vec![0., 0.],
         outputs: vec![0., 0.],
+        weight_mask: None,
+        learning_rate_multiplier: 1.,
+        frozen: false,
     };
 
     let sigmoid = Sigmoid;
@@ -30,7 +33,7 @@ fn test_output_layer_forward_propagation() {
     let inputs = &[1.2, -2.0];
 
     let mut output_layer = OutputLayer {
-        weights: vec![vec![-1.2, 0.4], vec![2.0, -1.0]],
+        weights: WeightMatrix::from_rows(&[[-1.2, 0.4], [2.0, -1.0]]),
         neuron_gradients: vec![0.; 2],
         activation_fn: &Sigmoid,
         outputs_before_activation: vec![0., 0.],
@@ -38,6 +41,9 @@ fn test_output_layer_forward_propagation() {
         errors: vec![0., 0.],
         costs: vec![0., 0.],
         cost_fn: &MeanSquaredError,
+        weight_mask: None,
+        learning_rate_multiplier: 1.,
+        frozen: false,
     };
 
     let sigmoid = Sigmoid;
@@ -52,17 +58,21 @@ fn test_output_layer_forward_propagation() {
 #[test]
 fn test_forward_propagation() {
     let mut network: Network = Network {
+        feature_layers: Vec::new(),
         hidden_layers: vec![DenseLayer {
-            weights: vec![vec![-1.2, 0.4], vec![2.0, -1.0]],
+            weights: WeightMatrix::from_rows(&[[-1.2, 0.4], [2.0, -1.0]]),
             biases: vec![1.0, -2.0],
             neuron_gradients: vec![0.; 2],
             activation_fn: &Sigmoid,
             errors_scratch: vec![0., 0.],
             outputs_before_activation: vec![0., 0.],
             outputs: vec![0., 0.],
+            weight_mask: None,
+            learning_rate_multiplier: 1.,
+            frozen: false,
         }],
         outputs: Box::new(OutputLayer {
-            weights: vec![vec![-1.2, 0.4], vec![2.0, -1.0]],
+            weights: WeightMatrix::from_rows(&[[-1.2, 0.4], [2.0, -1.0]]),
             neuron_gradients: vec![0.; 2],
             activation_fn: &Sigmoid,
             outputs_before_activation: vec![0., 0.],
@@ -70,6 +80,9 @@ fn test_forward_propagation() {
             errors: vec![0., 0.],
             costs: vec![0., 0.],
             cost_fn: &MeanSquaredError,
+            weight_mask: None,
+            learning_rate_multiplier: 1.,
+            frozen: false,
         }),
         learning_rate: 0.2,
     };
@@ -90,7 +103,7 @@ fn test_forward_propagation() {
 #[test]
 fn test_error_computation() {
     let mut output_layer = OutputLayer {
-        weights: vec![vec![-1.2, 0.4], vec![2.0, -1.0]],
+        weights: WeightMatrix::from_rows(&[[-1.2, 0.4], [2.0, -1.0]]),
         neuron_gradients: vec![0.; 2],
         activation_fn: &Sigmoid,
         outputs_before_activation: vec![0., 0.],
@@ -98,6 +111,9 @@ fn test_error_computation() {
         errors: vec![0., 0.],
         costs: vec![0., 0.],
         cost_fn: &MeanSquaredError,
+        weight_mask: None,
+        learning_rate_multiplier: 1.,
+        frozen: false,
     };
 
     let actual_values = &[0.0, 1.0];
@@ -117,7 +133,7 @@ fn test_single_neuron_weight_updating() {
     let desired_outputs = &[0.];
 
     let mut output_layer = OutputLayer {
-        weights: vec![vec![-0.2, 0.9]],
+        weights: WeightMatrix::from_rows(&[[-0.2, 0.9]]),
         neuron_gradients: vec![0.],
         activation_fn: &Sigmoid,
         outputs: vec![0.0],
@@ -125,6 +141,9 @@ fn test_single_neuron_weight_updating() {
         errors: vec![0.],
         costs: vec![0.],
         cost_fn: &MeanSquaredError,
+        weight_mask: None,
+        learning_rate_multiplier: 1.,
+        frozen: false,
     };
 
     // Run forward once with initial random weights and compute our costs
@@ -167,7 +186,7 @@ fn test_single_neuron_weight_updating() {
 #[test]
 fn test_weight_updating() {
     let mut output_layer = OutputLayer {
-        weights: vec![vec![-1.2, 0.4], vec![2.0, -1.0]],
+        weights: WeightMatrix::from_rows(&[[-1.2, 0.4], [2.0, -1.0]]),
         neuron_gradients: vec![0.; 2],
         activation_fn: &Sigmoid,
         outputs: vec![-0.2, 2.4],
@@ -175,6 +194,9 @@ fn test_weight_updating() {
         errors: vec![0., 0.],
         costs: vec![0., 0.],
         cost_fn: &MeanSquaredError,
+        weight_mask: None,
+        learning_rate_multiplier: 1.,
+        frozen: false,
     };
 
     // Run forward once with initial random weights and compute our costs
@@ -210,13 +232,16 @@ fn test_weight_updating() {
 #[test]
 fn test_hidden_layer_single_weight_updating() {
     let mut dense_layer = DenseLayer {
-        weights: vec![vec![1.0]],
+        weights: WeightMatrix::from_rows(&[[1.0]]),
         biases: vec![0.0],
         neuron_gradients: vec![0.],
         errors_scratch: vec![0.],
         activation_fn: &Identity,
         outputs_before_activation: vec![0.],
         outputs: vec![0.],
+        weight_mask: None,
+        learning_rate_multiplier: 1.,
+        frozen: false,
     };
 
     // Run forward once with initial random weights and compute our costs
@@ -224,7 +249,7 @@ fn test_hidden_layer_single_weight_updating() {
     let _outputs = &[0.];
     dense_layer.forward_propagate(inputs);
 
-    let output_weights = &[vec![1.]];
+    let output_weights = &WeightMatrix::from_rows(&[[1.]]);
     // Gradient is calculated for an output layer with an identity activation function and an
     // expected output of 0 which yields an error of -1 and a gradient of -2.
     let fake_output_gradients = &[-2.];
@@ -254,13 +279,16 @@ fn test_hidden_layer_single_weight_updating() {
 #[test]
 fn test_hidden_layer_single_neuron_bias_updating() {
     let mut dense_layer = DenseLayer {
-        weights: vec![vec![1.0]],
+        weights: WeightMatrix::from_rows(&[[1.0]]),
         biases: vec![0.0],
         neuron_gradients: vec![0.],
         activation_fn: &Identity,
         errors_scratch: vec![0.],
         outputs_before_activation: vec![0.],
         outputs: vec![0.],
+        weight_mask: None,
+        learning_rate_multiplier: 1.,
+        frozen: false,
     };
 
     // Run forward once with initial random weights and compute our costs
@@ -268,7 +296,7 @@ fn test_hidden_layer_single_neuron_bias_updating() {
     let _outputs = &[0.];
     dense_layer.forward_propagate(inputs);
 
-    let output_weights = &[vec![1.]];
+    let output_weights = &WeightMatrix::from_rows(&[[1.]]);
     // Gradient is calculated for an output layer with an identity activation function and an
     // expected output of 0 which yields an error of -1 and a gradient of -2.
     let fake_output_gradients = &[-2.];
@@ -301,6 +329,7 @@ fn test_most_basic_full_neural_net_training() {
     // We use a miniscule learning rate due to the huge input values.
     let learning_rate = 0.005;
     let mut network: Network = Network {
+        feature_layers: Vec::new(),
         hidden_layers: vec![DenseLayer::new(
             hidden_layer_neuron_count,
             INPUT_COUNT,
@@ -336,10 +365,7 @@ fn test_most_basic_full_neural_net_training() {
     assert_eq!(network.outputs.neuron_gradients[0], -20.);
 
     // Compute gradients for the hidden layer
-    network.hidden_layers[0].compute_gradients(
-        network.outputs.weights.as_slice(),
-        network.outputs.neuron_gradients.as_slice(),
-    );
+    network.hidden_layers[0].compute_gradients(&network.outputs.weights, network.outputs.neuron_gradients.as_slice());
 
     // Actually update output layer weights using the computed gradient and output from the hidden
     // layer.
@@ -404,6 +430,7 @@ fn test_learns_to_always_output_1() {
     let mut rng = pcg::Pcg::default();
 
     let mut network: Network = Network {
+        feature_layers: Vec::new(),
         hidden_layers: vec![DenseLayer::new(
             1,
             INPUT_COUNT,
@@ -467,6 +494,7 @@ fn test_multiplies_inputs() {
     let hidden_layer_neuron_count = 8;
 
     let mut network: Network = Network {
+        feature_layers: Vec::new(),
         hidden_layers: vec![
             DenseLayer::new(
                 hidden_layer_neuron_count,
@@ -548,6 +576,7 @@ fn test_complex_network_application() {
     let mut init_biases = |_| 0.;
 
     let mut network: Network = Network {
+        feature_layers: Vec::new(),
         hidden_layers: vec![
             DenseLayer::new(
                 hidden_layer_neuron_count,
@@ -586,3 +615,2705 @@ fn test_complex_network_application() {
     assert_eq!(network.outputs.outputs_before_activation[0], -2. * 0.5);
     assert_eq!(network.outputs.outputs[0], (-1.0f32).tanh());
 }
+
+fn build_diverging_network() -> Network {
+    // Identity activations with a gigantic learning rate will blow the weights up to infinity after a couple of
+    // steps.
+    let learning_rate = 1e30;
+    Network {
+        feature_layers: Vec::new(),
+        hidden_layers: vec![DenseLayer::new(1, 1, &mut |_, _| 1., &mut |_| 0., &Identity)],
+        outputs: Box::new(OutputLayer::new(&Identity, &MeanSquaredError, &mut |_, _| 1., 1, 1)),
+        learning_rate,
+    }
+}
+
+#[test]
+fn test_divergence_is_reported() {
+    let mut network = build_diverging_network();
+    let learning_rate = network.learning_rate;
+
+    let mut err = None;
+    for _ in 0..10 {
+        if let Err(e) = network.try_train_one_example(&[5.], &[-5.], learning_rate) {
+            err = Some(e);
+            break;
+        }
+    }
+
+    match err {
+        Some(NnError::NonFiniteCost { .. }) | Some(NnError::NonFiniteWeights { .. }) => (),
+        other => panic!("Expected divergence to be detected, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_divergence_rollback_restores_weights() {
+    let mut network = build_diverging_network();
+    let learning_rate = network.learning_rate;
+    let mut scratch = NetworkSnapshot::default();
+
+    for _ in 0..10 {
+        let before = network.snapshot();
+        match network.try_train_one_example_with_rollback(&[5.], &[-5.], learning_rate, &mut scratch) {
+            Ok(_) => continue,
+            Err(_) => {
+                assert!(network.check_weights_finite().is_ok());
+                assert_eq!(before.hidden_layer_weights, vec![network.hidden_layers[0]
+                    .weights
+                    .clone()]);
+                assert_eq!(before.hidden_layer_biases, vec![network.hidden_layers[0]
+                    .biases
+                    .clone()]);
+                assert_eq!(before.output_weights, network.outputs.weights);
+                return;
+            },
+        }
+    }
+
+    panic!("Expected training to diverge");
+}
+
+#[test]
+fn test_checked_entry_points_validate_dimensions() {
+    let mut network = Network {
+        feature_layers: Vec::new(),
+        hidden_layers: vec![DenseLayer::new(3, 2, &mut |_, _| 0.5, &mut |_| 0., &Identity)],
+        outputs: Box::new(OutputLayer::new(&Identity, &MeanSquaredError, &mut |_, _| 0.5, 3, 1)),
+        learning_rate: 0.1,
+    };
+    assert_eq!(network.input_count(), 2);
+    assert_eq!(network.output_count(), 1);
+
+    assert_eq!(network.try_compute(&[1.]).unwrap_err(), NnError::InvalidInputLength {
+        expected: 2,
+        actual: 1
+    });
+    assert_eq!(
+        network.try_train_one_example(&[1., 2., 3.], &[1.], 0.1).unwrap_err(),
+        NnError::InvalidInputLength { expected: 2, actual: 3 }
+    );
+    assert_eq!(
+        network.try_train_one_example(&[1., 2.], &[1., 2.], 0.1).unwrap_err(),
+        NnError::InvalidTargetLength { expected: 1, actual: 2 }
+    );
+
+    assert_eq!(network.try_compute(&[1., 2.]).unwrap(), &[1.5 * 0.5 * 3.]);
+    assert!(network.try_train_one_example(&[1., 2.], &[1.], 0.1).is_ok());
+}
+
+#[test]
+#[should_panic]
+fn test_unchecked_compute_panics_on_wrong_input_length() {
+    let mut network = Network {
+        feature_layers: Vec::new(),
+        hidden_layers: vec![DenseLayer::new(3, 2, &mut |_, _| 0.5, &mut |_| 0., &Identity)],
+        outputs: Box::new(OutputLayer::new(&Identity, &MeanSquaredError, &mut |_, _| 0.5, 3, 1)),
+        learning_rate: 0.1,
+    };
+    network.compute(&[1.]);
+}
+
+fn build_single_neuron_network(learning_rate: Weight) -> Network {
+    Network {
+        feature_layers: Vec::new(),
+        hidden_layers: vec![DenseLayer::new(1, 1, &mut |_, _| 0.5, &mut |_| 0., &Identity)],
+        outputs: Box::new(OutputLayer::new(&Identity, &MeanSquaredError, &mut |_, _| 0.5, 1, 1)),
+        learning_rate,
+    }
+}
+
+#[test]
+fn test_early_stopping_runs_out_of_patience() {
+    // With a learning rate of zero the validation cost will never improve
+    let mut network = build_single_neuron_network(0.);
+    let config = EarlyStoppingConfig {
+        eval_interval: 10,
+        patience: 3,
+        min_delta: 0.,
+        max_iterations: 1_000,
+    };
+
+    let report = network
+        .train_with_early_stopping(&[1., 2.], &[1., 1.], &[1.5], &[1.], 0., &config)
+        .unwrap();
+    assert_eq!(report.stop_reason, StopReason::PatienceExhausted);
+    assert_eq!(report.iterations, 30);
+    assert_eq!(report.best_iteration, 0);
+    assert_eq!(report.validation_costs.len(), 4);
+}
+
+#[test]
+fn test_early_stopping_restores_best_weights() {
+    // The validation set wants the opposite of what the network is being trained to do, so the validation cost only
+    // gets worse as training goes on and the initial weights should be restored at the end.
+    let mut network = build_single_neuron_network(0.01);
+    let initial_weights = network.snapshot();
+    let config = EarlyStoppingConfig {
+        eval_interval: 5,
+        patience: 4,
+        min_delta: 0.,
+        max_iterations: 1_000,
+    };
+
+    let report = network
+        .train_with_early_stopping(&[1.], &[1.], &[1.], &[-1.], 0.01, &config)
+        .unwrap();
+    assert_eq!(report.stop_reason, StopReason::PatienceExhausted);
+    assert_eq!(report.best_iteration, 0);
+    assert_eq!(
+        network.snapshot().hidden_layer_weights,
+        initial_weights.hidden_layer_weights
+    );
+    assert_eq!(network.snapshot().output_weights, initial_weights.output_weights);
+    let validation = InMemoryDataset::new(1, 1, vec![1.], vec![-1.]).unwrap();
+    assert_eq!(
+        network.evaluate_dataset(&validation).unwrap(),
+        report.best_validation_cost
+    );
+}
+
+#[test]
+fn test_early_stopping_keeps_improving_weights() {
+    let mut network = build_single_neuron_network(0.05);
+    let config = EarlyStoppingConfig {
+        eval_interval: 7,
+        patience: 5,
+        min_delta: 0.,
+        max_iterations: 200,
+    };
+
+    let report = network
+        .train_with_early_stopping(&[0.5, 1., 1.5], &[1., 2., 3.], &[2.], &[4.], 0.05, &config)
+        .unwrap();
+    assert_eq!(report.stop_reason, StopReason::MaxIterations);
+    assert!(report.best_iteration > 0);
+    assert!(report.best_validation_cost < report.validation_costs[0]);
+    let validation = InMemoryDataset::new(1, 1, vec![2.], vec![4.]).unwrap();
+    assert_eq!(
+        network.evaluate_dataset(&validation).unwrap(),
+        report.best_validation_cost
+    );
+}
+
+#[test]
+fn test_early_stopping_rejects_empty_validation_set() {
+    // Otherwise the empty validation set would count as a perfect score for the untrained weights, which would be
+    // restored at the end
+    let mut network = build_single_neuron_network(0.05);
+    let config = EarlyStoppingConfig {
+        eval_interval: 1,
+        patience: 1,
+        min_delta: 0.,
+        max_iterations: 10,
+    };
+
+    assert_eq!(
+        network
+            .train_with_early_stopping(&[1.], &[2.], &[], &[], 0.05, &config)
+            .err(),
+        Some(NnError::EmptyDataset)
+    );
+    let empty = InMemoryDataset::empty(1, 1);
+    assert_eq!(network.evaluate_dataset(&empty), Err(NnError::EmptyDataset));
+}
+
+fn build_counting_dataset(len: usize) -> InMemoryDataset {
+    // Each example's input is its index and its target is double that, which makes it easy to check that inputs and
+    // targets stay paired up.
+    let inputs = (0..len).map(|i| i as Weight).collect();
+    let targets = (0..len).map(|i| i as Weight * 2.).collect();
+    InMemoryDataset::new(1, 1, inputs, targets).unwrap()
+}
+
+#[test]
+fn test_dataset_rejects_mismatched_buffers() {
+    assert!(InMemoryDataset::new(2, 1, vec![1., 2., 3.], vec![1.]).is_err());
+    assert!(InMemoryDataset::new(2, 1, vec![1., 2., 3., 4.], vec![1.]).is_err());
+    assert_eq!(
+        InMemoryDataset::new(2, 1, vec![1., 2., 3., 4.], vec![1., 2.])
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn test_dataset_shuffled_batches_cover_every_example() {
+    let dataset = build_counting_dataset(10);
+    let mut rng = pcg::Pcg::default();
+
+    let batches = dataset.shuffled_batches(3, &mut rng);
+    assert_eq!(batches.batch_count(), 4);
+    let batch_sizes: Vec<usize> = batches.iter().map(|batch| batch.len()).collect();
+    assert_eq!(batch_sizes, vec![3, 3, 3, 1]);
+
+    let mut seen = Vec::new();
+    for batch in batches.iter() {
+        for (inputs, targets) in batch.iter() {
+            assert_eq!(targets[0], inputs[0] * 2.);
+            seen.push(inputs[0] as usize);
+        }
+    }
+    assert_ne!(seen, (0..10).collect::<Vec<_>>());
+    seen.sort_unstable();
+    assert_eq!(seen, (0..10).collect::<Vec<_>>());
+
+    let mut shuffled = dataset.clone();
+    shuffled.shuffle(&mut rng);
+    assert_ne!(shuffled, dataset);
+    for ix in 0..shuffled.len() {
+        let (inputs, targets) = shuffled.get(ix);
+        assert_eq!(targets[0], inputs[0] * 2.);
+    }
+}
+
+#[test]
+fn test_dataset_splits_and_folds() {
+    let dataset = build_counting_dataset(10);
+
+    let splits = dataset.split(0.6, 0.2);
+    assert_eq!(splits.train.inputs(), &[0., 1., 2., 3., 4., 5.]);
+    assert_eq!(splits.validation.inputs(), &[6., 7.]);
+    assert_eq!(splits.test.inputs(), &[8., 9.]);
+    assert_eq!(splits.test.targets(), &[16., 18.]);
+
+    let mut validation_examples = Vec::new();
+    let mut fold_count = 0;
+    for (train, validation) in dataset.k_folds(3) {
+        assert_eq!(train.len() + validation.len(), 10);
+        assert!(validation.len() == 3 || validation.len() == 4);
+        for ix in 0..validation.len() {
+            assert!(!train.inputs().contains(&validation.get(ix).0[0]));
+        }
+        validation_examples.extend_from_slice(validation.inputs());
+        fold_count += 1;
+    }
+    assert_eq!(fold_count, 3);
+    assert_eq!(validation_examples, dataset.inputs());
+}
+
+#[test]
+fn test_fit_reduces_cost() {
+    let mut rng = pcg::Pcg::default();
+    let mut dataset = InMemoryDataset::empty(2, 1);
+    for _ in 0..200 {
+        let a = rng.gen_range(0., 1.);
+        let b = rng.gen_range(0., 1.);
+        dataset.push(&[a, b], &[a + b]).unwrap();
+    }
+
+    let mut network = build_single_neuron_network(0.05);
+    assert!(network.fit(&dataset, 1, &mut rng).is_err());
+
+    let mut network = Network {
+        feature_layers: Vec::new(),
+        hidden_layers: vec![DenseLayer::new(
+            4,
+            2,
+            &mut |_, _| rng.gen_range(-0.5, 0.5),
+            &mut |_| 0.,
+            &Identity,
+        )],
+        outputs: Box::new(OutputLayer::new(&Identity, &MeanSquaredError, &mut |_, _| 0.5, 4, 1)),
+        learning_rate: 0.05,
+    };
+    let initial_cost = network.evaluate_dataset(&dataset).unwrap();
+    let epoch_costs = network.fit(&dataset, 10, &mut rng).unwrap();
+    assert_eq!(epoch_costs.len(), 10);
+    assert!(network.evaluate_dataset(&dataset).unwrap() < initial_cost * 0.01);
+}
+
+#[test]
+fn test_read_csv_selects_columns() {
+    let csv = "id,\"size, sq ft\",rooms,price\n\nhouse-1, 1200, 3, 250.5\nhouse-2,800,2,180\n";
+    let data = read_csv(
+        csv.as_bytes(),
+        &CsvOptions::default(),
+        Some(&["size, sq ft".parse().unwrap(), ColumnSelector::Index(2)]),
+        &["price".parse().unwrap()],
+    )
+    .unwrap();
+    assert_eq!(data.feature_names, vec!["size, sq ft".to_owned(), "rooms".to_owned()]);
+    assert_eq!(data.target_names, vec!["price".to_owned()]);
+    assert_eq!(data.dataset.inputs(), &[1200., 3., 800., 2.]);
+    assert_eq!(data.dataset.targets(), &[250.5, 180.]);
+
+    // Without a header, the first line is data and every non-target column is a feature by default
+    let options = CsvOptions {
+        has_header: false,
+        delimiter: ';',
+    };
+    let data = read_csv("1;2;3\n4;5;6".as_bytes(), &options, None, &[ColumnSelector::Index(0)]).unwrap();
+    assert_eq!(data.feature_names, vec!["1".to_owned(), "2".to_owned()]);
+    assert_eq!(data.dataset.inputs(), &[2., 3., 5., 6.]);
+    assert_eq!(data.dataset.targets(), &[1., 4.]);
+}
+
+#[test]
+fn test_read_csv_reports_errors() {
+    let options = CsvOptions::default();
+    let res = read_csv("a,b\n1,2\n\n3,x\n".as_bytes(), &options, None, &["b".parse().unwrap()]);
+    match res {
+        Err(NnError::Csv { line: 4, message }) => assert!(message.contains("\"x\"")),
+        other => panic!("expected a parse error on line 4; got {:?}", other),
+    }
+
+    let res = read_csv("a,b\n1,2,3\n".as_bytes(), &options, None, &["b".parse().unwrap()]);
+    assert!(matches!(res, Err(NnError::Csv { line: 2, .. })));
+
+    let res = read_csv("a,b\n1,2\n".as_bytes(), &options, None, &["c".parse().unwrap()]);
+    assert_eq!(res.unwrap_err(), NnError::UnknownColumn("c".to_owned()));
+
+    let res = read_csv("a,b\n1,2\n".as_bytes(), &options, None, &[ColumnSelector::Index(2)]);
+    assert!(matches!(res, Err(NnError::Csv { line: 1, .. })));
+}
+
+#[test]
+fn test_read_idx() {
+    // Two 2x3 unsigned byte "images"
+    let mut bytes = vec![0, 0, 0x08, 3, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 3];
+    bytes.extend_from_slice(&[0, 1, 2, 3, 4, 5, 255, 254, 253, 252, 251, 250]);
+    let images = read_idx(bytes.as_slice()).unwrap();
+    assert_eq!(images.dims, vec![2, 2, 3]);
+    assert_eq!(images.len(), 2);
+    assert_eq!(images.entry_size(), 6);
+    assert_eq!(images.data[6], 255.);
+
+    let labels = read_idx([0u8, 0, 0x08, 1, 0, 0, 0, 2, 3, 0].as_slice()).unwrap();
+    let dataset = idx_classification_dataset(&images, &labels, 4, 1. / 255.).unwrap();
+    assert_eq!(dataset.len(), 2);
+    assert_eq!(dataset.get(0).1, &[0., 0., 0., 1.]);
+    assert_eq!(dataset.get(1).0[0], 1.);
+    assert!(idx_classification_dataset(&images, &labels, 3, 1.).is_err());
+
+    let floats = read_idx([0u8, 0, 0x0D, 1, 0, 0, 0, 1, 0x3f, 0xc0, 0, 0].as_slice()).unwrap();
+    assert_eq!(floats.data, vec![1.5]);
+
+    assert!(matches!(
+        read_idx([0u8, 0, 0x08, 1, 0, 0, 0, 3, 1].as_slice()),
+        Err(NnError::InvalidIdx(_))
+    ));
+    assert!(matches!(
+        read_idx([0x1fu8, 0x8b, 0x08, 0].as_slice()),
+        Err(NnError::InvalidIdx(_))
+    ));
+}
+
+#[test]
+fn test_softmax_outputs_sum_to_one() {
+    let mut outputs = [0.; 3];
+    SOFTMAX.apply_batch(&mut outputs, &[1000., 1000., 998.]);
+    assert!((outputs.iter().sum::<Weight>() - 1.).abs() < 1e-6);
+    assert!((outputs[0] - outputs[1]).abs() < 1e-6);
+    assert!(outputs[2] < outputs[0]);
+
+    assert_eq!(CROSS_ENTROPY.get_cost(-0.3), 0.);
+    assert!((CROSS_ENTROPY.get_cost(0.5) - 2f32.ln()).abs() < 1e-6);
+    assert!(CROSS_ENTROPY.get_cost(1.).is_finite());
+}
+
+#[test]
+fn test_softmax_cross_entropy_classification() {
+    // Three classes depending on which of the two inputs is larger and whether their sum is large
+    let mut rng = pcg::Pcg::default();
+    let mut dataset = InMemoryDataset::empty(2, 3);
+    for _ in 0..600 {
+        let a = rng.gen_range(0., 1.);
+        let b = rng.gen_range(0., 1.);
+        let class = if a + b > 1.2 {
+            2
+        } else if a > b {
+            1
+        } else {
+            0
+        };
+        let mut target = [0.; 3];
+        target[class] = 1.;
+        dataset.push(&[a, b], &target).unwrap();
+    }
+
+    let mut network = Network {
+        feature_layers: Vec::new(),
+        hidden_layers: vec![DenseLayer::new(
+            16,
+            2,
+            &mut |_, _| rng.gen_range(-1., 1.),
+            &mut |_| 0.,
+            &LeakyReLU,
+        )],
+        outputs: Box::new(OutputLayer::new(
+            &Softmax,
+            &CrossEntropy,
+            &mut |_, _| rng.gen_range(-0.5, 0.5),
+            16,
+            3,
+        )),
+        learning_rate: 0.05,
+    };
+    let initial_accuracy = network.classification_accuracy(&dataset).unwrap();
+    network.fit(&dataset, 40, &mut rng).unwrap();
+    let accuracy = network.classification_accuracy(&dataset).unwrap();
+    assert!(accuracy > 0.9 && accuracy > initial_accuracy, "accuracy={}", accuracy);
+    assert_eq!(argmax(&[0.1, 0.7, 0.7, 0.2]), 1);
+}
+
+#[test]
+fn test_weight_matrix_layout() {
+    let mut matrix = WeightMatrix::from_fn(3, 5, |row_ix, col_ix| (row_ix * 10 + col_ix) as Weight);
+    assert_eq!(matrix.row_count(), 3);
+    assert_eq!(matrix.col_count(), 5);
+    assert_eq!(matrix.stride(), 8);
+    assert_eq!(matrix.as_slice().len(), 24);
+    for row_ix in 0..3 {
+        assert_eq!(matrix.row_ptr(row_ix) as usize % 16, 0);
+        assert_eq!(matrix[row_ix].len(), 5);
+        // Padding at the end of each row stays zeroed
+        assert!(matrix.as_slice()[row_ix * 8 + 5..(row_ix + 1) * 8]
+            .iter()
+            .all(|&w| w == 0.));
+    }
+    assert_eq!(matrix[2][4], 24.);
+    assert_eq!(matrix.column(1).collect::<Vec<_>>(), vec![1., 11., 21.]);
+
+    matrix[1][0] = -1.;
+    assert_eq!(matrix.to_rows()[1], vec![-1., 11., 12., 13., 14.]);
+    assert_eq!(WeightMatrix::from_rows(&matrix.to_rows()), matrix);
+    assert_eq!(format!("{:?}", WeightMatrix::from_rows(&[[1., 2.]])), "[[1.0, 2.0]]");
+
+    let mut copy = WeightMatrix::default();
+    copy.clone_from(&matrix);
+    assert_eq!(copy, matrix);
+}
+
+fn assert_close(actual: &[Weight], expected: &[Weight]) {
+    assert_eq!(actual.len(), expected.len());
+    for (&actual, &expected) in actual.iter().zip(expected) {
+        assert!(
+            (actual - expected).abs() <= 1e-4 * (1. + expected.abs()),
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+}
+
+/// Runs every kernel on every backend that this machine supports and compares the results against the scalar
+/// backend.  The `Simd128` backend is always included, so the wasm code paths get tested even on native builds.
+#[test]
+fn test_kernel_backends_match_scalar() {
+    let mut rng = pcg::Pcg::default();
+    // Cover lengths that leave every possible remainder for both the 4 and 8-wide loops
+    for len in 0..40 {
+        let a: Vec<Weight> = (0..len).map(|_| rng.gen_range(-1., 1.)).collect();
+        let b: Vec<Weight> = (0..len).map(|_| rng.gen_range(-1., 1.)).collect();
+        let alpha = rng.gen_range(-1., 1.);
+
+        let scalar = KernelBackend::Scalar;
+        let expected_dot = scalar.dot(&a, &b);
+        let mut expected_axpy = b.clone();
+        scalar.axpy(&mut expected_axpy, alpha, &a);
+        let mut expected = vec![vec![0.; len]; 4];
+        scalar.relu(&mut expected[0], &a);
+        scalar.relu_derivative(&mut expected[1], &b, &a);
+        scalar.leaky_relu(&mut expected[2], &a);
+        scalar.leaky_relu_derivative(&mut expected[3], &b, &a);
+
+        for backend in KernelBackend::available() {
+            assert_close(&[backend.dot(&a, &b)], &[expected_dot]);
+            let mut actual = b.clone();
+            backend.axpy(&mut actual, alpha, &a);
+            assert_close(&actual, &expected_axpy);
+
+            let mut actual = vec![0.; len];
+            backend.relu(&mut actual, &a);
+            assert_eq!(actual, expected[0], "{:?}", backend);
+            backend.relu_derivative(&mut actual, &b, &a);
+            assert_eq!(actual, expected[1], "{:?}", backend);
+            backend.leaky_relu(&mut actual, &a);
+            assert_eq!(actual, expected[2], "{:?}", backend);
+            backend.leaky_relu_derivative(&mut actual, &b, &a);
+            assert_eq!(actual, expected[3], "{:?}", backend);
+        }
+    }
+
+    assert!(KernelBackend::available().any(|backend| backend == KernelBackend::Simd128));
+    for backend in KernelBackend::ALL.iter().filter(|backend| !backend.is_available()) {
+        assert_eq!(
+            set_kernel_backend(*backend),
+            Err(NnError::KernelBackendUnavailable(*backend))
+        );
+    }
+}
+
+/// Trains randomly sized networks with each backend selected in turn and checks that they all end up with the same
+/// outputs and weights as the scalar backend.
+#[test]
+fn test_kernel_backends_train_identically() {
+    let mut rng = pcg::Pcg::default();
+    for _ in 0..10 {
+        let input_count = rng.gen_range(1, 24);
+        let mut layer_sizes = vec![input_count];
+        for _ in 0..rng.gen_range(1, 4) {
+            layer_sizes.push(rng.gen_range(1, 24));
+        }
+        let output_count = rng.gen_range(1, 10);
+
+        let mut network = Network {
+            feature_layers: Vec::new(),
+            hidden_layers: layer_sizes
+                .windows(2)
+                .enumerate()
+                .map(|(layer_ix, sizes)| {
+                    let activation_fn: &'static dyn ActivationFunction =
+                        if layer_ix % 2 == 0 { &ReLU } else { &LeakyReLU };
+                    DenseLayer::new(
+                        sizes[1],
+                        sizes[0],
+                        &mut |_, _| rng.gen_range(-1., 1.),
+                        &mut |_| 0.,
+                        activation_fn,
+                    )
+                })
+                .collect(),
+            outputs: Box::new(OutputLayer::new(
+                &Identity,
+                &MeanSquaredError,
+                &mut |_, _| rng.gen_range(-1., 1.),
+                *layer_sizes.last().unwrap(),
+                output_count,
+            )),
+            learning_rate: 0.01,
+        };
+        let initial_weights = network.snapshot();
+        let examples: Vec<(Vec<Weight>, Vec<Weight>)> = (0..5)
+            .map(|_| {
+                let inputs = (0..input_count).map(|_| rng.gen_range(-1., 1.)).collect();
+                let expected = (0..output_count).map(|_| rng.gen_range(-1., 1.)).collect();
+                (inputs, expected)
+            })
+            .collect();
+
+        let mut train = |backend: KernelBackend| {
+            set_kernel_backend(backend).unwrap();
+            network.restore(&initial_weights);
+            for (inputs, expected) in &examples {
+                network.train_one_example(inputs, expected, 0.01);
+            }
+            (network.compute(&examples[0].0).to_vec(), network.snapshot())
+        };
+
+        let (expected_outputs, expected_weights) = train(KernelBackend::Scalar);
+        for backend in KernelBackend::available() {
+            let (outputs, weights) = train(backend);
+            assert_close(&outputs, &expected_outputs);
+            for (layer, expected_layer) in weights
+                .hidden_layer_weights
+                .iter()
+                .zip(&expected_weights.hidden_layer_weights)
+            {
+                assert_close(layer.as_slice(), expected_layer.as_slice());
+            }
+            for (biases, expected_biases) in weights
+                .hidden_layer_biases
+                .iter()
+                .zip(&expected_weights.hidden_layer_biases)
+            {
+                assert_close(biases, expected_biases);
+            }
+            assert_close(
+                weights.output_weights.as_slice(),
+                expected_weights.output_weights.as_slice(),
+            );
+        }
+    }
+    set_kernel_backend(KernelBackend::best()).unwrap();
+}
+
+fn build_random_network(rng: &mut pcg::Pcg, layer_sizes: &[usize], learning_rate: Weight) -> Network {
+    let (&output_count, layer_sizes) = layer_sizes.split_last().unwrap();
+    Network {
+        feature_layers: Vec::new(),
+        hidden_layers: layer_sizes
+            .windows(2)
+            .map(|sizes| {
+                DenseLayer::new(
+                    sizes[1],
+                    sizes[0],
+                    &mut |_, _| rng.gen_range(-1., 1.),
+                    &mut |_| 0.,
+                    &LeakyReLU,
+                )
+            })
+            .collect(),
+        outputs: Box::new(OutputLayer::new(
+            &Identity,
+            &MeanSquaredError,
+            &mut |_, _| rng.gen_range(-1., 1.),
+            *layer_sizes.last().unwrap(),
+            output_count,
+        )),
+        learning_rate,
+    }
+}
+
+fn assert_snapshots_close(actual: &NetworkSnapshot, expected: &NetworkSnapshot) {
+    for (layer, expected_layer) in actual.hidden_layer_weights.iter().zip(&expected.hidden_layer_weights) {
+        assert_close(layer.as_slice(), expected_layer.as_slice());
+    }
+    for (biases, expected_biases) in actual.hidden_layer_biases.iter().zip(&expected.hidden_layer_biases) {
+        assert_close(biases, expected_biases);
+    }
+    assert_close(actual.output_weights.as_slice(), expected.output_weights.as_slice());
+}
+
+#[test]
+fn test_single_example_batch_matches_train_one_example() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[5, 7, 3, 2], 0.05);
+    let initial_weights = network.snapshot();
+    let mut dataset = InMemoryDataset::empty(5, 2);
+    dataset.push(&[0.1, -0.4, 0.9, 0.3, -1.], &[0.5, -0.25]).unwrap();
+
+    let expected_cost = network.train_one_example(dataset.get(0).0, dataset.get(0).1, 0.05);
+    let expected_weights = network.snapshot();
+
+    network.restore(&initial_weights);
+    let mut trainer = BatchTrainer::new(&network, 1).unwrap();
+    let cost = trainer
+        .train_batch(&mut network, &dataset.batches(1).iter().next().unwrap(), 0.05)
+        .unwrap();
+    assert_close(&[cost], &[expected_cost]);
+    assert_snapshots_close(&network.snapshot(), &expected_weights);
+}
+
+#[test]
+fn test_parallel_train_batch_matches_single_threaded() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[9, 17, 6, 3], 0.01);
+    let initial_weights = network.snapshot();
+    let mut dataset = InMemoryDataset::empty(9, 3);
+    for _ in 0..45 {
+        let inputs: Vec<Weight> = (0..9).map(|_| rng.gen_range(-1., 1.)).collect();
+        let targets: Vec<Weight> = (0..3).map(|_| rng.gen_range(-1., 1.)).collect();
+        dataset.push(&inputs, &targets).unwrap();
+    }
+
+    let mut train = |thread_count: usize| {
+        network.restore(&initial_weights);
+        let mut trainer = BatchTrainer::new(&network, thread_count).unwrap();
+        let costs: Vec<Weight> = dataset
+            .batches(16)
+            .iter()
+            .map(|batch| trainer.train_batch(&mut network, &batch, 0.01).unwrap())
+            .collect();
+        (costs, network.snapshot())
+    };
+
+    let (expected_costs, expected_weights) = train(1);
+    // More threads than there are examples in the last batch leaves some workers idle
+    for thread_count in [2, 4, 20] {
+        let (costs, weights) = train(thread_count);
+        assert_close(&costs, &expected_costs);
+        assert_snapshots_close(&weights, &expected_weights);
+    }
+}
+
+#[test]
+fn test_single_threaded_hogwild_matches_fit() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[6, 10, 4, 2], 0.02);
+    let initial_weights = network.snapshot();
+    let mut dataset = InMemoryDataset::empty(6, 2);
+    for _ in 0..30 {
+        // Mostly-zero inputs, which the shared weight updates skip over
+        let inputs: Vec<Weight> = (0..6)
+            .map(|_| {
+                if rng.gen_range(0., 1.) < 0.6 {
+                    0.
+                } else {
+                    rng.gen_range(-1., 1.)
+                }
+            })
+            .collect();
+        let targets: Vec<Weight> = (0..2).map(|_| rng.gen_range(-1., 1.)).collect();
+        dataset.push(&inputs, &targets).unwrap();
+    }
+
+    let expected_costs = network.fit(&dataset, 2, &mut pcg::Pcg::new(7, 1)).unwrap();
+    let expected_weights = network.snapshot();
+
+    network.restore(&initial_weights);
+    let mut trainer = HogwildTrainer::new(&network, 1).unwrap();
+    let mut shuffle_rng = pcg::Pcg::new(7, 1);
+    let costs: Vec<Weight> = (0..2)
+        .map(|_| {
+            trainer
+                .train_epoch(&mut network, &dataset, 0.02, &mut shuffle_rng)
+                .unwrap()
+        })
+        .collect();
+    assert_close(&costs, &expected_costs);
+    assert_snapshots_close(&network.snapshot(), &expected_weights);
+}
+
+#[test]
+fn test_multithreaded_hogwild_converges() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[4, 12, 1], 0.01);
+    let mut dataset = InMemoryDataset::empty(4, 1);
+    for _ in 0..400 {
+        let inputs: Vec<Weight> = (0..4).map(|_| rng.gen_range(-1., 1.)).collect();
+        let target = inputs[0] - 0.5 * inputs[2];
+        dataset.push(&inputs, &[target]).unwrap();
+    }
+
+    let mut trainer = HogwildTrainer::new(&network, 4).unwrap();
+    let initial_cost = network.evaluate_dataset(&dataset).unwrap();
+    for _ in 0..20 {
+        trainer.train_epoch(&mut network, &dataset, 0.01, &mut rng).unwrap();
+    }
+    let final_cost = network.evaluate_dataset(&dataset).unwrap();
+    assert!(
+        final_cost < initial_cost * 0.1,
+        "cost only went from {} to {}",
+        initial_cost,
+        final_cost
+    );
+}
+
+/// Checks the blocked matrix multiplication against plain dot products on every backend, with shapes that leave
+/// partial tiles in both directions and rows long enough to span several blocks.
+#[test]
+fn test_matmul_transposed_matches_naive() {
+    let mut rng = pcg::Pcg::default();
+    for &(a_rows, b_rows, cols) in &[
+        (0, 3, 5),
+        (3, 0, 5),
+        (1, 1, 1),
+        (4, 2, 8),
+        (5, 3, 7),
+        (9, 7, 33),
+        (6, 5, 600),
+    ] {
+        let a = WeightMatrix::from_fn(a_rows, cols, |_, _| rng.gen_range(-1., 1.));
+        let b = WeightMatrix::from_fn(b_rows, cols, |_, _| rng.gen_range(-1., 1.));
+        let expected = WeightMatrix::from_fn(a_rows, b_rows, |i, j| a[i].iter().zip(&b[j]).map(|(a, b)| a * b).sum());
+
+        for backend in KernelBackend::available() {
+            // Start out with garbage to make sure that it gets overwritten
+            let mut out = WeightMatrix::from_fn(a_rows, b_rows, |_, _| 100.);
+            backend.matmul_transposed(&a, &b, &mut out);
+            assert_close(out.as_slice(), expected.as_slice());
+        }
+    }
+}
+
+#[test]
+fn test_forward_propagate_batch_matches_compute() {
+    let mut rng = pcg::Pcg::default();
+    let mut activations = BatchActivations::new();
+    for &layer_sizes in &[&[2, 3, 1][..], &[7, 13, 5, 4], &[30, 9, 10]] {
+        let mut network = build_random_network(&mut rng, layer_sizes, 0.01);
+        network.outputs.activation_fn = &Softmax;
+        let input_count = network.input_count();
+
+        for &example_count in &[0, 1, 5, 18] {
+            let examples: Vec<Weight> = (0..example_count * input_count)
+                .map(|_| rng.gen_range(-1., 1.))
+                .collect();
+            let outputs = network
+                .forward_propagate_batch(&examples, &mut activations)
+                .unwrap()
+                .clone();
+            assert_eq!(outputs.row_count(), example_count);
+            assert_eq!(
+                network.compute_batch(&examples).unwrap().len(),
+                example_count * network.output_count()
+            );
+
+            for (example_ix, example) in examples.chunks_exact(input_count).enumerate() {
+                let expected = network.compute(example).to_owned();
+                assert_close(&outputs[example_ix], &expected);
+                for (layer, layer_outputs) in network.hidden_layers.iter().zip(&activations.hidden_layers) {
+                    assert_close(&layer_outputs[example_ix], &layer.outputs);
+                }
+            }
+        }
+
+        assert_eq!(
+            network.compute_batch(&[0.; 3][..input_count % 3 + 1]),
+            Err(NnError::InvalidBatchLength {
+                example_len: input_count,
+                buffer_len: input_count % 3 + 1,
+            })
+        );
+    }
+}
+
+/// Same as `build_random_network`, but in any precision.  Weights are generated as `f64`s and then converted, so
+/// networks built from identically seeded RNGs start out the same apart from rounding.
+fn build_random_network_in<T: Float>(rng: &mut pcg::Pcg, layer_sizes: &[usize], learning_rate: f64) -> Network<T> {
+    let (&output_count, layer_sizes) = layer_sizes.split_last().unwrap();
+    Network {
+        feature_layers: Vec::new(),
+        hidden_layers: layer_sizes
+            .windows(2)
+            .map(|sizes| {
+                DenseLayer::new(
+                    sizes[1],
+                    sizes[0],
+                    &mut |_, _| T::from_f64(rng.gen_range(-1., 1.)),
+                    &mut |_| T::ZERO,
+                    &LeakyReLU,
+                )
+            })
+            .collect(),
+        outputs: Box::new(OutputLayer::new(
+            &Identity,
+            &MeanSquaredError,
+            &mut |_, _| T::from_f64(rng.gen_range(-1., 1.)),
+            *layer_sizes.last().unwrap(),
+            output_count,
+        )),
+        learning_rate: T::from_f64(learning_rate),
+    }
+}
+
+#[test]
+fn test_f32_and_f64_networks_train_the_same() {
+    let layer_sizes = [4, 8, 6, 2];
+    let mut network_f32 = build_random_network_in::<f32>(&mut pcg::Pcg::new(3, 0), &layer_sizes, 0.01);
+    let mut network_f64 = build_random_network_in::<f64>(&mut pcg::Pcg::new(3, 0), &layer_sizes, 0.01);
+
+    let mut rng = pcg::Pcg::default();
+    let mut costs = Vec::new();
+    for _ in 0..2000 {
+        let example: Vec<f64> = (0..4).map(|_| rng.gen_range(-1., 1.)).collect();
+        let expected = [example[0] * example[1], example[2] - example[3]];
+
+        let example_f32: Vec<f32> = example.iter().map(|&x| x as f32).collect();
+        let expected_f32 = expected.map(|x| x as f32);
+        let cost_f32 = network_f32.train_one_example(&example_f32, &expected_f32, 0.01);
+        let cost_f64 = network_f64.train_one_example(&example, &expected, 0.01);
+        assert!(
+            (cost_f32 as f64 - cost_f64).abs() <= 1e-3 * (1. + cost_f64),
+            "{} != {}",
+            cost_f32,
+            cost_f64
+        );
+
+        costs.push(cost_f64);
+    }
+    let average_cost = |costs: &[f64]| costs.iter().sum::<f64>() / costs.len() as f64;
+    assert!(average_cost(&costs[costs.len() - 200..]) < average_cost(&costs[..200]) * 0.8);
+
+    let (snapshot_f32, snapshot_f64) = (network_f32.snapshot(), network_f64.snapshot());
+    for (&weight_f32, &weight_f64) in snapshot_f32
+        .output_weights
+        .as_slice()
+        .iter()
+        .zip(snapshot_f64.output_weights.as_slice())
+    {
+        assert!((weight_f32 as f64 - weight_f64).abs() < 1e-3);
+    }
+}
+
+/// The weight or bias at `param_ix` when counting through each of `params` in turn, going through all of a layer's
+/// weights before its biases.
+fn nth_param<'a>(
+    params: impl IntoIterator<Item = (&'a mut WeightMatrix<f64>, &'a mut [f64])>,
+    mut param_ix: usize,
+) -> Option<&'a mut f64> {
+    for (weights, biases) in params {
+        let (row_count, col_count) = (weights.row_count(), weights.col_count());
+        if param_ix < row_count * col_count {
+            return Some(&mut weights[param_ix / col_count][param_ix % col_count]);
+        }
+        param_ix -= row_count * col_count;
+        if param_ix < biases.len() {
+            return Some(&mut biases[param_ix]);
+        }
+        param_ix -= biases.len();
+    }
+    None
+}
+
+/// The parameter at `param_ix` when counting through the feature layers, the hidden layers and then the output layer.
+fn network_param(network: &mut Network<f64>, param_ix: usize) -> Option<&mut f64> {
+    let feature_layers = network
+        .feature_layers
+        .iter_mut()
+        .filter_map(|layer| layer.weights_and_biases_mut());
+    let hidden_layers = network
+        .hidden_layers
+        .iter_mut()
+        .map(|layer| (&mut layer.weights, &mut layer.biases[..]));
+    let outputs = std::iter::once((&mut network.outputs.weights, &mut [][..]));
+    nth_param(feature_layers.chain(hidden_layers).chain(outputs), param_ix)
+}
+
+/// Trains `network` with `train` and checks that every parameter moved by exactly minus the gradient of `total_cost`,
+/// estimated with central finite differences.  That only holds if `train` uses a learning rate of 1.  `get_param`
+/// returns the parameter at an index, or `None` once they've all been counted.  Returns how much each parameter was
+/// updated by, and leaves `network` with the parameters it had before training.
+fn assert_updates_match_finite_differences<N>(
+    network: &mut N,
+    get_param: impl Fn(&mut N, usize) -> Option<&mut f64>,
+    train: impl FnOnce(&mut N),
+    total_cost: impl Fn(&mut N) -> f64,
+) -> Vec<f64> {
+    let before: Vec<f64> = (0..)
+        .map_while(|param_ix| get_param(network, param_ix).map(|param| *param))
+        .collect();
+    train(network);
+    let mut updates = Vec::with_capacity(before.len());
+    for (param_ix, before) in before.into_iter().enumerate() {
+        let param = get_param(network, param_ix).unwrap();
+        updates.push(*param - before);
+        *param = before;
+    }
+
+    let step = 1e-6;
+    for (param_ix, &update) in updates.iter().enumerate() {
+        *get_param(network, param_ix).unwrap() += step;
+        let cost_above = total_cost(network);
+        *get_param(network, param_ix).unwrap() -= 2. * step;
+        let cost_below = total_cost(network);
+        *get_param(network, param_ix).unwrap() += step;
+
+        let gradient = (cost_above - cost_below) / (2. * step);
+        assert!(
+            (gradient + update).abs() < 1e-6,
+            "param {}: {} != {}",
+            param_ix,
+            gradient,
+            -update
+        );
+    }
+    updates
+}
+
+/// Checks that the updates made by backpropagation match gradients estimated with finite differences.  This needs
+/// `f64`: in `f32`, the rounding error in the estimates swamps the differences being tested for.
+#[test]
+fn test_f64_gradients_match_finite_differences() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network_in::<f64>(&mut rng, &[3, 5, 4, 2], 1.);
+    let example: Vec<f64> = (0..3).map(|_| rng.gen_range(-1., 1.)).collect();
+    let expected = [0.5, -0.25];
+    let total_cost = |network: &mut Network<f64>| -> f64 {
+        let outputs = network.compute(&example);
+        outputs
+            .iter()
+            .zip(&expected)
+            .map(|(output, expected)| (expected - output).powi(2))
+            .sum()
+    };
+
+    assert_updates_match_finite_differences(
+        &mut network,
+        network_param,
+        |network| {
+            network.train_one_example(&example, &expected, 1.);
+        },
+        total_cost,
+    );
+}
+
+#[test]
+fn test_dot_i8_backends_match_scalar() {
+    let mut rng = pcg::Pcg::default();
+    // Cover every remainder for the 16 and 32-wide loops, plus the extremes that could overflow an i16 accumulator
+    for len in 0..70 {
+        let mut a: Vec<i8> = (0..len).map(|_| rng.gen_range(-128i32, 128) as i8).collect();
+        let mut b: Vec<i8> = (0..len).map(|_| rng.gen_range(-128i32, 128) as i8).collect();
+        let expected = KernelBackend::Scalar.dot_i8(&a, &b);
+        for backend in KernelBackend::available() {
+            assert_eq!(backend.dot_i8(&a, &b), expected, "{:?}", backend);
+        }
+
+        a.iter_mut().for_each(|a| *a = -128);
+        b.iter_mut()
+            .enumerate()
+            .for_each(|(ix, b)| *b = if ix % 3 == 0 { 127 } else { -128 });
+        let expected = KernelBackend::Scalar.dot_i8(&a, &b);
+        for backend in KernelBackend::available() {
+            assert_eq!(backend.dot_i8(&a, &b), expected, "{:?}", backend);
+        }
+    }
+}
+
+#[test]
+fn test_quantization_params_round_trip() {
+    let mut rng = pcg::Pcg::default();
+    for &(min, max) in &[(-1., 1.), (0., 6.), (-0.3, 5.), (2., 3.), (-4., -1.), (0., 0.)] {
+        let params = QuantizationParams::from_range(min, max);
+        assert_eq!(params.dequantize(params.quantize(0.)), 0.);
+        for _ in 0..100 {
+            let value: Weight = if min < max { rng.gen_range(min, max) } else { min };
+            let error = (params.dequantize(params.quantize(value)) - value).abs();
+            assert!(
+                error <= params.scale * 0.501,
+                "{} -> {} in {:?}",
+                value,
+                error,
+                (min, max)
+            );
+        }
+    }
+
+    let params = QuantizationParams::symmetric(2.);
+    assert_eq!(params.quantize(2.), 127);
+    assert_eq!(params.quantize(-2.), -127);
+    assert_eq!(params.quantize(10.), 127);
+    assert_eq!(params.quantize(0.), 0);
+}
+
+/// Builds a dataset of random inputs labelled with the class that `network` predicts for them
+fn build_labelled_dataset(rng: &mut pcg::Pcg, network: &mut Network, len: usize) -> InMemoryDataset {
+    let (input_count, output_count) = (network.input_count(), network.output_count());
+    let inputs: Vec<Weight> = (0..len * input_count).map(|_| rng.gen_range(-1., 1.)).collect();
+    let mut targets = vec![0.; len * output_count];
+    for (example, target) in inputs
+        .chunks_exact(input_count)
+        .zip(targets.chunks_exact_mut(output_count))
+    {
+        target[argmax(network.compute(example))] = 1.;
+    }
+    InMemoryDataset::new(input_count, output_count, inputs, targets).unwrap()
+}
+
+#[test]
+fn test_quantized_network_matches_float() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[16, 24, 12, 5], 0.01);
+    let calibration_data = build_labelled_dataset(&mut rng, &mut network, 200);
+    let test_data = build_labelled_dataset(&mut rng, &mut network, 500);
+    let mean_output = (0..test_data.len())
+        .flat_map(|ix| network.compute(test_data.get(ix).0).to_owned())
+        .map(Weight::abs)
+        .sum::<Weight>()
+        / (test_data.len() * network.output_count()) as Weight;
+
+    for &granularity in &[WeightGranularity::PerLayer, WeightGranularity::PerChannel] {
+        let mut quantized = QuantizedNetwork::quantize(&network, &calibration_data, granularity).unwrap();
+        assert_eq!(quantized.input_count(), 16);
+        assert_eq!(quantized.output_count(), 5);
+        assert!(quantized.parameter_bytes() < (16 * 24 + 24 * 12 + 12 * 5) * std::mem::size_of::<Weight>() / 2);
+
+        for (layer, quantized_layer) in network.hidden_layers.iter().zip(&quantized.hidden_layers) {
+            for neuron_ix in 0..layer.weights.row_count() {
+                for input_ix in 0..layer.weights.col_count() {
+                    let error = (quantized_layer.dequantized_weight(neuron_ix, input_ix)
+                        - layer.weights[neuron_ix][input_ix])
+                        .abs();
+                    assert!(error <= 0.5 / 127. + 1e-6);
+                }
+            }
+        }
+
+        let report = quantized.compare(&network, &test_data).unwrap();
+        assert_eq!(report.float_accuracy, 1.);
+        assert_eq!(report.quantized_accuracy, report.argmax_agreement);
+        assert!(report.argmax_agreement > 0.95, "{:?}", report);
+        assert!(report.mean_output_error < 0.01 * mean_output, "{:?}", report);
+        assert!(report.accuracy_delta() > -0.05, "{:?}", report);
+
+        let (inputs, _) = test_data.get(0);
+        let expected = network.compute(inputs).to_owned();
+        let outputs = quantized.compute(inputs).unwrap();
+        for (output, expected) in outputs.iter().zip(&expected) {
+            assert!((output - expected).abs() <= report.max_output_error);
+        }
+    }
+}
+
+#[test]
+fn test_quantization_validates_datasets() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[4, 6, 3], 0.01);
+    let empty = InMemoryDataset::new(4, 3, Vec::new(), Vec::new()).unwrap();
+    assert_eq!(
+        QuantizedNetwork::quantize(&network, &empty, WeightGranularity::PerLayer).err(),
+        Some(NnError::EmptyDataset)
+    );
+
+    let dataset = build_labelled_dataset(&mut rng, &mut network, 10);
+    let mut quantized = QuantizedNetwork::quantize(&network, &dataset, WeightGranularity::PerLayer).unwrap();
+    assert_eq!(quantized.compare(&network, &empty), Err(NnError::EmptyDataset));
+    assert_eq!(
+        quantized.compute(&[0.; 3]).err(),
+        Some(NnError::InvalidInputLength { expected: 4, actual: 3 })
+    );
+
+    let other_network = build_random_network(&mut rng, &[5, 6, 3], 0.01);
+    assert_eq!(
+        QuantizedNetwork::quantize(&other_network, &dataset, WeightGranularity::PerLayer).err(),
+        Some(NnError::InvalidInputLength { expected: 5, actual: 4 })
+    );
+}
+
+#[test]
+fn test_predict_matches_compute_without_modifying_network() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Network>();
+    assert_send_sync::<Network<f64>>();
+
+    let mut rng = pcg::Pcg::default();
+    let mut workspace = InferenceWorkspace::new();
+    for &layer_sizes in &[&[2, 1][..], &[7, 13, 5, 4], &[30, 9, 10]] {
+        let mut network = build_random_network(&mut rng, layer_sizes, 0.01);
+        network.outputs.activation_fn = &Softmax;
+        let stale_inputs: Vec<Weight> = (0..network.input_count()).map(|_| rng.gen_range(-1., 1.)).collect();
+        let stale_outputs = network.compute(&stale_inputs).to_owned();
+
+        for _ in 0..5 {
+            let inputs: Vec<Weight> = (0..network.input_count()).map(|_| rng.gen_range(-1., 1.)).collect();
+            let outputs = network.predict(&inputs, &mut workspace).unwrap().to_owned();
+            // The values left on the layers by the last call to `compute` are untouched
+            assert_eq!(network.outputs.outputs, stale_outputs);
+
+            let expected = network.compute(&inputs).to_owned();
+            assert_eq!(outputs, expected);
+            assert_eq!(workspace.hidden_layers.len(), network.hidden_layers.len());
+            for (layer, layer_outputs) in network.hidden_layers.iter().zip(&workspace.hidden_layers) {
+                assert_eq!(layer_outputs, &layer.outputs);
+            }
+            network.compute(&stale_inputs);
+        }
+
+        assert_eq!(
+            network.predict(&[0.; 31], &mut workspace).err(),
+            Some(NnError::InvalidInputLength {
+                expected: network.input_count(),
+                actual: 31,
+            })
+        );
+    }
+}
+
+#[test]
+fn test_predict_from_many_threads() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[8, 16, 3], 0.01);
+    let examples: Vec<Vec<Weight>> = (0..64)
+        .map(|_| (0..8).map(|_| rng.gen_range(-1., 1.)).collect())
+        .collect();
+    let expected: Vec<Vec<Weight>> = examples
+        .iter()
+        .map(|example| network.compute(example).to_owned())
+        .collect();
+
+    let network = &network;
+    std::thread::scope(|scope| {
+        for thread_ix in 0..4 {
+            let (examples, expected) = (&examples, &expected);
+            scope.spawn(move || {
+                let mut workspace = InferenceWorkspace::new();
+                for (example, expected) in examples.iter().zip(expected).skip(thread_ix).step_by(4) {
+                    assert_eq!(network.predict(example, &mut workspace).unwrap(), expected.as_slice());
+                }
+            });
+        }
+    });
+}
+
+/// Every weight of `network` along with whether it's been pruned, layer by layer with the output layer last
+fn weights_and_pruned(network: &Network) -> Vec<Vec<(Weight, bool)>> {
+    let layers = network
+        .hidden_layers
+        .iter()
+        .map(|layer| (&layer.weights, &layer.weight_mask))
+        .chain(std::iter::once((
+            &network.outputs.weights,
+            &network.outputs.weight_mask,
+        )));
+    layers
+        .map(|(weights, mask)| {
+            (0..weights.row_count())
+                .flat_map(|neuron_ix| (0..weights.col_count()).map(move |input_ix| (neuron_ix, input_ix)))
+                .map(|(neuron_ix, input_ix)| {
+                    let pruned = mask.as_ref().is_some_and(|mask| mask[neuron_ix][input_ix] == 0.);
+                    (weights[neuron_ix][input_ix], pruned)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn assert_pruned_weights_are_zero(network: &Network) {
+    for layer in weights_and_pruned(network) {
+        for (weight, pruned) in layer {
+            assert!(!pruned || weight == 0., "pruned weight has been trained to {}", weight);
+        }
+    }
+}
+
+#[test]
+fn test_prune_by_magnitude() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[10, 20, 5], 0.01);
+    let before = weights_and_pruned(&network);
+    network.prune_by_magnitude(0.3, PruningScope::PerLayer);
+
+    let sparsity = network.weight_sparsity();
+    assert_eq!(sparsity, vec![
+        LayerSparsity {
+            pruned_count: 60,
+            weight_count: 200,
+        },
+        LayerSparsity {
+            pruned_count: 30,
+            weight_count: 100,
+        },
+    ]);
+    assert_close(&[network.sparsity()], &[0.3]);
+    // Every pruned weight is now zero, and was no bigger than any of the weights that were kept in its layer
+    for (layer, layer_before) in weights_and_pruned(&network).iter().zip(&before) {
+        let largest_pruned = layer
+            .iter()
+            .zip(layer_before)
+            .filter(|((_, pruned), _)| *pruned)
+            .map(|(_, (weight, _))| weight.abs())
+            .fold(0., Weight::max);
+        for ((weight, pruned), (weight_before, _)) in layer.iter().zip(layer_before) {
+            if *pruned {
+                assert_eq!(*weight, 0.);
+            } else {
+                assert_eq!(weight, weight_before);
+                assert!(weight.abs() >= largest_pruned);
+            }
+        }
+    }
+
+    // Pruning again takes a fraction of the weights that are left
+    network.prune_by_magnitude(0.5, PruningScope::Global);
+    let pruned_count: usize = network.weight_sparsity().iter().map(|layer| layer.pruned_count).sum();
+    assert_eq!(pruned_count, 90 + 105);
+    let largest_pruned = before
+        .iter()
+        .flatten()
+        .zip(weights_and_pruned(&network).iter().flatten())
+        .filter(|(_, (_, pruned))| *pruned)
+        .map(|((weight, _), _)| weight.abs())
+        .fold(0., Weight::max);
+    for (weight, pruned) in weights_and_pruned(&network).iter().flatten() {
+        assert!(*pruned || weight.abs() >= largest_pruned);
+    }
+
+    network.clear_weight_masks();
+    assert_eq!(network.sparsity(), 0.);
+}
+
+#[test]
+fn test_pruned_weights_stay_zero_during_training() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[6, 12, 4, 3], 0.02);
+    let mut dataset = InMemoryDataset::empty(6, 3);
+    for _ in 0..40 {
+        let inputs: Vec<Weight> = (0..6).map(|_| rng.gen_range(-1., 1.)).collect();
+        let targets: Vec<Weight> = (0..3).map(|_| rng.gen_range(-1., 1.)).collect();
+        dataset.push(&inputs, &targets).unwrap();
+    }
+    network.prune_by_magnitude(0.6, PruningScope::Global);
+    let sparsity = network.weight_sparsity();
+
+    let cost = network.fit(&dataset, 3, &mut rng).unwrap();
+    assert!(cost.iter().all(|cost| cost.is_finite()));
+    assert_pruned_weights_are_zero(&network);
+
+    let mut trainer = BatchTrainer::new(&network, 2).unwrap();
+    for batch in dataset.shuffled_batches(8, &mut rng).iter() {
+        trainer.train_batch(&mut network, &batch, 0.02).unwrap();
+    }
+    assert_pruned_weights_are_zero(&network);
+
+    let mut trainer = HogwildTrainer::new(&network, 2).unwrap();
+    trainer.train_epoch(&mut network, &dataset, 0.02, &mut rng).unwrap();
+    assert_pruned_weights_are_zero(&network);
+    assert_eq!(network.weight_sparsity(), sparsity);
+}
+
+#[test]
+fn test_rewind_weights() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[4, 8, 2], 0.05);
+    let initial_weights = network.snapshot();
+    let mut dataset = InMemoryDataset::empty(4, 2);
+    for _ in 0..20 {
+        let inputs: Vec<Weight> = (0..4).map(|_| rng.gen_range(-1., 1.)).collect();
+        dataset
+            .push(&inputs, &[inputs[0] * inputs[1], inputs[2] - inputs[3]])
+            .unwrap();
+    }
+
+    network.fit(&dataset, 5, &mut rng).unwrap();
+    network.prune_by_magnitude(0.5, PruningScope::Global);
+    network.rewind_weights(&initial_weights);
+
+    network.clear_weight_masks();
+    let rewound = weights_and_pruned(&network);
+    network.restore(&initial_weights);
+    let zeroed_count = rewound
+        .iter()
+        .flatten()
+        .zip(weights_and_pruned(&network).iter().flatten())
+        .map(|((rewound, _), (initial, _))| {
+            assert!(*rewound == 0. || rewound == initial);
+            (*rewound == 0.) as usize
+        })
+        .sum::<usize>();
+    assert_eq!(zeroed_count, (4 * 8 + 8 * 2) / 2);
+}
+
+#[test]
+fn test_fit_with_pruning_reaches_target_sparsity() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[3, 16, 1], 0.01);
+    let mut dataset = InMemoryDataset::empty(3, 1);
+    for _ in 0..50 {
+        let inputs: Vec<Weight> = (0..3).map(|_| rng.gen_range(0., 1.)).collect();
+        dataset.push(&inputs, &[inputs.iter().sum::<Weight>() / 3.]).unwrap();
+    }
+
+    let schedule = PruningSchedule {
+        rounds: 3,
+        target_sparsity: 0.75,
+        epochs_per_round: 2,
+        ..Default::default()
+    };
+    assert_close(&[(1. - schedule.fraction_per_round()).powi(3)], &[
+        1. - schedule.target_sparsity
+    ]);
+    let rounds = network.fit_with_pruning(&dataset, &schedule, &mut rng).unwrap();
+    assert_eq!(rounds.len(), 4);
+    assert_eq!(
+        rounds[0].sparsity.iter().map(|layer| layer.pruned_count).sum::<usize>(),
+        0
+    );
+    for round in &rounds {
+        assert_eq!(round.epoch_costs.len(), 2);
+        assert!(round.epoch_costs.iter().all(|cost| cost.is_finite()));
+    }
+    assert!((network.sparsity() - 0.75).abs() < 0.05, "{}", network.sparsity());
+    assert_pruned_weights_are_zero(&network);
+}
+
+#[test]
+fn test_sparse_matrix_round_trip() {
+    let dense = WeightMatrix::from_rows(&[[0.5, -0.05, 0.], [0., 0., 0.], [-2., 0.1, 0.3]]);
+    let sparse = SparseMatrix::from_dense(&dense, 0.1);
+    assert_eq!(sparse.stored_count(), 3);
+    assert_close(&[sparse.density()], &[3. / 9.]);
+    assert_eq!(sparse.row(0), (&[0u32][..], &[0.5][..]));
+    assert_eq!(sparse.row(1), (&[][..], &[][..]));
+    assert_eq!(sparse.row(2), (&[0u32, 2][..], &[-2., 0.3][..]));
+    assert_eq!(sparse.to_dense().to_rows(), vec![
+        vec![0.5, 0., 0.],
+        vec![0., 0., 0.],
+        vec![-2., 0., 0.3]
+    ]);
+    assert_eq!(sparse.mask().to_rows(), vec![vec![1., 0., 0.], vec![0., 0., 0.], vec![
+        1., 0., 1.
+    ]]);
+
+    let all_nonzero = SparseMatrix::from_dense(&dense, 0.);
+    assert_eq!(all_nonzero.stored_count(), 5);
+    assert_eq!(all_nonzero.to_dense().to_rows(), dense.to_rows());
+}
+
+#[test]
+fn test_sparse_network_matches_pruned_network() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[12, 16, 8, 3], 0.01);
+    network.prune_by_magnitude(0.7, PruningScope::Global);
+    let mut sparse = SparseNetwork::from_network(&network, 0.).unwrap();
+    assert!((sparse.density() - 0.3).abs() < 0.01, "{}", sparse.density());
+    let dense_parameter_count = 12 * 16 + 16 * 8 + 8 * 3 + 16 + 8;
+    assert!(sparse.parameter_bytes() < dense_parameter_count * std::mem::size_of::<Weight>());
+
+    for _ in 0..20 {
+        let inputs: Vec<Weight> = (0..12).map(|_| rng.gen_range(-1., 1.)).collect();
+        let expected = [inputs[0] * inputs[1], inputs[2] - inputs[3], inputs[4].abs()];
+        assert_close(sparse.compute(&inputs).unwrap(), network.compute(&inputs));
+
+        let sparse_cost = sparse.train_one_example(&inputs, &expected, 0.01).unwrap();
+        let dense_cost = network.train_one_example(&inputs, &expected, 0.01);
+        assert_close(&[sparse_cost], &[dense_cost]);
+    }
+
+    // The weights that weren't stored should still be masked out when converting back
+    let converted = sparse.to_network(0.01);
+    assert_eq!(converted.weight_sparsity(), network.weight_sparsity());
+    for (converted, trained) in weights_and_pruned(&converted).iter().zip(&weights_and_pruned(&network)) {
+        let (converted, _): (Vec<Weight>, Vec<bool>) = converted.iter().copied().unzip();
+        let (trained, _): (Vec<Weight>, Vec<bool>) = trained.iter().copied().unzip();
+        assert_close(&converted, &trained);
+    }
+    for (converted, trained) in converted.hidden_layers.iter().zip(&network.hidden_layers) {
+        assert_close(&converted.biases, &trained.biases);
+    }
+
+    assert_eq!(sparse.compute(&[0.; 3]).unwrap_err(), NnError::InvalidInputLength {
+        expected: 12,
+        actual: 3
+    });
+    assert_eq!(
+        sparse.train_one_example(&[0.; 12], &[0.; 2], 0.01).unwrap_err(),
+        NnError::InvalidTargetLength { expected: 3, actual: 2 }
+    );
+}
+
+#[test]
+fn test_conv2d_and_pooling_forward_propagation() {
+    let config = Conv2DConfig {
+        out_channels: 1,
+        kernel_height: 2,
+        kernel_width: 2,
+        stride: 2,
+        padding: 1,
+    };
+    let kernel = [1., 0., 0., -1.];
+    let mut conv: Conv2DLayer = Conv2DLayer::new(
+        ImageShape::new(1, 3, 3),
+        config,
+        &mut |_, ix| kernel[ix],
+        &mut |_| 0.5,
+        &Identity,
+    );
+    assert_eq!(conv.output_shape, ImageShape::new(1, 2, 2));
+
+    let inputs = [1., 2., 3., 4., 5., 6., 7., 8., 9.];
+    conv.forward_propagate(&inputs);
+    assert_eq!(conv.outputs, vec![-0.5, -2.5, -6.5, -3.5]);
+
+    let mut max_pool = Pool2DLayer::new(conv.output_shape, PoolingMode::Max, 2, 1);
+    max_pool.forward_propagate(&conv.outputs);
+    assert_eq!(max_pool.outputs, vec![-0.5]);
+    let mut average_pool = Pool2DLayer::new(conv.output_shape, PoolingMode::Average, 2, 1);
+    average_pool.forward_propagate(&conv.outputs);
+    assert_eq!(average_pool.outputs, vec![-3.25]);
+
+    max_pool.errors_scratch[0] = 1.;
+    let mut input_errors = [0.; 4];
+    max_pool.backpropagate_errors(&conv.outputs, &mut input_errors);
+    assert_eq!(input_errors, [1., 0., 0., 0.]);
+    average_pool.errors_scratch[0] = 1.;
+    average_pool.backpropagate_errors(&conv.outputs, &mut input_errors);
+    assert_eq!(input_errors, [0.25; 4]);
+}
+
+/// Conv -> average pool -> strided conv -> max pool -> flatten, so that every kind of feature layer is covered.
+fn build_feature_layers<T: Float>(rng: &mut pcg::Pcg, input_shape: ImageShape) -> Vec<FeatureLayer<T>> {
+    let mut init_weights = |_, _| T::from_f64(rng.gen_range(-1., 1.));
+    let conv = Conv2DLayer::new(
+        input_shape,
+        Conv2DConfig {
+            out_channels: 2,
+            kernel_height: 3,
+            kernel_width: 3,
+            stride: 1,
+            padding: 1,
+        },
+        &mut init_weights,
+        &mut |_| T::from_f64(0.1),
+        &LeakyReLU,
+    );
+    let average_pool = Pool2DLayer::new(conv.output_shape, PoolingMode::Average, 2, 1);
+    let strided_conv = Conv2DLayer::new(
+        average_pool.output_shape,
+        Conv2DConfig {
+            out_channels: 3,
+            kernel_height: 2,
+            kernel_width: 2,
+            stride: 2,
+            padding: 0,
+        },
+        &mut init_weights,
+        &mut |_| T::ZERO,
+        &LeakyReLU,
+    );
+    let max_pool = Pool2DLayer::new(strided_conv.output_shape, PoolingMode::Max, 2, 1);
+    let flatten = FlattenLayer::new(max_pool.output_shape);
+
+    vec![
+        FeatureLayer::Conv2D(conv),
+        FeatureLayer::Pool2D(average_pool),
+        FeatureLayer::Conv2D(strided_conv),
+        FeatureLayer::Pool2D(max_pool),
+        FeatureLayer::Flatten(flatten),
+    ]
+}
+
+/// The weight at `weight_ix` in the kernel of `out_channel`, or its bias if `weight_ix` is `None`.
+/// Same as `test_f64_gradients_match_finite_differences`, for the kernels and biases of convolution layers.
+#[test]
+fn test_conv2d_gradients_match_finite_differences() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network_in::<f64>(&mut rng, &[3, 4, 2], 1.);
+    network.feature_layers = build_feature_layers(&mut rng, ImageShape::new(1, 5, 5));
+    assert_eq!(network.input_count(), 25);
+    assert_eq!(network.feature_layers[4].output_shape().len(), 3);
+
+    let example: Vec<f64> = (0..25).map(|_| rng.gen_range(-1., 1.)).collect();
+    let expected = [0.5, -0.25];
+    let total_cost = |network: &mut Network<f64>| -> f64 {
+        let outputs = network.compute(&example);
+        outputs
+            .iter()
+            .zip(&expected)
+            .map(|(output, expected)| (expected - output).powi(2))
+            .sum()
+    };
+
+    let updates = assert_updates_match_finite_differences(
+        &mut network,
+        network_param,
+        |network| {
+            network.train_one_example(&example, &expected, 1.);
+        },
+        total_cost,
+    );
+    // Both convolution layers are checked along with the dense layers
+    assert_eq!(updates.len(), 2 * (9 + 1) + 3 * (2 * 4 + 1) + (4 * 3 + 4) + 2 * 4);
+}
+
+/// Builds images of a bright bar on a noisy background, with the target set to whether it's vertical or horizontal.
+fn build_bar_dataset(rng: &mut pcg::Pcg, size: usize, len: usize) -> InMemoryDataset {
+    let mut dataset = InMemoryDataset::empty(size * size, 2);
+    for example_ix in 0..len {
+        let vertical = example_ix % 2 == 0;
+        let position = rng.gen_range(0, size);
+        let image: Vec<Weight> = (0..size * size)
+            .map(|ix| {
+                let on_bar = if vertical {
+                    ix % size == position
+                } else {
+                    ix / size == position
+                };
+                if on_bar {
+                    1.
+                } else {
+                    rng.gen_range(0., 0.3)
+                }
+            })
+            .collect();
+        dataset
+            .push(&image, if vertical { &[1., 0.] } else { &[0., 1.] })
+            .unwrap();
+    }
+    dataset
+}
+
+#[test]
+fn test_cnn_learns_to_classify_bars() {
+    let mut rng = pcg::Pcg::default();
+    let conv = Conv2DLayer::new(
+        ImageShape::new(1, 6, 6),
+        Conv2DConfig {
+            out_channels: 4,
+            kernel_height: 3,
+            kernel_width: 3,
+            stride: 1,
+            padding: 0,
+        },
+        &mut |_, _| rng.gen_range(-0.5, 0.5),
+        &mut |_| 0.,
+        &LeakyReLU,
+    );
+    let pool = Pool2DLayer::new(conv.output_shape, PoolingMode::Max, 2, 2);
+    let flatten = FlattenLayer::new(pool.output_shape);
+    let mut network = build_random_network(&mut rng, &[flatten.input_shape.len(), 8, 2], 0.02);
+    network.outputs.activation_fn = &Softmax;
+    network.outputs.cost_fn = &CrossEntropy;
+    network.feature_layers = vec![
+        FeatureLayer::Conv2D(conv),
+        FeatureLayer::Pool2D(pool),
+        FeatureLayer::Flatten(flatten),
+    ];
+    let train = build_bar_dataset(&mut rng, 6, 400);
+    let test = build_bar_dataset(&mut rng, 6, 100);
+
+    let initial_weights = network.snapshot();
+    let accuracy_before = network.classification_accuracy(&test).unwrap();
+    network.fit(&train, 10, &mut rng).unwrap();
+    let accuracy = network.classification_accuracy(&test).unwrap();
+    assert!(accuracy > 0.9, "{} -> {}", accuracy_before, accuracy);
+
+    // `predict` runs the feature layers too
+    let mut workspace = InferenceWorkspace::new();
+    for ix in 0..10 {
+        let (inputs, _) = test.get(ix);
+        let predicted = network.predict(inputs, &mut workspace).unwrap().to_vec();
+        assert_close(&predicted, network.compute(inputs));
+    }
+
+    network.restore(&initial_weights);
+    assert_eq!(network.classification_accuracy(&test).unwrap(), accuracy_before);
+    assert_eq!(
+        network
+            .forward_propagate_batch(test.get(0).0, &mut BatchActivations::new())
+            .unwrap_err(),
+        NnError::UnsupportedFeatureLayers
+    );
+    assert_eq!(
+        BatchTrainer::new(&network, 2).err(),
+        Some(NnError::UnsupportedFeatureLayers)
+    );
+    assert_eq!(
+        HogwildTrainer::new(&network, 2).err(),
+        Some(NnError::UnsupportedFeatureLayers)
+    );
+    assert_eq!(
+        SparseNetwork::from_network(&network, 0.).err(),
+        Some(NnError::UnsupportedFeatureLayers)
+    );
+}
+
+fn build_sequence_network<T: Float>(
+    rng: &mut pcg::Pcg,
+    cell_type: CellType,
+    layer_sizes: &[usize],
+) -> SequenceNetwork<T> {
+    let (&output_count, layer_sizes) = layer_sizes.split_last().unwrap();
+    let recurrent_layers = layer_sizes
+        .windows(2)
+        .map(|sizes| {
+            let scale = 1. / ((sizes[0] + sizes[1]) as f64).sqrt();
+            RecurrentLayer::new(cell_type, sizes[0], sizes[1], &mut |_, _| {
+                T::from_f64(rng.gen_range(-scale, scale))
+            })
+        })
+        .collect();
+    let outputs = Box::new(OutputLayer::new(
+        &Identity,
+        &MeanSquaredError,
+        &mut |_, _| T::from_f64(rng.gen_range(-0.5, 0.5)),
+        *layer_sizes.last().unwrap(),
+        output_count,
+    ));
+    SequenceNetwork::new(recurrent_layers, outputs).unwrap()
+}
+
+/// The parameter at `param_ix` when counting through the recurrent layers and then the output layer.
+fn sequence_param(network: &mut SequenceNetwork<f64>, param_ix: usize) -> Option<&mut f64> {
+    let recurrent_layers = network
+        .recurrent_layers
+        .iter_mut()
+        .map(|layer| (&mut layer.weights, &mut layer.biases[..]));
+    let outputs = std::iter::once((&mut network.outputs.weights, &mut [][..]));
+    nth_param(recurrent_layers.chain(outputs), param_ix)
+}
+
+#[test]
+fn test_recurrent_gradients_match_finite_differences() {
+    for cell_type in [CellType::Rnn, CellType::Lstm, CellType::Gru] {
+        let mut rng = pcg::Pcg::default();
+        let mut network = build_sequence_network::<f64>(&mut rng, cell_type, &[2, 3, 3, 2]);
+        let inputs: Vec<Vec<f64>> = (0..4)
+            .map(|_| vec![rng.gen_range(-1., 1.), rng.gen_range(-1., 1.)])
+            .collect();
+        let targets: Vec<Vec<f64>> = (0..4)
+            .map(|_| vec![rng.gen_range(-1., 1.), rng.gen_range(-1., 1.)])
+            .collect();
+        let total_cost = |network: &mut SequenceNetwork<f64>| -> f64 {
+            let outputs = network.compute_sequence(&inputs).unwrap();
+            outputs
+                .iter()
+                .zip(&targets)
+                .flat_map(|(outputs, targets)| outputs.iter().zip(targets))
+                .map(|(output, target)| (target - output).powi(2))
+                .sum()
+        };
+
+        assert_updates_match_finite_differences(
+            &mut network,
+            sequence_param,
+            |network| {
+                network.train_sequence(&inputs, &targets, 1.).unwrap();
+            },
+            total_cost,
+        );
+    }
+}
+
+#[test]
+fn test_recurrent_networks_learn_to_sum_sequences() {
+    for cell_type in [CellType::Rnn, CellType::Lstm, CellType::Gru] {
+        let mut rng = pcg::Pcg::default();
+        let mut network = build_sequence_network::<Weight>(&mut rng, cell_type, &[1, 8, 1]);
+        let sequences: Vec<Vec<[Weight; 1]>> = (0..200)
+            .map(|_| (0..5).map(|_| [rng.gen_range(-0.3, 0.3)]).collect())
+            .collect();
+        let sums: Vec<[Weight; 1]> = sequences
+            .iter()
+            .map(|sequence| [sequence.iter().map(|step| step[0]).sum()])
+            .collect();
+
+        let average_cost = |network: &mut SequenceNetwork, learning_rate: Weight| -> Weight {
+            let mut total_cost = 0.;
+            for (sequence, sum) in sequences.iter().zip(&sums) {
+                total_cost += network.train_sequence(sequence, &[sum], learning_rate).unwrap();
+            }
+            total_cost / sequences.len() as Weight
+        };
+
+        let initial_cost = average_cost(&mut network, 0.);
+        for _ in 0..40 {
+            average_cost(&mut network, 0.02);
+        }
+        let final_cost = average_cost(&mut network, 0.);
+        assert!(
+            final_cost < initial_cost * 0.05,
+            "{:?}: cost went from {} to {}",
+            cell_type,
+            initial_cost,
+            final_cost
+        );
+    }
+}
+
+#[test]
+fn test_truncated_bptt_learns_to_echo_sequences() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_sequence_network::<Weight>(&mut rng, CellType::Gru, &[1, 8, 1]);
+    network.bptt_steps = Some(4);
+    let average_cost = |network: &mut SequenceNetwork, rng: &mut pcg::Pcg, learning_rate: Weight| -> Weight {
+        let mut total_cost = 0.;
+        for _ in 0..100 {
+            let inputs: Vec<[Weight; 1]> = (0..12).map(|_| [rng.gen_range(-1., 1.)]).collect();
+            // Each output should be the input from the previous timestep
+            let targets: Vec<[Weight; 1]> = std::iter::once([0.]).chain(inputs.iter().copied()).take(12).collect();
+            total_cost += network.train_sequence(&inputs, &targets, learning_rate).unwrap();
+        }
+        total_cost / 100.
+    };
+
+    let initial_cost = average_cost(&mut network, &mut rng, 0.);
+    for _ in 0..30 {
+        average_cost(&mut network, &mut rng, 0.02);
+    }
+    let final_cost = average_cost(&mut network, &mut rng, 0.);
+    assert!(
+        final_cost < initial_cost * 0.05,
+        "cost went from {} to {}",
+        initial_cost,
+        final_cost
+    );
+}
+
+/// Uniform initialization scaled by the number of inputs to each neuron.
+fn scaled_init<T: Float>(rng: &mut pcg::Pcg, fan_in: usize) -> impl FnMut(usize, usize) -> T + '_ {
+    let scale = 1. / (fan_in as f64).sqrt();
+    move |_, _| T::from_f64(rng.gen_range(-scale, scale))
+}
+
+fn build_transformer<T: Float>(
+    rng: &mut pcg::Pcg,
+    input_count: usize,
+    model_dim: usize,
+    causal_blocks: &[bool],
+    output_count: usize,
+) -> TransformerEncoder<T> {
+    let input_projection = PositionwiseDense::new(input_count, model_dim, &mut scaled_init(rng, input_count));
+    let blocks = causal_blocks
+        .iter()
+        .map(|&causal| {
+            let attention = MultiHeadAttention::new(model_dim, 2, causal, &mut scaled_init(rng, model_dim));
+            let feed_forward_hidden =
+                PositionwiseDense::new(model_dim, 2 * model_dim, &mut scaled_init(rng, model_dim));
+            let feed_forward_output =
+                PositionwiseDense::new(2 * model_dim, model_dim, &mut scaled_init(rng, 2 * model_dim));
+            TransformerEncoderBlock::new(attention, feed_forward_hidden, &Tanh, feed_forward_output)
+        })
+        .collect();
+    let outputs = Box::new(OutputLayer::new(
+        &Identity,
+        &MeanSquaredError,
+        &mut scaled_init(rng, model_dim),
+        model_dim,
+        output_count,
+    ));
+    TransformerEncoder::new(input_projection, blocks, outputs).unwrap()
+}
+
+/// Every weight, bias and gain of a transformer, in a fixed order.
+fn transformer_params(network: &mut TransformerEncoder<f64>) -> Vec<&mut f64> {
+    fn dense_params(dense: &mut PositionwiseDense<f64>) -> impl Iterator<Item = &mut f64> {
+        let (stride, col_count) = (dense.weights.stride(), dense.weights.col_count());
+        dense
+            .weights
+            .as_mut_slice()
+            .chunks_mut(stride)
+            .flat_map(move |row| row[..col_count].iter_mut())
+            .chain(dense.biases.iter_mut())
+    }
+
+    let mut params: Vec<&mut f64> = dense_params(&mut network.input_projection).collect();
+    for block in &mut network.blocks {
+        params.extend(block.attention_norm.gains.iter_mut());
+        params.extend(block.attention_norm.biases.iter_mut());
+        params.extend(dense_params(&mut block.attention.query_projection));
+        params.extend(dense_params(&mut block.attention.key_projection));
+        params.extend(dense_params(&mut block.attention.value_projection));
+        params.extend(dense_params(&mut block.attention.output_projection));
+        params.extend(block.feed_forward_norm.gains.iter_mut());
+        params.extend(block.feed_forward_norm.biases.iter_mut());
+        params.extend(dense_params(&mut block.feed_forward_hidden));
+        params.extend(dense_params(&mut block.feed_forward_output));
+    }
+    let (stride, col_count) = (network.outputs.weights.stride(), network.outputs.weights.col_count());
+    params.extend(
+        network
+            .outputs
+            .weights
+            .as_mut_slice()
+            .chunks_mut(stride)
+            .flat_map(move |row| row[..col_count].iter_mut()),
+    );
+    params
+}
+
+#[test]
+fn test_attention_weights_are_normalized_and_causal() {
+    let encoding = sinusoidal_positional_encoding::<Weight>(3, 4);
+    assert_eq!(&encoding[0], &[0., 1., 0., 1.]);
+    assert_close(&encoding[2], &[(2.).sin(), (2.).cos(), (0.02).sin(), (0.02).cos()]);
+
+    let mut rng = pcg::Pcg::default();
+    for causal in [false, true] {
+        let mut attention = MultiHeadAttention::<Weight>::new(4, 2, causal, &mut |_, _| rng.gen_range(-1., 1.));
+        let inputs = WeightMatrix::from_fn(5, 4, |_, _| rng.gen_range(-1., 1.));
+        attention.forward_propagate(&inputs);
+        assert_eq!(attention.attention_weights().len(), 2);
+        for weights in attention.attention_weights() {
+            for pos in 0..5 {
+                assert!((weights[pos].iter().sum::<Weight>() - 1.).abs() < 1e-5);
+                if causal {
+                    assert!(weights[pos][pos + 1..].iter().all(|&weight| weight == 0.));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_transformer_gradients_match_finite_differences() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_transformer::<f64>(&mut rng, 3, 4, &[false, true], 2);
+    let inputs: Vec<Vec<f64>> = (0..4)
+        .map(|_| (0..3).map(|_| rng.gen_range(-1., 1.)).collect())
+        .collect();
+    let targets: Vec<Vec<f64>> = (0..4)
+        .map(|_| vec![rng.gen_range(-1., 1.), rng.gen_range(-1., 1.)])
+        .collect();
+    let total_cost = |network: &mut TransformerEncoder<f64>| -> f64 {
+        let outputs = network.compute_sequence(&inputs).unwrap();
+        outputs
+            .iter()
+            .zip(&targets)
+            .flat_map(|(outputs, targets)| outputs.iter().zip(targets))
+            .map(|(output, target)| (target - output).powi(2))
+            .sum()
+    };
+
+    assert_updates_match_finite_differences(
+        &mut network,
+        |network, param_ix| transformer_params(network).into_iter().nth(param_ix),
+        |network| {
+            network.train_sequence(&inputs, &targets, 1.).unwrap();
+        },
+        total_cost,
+    );
+}
+
+/// Each sequence holds random values along with a flag that's set at exactly one position, and the target is the value
+/// at that position.  Nothing about the positions themselves helps, so the network has to learn to attend to the flag.
+#[test]
+fn test_transformer_learns_to_copy_flagged_value() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_transformer::<Weight>(&mut rng, 2, 8, &[false], 1);
+    let build_sequence = |rng: &mut pcg::Pcg| {
+        let flagged_pos = rng.gen_range(0, 6);
+        let inputs: Vec<[Weight; 2]> = (0..6)
+            .map(|pos| [rng.gen_range(-1., 1.), if pos == flagged_pos { 1. } else { 0. }])
+            .collect();
+        let target = [inputs[flagged_pos][0]];
+        (inputs, target)
+    };
+    let sequences: Vec<_> = (0..500).map(|_| build_sequence(&mut rng)).collect();
+    let average_cost = |network: &mut TransformerEncoder, learning_rate: Weight| -> Weight {
+        let mut total_cost = 0.;
+        for (inputs, target) in &sequences {
+            total_cost += network.train_sequence(inputs, &[target], learning_rate).unwrap();
+        }
+        total_cost / sequences.len() as Weight
+    };
+
+    let initial_cost = average_cost(&mut network, 0.);
+    for _ in 0..10 {
+        average_cost(&mut network, 0.01);
+    }
+    let final_cost = average_cost(&mut network, 0.);
+    assert!(
+        final_cost < initial_cost * 0.1,
+        "cost went from {} to {}",
+        initial_cost,
+        final_cost
+    );
+}
+
+#[test]
+fn test_embedding_layer_forward_propagation() {
+    let categorical_inputs = vec![
+        CategoricalInput {
+            input_ix: 3,
+            category_count: 2,
+        },
+        CategoricalInput {
+            input_ix: 1,
+            category_count: 3,
+        },
+    ];
+    let mut layer = EmbeddingLayer::<Weight>::new(4, categorical_inputs, 2, &mut |row, col| (row * 10 + col) as Weight);
+    assert_eq!(layer.vectors.row_count(), 5);
+    assert_eq!(layer.output_count(), 6);
+
+    let inputs = [0.5, 2., -1., 1.];
+    layer.validate_inputs(&inputs).unwrap();
+    layer.forward_propagate(&inputs);
+    // Input 1 uses rows 0..3 and input 3 uses rows 3..5
+    assert_eq!(layer.outputs, vec![0.5, 20., 21., -1., 40., 41.]);
+
+    for (bad_value, input_ix) in [(3., 1), (-1., 1), (0.5, 3)] {
+        let mut inputs = inputs;
+        inputs[input_ix] = bad_value;
+        assert!(matches!(
+            layer.validate_inputs(&inputs),
+            Err(NnError::InvalidCategory { input_ix: ix, .. }) if ix == input_ix
+        ));
+    }
+}
+
+#[test]
+fn test_embedding_gradients_match_finite_differences() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network_in::<f64>(&mut rng, &[5, 4, 2], 1.);
+    let embedding = EmbeddingLayer::new(
+        3,
+        vec![
+            CategoricalInput {
+                input_ix: 0,
+                category_count: 4,
+            },
+            CategoricalInput {
+                input_ix: 2,
+                category_count: 3,
+            },
+        ],
+        2,
+        &mut |_, _| rng.gen_range(-1., 1.),
+    );
+    network.feature_layers.push(FeatureLayer::Embedding(embedding));
+    assert_eq!(network.input_count(), 3);
+    assert!(network.validate_inputs(&[4., 0.5, 1.]).is_err());
+
+    let example = [2., 0.3, 1.];
+    let expected = [0.5, -0.25];
+    let total_cost = |network: &mut Network<f64>| -> f64 {
+        let outputs = network.compute(&example);
+        outputs
+            .iter()
+            .zip(&expected)
+            .map(|(output, expected)| (expected - output).powi(2))
+            .sum()
+    };
+
+    let updates = assert_updates_match_finite_differences(
+        &mut network,
+        network_param,
+        |network| {
+            network.train_one_example(&example, &expected, 1.);
+        },
+        total_cost,
+    );
+    // The vectors come first, and only the ones for category 2 of the first input and category 1 of the second are
+    // used
+    for (param_ix, &update) in updates[..7 * 2].iter().enumerate() {
+        if ![2, 5].contains(&(param_ix / 2)) {
+            assert_eq!(update, 0.);
+        }
+    }
+}
+
+/// The parameter at `param_ix` when counting through the output weights and then every dense layer of a graph network.
+fn graph_param(network: &mut GraphNetwork<f64>, param_ix: usize) -> Option<&mut f64> {
+    let output_weight_count = network.outputs.weights.row_count() * network.outputs.weights.col_count();
+    if param_ix < output_weight_count {
+        return nth_param(std::iter::once((&mut network.outputs.weights, &mut [][..])), param_ix);
+    }
+    let dense_layers = network
+        .dense_layers_mut()
+        .map(|layer| (&mut layer.weights, &mut layer.biases[..]));
+    nth_param(dense_layers, param_ix - output_weight_count)
+}
+
+#[test]
+fn test_graph_gradients_match_finite_differences() {
+    let mut rng = pcg::Pcg::default();
+    let mut builder = GraphNetworkBuilder::<f64>::new(3);
+    let input = builder.input();
+    let first = builder.dense(input, 4, &mut scaled_init(&mut rng, 3), &mut |_| 0.1, &Tanh);
+    let second = builder.dense(first, 4, &mut scaled_init(&mut rng, 4), &mut |_| -0.1, &Sigmoid);
+    let branch = builder.dense(input, 4, &mut scaled_init(&mut rng, 3), &mut |_| 0., &Tanh);
+    // `first` and `input` both fan out to several nodes, and `first` is added in twice
+    let sum = builder.add(&[first, second, branch, first]);
+    let joined = builder.concat(&[sum, input]);
+    assert_eq!(builder.size(joined), 7);
+    let last = builder.dense(joined, 3, &mut scaled_init(&mut rng, 7), &mut |_| 0., &Tanh);
+    let outputs = Box::new(OutputLayer::new(
+        &Identity,
+        &MeanSquaredError,
+        &mut scaled_init(&mut rng, 3),
+        3,
+        2,
+    ));
+    let mut network = builder.build(last, outputs).unwrap();
+    assert!(network.compute(&[0.5, 1.]).is_err());
+
+    let example = [0.4, -0.7, 0.2];
+    let expected = [0.5, -0.25];
+    let total_cost = |network: &mut GraphNetwork<f64>| -> f64 {
+        let outputs = network.compute(&example).unwrap();
+        outputs
+            .iter()
+            .zip(&expected)
+            .map(|(output, expected)| (expected - output).powi(2))
+            .sum()
+    };
+
+    assert_updates_match_finite_differences(
+        &mut network,
+        graph_param,
+        |network| {
+            network.train_one_example(&example, &expected, 1.).unwrap();
+        },
+        total_cost,
+    );
+}
+
+/// Builds a network with `depth` hidden layers of sigmoid neurons, either stacked directly on top of each other or
+/// paired up into residual blocks.
+fn build_deep_graph(rng: &mut pcg::Pcg, depth: usize, residual: bool) -> GraphNetwork {
+    let mut builder = GraphNetworkBuilder::new(4);
+    let mut node = builder.dense(builder.input(), 8, &mut scaled_init(rng, 4), &mut |_| 0., &Identity);
+    for _ in 0..depth / 2 {
+        if residual {
+            node = builder.residual_block(node, 8, &mut scaled_init(rng, 8), &mut |_| 0., &Sigmoid);
+        } else {
+            node = builder.dense(node, 8, &mut scaled_init(rng, 8), &mut |_| 0., &Sigmoid);
+            node = builder.dense(node, 8, &mut scaled_init(rng, 8), &mut |_| 0., &Sigmoid);
+        }
+    }
+    let outputs = OutputLayer::new(&Identity, &MeanSquaredError, &mut scaled_init(rng, 8), 8, 1);
+    builder.build(node, Box::new(outputs)).unwrap()
+}
+
+#[test]
+fn test_residual_graph_trains_deeper_than_plain_stack() {
+    let mut rng = pcg::Pcg::default();
+    let examples: Vec<([Weight; 4], [Weight; 1])> = (0..500)
+        .map(|_| {
+            let inputs = [
+                rng.gen_range(-1., 1.),
+                rng.gen_range(-1., 1.),
+                rng.gen_range(-1., 1.),
+                rng.gen_range(-1., 1.),
+            ];
+            (inputs, [inputs[0] * inputs[1] - inputs[2] + 0.5 * inputs[3]])
+        })
+        .collect();
+    let average_cost = |network: &mut GraphNetwork, learning_rate: Weight| -> Weight {
+        let mut total_cost = 0.;
+        for (inputs, target) in &examples {
+            total_cost += network.train_one_example(inputs, target, learning_rate).unwrap();
+        }
+        total_cost / examples.len() as Weight
+    };
+
+    let mut final_costs = Vec::new();
+    for residual in [false, true] {
+        let mut network = build_deep_graph(&mut rng, 16, residual);
+        for _ in 0..20 {
+            average_cost(&mut network, 0.002);
+        }
+        final_costs.push(average_cost(&mut network, 0.));
+    }
+    assert!(
+        final_costs[1] < final_costs[0] * 0.2,
+        "plain cost={}, residual cost={}",
+        final_costs[0],
+        final_costs[1]
+    );
+}
+
+fn assert_updates_match_gradients(before: &WeightMatrix<f64>, after: &WeightMatrix<f64>, grads: &WeightMatrix<f64>) {
+    for ((before, after), grads) in before.iter_rows().zip(after.iter_rows()).zip(grads.iter_rows()) {
+        for ((before, after), grad) in before.iter().zip(after).zip(grads) {
+            assert!((after - before + grad).abs() < 1e-12, "{} != {}", after - before, -grad);
+        }
+    }
+}
+
+#[test]
+fn test_autodiff_matches_dense_layer_gradients() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network_in::<f64>(&mut rng, &[3, 4, 2], 1.);
+    for bias in &mut network.hidden_layers[0].biases {
+        *bias = rng.gen_range(-1., 1.);
+    }
+    let example = [0.4, -0.7, 0.2];
+    let expected = [0.5, -0.25];
+
+    // Examples are rows, so each layer multiplies them by its transposed weights
+    let mut tape = Tape::new();
+    let inputs = tape.leaf(WeightMatrix::from_rows(&[example]));
+    let targets = tape.leaf(WeightMatrix::from_rows(&[expected]));
+    let hidden_weights = tape.leaf(network.hidden_layers[0].weights.clone());
+    let hidden_biases = tape.leaf(WeightMatrix::from_rows(&[&network.hidden_layers[0].biases]));
+    let output_weights = tape.leaf(network.outputs.weights.clone());
+    let hidden_weights_t = tape.transpose(hidden_weights);
+    let hidden = tape.matmul(inputs, hidden_weights_t);
+    let hidden_before_activation = tape.add_row(hidden, hidden_biases);
+    let hidden = tape.activation(hidden_before_activation, &LeakyReLU);
+    let output_weights_t = tape.transpose(output_weights);
+    let outputs = tape.matmul(hidden, output_weights_t);
+    let errors = tape.sub(targets, outputs);
+    let squared_errors = tape.mul(errors, errors);
+    let cost = tape.sum(squared_errors);
+    tape.backward(cost);
+
+    // With a learning rate of 1, every parameter moves by exactly minus its gradient
+    let before = network.snapshot();
+    let mean_cost = network.train_one_example(&example, &expected, 1.);
+    let after = network.snapshot();
+    assert!((mean_cost - tape.scalar(cost) / 2.).abs() < 1e-12);
+    assert_eq!(tape.value(outputs).row(0), network.outputs.outputs.as_slice());
+
+    // The neuron gradients of the network are the errors of each neuron's output before activation
+    for (neuron_gradient, grad) in network.hidden_layers[0]
+        .neuron_gradients
+        .iter()
+        .zip(tape.grad(hidden_before_activation).row(0))
+    {
+        assert!((neuron_gradient + grad).abs() < 1e-12);
+    }
+    assert_updates_match_gradients(
+        &before.hidden_layer_weights[0],
+        &after.hidden_layer_weights[0],
+        tape.grad(hidden_weights),
+    );
+    assert_updates_match_gradients(
+        &WeightMatrix::from_rows(&[&before.hidden_layer_biases[0]]),
+        &WeightMatrix::from_rows(&[&after.hidden_layer_biases[0]]),
+        tape.grad(hidden_biases),
+    );
+    assert_updates_match_gradients(&before.output_weights, &after.output_weights, tape.grad(output_weights));
+}
+
+/// A made up loss that runs through every operation the tape supports.  Returns the loss along with the variables for
+/// each of `params`.
+fn record_custom_loss(tape: &mut Tape<f64>, params: &[WeightMatrix<f64>; 3]) -> (Var, [Var; 3]) {
+    tape.clear();
+    let [a, b, bias] = [
+        tape.leaf(params[0].clone()),
+        tape.leaf(params[1].clone()),
+        tape.leaf(params[2].clone()),
+    ];
+    let targets = tape.leaf(WeightMatrix::from_rows(&[[1., 0.], [0., 1.], [0., 1.]]));
+
+    // Cross-entropy of a softmax over `a * b + bias`
+    let logits = tape.matmul(a, b);
+    let logits = tape.add_row(logits, bias);
+    let probabilities = tape.softmax(logits);
+    let log_probabilities = tape.ln(probabilities);
+    let target_log_probabilities = tape.mul(log_probabilities, targets);
+    let log_likelihood = tape.sum(target_log_probabilities);
+    let cross_entropy = tape.scale(log_likelihood, -1.);
+
+    let a_t = tape.transpose(a);
+    let hidden = tape.activation(a_t, &Sigmoid);
+    let scaled_hidden = tape.scale(hidden, 0.5);
+    let exp_hidden = tape.exp(scaled_hidden);
+    let penalty = tape.mean(exp_hidden);
+    let column_sums = tape.sum_rows(hidden);
+    let squared_sums = tape.mul(column_sums, column_sums);
+    let sums_penalty = tape.sum(squared_sums);
+
+    let loss = tape.add(cross_entropy, penalty);
+    let loss = tape.sub(loss, sums_penalty);
+    (loss, [a, b, bias])
+}
+
+#[test]
+fn test_autodiff_gradients_match_finite_differences() {
+    let mut rng = pcg::Pcg::default();
+    let mut params = [
+        WeightMatrix::from_fn(3, 4, |_, _| rng.gen_range(-1., 1.)),
+        WeightMatrix::from_fn(4, 2, |_, _| rng.gen_range(-1., 1.)),
+        WeightMatrix::from_fn(1, 2, |_, _| rng.gen_range(-1., 1.)),
+    ];
+    let total_cost = |params: &mut [WeightMatrix<f64>; 3]| {
+        let mut tape = Tape::new();
+        let (loss, _) = record_custom_loss(&mut tape, params);
+        tape.scalar(loss)
+    };
+
+    // Stepping every parameter by minus its gradient stands in for a training step with a learning rate of 1
+    assert_updates_match_finite_differences(
+        &mut params,
+        |params, param_ix| nth_param(params.iter_mut().map(|param| (param, &mut [][..])), param_ix),
+        |params| {
+            let mut tape = Tape::new();
+            let (loss, vars) = record_custom_loss(&mut tape, params);
+            tape.backward(loss);
+            for (param, var) in params.iter_mut().zip(vars) {
+                let grads = tape.grad(var);
+                for row_ix in 0..grads.row_count() {
+                    for col_ix in 0..grads.col_count() {
+                        param[row_ix][col_ix] -= grads[row_ix][col_ix];
+                    }
+                }
+            }
+        },
+        total_cost,
+    );
+}
+
+/// A shared trunk feeding a regression head with two outputs and a classification head with three.
+fn build_multi_head_network<T: Float>(rng: &mut pcg::Pcg, loss_weights: [f64; 2]) -> MultiHeadNetwork<T> {
+    let first_layer = DenseLayer::new(5, 3, &mut scaled_init(rng, 3), &mut |_| T::ZERO, &Tanh);
+    let second_layer = DenseLayer::new(4, 5, &mut scaled_init(rng, 5), &mut |_| T::ZERO, &Tanh);
+    let regression = OutputLayer::new(&Identity, &MeanSquaredError, &mut scaled_init(rng, 4), 4, 2);
+    let classification = OutputLayer::new(&Softmax, &CrossEntropy, &mut scaled_init(rng, 4), 4, 3);
+    MultiHeadNetwork::new(vec![first_layer, second_layer], vec![
+        OutputHead::new(regression, T::from_f64(loss_weights[0])),
+        OutputHead::new(classification, T::from_f64(loss_weights[1])),
+    ])
+    .unwrap()
+}
+
+/// The parameter at `param_ix` when counting through every hidden layer and then the weights of every head.
+fn multi_head_param(network: &mut MultiHeadNetwork<f64>, param_ix: usize) -> Option<&mut f64> {
+    let hidden_layers = network
+        .hidden_layers
+        .iter_mut()
+        .map(|layer| (&mut layer.weights, &mut layer.biases[..]));
+    let heads = network
+        .heads
+        .iter_mut()
+        .map(|head| (&mut head.layer.weights, &mut [][..]));
+    nth_param(hidden_layers.chain(heads), param_ix)
+}
+
+#[test]
+fn test_multi_head_gradients_match_finite_differences() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_multi_head_network::<f64>(&mut rng, [0.5, 2.]);
+    let example = [0.4, -0.7, 0.2];
+    let expected: [&[f64]; 2] = [&[0.5, -0.25], &[0., 1., 0.]];
+    assert_eq!(
+        network.validate_example(&example, &expected[..1]),
+        Err(NnError::InvalidHeadCount { expected: 2, actual: 1 })
+    );
+    assert!(network.validate_example(&example, &[expected[1], expected[0]]).is_err());
+
+    // Training minimizes the weighted sum of every head's total cost
+    let total_cost = |network: &mut MultiHeadNetwork<f64>| -> f64 {
+        network.compute(&example).unwrap();
+        let mut total_cost = 0.;
+        for (head, expected) in network.heads.iter_mut().zip(&expected) {
+            head.layer.compute_costs(expected);
+            total_cost += head.loss_weight * head.layer.costs.iter().sum::<f64>();
+        }
+        total_cost
+    };
+
+    assert_updates_match_finite_differences(
+        &mut network,
+        multi_head_param,
+        |network| {
+            let head_costs = network.train_one_example(&example, &expected, 1.).unwrap().to_vec();
+            for (head, &head_cost) in network.heads.iter().zip(&head_costs) {
+                let average_cost = head.layer.costs.iter().sum::<f64>() / head.output_count() as f64;
+                assert_eq!(head_cost, average_cost);
+            }
+        },
+        total_cost,
+    );
+
+    // A head with a loss weight of zero isn't trained at all
+    network.heads[1].loss_weight = 0.;
+    let head_weights = network.heads[1].layer.weights.clone();
+    network.train_one_example(&example, &expected, 1.).unwrap();
+    assert!(network.heads[1].layer.weights == head_weights);
+}
+
+#[test]
+fn test_multi_head_network_learns_regression_and_classification() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_multi_head_network::<Weight>(&mut rng, [1., 1.]);
+    // The regression head predicts the sum and product of the first two inputs, and the classification head predicts
+    // which of the three inputs is largest
+    let examples: Vec<([Weight; 3], [Weight; 2], [Weight; 3])> = (0..1000)
+        .map(|_| {
+            let inputs = [rng.gen_range(-1., 1.), rng.gen_range(-1., 1.), rng.gen_range(-1., 1.)];
+            let mut class = [0.; 3];
+            class[argmax(&inputs)] = 1.;
+            (inputs, [inputs[0] + inputs[1], inputs[0] * inputs[1]], class)
+        })
+        .collect();
+    let average_costs = |network: &mut MultiHeadNetwork, learning_rate: Weight| -> [Weight; 2] {
+        let mut total_costs = [0.; 2];
+        for (inputs, regression_target, class) in &examples {
+            let expected: [&[Weight]; 2] = [regression_target, class];
+            let head_costs = network.train_one_example(inputs, &expected, learning_rate).unwrap();
+            total_costs[0] += head_costs[0];
+            total_costs[1] += head_costs[1];
+        }
+        total_costs.map(|cost| cost / examples.len() as Weight)
+    };
+
+    let initial_costs = average_costs(&mut network, 0.);
+    for _ in 0..20 {
+        average_costs(&mut network, 0.02);
+    }
+    let final_costs = average_costs(&mut network, 0.);
+    for head_ix in 0..2 {
+        assert!(
+            final_costs[head_ix] < initial_costs[head_ix] * 0.25,
+            "head {} cost went from {} to {}",
+            head_ix,
+            initial_costs[head_ix],
+            final_costs[head_ix]
+        );
+    }
+
+    let correct_count = examples
+        .iter()
+        .filter(|(inputs, _, class)| argmax(network.compute(inputs).unwrap()[1]) == argmax(class))
+        .count();
+    assert!(correct_count > 900, "{} of 1000 classified correctly", correct_count);
+}
+
+#[test]
+fn test_frozen_and_scaled_layers_in_every_trainer() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[5, 7, 3, 2], 0.05);
+    let initial_weights = network.snapshot();
+    let mut dataset = InMemoryDataset::empty(5, 2);
+    dataset.push(&[0.1, -0.4, 0.9, 0.3, -1.], &[0.5, -0.25]).unwrap();
+    let (example, expected) = dataset.get(0);
+
+    network.train_one_example(example, expected, 0.05);
+    let unscaled_weights = network.snapshot();
+
+    network.restore(&initial_weights);
+    network.hidden_layers[0].learning_rate_multiplier = 0.5;
+    network.hidden_layers[1].frozen = true;
+    network.outputs.learning_rate_multiplier = 2.;
+    network.train_one_example(example, expected, 0.05);
+    let expected_weights = network.snapshot();
+
+    // The frozen layer doesn't change, but it still passes errors back so the first layer gets the same gradients as
+    // before, scaled by its multiplier
+    assert!(expected_weights.hidden_layer_weights[1] == initial_weights.hidden_layer_weights[1]);
+    assert_eq!(
+        expected_weights.hidden_layer_biases[1],
+        initial_weights.hidden_layer_biases[1]
+    );
+    let updates = |snapshot: &NetworkSnapshot| -> Vec<Weight> {
+        snapshot.hidden_layer_weights[0]
+            .iter_rows()
+            .flatten()
+            .chain(&snapshot.hidden_layer_biases[0])
+            .zip(
+                initial_weights.hidden_layer_weights[0]
+                    .iter_rows()
+                    .flatten()
+                    .chain(&initial_weights.hidden_layer_biases[0]),
+            )
+            .map(|(after, before)| after - before)
+            .collect()
+    };
+    let half_updates: Vec<Weight> = updates(&unscaled_weights).iter().map(|update| update * 0.5).collect();
+    assert_close(&updates(&expected_weights), &half_updates);
+    let output_updates = |snapshot: &NetworkSnapshot| -> Vec<Weight> {
+        snapshot
+            .output_weights
+            .iter_rows()
+            .flatten()
+            .zip(initial_weights.output_weights.iter_rows().flatten())
+            .map(|(after, before)| after - before)
+            .collect()
+    };
+    let doubled_updates: Vec<Weight> = output_updates(&unscaled_weights)
+        .iter()
+        .map(|update| update * 2.)
+        .collect();
+    assert_close(&output_updates(&expected_weights), &doubled_updates);
+
+    network.restore(&initial_weights);
+    let mut trainer = BatchTrainer::new(&network, 1).unwrap();
+    trainer
+        .train_batch(&mut network, &dataset.batches(1).iter().next().unwrap(), 0.05)
+        .unwrap();
+    assert_snapshots_close(&network.snapshot(), &expected_weights);
+
+    network.restore(&initial_weights);
+    let mut trainer = HogwildTrainer::new(&network, 1).unwrap();
+    trainer
+        .train_epoch(&mut network, &dataset, 0.05, &mut pcg::Pcg::default())
+        .unwrap();
+    assert_snapshots_close(&network.snapshot(), &expected_weights);
+
+    // Sparse networks keep both settings, including when converted back
+    network.restore(&initial_weights);
+    let mut sparse = SparseNetwork::from_network(&network, -1.).unwrap();
+    sparse.train_one_example(example, expected, 0.05).unwrap();
+    let network = sparse.to_network(0.05);
+    assert_snapshots_close(&network.snapshot(), &expected_weights);
+    assert!(network.hidden_layers[1].frozen);
+    assert_eq!(network.hidden_layers[0].learning_rate_multiplier, 0.5);
+    assert_eq!(network.outputs.learning_rate_multiplier, 2.);
+}
+
+/// Trains a network on one function, then swaps in a new output layer and trains it on a related function with the
+/// hidden layers frozen.
+#[test]
+fn test_transfer_learning_with_frozen_hidden_layers() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[2, 16, 16, 1], 0.02);
+    let mut build_dataset = |f: fn(Weight, Weight) -> Weight| {
+        let mut dataset = InMemoryDataset::empty(2, 1);
+        for _ in 0..500 {
+            let (a, b) = (rng.gen_range(-1., 1.), rng.gen_range(-1., 1.));
+            dataset.push(&[a, b], &[f(a, b)]).unwrap();
+        }
+        dataset
+    };
+    let first_task = build_dataset(|a, b| (a * 2.).sin() + b);
+    let second_task = build_dataset(|a, b| 0.5 - (a * 2.).sin() - b);
+    network.fit(&first_task, 20, &mut rng).unwrap();
+
+    let trunk_weights = network.snapshot();
+    *network.outputs = OutputLayer::new(
+        &Identity,
+        &MeanSquaredError,
+        &mut |_, _| rng.gen_range(-0.1, 0.1),
+        16,
+        1,
+    );
+    for layer in &mut network.hidden_layers {
+        layer.frozen = true;
+    }
+    let costs = network.fit(&second_task, 20, &mut rng).unwrap();
+    assert!(
+        costs[costs.len() - 1] < costs[0] * 0.1,
+        "cost went from {} to {}",
+        costs[0],
+        costs[costs.len() - 1]
+    );
+    let snapshot = network.snapshot();
+    for layer_ix in 0..2 {
+        assert!(snapshot.hidden_layer_weights[layer_ix] == trunk_weights.hidden_layer_weights[layer_ix]);
+        assert_eq!(
+            snapshot.hidden_layer_biases[layer_ix],
+            trunk_weights.hidden_layer_biases[layer_ix]
+        );
+    }
+}
+
+fn build_network_with(
+    rng: &mut pcg::Pcg,
+    hidden_activation_fn: &'static dyn ActivationFunction,
+    output_activation_fn: &'static dyn ActivationFunction,
+    cost_fn: &'static dyn CostFunction,
+) -> Result<Network, NnError> {
+    let hidden_layer = DenseLayer::new(3, 2, &mut scaled_init(rng, 2), &mut |_| 0., hidden_activation_fn);
+    let outputs = OutputLayer::new(output_activation_fn, cost_fn, &mut scaled_init(rng, 3), 3, 2);
+    Network::new(Vec::new(), vec![hidden_layer], Box::new(outputs), 0.1)
+}
+
+#[test]
+fn test_softmax_must_be_paired_with_cross_entropy() {
+    let mut rng = pcg::Pcg::default();
+    assert!(build_network_with(&mut rng, &Tanh, &Softmax, &CrossEntropy).is_ok());
+    assert!(build_network_with(&mut rng, &Tanh, &Identity, &MeanSquaredError).is_ok());
+    assert_eq!(
+        build_network_with(&mut rng, &Tanh, &Softmax, &MeanSquaredError).err(),
+        Some(NnError::UnpairedSoftmax { layer_ix: 1 })
+    );
+    assert_eq!(
+        build_network_with(&mut rng, &Tanh, &Sigmoid, &CrossEntropy).err(),
+        Some(NnError::UnpairedSoftmax { layer_ix: 1 })
+    );
+    assert_eq!(
+        build_network_with(&mut rng, &Softmax, &Softmax, &CrossEntropy).err(),
+        Some(NnError::UnpairedSoftmax { layer_ix: 0 })
+    );
+
+    let trunk = DenseLayer::new(3, 2, &mut scaled_init(&mut rng, 2), &mut |_| 0., &Tanh);
+    let regression = OutputLayer::new(&Identity, &MeanSquaredError, &mut scaled_init(&mut rng, 3), 3, 1);
+    let classification = OutputLayer::new(&Softmax, &MeanSquaredError, &mut scaled_init(&mut rng, 3), 3, 2);
+    let heads = vec![OutputHead::new(regression, 1.), OutputHead::new(classification, 1.)];
+    assert_eq!(
+        MultiHeadNetwork::new(vec![trunk], heads).err(),
+        Some(NnError::UnpairedSoftmax { layer_ix: 2 })
+    );
+
+    // The tape records `Softmax` with its full derivative rather than the shortcut used by output layers
+    let mut tape = Tape::<f64>::new();
+    let x = tape.leaf(WeightMatrix::from_rows(&[[0.3, -1.2, 2.], [1., 0.5, -0.4]]));
+    let scale = tape.leaf(WeightMatrix::from_rows(&[[1., -2., 0.5], [-1., 3., 2.]]));
+    let activated = tape.activation(x, &Softmax);
+    let weighted = tape.mul(activated, scale);
+    let loss = tape.sum(weighted);
+    tape.backward(loss);
+    let activation_grad = tape.grad(x).clone();
+
+    tape.clear();
+    let x = tape.leaf(WeightMatrix::from_rows(&[[0.3, -1.2, 2.], [1., 0.5, -0.4]]));
+    let scale = tape.leaf(WeightMatrix::from_rows(&[[1., -2., 0.5], [-1., 3., 2.]]));
+    let activated = tape.softmax(x);
+    let weighted = tape.mul(activated, scale);
+    let loss = tape.sum(weighted);
+    tape.backward(loss);
+    assert_eq!(activation_grad.as_slice(), tape.grad(x).as_slice());
+}
+
+#[test]
+fn test_embedding_must_be_the_first_feature_layer() {
+    let mut rng = pcg::Pcg::default();
+    let mut build_embedding = || {
+        let categorical_inputs = vec![CategoricalInput {
+            input_ix: 0,
+            category_count: 3,
+        }];
+        FeatureLayer::Embedding(EmbeddingLayer::new(2, categorical_inputs, 2, &mut |_, _| {
+            rng.gen_range(-1., 1.)
+        }))
+    };
+    let first = build_embedding();
+    let second = build_embedding();
+
+    let build = |feature_layers: Vec<FeatureLayer>, rng: &mut pcg::Pcg| {
+        let outputs = OutputLayer::new(&Identity, &MeanSquaredError, &mut scaled_init(rng, 3), 3, 1);
+        Network::new(feature_layers, Vec::new(), Box::new(outputs), 0.1)
+    };
+    let flatten = FeatureLayer::Flatten(FlattenLayer::new(ImageShape::new(1, 1, 2)));
+    assert_eq!(
+        build(vec![flatten, second], &mut rng).err(),
+        Some(NnError::MisplacedEmbedding { layer_ix: 1 })
+    );
+    assert!(build(vec![first], &mut rng).is_ok());
+}
+
+#[test]
+fn test_evaluation_reports_invalid_categories() {
+    let mut rng = pcg::Pcg::default();
+    let mut network = build_random_network(&mut rng, &[3, 4, 2], 0.05);
+    let categorical_inputs = vec![CategoricalInput {
+        input_ix: 0,
+        category_count: 3,
+    }];
+    let embedding = EmbeddingLayer::new(2, categorical_inputs, 2, &mut |_, _| rng.gen_range(-1., 1.));
+    network.feature_layers.push(FeatureLayer::Embedding(embedding));
+
+    let valid = InMemoryDataset::new(2, 2, vec![0., 0.5, 2., -0.5], vec![1., 0., 0., 1.]).unwrap();
+    let invalid = InMemoryDataset::new(2, 2, vec![0., 0.5, 3., -0.5], vec![1., 0., 0., 1.]).unwrap();
+    let is_invalid_category = |res: Result<Weight, NnError>| matches!(res, Err(NnError::InvalidCategory { .. }));
+    assert!(is_invalid_category(network.evaluate_dataset(&invalid)));
+    assert!(is_invalid_category(network.classification_accuracy(&invalid)));
+
+    let config = EarlyStoppingConfig {
+        eval_interval: 1,
+        patience: 1,
+        min_delta: 0.,
+        max_iterations: 4,
+    };
+    for (train, validation) in [(&valid, &invalid), (&invalid, &valid)] {
+        let res = network.train_with_early_stopping(
+            train.inputs(),
+            train.targets(),
+            validation.inputs(),
+            validation.targets(),
+            0.05,
+            &config,
+        );
+        assert!(is_invalid_category(res.map(|report| report.best_validation_cost)));
+    }
+    network.evaluate_dataset(&valid).unwrap();
+}
+
+#[test]
+fn test_frozen_layers_in_other_networks() {
+    let mut rng = pcg::Pcg::default();
+
+    let mut network = build_random_network_in::<Weight>(&mut rng, &[3, 4, 2], 0.05);
+    network.feature_layers = build_feature_layers(&mut rng, ImageShape::new(1, 5, 5));
+    let example: Vec<Weight> = (0..25).map(|_| rng.gen_range(-1., 1.)).collect();
+    let before = network.snapshot();
+    for layer in &mut network.feature_layers {
+        if let FeatureLayer::Conv2D(layer) = layer {
+            layer.frozen = true;
+        }
+    }
+    network.outputs.frozen = true;
+    network.train_one_example(&example, &[0.5, -0.25], 0.05);
+    let after = network.snapshot();
+    assert!(after.feature_layer_weights == before.feature_layer_weights);
+    assert!(after.feature_layer_biases == before.feature_layer_biases);
+    assert!(after.output_weights == before.output_weights);
+    assert!(after.hidden_layer_weights != before.hidden_layer_weights);
+
+    let mut network = build_random_network_in::<Weight>(&mut rng, &[4, 3, 1], 0.05);
+    let categorical_inputs = vec![CategoricalInput {
+        input_ix: 0,
+        category_count: 3,
+    }];
+    let mut embedding = EmbeddingLayer::new(2, categorical_inputs, 3, &mut |_, _| rng.gen_range(-1., 1.));
+    embedding.frozen = true;
+    network.feature_layers.push(FeatureLayer::Embedding(embedding));
+    let before = network.snapshot();
+    network.train_one_example(&[1., 0.5], &[0.5], 0.05);
+    assert!(network.snapshot().feature_layer_weights == before.feature_layer_weights);
+
+    let mut network = build_multi_head_network::<Weight>(&mut rng, [1., 1.]);
+    network.heads[0].layer.frozen = true;
+    let head_weights = network.heads[0].layer.weights.clone();
+    network
+        .train_one_example(&[0.4, -0.7, 0.2], &[&[0.5, -0.25][..], &[0., 1., 0.]], 0.05)
+        .unwrap();
+    assert!(network.heads[0].layer.weights == head_weights);
+
+    let mut network = build_deep_graph(&mut rng, 2, true);
+    network.outputs.frozen = true;
+    let output_weights = network.outputs.weights.clone();
+    network.train_one_example(&[0.1, 0.2, 0.3, 0.4], &[0.5], 0.05).unwrap();
+    assert!(network.outputs.weights == output_weights);
+
+    let sequence = [[0.3, -0.2], [0.8, 0.1]];
+    let mut network = build_sequence_network::<Weight>(&mut rng, CellType::Gru, &[2, 3, 2]);
+    network.outputs.frozen = true;
+    let output_weights = network.outputs.weights.clone();
+    network.train_sequence(&sequence, &sequence, 0.05).unwrap();
+    assert!(network.outputs.weights == output_weights);
+
+    let mut network = build_transformer::<Weight>(&mut rng, 2, 4, &[true], 2);
+    network.outputs.frozen = true;
+    let output_weights = network.outputs.weights.clone();
+    network.train_sequence(&sequence, &sequence, 0.05).unwrap();
+    assert!(network.outputs.weights == output_weights);
+}
@@ -1,106 +1,32 @@
-use std::mem::MaybeUninit;
-
-use libnn::Network;
-use palette::{
-    encoding::{Linear, Srgb},
-    rgb::Rgb,
-    Gradient,
-};
-
-const COLORIZER_LUT_SIZE: usize = 512;
-type ColorizerLUT = [[u8; 4]; COLORIZER_LUT_SIZE];
-
-static mut COLORIZER_LUT: *const ColorizerLUT = std::ptr::null();
-
-fn colorizer_lut() -> &'static ColorizerLUT { unsafe { &*COLORIZER_LUT } }
-
-const COLORIZER_LUT_RANGE: [f32; 2] = [-2.5, 2.5];
-
-fn build_lut(gradient: &Gradient<Rgb<Linear<Srgb>>>) -> ColorizerLUT {
-    let [min, max] = COLORIZER_LUT_RANGE;
-    let range = max - min;
-
-    let mut lut = MaybeUninit::<ColorizerLUT>::uninit();
-    for i in 0..COLORIZER_LUT_SIZE {
-        // We want to be able to look up in the LUT by raw, unscaled values from [-2.5, 2.5]
-        // The scaler converts the raw value to a value in [-1, 1]
-        // Then we convert that to a value in [0, COLORIZER_LUT_SIZE]
-        let x = min + (i as f32 / (COLORIZER_LUT_SIZE - 1) as f32) * range; // [-2.5, 2.5]
-        let scaled_x = scale_output(x); // [-1, 1]
-        let scaled_x = scaled_x + 1.0; // [0, 2]
-        let scaled_x = scaled_x / 2.0; // [0, 1]
-
-        let color = gradient.get(scaled_x);
-        unsafe {
-            (lut.as_mut_ptr() as *mut [u8; 4]).add(i).write([
-                (color.red * 255.) as u8,
-                (color.green * 255.) as u8,
-                (color.blue * 255.) as u8,
-                255,
-            ])
-        }
-    }
-
-    unsafe { lut.assume_init() }
-}
-
-fn build_even_color_steps(color_steps: &[[u8; 3]]) -> Vec<(f32, Rgb<Linear<Srgb>>)> {
-    color_steps
-        .into_iter()
-        .enumerate()
-        .map(|(i, [r, g, b])| {
-            (
-                i as f32 / ((color_steps.len() - 1) as f32),
-                Rgb::new(*r as f32 / 255., *g as f32 / 255., *b as f32 / 255.),
-            )
-        })
-        .collect()
-}
-
-pub fn initialize_colorizer_luts() {
-    if unsafe { !COLORIZER_LUT.is_null() } {
-        return;
+use colorizer::{colorize_output, initialize_colorizer_luts};
+use libnn::{BatchActivations, InferenceWorkspace, Network, NnError, WeightMatrix};
+
+/// Used in place of the color for pruned weights when they're highlighted.  The alpha of 0 lets the frontend tell them
+/// apart from weights that have just been trained to zero.
+pub const PRUNED_WEIGHT_COLOR: [u8; 4] = [255, 0, 255, 0];
+
+/// Colors the weights connecting the neuron at `input_ix` of one layer to each neuron of the next, given the next
+/// layer's weights.  With `highlight_pruned`, weights that have been pruned are colored with `PRUNED_WEIGHT_COLOR`.
+pub fn colorize_input_weights(
+    weights: &WeightMatrix,
+    weight_mask: Option<&WeightMatrix>,
+    input_ix: usize,
+    highlight_pruned: bool,
+) -> Vec<u8> {
+    if input_ix >= weights.col_count() {
+        return Vec::new();
     }
 
-    let color_steps = build_even_color_steps(&[
-        [10, 243, 255],
-        [18, 194, 227],
-        [27, 145, 198],
-        [28, 99, 150],
-        [22, 58, 83],
-        [16, 16, 16],
-        [112, 112, 10],
-        [207, 207, 3],
-        [255, 204, 0],
-        [255, 102, 0],
-        [255, 0, 0],
-    ]);
-
-    let lut = build_lut(&Gradient::with_domain(color_steps));
-    unsafe { COLORIZER_LUT = Box::into_raw(box lut) };
-}
-
-/// Scales inputs into the range [-1, 1]
-fn scale_output(value: f32) -> f32 { fastapprox::fast::tanh(value * 0.8) }
-
-fn clamp(min: f32, max: f32, val: f32) -> f32 {
-    if val < min {
-        min
-    } else if val > max {
-        max
-    } else {
-        val
+    let mut buf = Vec::with_capacity(weights.row_count() * 4);
+    for neuron_ix in 0..weights.row_count() {
+        let is_pruned = weight_mask.is_some_and(|mask| mask[neuron_ix][input_ix] == 0.);
+        if highlight_pruned && is_pruned {
+            buf.extend_from_slice(&PRUNED_WEIGHT_COLOR);
+        } else {
+            buf.extend_from_slice(&colorize_output(weights[neuron_ix][input_ix]));
+        }
     }
-}
-
-pub fn colorize_output(val: f32) -> [u8; 4] {
-    let val = clamp(COLORIZER_LUT_RANGE[0], COLORIZER_LUT_RANGE[1], val);
-    // Scale val from [-2.5, 2.5] to [0, COLORIZER_LUT_SIZE]
-    let lut_ix = ((val + 2.5) * (COLORIZER_LUT_SIZE - 1) as f32 / 5.) as usize;
-    debug_assert!(lut_ix < COLORIZER_LUT_SIZE);
-
-    let lut = colorizer_lut();
-    unsafe { *lut.get_unchecked(lut_ix) }
+    buf
 }
 
 pub fn build_layer_outputs_buf(output_count: usize) -> Vec<u8> { vec![0; output_count * 24 * 24 * 4] }
@@ -128,16 +54,17 @@ impl LayerVizState {
         }
     }
 
-    fn populate_layer_outputs_buf(buf: &mut Vec<u8>, outputs: &[f32], viz_scale_multiplier: usize) {
+    /// Returns `false` without touching the rest of the buffer if any of the outputs are NaN or infinite.
+    fn populate_layer_outputs_buf(buf: &mut Vec<u8>, outputs: &[f32], viz_scale_multiplier: usize) -> bool {
+        if !outputs.iter().all(|output| output.is_finite()) {
+            return false;
+        }
         buf.resize(outputs.len() * viz_scale_multiplier * viz_scale_multiplier * 4, 0);
 
         let buf_ptr = buf.as_mut_ptr() as *mut u8 as *mut u32;
 
         for (i, output) in outputs.iter().enumerate() {
             let color = colorize_output(*output);
-            if color[0] == 0 && color[1] == 0 && color[2] == 0 {
-                panic!("{}", *output);
-            }
             let color: u32 = unsafe { std::mem::transmute(color) };
 
             for y in 0..viz_scale_multiplier {
@@ -151,53 +78,78 @@ impl LayerVizState {
                 }
             }
         }
+
+        true
     }
 
-    pub fn update(&mut self, network: &Network, example: &[f32], viz_scale_multiplier: usize) {
+    /// `activations` should hold the outputs of each layer for `example`.
+    pub fn update(
+        &mut self,
+        activations: &InferenceWorkspace,
+        example: &[f32],
+        viz_scale_multiplier: usize,
+    ) -> Result<(), NnError> {
         Self::populate_layer_outputs_buf(&mut self.input_layer_buffer, example, viz_scale_multiplier);
-        for (layer_ix, hidden_layer) in network.hidden_layers.iter().enumerate() {
-            Self::populate_layer_outputs_buf(
+        for (layer_ix, hidden_layer_outputs) in activations.hidden_layers.iter().enumerate() {
+            if !Self::populate_layer_outputs_buf(
                 &mut self.hidden_layer_buffers[layer_ix],
-                &hidden_layer.outputs,
+                hidden_layer_outputs,
                 viz_scale_multiplier,
-            );
+            ) {
+                return Err(NnError::NonFiniteOutputs { layer_ix });
+            }
         }
-        Self::populate_layer_outputs_buf(
+        if !Self::populate_layer_outputs_buf(
             &mut self.output_layer_buffer,
-            &network.outputs.outputs,
+            &activations.outputs,
             viz_scale_multiplier,
-        );
+        ) {
+            return Err(NnError::NonFiniteOutputs {
+                layer_ix: activations.hidden_layers.len(),
+            });
+        }
+
+        Ok(())
     }
 
-    pub fn build_neuron_response_viz(network: &mut Network, layer_ix: usize, neuron_ix: usize, size: usize) -> Vec<u8> {
-        let mut example = [0., 0.];
-        let neuron_output = match layer_ix {
-            0 => example.get(neuron_ix),
-            layer_ix if layer_ix <= network.hidden_layers.len() => network
-                .hidden_layers
-                .get(layer_ix - 1)
-                .and_then(|neuron| neuron.outputs.get(neuron_ix)),
-            _ => network.outputs.outputs.get(neuron_ix),
-        };
-        let neuron_output = match neuron_output {
-            Some(output) => output as *const f32,
-            None => return Vec::new(),
-        };
+    pub fn build_neuron_response_viz(
+        network: &Network,
+        activations: &mut BatchActivations,
+        layer_ix: usize,
+        neuron_ix: usize,
+        size: usize,
+    ) -> Vec<u8> {
+        // The response is plotted over the 2D input space, so it only makes sense for 2-input networks
+        if network.input_count() != 2 {
+            return Vec::new();
+        }
 
-        let mut buf = Vec::with_capacity(size * size * 4);
+        // Every pixel of the grid is evaluated in a single batch, top row first
+        let mut examples = Vec::with_capacity(size * size * 2);
         for y in (0..size).rev() {
             let y = y as f32 / (size - 1) as f32;
             for x in 0..size {
                 let x = x as f32 / (size - 1) as f32;
-                example = [x, y];
-                network.forward_propagate(&example);
-                let neuron_output = unsafe { *neuron_output };
-                let color = colorize_output(neuron_output);
-                buf.extend_from_slice(&color);
+                examples.extend_from_slice(&[x, y]);
             }
         }
+        if network.forward_propagate_batch(&examples, activations).is_err() {
+            return Vec::new();
+        }
 
-        buf
+        let layer_outputs = match layer_ix {
+            0 => &activations.inputs,
+            layer_ix if layer_ix <= network.hidden_layers.len() => &activations.hidden_layers[layer_ix - 1],
+            _ => &activations.outputs,
+        };
+        if neuron_ix >= layer_outputs.col_count() {
+            return Vec::new();
+        }
+
+        layer_outputs
+            .column(neuron_ix)
+            .flat_map(|neuron_output| colorize_output(neuron_output).into_iter())
+            .collect()
     }
 
     pub fn build_color_scale_legend(low: f32, high: f32, width: usize, height: usize) -> Vec<u8> {
@@ -6,10 +6,11 @@
     thread_local
 )]
 
-use layer_viz::{colorize_output, initialize_colorizer_luts, LayerVizState};
+use colorizer::initialize_colorizer_luts;
+use layer_viz::{colorize_input_weights, LayerVizState};
 use libnn::{
-    ActivationFunction, CostFunction, DenseLayer, Network, OutputLayer, Weight, AMEO, GAUSSIAN, GCU, IDENTITY,
-    LEAKY_RELU, MEAN_SQUARED_ERROR, RELU, SIGMOID, SWISH, TANH,
+    ActivationFunction, BatchActivations, CostFunction, DenseLayer, InferenceWorkspace, LayerSparsity, Network, NetworkSnapshot, NnError, OutputLayer, PruningScope, Weight, AMEO,
+    GAUSSIAN, GCU, IDENTITY, LEAKY_RELU, MEAN_SQUARED_ERROR, RELU, SIGMOID, SWISH, TANH,
 };
 use rand::prelude::*;
 use wasm_bindgen::prelude::*;
@@ -19,8 +20,18 @@ mod layer_viz;
 pub struct NNCtx {
     pub network: Network,
     pub viz_state: LayerVizState,
+    /// Holds the network's parameters from the start of the current training batch so that they can be restored if
+    /// training diverges partway through it.
+    pub rollback_snapshot: NetworkSnapshot,
+    /// Re-used between calls that run many examples through the network at once.
+    pub batch_activations: BatchActivations,
+    /// Holds the outputs of each layer for predictions and visualizations so that they don't overwrite the values
+    /// stored on the layers during training.
+    pub inference_workspace: InferenceWorkspace,
 }
 
+fn to_js_err(err: NnError) -> JsValue { JsValue::from_str(&err.to_string()) }
+
 #[derive(Clone, Copy)]
 #[repr(u8)]
 pub enum ActivationFunctionType {
@@ -224,89 +235,162 @@ pub fn create_nn_ctx(
     );
 
     let network = Network {
+        feature_layers: Vec::new(),
         hidden_layers,
         outputs: output_layer,
         learning_rate,
     };
     let viz_state = LayerVizState::new(&network, input_count);
 
-    let ctx = box NNCtx { network, viz_state };
+    let ctx = box NNCtx {
+        network,
+        viz_state,
+        rollback_snapshot: NetworkSnapshot::default(),
+        batch_activations: BatchActivations::new(),
+        inference_workspace: InferenceWorkspace::new(),
+    };
     Box::into_raw(ctx)
 }
 
 #[wasm_bindgen]
 pub fn free_nn_ctx(ctx: *mut NNCtx) { unsafe { drop(Box::from_raw(ctx)) } }
 
+/// Trains the network on a single example.  If training diverges, the step is rolled back and an exception is thrown.
 #[wasm_bindgen]
-pub fn train(ctx: *mut NNCtx, example: &[Weight], expected: &[Weight], learning_rate: Weight) -> Weight {
-    let network: &mut Network = unsafe { &mut (*ctx).network };
+pub fn train(ctx: *mut NNCtx, example: &[Weight], expected: &[Weight], learning_rate: Weight) -> Result<Weight, JsValue> {
+    let ctx = unsafe { &mut *ctx };
 
-    network.train_one_example(example, expected, learning_rate)
+    ctx.network
+        .try_train_one_example_with_rollback(example, expected, learning_rate, &mut ctx.rollback_snapshot)
+        .map_err(to_js_err)
 }
 
+/// Trains the network on each of the provided examples in order.  If training diverges, the network is rolled back to
+/// the state it was in before the batch started and an exception is thrown.
 #[wasm_bindgen]
 pub fn train_many_examples(
     ctx: *mut NNCtx,
     examples: &[Weight],
     expected: &[Weight],
     learning_rate: Weight,
-) -> Vec<Weight> {
-    let network: &mut Network = unsafe { &mut (*ctx).network };
+) -> Result<Vec<Weight>, JsValue> {
+    let ctx = unsafe { &mut *ctx };
+    let network = &mut ctx.network;
 
-    let input_dims = network.hidden_layers[0].weights[0].len();
-    let output_dims = network.outputs.outputs.len();
-    let iterations = examples.len() / input_dims;
-    let mut costs = Vec::with_capacity(iterations);
+    let input_dims = network.input_count();
+    let output_dims = network.output_count();
+    let iterations = network.count_examples(examples, expected).map_err(to_js_err)?;
 
-    assert_eq!(examples.len(), input_dims * iterations);
-    assert_eq!(expected.len(), output_dims * iterations);
+    network.snapshot_into(&mut ctx.rollback_snapshot);
+    let mut costs = Vec::with_capacity(iterations);
 
     for iteration in 0..iterations {
-        let cost = network.train_one_example(
+        let res = network.try_train_one_example(
             &examples[iteration * input_dims..(iteration + 1) * input_dims],
             &expected[iteration * output_dims..(iteration + 1) * output_dims],
             learning_rate,
         );
-        costs.push(cost);
+        match res {
+            Ok(cost) => costs.push(cost),
+            Err(err) => {
+                network.restore(&ctx.rollback_snapshot);
+                return Err(to_js_err(err));
+            },
+        }
     }
 
-    costs
+    Ok(costs)
 }
 
 #[wasm_bindgen]
-pub fn predict(ctx: *mut NNCtx, example: &[Weight]) -> Vec<Weight> {
-    let network: &mut Network = unsafe { &mut (*ctx).network };
-    network.compute(example).to_owned()
+pub fn predict(ctx: *mut NNCtx, example: &[Weight]) -> Result<Vec<Weight>, JsValue> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.network
+        .predict(example, &mut ctx.inference_workspace)
+        .map(<[Weight]>::to_owned)
+        .map_err(to_js_err)
 }
 
+/// Runs `example` through the network `steps` times, sweeping the value at `example_dim_to_replace` from `min_input`
+/// up towards `max_input`.  Returns the outputs for each step back-to-back.
 #[wasm_bindgen]
 pub fn predict_batch(
     ctx: *mut NNCtx,
-    mut example: Vec<Weight>,
+    example: Vec<Weight>,
     example_dim_to_replace: usize,
     min_input: Weight,
     max_input: Weight,
     steps: usize,
-) -> Vec<Weight> {
-    let network: &mut Network = unsafe { &mut (*ctx).network };
-    let mut outputs: Vec<Weight> = Vec::with_capacity(steps * network.outputs.outputs.len());
-
-    let range = max_input - min_input;
-    let step_size = range / steps as f32;
-    example[example_dim_to_replace] = min_input;
-    for _ in 0..steps {
-        outputs.extend_from_slice(network.compute(&example));
-        example[example_dim_to_replace] += step_size;
+) -> Result<Vec<Weight>, JsValue> {
+    let ctx = unsafe { &mut *ctx };
+    ctx.network.validate_inputs(&example).map_err(to_js_err)?;
+    if example_dim_to_replace >= example.len() {
+        return Err(JsValue::from_str(&format!(
+            "dimension {} is out of range for an example with {} values",
+            example_dim_to_replace,
+            example.len()
+        )));
+    }
+
+    let step_size = (max_input - min_input) / steps as f32;
+    let mut examples: Vec<Weight> = Vec::with_capacity(steps * example.len());
+    for step_ix in 0..steps {
+        examples.extend_from_slice(&example);
+        let start = examples.len() - example.len();
+        examples[start + example_dim_to_replace] = step_ix as f32 * step_size + min_input;
+    }
+
+    let outputs = ctx
+        .network
+        .forward_propagate_batch(&examples, &mut ctx.batch_activations)
+        .map_err(to_js_err)?;
+    Ok(outputs.iter_rows().flatten().copied().collect())
+}
+
+/// Evaluates a 2-input, 1-output network over a `steps` x `steps` grid covering `[min_input, max_input)` in both
+/// dimensions.  Returns `(a, b, output)` triplets for each point in the grid with `a` changing slowest.
+#[wasm_bindgen]
+pub fn compute_response_matrix(
+    ctx: *mut NNCtx,
+    steps: usize,
+    min_input: Weight,
+    max_input: Weight,
+) -> Result<Vec<Weight>, JsValue> {
+    let ctx = unsafe { &mut *ctx };
+    if ctx.network.input_count() != 2 || ctx.network.output_count() != 1 {
+        return Err(JsValue::from_str("only 2-input, 1-output networks are supported"));
+    }
+
+    let step_size = (max_input - min_input) / steps as f32;
+    let step_value = |step_ix: usize| step_ix as f32 * step_size + min_input;
+    let mut examples: Vec<Weight> = Vec::with_capacity(steps * steps * 2);
+    for a_step_ix in 0..steps {
+        for b_step_ix in 0..steps {
+            examples.extend_from_slice(&[step_value(a_step_ix), step_value(b_step_ix)]);
+        }
     }
 
-    outputs
+    let outputs = ctx
+        .network
+        .forward_propagate_batch(&examples, &mut ctx.batch_activations)
+        .map_err(to_js_err)?;
+    let mut response_matrix = Vec::with_capacity(steps * steps * 3);
+    for (example_ix, example) in examples.chunks_exact(2).enumerate() {
+        response_matrix.extend_from_slice(&[example[0], example[1], outputs[example_ix][0]]);
+    }
+
+    Ok(response_matrix)
 }
 
 #[wasm_bindgen]
-pub fn update_viz(ctx: *mut NNCtx, example: &[Weight], viz_scale_multiplier: usize) {
+pub fn update_viz(ctx: *mut NNCtx, example: &[Weight], viz_scale_multiplier: usize) -> Result<(), JsValue> {
     let ctx = unsafe { &mut (*ctx) };
-    ctx.network.forward_propagate(example);
-    ctx.viz_state.update(&ctx.network, example, viz_scale_multiplier);
+    ctx.network
+        .predict(example, &mut ctx.inference_workspace)
+        .map_err(to_js_err)?;
+    ctx.viz_state
+        .update(&ctx.inference_workspace, example, viz_scale_multiplier)
+        .map_err(to_js_err)
 }
 
 #[wasm_bindgen]
@@ -327,26 +411,85 @@ pub fn get_viz_output_layer_colors(ctx: *const NNCtx) -> Vec<u8> {
     ctx.viz_state.output_layer_buffer.clone()
 }
 
+/// Returns the colors of the weights connecting the selected neuron to each neuron of the next layer.  With
+/// `highlight_pruned`, pruned weights are colored with `PRUNED_WEIGHT_COLOR` instead of by their value.
 #[wasm_bindgen]
-pub fn get_input_weights_for_next_layer(ctx: *const NNCtx, layer_ix: isize, neuron_ix: usize) -> Vec<u8> {
+pub fn get_input_weights_for_next_layer(
+    ctx: *const NNCtx,
+    layer_ix: isize,
+    neuron_ix: usize,
+    highlight_pruned: bool,
+) -> Vec<u8> {
     let ctx = unsafe { &(*ctx) };
-    let next_layer_weights = match layer_ix {
-        0 => &ctx.network.hidden_layers[0].weights,
-        n if n > 0 && n < ctx.network.hidden_layers.len() as isize => &ctx.network.hidden_layers[n as usize].weights,
-        n if n == ctx.network.hidden_layers.len() as isize => &ctx.network.outputs.weights,
+    let (next_layer_weights, next_layer_mask) = match layer_ix {
+        n if n >= 0 && n < ctx.network.hidden_layers.len() as isize => {
+            let layer = &ctx.network.hidden_layers[n as usize];
+            (&layer.weights, layer.weight_mask.as_ref())
+        },
+        n if n == ctx.network.hidden_layers.len() as isize =>
+            (&ctx.network.outputs.weights, ctx.network.outputs.weight_mask.as_ref()),
         _ => return Vec::new(),
     };
 
-    next_layer_weights
+    colorize_input_weights(next_layer_weights, next_layer_mask, neuron_ix, highlight_pruned)
+}
+
+/// Prunes `fraction` of the remaining weights with the smallest magnitudes across the whole network.  Returns the
+/// fraction of each layer's weights that are pruned afterwards, with the output layer last.
+#[wasm_bindgen]
+pub fn prune_weights(ctx: *mut NNCtx, fraction: Weight) -> Result<Vec<Weight>, JsValue> {
+    let ctx = unsafe { &mut *ctx };
+    if !(0. ..=1.).contains(&fraction) {
+        return Err(JsValue::from_str(&format!(
+            "can't prune {} of the weights; the fraction must be between 0 and 1",
+            fraction
+        )));
+    }
+
+    ctx.network.prune_by_magnitude(fraction, PruningScope::Global);
+    Ok(get_weight_sparsity(ctx))
+}
+
+/// Returns the fraction of each layer's weights that have been pruned, with the output layer last.
+#[wasm_bindgen]
+pub fn get_weight_sparsity(ctx: *const NNCtx) -> Vec<Weight> {
+    let ctx = unsafe { &*ctx };
+    ctx.network
+        .weight_sparsity()
         .iter()
-        .flat_map(|neuron_weights| {
-            let weight = neuron_weights.get(neuron_ix).copied().unwrap_or_default();
-            let color = colorize_output(weight);
-            color.into_iter()
-        })
+        .map(LayerSparsity::sparsity)
         .collect()
 }
 
+/// Freezes or unfreezes a hidden layer and sets the multiplier applied to the learning rate when updating it.  Takes
+/// effect from the next training step, so it can be changed while the network is training.
+#[wasm_bindgen]
+pub fn set_hidden_layer_training(
+    ctx: *mut NNCtx,
+    hidden_layer_ix: usize,
+    frozen: bool,
+    learning_rate_multiplier: Weight,
+) -> Result<(), JsValue> {
+    let ctx = unsafe { &mut *ctx };
+    if !learning_rate_multiplier.is_finite() || learning_rate_multiplier < 0. {
+        return Err(JsValue::from_str(&format!(
+            "invalid learning rate multiplier {}; it must be a finite number that isn't negative",
+            learning_rate_multiplier
+        )));
+    }
+    let layer_count = ctx.network.hidden_layers.len();
+    let layer = ctx.network.hidden_layers.get_mut(hidden_layer_ix).ok_or_else(|| {
+        JsValue::from_str(&format!(
+            "hidden layer {} is out of range for a network with {} hidden layers",
+            hidden_layer_ix, layer_count
+        ))
+    })?;
+
+    layer.frozen = frozen;
+    layer.learning_rate_multiplier = learning_rate_multiplier;
+    Ok(())
+}
+
 #[wasm_bindgen]
 pub fn build_neuron_response_viz(ctx: *mut NNCtx, layer_ix: isize, neuron_ix: usize, size: usize) -> Vec<u8> {
     let ctx = unsafe { &mut (*ctx) };
@@ -356,7 +499,7 @@ pub fn build_neuron_response_viz(ctx: *mut NNCtx, layer_ix: isize, neuron_ix: us
     } else {
         layer_ix as usize
     };
-    LayerVizState::build_neuron_response_viz(&mut ctx.network, layer_ix, neuron_ix, size)
+    LayerVizState::build_neuron_response_viz(&ctx.network, &mut ctx.batch_activations, layer_ix, neuron_ix, size)
 }
 
 #[wasm_bindgen]
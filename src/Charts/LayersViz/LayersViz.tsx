@@ -32,6 +32,7 @@ interface LayersVizState {
   selectedNeuron: { layerIx: number | 'init_output'; neuronIx: number } | null;
   hiddenLayerCount: number;
   maxHiddenLayerSize: number;
+  highlightPrunedWeights: boolean;
 }
 
 class LayersViz extends React.Component<LayersVizProps, LayersVizState> {
@@ -53,6 +54,7 @@ class LayersViz extends React.Component<LayersVizProps, LayersVizState> {
       selectedNeuron: { layerIx: 'init_output', neuronIx: 0 },
       hiddenLayerCount: 2,
       maxHiddenLayerSize: 64,
+      highlightPrunedWeights: true,
     };
   }
 
@@ -231,17 +233,22 @@ class LayersViz extends React.Component<LayersVizProps, LayersVizState> {
 
     ctx.lineWidth = 1.3;
     for (let i = 0; i < selectedNeuronInputWeights.length / 4; i += 1) {
-      const [r, g, b] = [
+      const [r, g, b, a] = [
         selectedNeuronInputWeights[i * 4],
         selectedNeuronInputWeights[i * 4 + 1],
         selectedNeuronInputWeights[i * 4 + 2],
+        selectedNeuronInputWeights[i * 4 + 3],
       ];
+      // Pruned weights come back fully transparent when they're being highlighted
+      const isPruned = a === 0;
       ctx.beginPath();
-      ctx.strokeStyle = `rgb(${r}, ${g}, ${b})`;
+      ctx.strokeStyle = isPruned ? `rgba(${r}, ${g}, ${b}, 0.5)` : `rgb(${r}, ${g}, ${b})`;
+      ctx.setLineDash(isPruned ? [3, 3] : []);
       ctx.moveTo(startX, startY);
       ctx.lineTo(i * (VIZ_SCALE_MULTIPLIER / dpr) + VIZ_SCALE_MULTIPLIER / dpr / 2, endY);
       ctx.stroke();
     }
+    ctx.setLineDash([]);
   };
 
   private maybeRender = async (force = false) => {
@@ -276,7 +283,8 @@ class LayersViz extends React.Component<LayersVizProps, LayersVizState> {
       ? await this.props.nnCtx.getVizData(
           this.coord,
           this.state.selectedNeuron,
-          VIZ_SCALE_MULTIPLIER
+          VIZ_SCALE_MULTIPLIER,
+          this.state.highlightPrunedWeights
         )
       : null;
     this.isRendering = false;
@@ -355,6 +363,16 @@ class LayersViz extends React.Component<LayersVizProps, LayersVizState> {
           onMouseMove={this.handleCanvasMouseMove}
         />
       </div>
+      <label className='highlight-pruned-weights'>
+        <input
+          type='checkbox'
+          checked={this.state.highlightPrunedWeights}
+          onChange={evt =>
+            this.setState({ highlightPrunedWeights: evt.target.checked }, this.forceRender)
+          }
+        />
+        Highlight pruned weights
+      </label>
 
       <div className='bottom-vizs'>
         <div className='header'>
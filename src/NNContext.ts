@@ -26,7 +26,7 @@ export class NNContext {
     sourceFn: (inputs: Float32Array) => Float32Array,
     iterations: number,
     inputRange: [number, number]
-  ): Promise<Float32Array> {
+  ): Promise<Float32Array | null> {
     if (!(await nnWorker.getIsInitialized())) {
       throw new UnreachableException('Not initialized');
     }
@@ -48,11 +48,18 @@ export class NNContext {
       expecteds.set(expected, outputDims * i);
     }
 
-    return nnWorker.trainBatch(
-      Comlink.transfer(examples, [examples.buffer]),
-      Comlink.transfer(expecteds, [expecteds.buffer]),
-      this.definition.outputLayer.learningRate
-    );
+    try {
+      return await nnWorker.trainBatch(
+        Comlink.transfer(examples, [examples.buffer]),
+        Comlink.transfer(expecteds, [expecteds.buffer]),
+        this.definition.outputLayer.learningRate
+      );
+    } catch (err) {
+      // The engine rolls the network back to where it was before the batch when training diverges
+      this.isRunning = false;
+      alert(`${err}\n\nTry lowering the learning rate or resetting the network.`);
+      return null;
+    }
   }
 
   public async computeResponseMatrix(
@@ -92,12 +99,21 @@ export class NNContext {
   public getVizData(
     example: Float32Array,
     selectedNeuron: { layerIx: number | 'init_output'; neuronIx: number } | null,
-    vizScaleMultiplier: number
+    vizScaleMultiplier: number,
+    highlightPrunedWeights: boolean
   ) {
     if (!this.hasTrained) {
       return null;
     }
-    return nnWorker.getVizData(example, selectedNeuron, vizScaleMultiplier);
+    return nnWorker.getVizData(example, selectedNeuron, vizScaleMultiplier, highlightPrunedWeights);
+  }
+
+  public pruneWeights(fraction: number) {
+    return nnWorker.pruneWeights(fraction);
+  }
+
+  public setHiddenLayerTraining(layerIx: number, frozen: boolean, learningRateMultiplier: number) {
+    return nnWorker.setHiddenLayerTraining(layerIx, frozen, learningRateMultiplier);
   }
 
   public getNeuronResponse(layerIx: number, neuronIx: number, size: number) {
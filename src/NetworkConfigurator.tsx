@@ -63,6 +63,14 @@ const buildHiddenLayerSettings = (onDelete: () => void) => [
     label: 'bias initializer',
     options: buildValueInitializerOptions(),
   },
+  { type: 'checkbox', label: 'frozen' },
+  {
+    type: 'range',
+    label: 'learning rate multiplier',
+    scale: 'log',
+    min: 0.01,
+    max: 10,
+  },
   {
     type: 'button',
     label: 'delete',
@@ -77,6 +85,11 @@ interface HiddenLayerConfiguratorProps {
   layerIx: number;
   layer: HiddenLayerDefinition;
   onChange: (newLayer: HiddenLayerDefinition) => void;
+  /**
+   * Called when the layer is frozen or its learning rate multiplier changes.  These apply to the
+   * running network right away rather than waiting for it to be reset.
+   */
+  onTrainingChange: (frozen: boolean, learningRateMultiplier: number) => void;
   onDelete: () => void;
 }
 
@@ -84,6 +97,7 @@ const HiddenLayerConfigurator: React.FC<HiddenLayerConfiguratorProps> = ({
   layerIx,
   layer,
   onChange,
+  onTrainingChange,
   onDelete,
 }) => {
   const state = useMemo(
@@ -92,11 +106,15 @@ const HiddenLayerConfigurator: React.FC<HiddenLayerConfiguratorProps> = ({
       'activation function': layer.activationFunctionType,
       'weight initializer': layer.initWeightsFnDefinition,
       'bias initializer': layer.initBiasesFnDefinition,
+      frozen: layer.frozen ?? false,
+      'learning rate multiplier': layer.learningRateMultiplier ?? 1,
     }),
     [
       layer.activationFunctionType,
+      layer.frozen,
       layer.initBiasesFnDefinition,
       layer.initWeightsFnDefinition,
+      layer.learningRateMultiplier,
       layer.neuronCount,
     ]
   );
@@ -131,6 +149,19 @@ const HiddenLayerConfigurator: React.FC<HiddenLayerConfiguratorProps> = ({
             newDef.initBiasesFnDefinition = +val;
             break;
           }
+          case 'frozen': {
+            newDef.frozen = !!val;
+            onTrainingChange(newDef.frozen, newDef.learningRateMultiplier ?? 1);
+            break;
+          }
+          case 'learning rate multiplier': {
+            if (Number.isNaN(+val)) {
+              return;
+            }
+            newDef.learningRateMultiplier = +val;
+            onTrainingChange(newDef.frozen ?? false, newDef.learningRateMultiplier);
+            break;
+          }
           default: {
             console.error('Unhandled key in `HiddenLayerConfigurator`: ', key);
           }
@@ -291,6 +322,9 @@ const NetworkConfigurator: React.FC<NetworkConfiguratorProps> = ({
                   R.set(R.lensPath(['hiddenLayers', layerIx]), newHiddenLayer, definition)
                 );
               }}
+              onTrainingChange={(frozen, learningRateMultiplier) =>
+                nnCtx.setHiddenLayerTraining(layerIx, frozen, learningRateMultiplier)
+              }
               onDelete={() => {
                 if (definition.hiddenLayers.length === 1) {
                   return;
@@ -232,7 +232,8 @@ const buildSettings = (
     costs: Float32Array | number[] | null;
   }) => void,
   viewportWidth: number,
-  onTrain1mmStart: () => void
+  onTrain1mmStart: () => void,
+  setSparsity: (sparsity: number[]) => void
 ) => [
   {
     type: 'select',
@@ -284,6 +285,9 @@ const buildSettings = (
       );
       nnCtx.isRunning = true;
       const costs = await nnCtx.trainWithSourceFunction(sourceFn, 1_000, [0, 1]);
+      if (!costs) {
+        return;
+      }
 
       const responseMatrix = await nnCtx.computeResponseMatrix(RESPONSE_VIZ_RESOLUTION, [0, 1]);
       setOutputData({ responseMatrix, costs });
@@ -293,6 +297,10 @@ const buildSettings = (
       const batchCosts: Float32Array[] = [];
       for (let i = 0; i < iters; i++) {
         const costs = await nnCtx.trainWithSourceFunction(sourceFn, batchSize, [0, 1]);
+        if (!costs) {
+          updateViz();
+          return;
+        }
         batchCosts.push(costs);
 
         if (i % 3 === 0 || i === iters - 1) {
@@ -324,6 +332,9 @@ const buildSettings = (
       getSentry()?.captureMessage('Train 1k button clicked');
       nnCtx.isRunning = true;
       const costs = await nnCtx.trainWithSourceFunction(sourceFn, 1_000, [0, 1]);
+      if (!costs) {
+        return;
+      }
 
       const responseMatrix = await nnCtx.computeResponseMatrix(RESPONSE_VIZ_RESOLUTION, [0, 1]);
       setOutputData({ responseMatrix, costs });
@@ -347,6 +358,9 @@ const buildSettings = (
       getSentry()?.captureMessage('Train 1 example button clicked');
       nnCtx.isRunning = true;
       const costs = await nnCtx.trainWithSourceFunction(sourceFn, 1, [0, 1]);
+      if (!costs) {
+        return;
+      }
 
       const responseMatrix = await nnCtx.computeResponseMatrix(RESPONSE_VIZ_RESOLUTION, [0, 1]);
       setOutputData({ responseMatrix, costs });
@@ -354,6 +368,24 @@ const buildSettings = (
       updateViz();
     },
   },
+  {
+    type: 'button',
+    label: 'prune 20% of weights',
+    action: async () => {
+      if (nnCtx.isRunning || !(await nnCtx.getIsInitialized())) {
+        return;
+      }
+
+      getSentry()?.captureMessage('Prune weights button clicked');
+      const sparsity = await nnCtx.pruneWeights(0.2);
+      setSparsity(Array.from(sparsity));
+
+      // Pruning changes the network's outputs without adding any costs
+      const responseMatrix = await nnCtx.computeResponseMatrix(RESPONSE_VIZ_RESOLUTION, [0, 1]);
+      setOutputData({ responseMatrix, costs: new Float32Array() });
+      updateViz();
+    },
+  },
 ];
 
 type OutputDataAction = {
@@ -366,6 +398,25 @@ const outputDataReducer = (state: OutputData, action: OutputDataAction): OutputD
   costs: action.costs ? [...state.costs, ...action.costs] : [],
 });
 
+interface LayerSparsityProps {
+  /**
+   * The fraction of each layer's weights that are pruned, with the output layer last
+   */
+  sparsity: number[];
+}
+
+const LayerSparsity: React.FC<LayerSparsityProps> = ({ sparsity }) => (
+  <div className='layer-sparsity'>
+    <span>Weights pruned:</span>
+    {sparsity.map((layerSparsity, layerIx) => (
+      <span key={layerIx}>
+        {layerIx === sparsity.length - 1 ? 'output' : `layer ${layerIx + 1}`}:{' '}
+        {(layerSparsity * 100).toFixed(1)}%
+      </span>
+    ))}
+  </div>
+);
+
 const RuntimeControls: React.FC<RuntimeControlsProps> = ({
   nnCtx,
   isConstrainedLayout,
@@ -383,9 +434,18 @@ const RuntimeControls: React.FC<RuntimeControlsProps> = ({
     sourceFn: buildSourceFn((window as any).defaultTargetFunction ?? SourceFnType.ComplexFancy),
   });
   const setOutputData = useCallback((action: OutputDataAction) => dispatchOutputData(action), []);
+  const [sparsity, setSparsity] = useState<number[] | null>(null);
   const viewportWidth = useWindowSize().width;
   const settings = useMemo(
-    () => buildSettings(nnCtx, sourceFn, setOutputData, viewportWidth, () => setExpanded(true)),
+    () =>
+      buildSettings(
+        nnCtx,
+        sourceFn,
+        setOutputData,
+        viewportWidth,
+        () => setExpanded(true),
+        setSparsity
+      ),
     [nnCtx, setExpanded, setOutputData, sourceFn, viewportWidth]
   );
 
@@ -425,6 +485,7 @@ const RuntimeControls: React.FC<RuntimeControlsProps> = ({
         settings={settings}
         onChange={(_key: string, val: any) => setSourceFn({ sourceFn: buildSourceFn(+val) })}
       />
+      {sparsity ? <LayerSparsity sparsity={sparsity} /> : null}
       <OutputDataDisplay
         nnCtx={nnCtx}
         sourceFn={sourceFn}
@@ -55,7 +55,7 @@ export class NNWorkerCtx {
       def.outputLayer.initWeightsFnDefinition
     );
     this.hiddenLayerCount = def.hiddenLayers.length;
-    this.ctxPtr = this.engine.create_nn_ctx(
+    const ctxPtr = this.engine.create_nn_ctx(
       def.inputLayer.neuronCount,
       def.outputLayer.neuronCount,
       def.hiddenLayers.length,
@@ -66,6 +66,15 @@ export class NNWorkerCtx {
       outputLayerWeightInitParts.args[0],
       outputLayerWeightInitParts.args[1]
     );
+    def.hiddenLayers.forEach((hiddenLayer, layerIx) =>
+      this.engine.set_hidden_layer_training(
+        ctxPtr,
+        layerIx,
+        hiddenLayer.frozen ?? false,
+        hiddenLayer.learningRateMultiplier ?? 1
+      )
+    );
+    this.ctxPtr = ctxPtr;
   }
 
   public init(def: NeuralNetworkDefinition | null) {
@@ -89,12 +98,6 @@ export class NNWorkerCtx {
   }
 
   public computeResponseMatrix(steps: number, inputRange: [number, number]): Float32Array {
-    const responseMatrix = new Float32Array(
-      steps *
-        steps *
-        (this.definition.inputLayer.neuronCount + this.definition.outputLayer.neuronCount)
-    );
-
     if (
       this.definition.inputLayer.neuronCount !== 2 ||
       this.definition.outputLayer.neuronCount !== 1
@@ -105,28 +108,12 @@ export class NNWorkerCtx {
       throw new UnreachableException('Not initialized');
     }
 
-    const range = inputRange[1] - inputRange[0];
-    const stepSize = range / steps;
-    let i = 0;
-    for (let aStepIx = 0; aStepIx < steps; aStepIx++) {
-      const a = aStepIx * stepSize + inputRange[0];
-      const example = new Float32Array([a, 0]);
-      const outputs = this.engine.predict_batch(
-        this.ctxPtr,
-        example,
-        1,
-        inputRange[0],
-        inputRange[1],
-        steps
-      );
-      outputs.forEach((c, bStepIx) => {
-        const b = bStepIx * stepSize + inputRange[0];
-        responseMatrix[i] = a;
-        responseMatrix[i + 1] = b;
-        responseMatrix[i + 2] = c;
-        i += 3;
-      });
-    }
+    const responseMatrix = this.engine.compute_response_matrix(
+      this.ctxPtr,
+      steps,
+      inputRange[0],
+      inputRange[1]
+    );
 
     return Comlink.transfer(responseMatrix, [responseMatrix.buffer]);
   }
@@ -150,13 +137,19 @@ export class NNWorkerCtx {
   public getVizData(
     example: Float32Array,
     selectedNeuron: { layerIx: number | 'init_output'; neuronIx: number } | null,
-    vizScaleMultiplier: number
+    vizScaleMultiplier: number,
+    highlightPrunedWeights: boolean
   ) {
     if (!this.ctxPtr) {
       return null;
     }
 
-    this.engine.update_viz(this.ctxPtr, example, vizScaleMultiplier);
+    try {
+      this.engine.update_viz(this.ctxPtr, example, vizScaleMultiplier);
+    } catch (err) {
+      console.error('Failed to update layer viz: ', err);
+      return null;
+    }
 
     const hiddenLayerColors = [];
     for (let i = 0; i < this.hiddenLayerCount; i++) {
@@ -168,7 +161,8 @@ export class NNWorkerCtx {
       ? this.engine.get_input_weights_for_next_layer(
           this.ctxPtr,
           selectedNeuron.layerIx === 'init_output' ? -1 : selectedNeuron.layerIx,
-          selectedNeuron.neuronIx
+          selectedNeuron.neuronIx,
+          highlightPrunedWeights
         )
       : null;
 
@@ -185,6 +179,35 @@ export class NNWorkerCtx {
     );
   }
 
+  /**
+   * Prunes `fraction` of the remaining weights with the smallest magnitudes.  Returns the fraction
+   * of each layer's weights that are pruned afterwards, with the output layer last.
+   */
+  public pruneWeights(fraction: number): Float32Array {
+    if (!this.ctxPtr) {
+      throw new UnreachableException('Not initialized');
+    }
+
+    return this.engine.prune_weights(this.ctxPtr, fraction);
+  }
+
+  /**
+   * Freezes or unfreezes a hidden layer and sets the multiplier applied to the learning rate when
+   * updating it.  Applies to the running network without rebuilding it.
+   */
+  public setHiddenLayerTraining(layerIx: number, frozen: boolean, learningRateMultiplier: number) {
+    const hiddenLayer = this.definition.hiddenLayers[layerIx];
+    if (hiddenLayer) {
+      hiddenLayer.frozen = frozen;
+      hiddenLayer.learningRateMultiplier = learningRateMultiplier;
+    }
+    if (!this.ctxPtr) {
+      return;
+    }
+
+    this.engine.set_hidden_layer_training(this.ctxPtr, layerIx, frozen, learningRateMultiplier);
+  }
+
   public getNeuronResponse(layerIx: number, neuronIx: number, size: number) {
     if (!this.ctxPtr) {
       return null;
@@ -92,6 +92,15 @@ export interface HiddenLayerDefinition {
   activationFunctionType: ActivationFunctionType;
   initWeightsFnDefinition: ValueInitializerType;
   initBiasesFnDefinition: ValueInitializerType;
+  /**
+   * Frozen layers still pass errors back to earlier layers but don't update their own weights or
+   * biases.  Defaults to `false`.
+   */
+  frozen?: boolean;
+  /**
+   * Scales the learning rate used when updating this layer.  Defaults to 1.
+   */
+  learningRateMultiplier?: number;
 }
 
 export interface OutputLayerDefinition {